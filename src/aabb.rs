@@ -0,0 +1,126 @@
+use na::{Point3, Vector3};
+use crate::Float;
+use crate::interval::Interval;
+use crate::Ray;
+use crate::utils::INF;
+
+// Axis aligned bounding box. The default box is empty, min above max, and becomes the other box
+// in a union.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<Float>,
+    pub max: Point3<Float>,
+}
+
+impl Default for Aabb {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl Aabb {
+    // Box spanned by two opposite corners in any order
+    pub fn new(a: Point3<Float>, b: Point3<Float>) -> Self {
+        Self { min: a.inf(&b), max: a.sup(&b) }
+    }
+
+    pub fn empty() -> Self {
+        Self { min: Point3::new(INF, INF, INF), max: Point3::new(-INF, -INF, -INF) }
+    }
+
+    pub fn from_points<'a>(points: impl IntoIterator<Item = &'a Point3<Float>>) -> Self {
+        points.into_iter().fold(Self::empty(), |aabb, p| aabb.union(&Self::new(*p, *p)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|axis| self.axis(axis).is_empty())
+    }
+
+    // Extent along x, y or z as 0, 1 or 2
+    pub fn axis(&self, axis: usize) -> Interval {
+        Interval::new(self.min[axis], self.max[axis])
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb { min: self.min.inf(&other.min), max: self.max.sup(&other.max) }
+    }
+
+    pub fn center(&self) -> Point3<Float> {
+        na::center(&self.min, &self.max)
+    }
+
+    // Size along each axis, zero for an empty box
+    pub fn extent(&self) -> Vector3<Float> {
+        if self.is_empty() { Vector3::zeros() } else { self.max - self.min }
+    }
+
+    // Whether the ray passes through the box somewhere in trange, with the slab test
+    pub fn hit(&self, ray: &Ray, trange: Interval) -> bool {
+        let (mut near, mut far) = (trange.min, trange.max);
+        for axis in 0..3 {
+            let inverse = 1.0 / ray.dir[axis];
+            let t0 = (self.min[axis] - ray.orig[axis]) * inverse;
+            let t1 = (self.max[axis] - ray.orig[axis]) * inverse;
+            let (t0, t1) = if inverse < 0.0 { (t1, t0) } else { (t0, t1) };
+            // max and min skip the NaN of a ray in the plane of a side
+            near = near.max(t0);
+            far = far.min(t1);
+            if far < near {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn corners(&self) -> [Point3<Float>; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Point3::new(a.x, a.y, a.z), Point3::new(b.x, a.y, a.z), Point3::new(a.x, b.y, a.z), Point3::new(b.x, b.y, a.z),
+            Point3::new(a.x, a.y, b.z), Point3::new(b.x, a.y, b.z), Point3::new(a.x, b.y, b.z), Point3::new(b.x, b.y, b.z),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na::{point, vector};
+    use crate::aabb::Aabb;
+    use crate::interval::Interval;
+    use crate::Ray;
+    use crate::utils::INF;
+
+    #[test]
+    fn test_union() {
+        let empty = Aabb::empty();
+        assert!(empty.is_empty());
+        assert_eq!(empty.extent(), vector![0.0, 0.0, 0.0]);
+
+        let a = Aabb::new(point![1.0, 0.0, 0.0], point![0.0, 1.0, 1.0]);
+        assert_eq!(a.min, point![0.0, 0.0, 0.0]);
+        assert_eq!(a.axis(0), Interval::new(0.0, 1.0));
+        assert_eq!(empty.axis(2), Interval::EMPTY);
+        assert_eq!(empty.union(&a), a);
+        let b = a.union(&Aabb::new(point![-1.0, 0.5, 0.5], point![-1.0, 0.5, 0.5]));
+        assert_eq!((b.min, b.max), (point![-1.0, 0.0, 0.0], point![1.0, 1.0, 1.0]));
+        assert_eq!(b.center(), point![0.0, 0.5, 0.5]);
+
+        let points = [point![2.0, -1.0, 0.0], point![0.0, 3.0, -2.0]];
+        assert_eq!(Aabb::from_points(&points), Aabb::new(points[0], points[1]));
+        assert!(b.corners().iter().all(|c| c.x.abs() == 1.0));
+    }
+
+    #[test]
+    fn test_hit() {
+        let aabb = Aabb::new(point![-1.0, -1.0, -1.0], point![1.0, 1.0, 1.0]);
+        let towards = Ray::new(point![0.5, 0.5, 5.0], vector![0.0, 0.0, -1.0]);
+        assert!(aabb.hit(&towards, Interval::new(0.0, INF)));
+        // Too short, pointing away or passing by
+        assert!(!aabb.hit(&towards, Interval::new(0.0, 3.0)));
+        assert!(!aabb.hit(&Ray::new(point![0.5, 0.5, 5.0], vector![0.0, 0.0, 1.0]), Interval::new(0.0, INF)));
+        assert!(!aabb.hit(&Ray::new(point![0.5, 2.0, 5.0], vector![0.0, 0.0, -1.0]), Interval::new(0.0, INF)));
+        // From inside, and diagonally through an edge
+        assert!(aabb.hit(&Ray::new(point![0.0, 0.0, 0.0], vector![1.0, 2.0, 3.0]), Interval::new(0.0, INF)));
+        assert!(aabb.hit(&Ray::new(point![-3.0, 0.0, -3.0], vector![1.0, 0.0, 1.0]), Interval::new(0.0, INF)));
+        assert!(!Aabb::empty().hit(&towards, Interval::UNIVERSE));
+    }
+}
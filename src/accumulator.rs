@@ -0,0 +1,448 @@
+use std::io::{BufWriter, Error, ErrorKind, Read, Result, Write};
+use na::Vector3;
+use crate::Float;
+use crate::image::{DimensionMismatch, PPM};
+use crate::RGB;
+use crate::tonemap::ToneMap;
+
+// Unaveraged sample sums and sample counts per pixel. Accumulators of the same image can be
+// merged, e.g. to combine renders made with different sample ranges on different machines.
+#[derive(Clone)]
+pub struct Accumulator {
+    width: usize,
+    height: usize,
+    sums: Vec<Vector3<Float>>,
+    counts: Vec<u32>,
+}
+
+impl Accumulator {
+    pub fn new(w: usize, h: usize) -> Self {
+        Self {
+            width: w,
+            height: h,
+            sums: vec![Vector3::zeros(); w * h],
+            counts: vec![0; w * h],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn add(&mut self, i: usize, j: usize, sum: Vector3<Float>, count: u32) {
+        let idx = i * self.width + j;
+        self.sums[idx] += sum;
+        self.counts[idx] += count;
+    }
+
+    pub fn add_sample(&mut self, i: usize, j: usize, color: RGB) {
+        self.add(i, j, Vector3::new(color.0, color.1, color.2), 1);
+    }
+
+    // Drops all samples, keeping the buffers
+    pub fn clear(&mut self) {
+        self.sums.fill(Vector3::zeros());
+        self.counts.fill(0);
+    }
+
+    pub fn sum(&self, i: usize, j: usize) -> Vector3<Float> {
+        self.sums[i * self.width + j]
+    }
+
+    pub fn count(&self, i: usize, j: usize) -> u32 {
+        self.counts[i * self.width + j]
+    }
+
+    pub fn merge(&mut self, other: &Accumulator) -> std::result::Result<(), DimensionMismatch> {
+        if (self.width, self.height) != (other.width, other.height) {
+            return Err(DimensionMismatch {
+                expected: (self.width, self.height),
+                found: (other.width, other.height),
+            });
+        }
+
+        for (sum, other) in self.sums.iter_mut().zip(&other.sums) {
+            *sum += other;
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        Ok(())
+    }
+
+    // Average of every pixel, pixels without samples are black
+    pub fn average(&self, i: usize, j: usize) -> RGB {
+        let idx = i * self.width + j;
+        if self.counts[idx] == 0 {
+            return RGB::default();
+        }
+        RGB::from(self.sums[idx]) * (1.0 / self.counts[idx] as Float)
+    }
+
+    // Develops the accumulated samples into an image, tone mapping and exposure are set on the result
+    pub fn to_ppm(&self) -> PPM {
+        let mut image = PPM::new(self.width, self.height);
+        for i in 0..self.height {
+            for j in 0..self.width {
+                image[(i, j)] = self.average(i, j);
+            }
+        }
+        image
+    }
+
+    // to_ppm with the display settings for saving: exposure in stops, tone mapping and gamma
+    pub fn develop(&self, exposure_ev: Float, tonemap: ToneMap, gamma: Float) -> PPM {
+        let mut image = self.to_ppm();
+        image.set_exposure_ev(exposure_ev);
+        image.set_tonemap(tonemap);
+        image.set_gamma(gamma);
+        image
+    }
+
+    // Sample sums as a color PFM (32-bit floats, little endian, rows stored bottom-up)
+    pub fn save_pfm(&self, writer: &mut dyn Write) -> Result<()> {
+        let mut contents = BufWriter::new(writer);
+        write!(contents, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for i in (0..self.height).rev() {
+            for sum in &self.sums[i * self.width..(i + 1) * self.width] {
+                for v in sum.iter() {
+                    #[allow(clippy::unnecessary_cast)] // Float is f32 already with the f32 feature
+                    contents.write_all(&(*v as f32).to_le_bytes())?;
+                }
+            }
+        }
+        contents.flush()
+    }
+
+    // Sample counts as a grayscale PFM sidecar, exact for counts up to 2^24
+    pub fn save_counts(&self, writer: &mut dyn Write) -> Result<()> {
+        let mut contents = BufWriter::new(writer);
+        write!(contents, "Pf\n{} {}\n-1.0\n", self.width, self.height)?;
+        for i in (0..self.height).rev() {
+            for count in &self.counts[i * self.width..(i + 1) * self.width] {
+                contents.write_all(&(*count as f32).to_le_bytes())?;
+            }
+        }
+        contents.flush()
+    }
+
+    pub fn load_pfm(sums: &mut dyn Read, counts: &mut dyn Read) -> Result<Self> {
+        let (width, height, sum_values) = read_pfm(sums, 3)?;
+        let (count_width, count_height, count_values) = read_pfm(counts, 1)?;
+        if (width, height) != (count_width, count_height) {
+            return Err(Error::new(ErrorKind::InvalidData, "sample counts do not match the image size"));
+        }
+
+        let mut accumulator = Accumulator::new(width, height);
+        for (idx, sum) in sum_values.chunks(3).enumerate() {
+            accumulator.sums[idx] = Vector3::new(sum[0] as Float, sum[1] as Float, sum[2] as Float);
+        }
+        for (idx, count) in count_values.iter().enumerate() {
+            if *count < 0.0 || count.fract() != 0.0 {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid sample count {}", count)));
+            }
+            accumulator.counts[idx] = *count as u32;
+        }
+        Ok(accumulator)
+    }
+}
+
+// The same samples developed at every exposure, to pick one after rendering. The images only
+// differ in the exposure applied before tone mapping.
+pub fn develop_bracket(accumulator: &Accumulator, evs: &[Float], tonemap: ToneMap, gamma: Float) -> Vec<PPM> {
+    evs.iter().map(|&ev| accumulator.develop(ev, tonemap, gamma)).collect()
+}
+
+// Mean and sample variance of a stream of values by Welford's method, which keeps its precision
+// where the sum of squares less the squared sum would cancel out
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RunningVariance {
+    count: u32,
+    mean: Float,
+    m2: Float, // Sum of squared differences from the mean
+}
+
+impl RunningVariance {
+    pub fn add(&mut self, value: Float) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as Float;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Float {
+        self.mean
+    }
+
+    // Unbiased sample variance, zero with fewer than two values
+    pub fn variance(&self) -> Float {
+        if self.count < 2 { 0.0 } else { self.m2 / (self.count - 1) as Float }
+    }
+
+    // How far the mean is likely off from the true one
+    pub fn standard_error(&self) -> Float {
+        if self.count == 0 { 0.0 } else { (self.variance() / self.count as Float).sqrt() }
+    }
+}
+
+// Reads a PFM file into top-down rows of floats
+fn read_pfm(reader: &mut dyn Read, channels: usize) -> Result<(usize, usize, Vec<f32>)> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("invalid PFM: {}", msg));
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+
+    // Header is three whitespace separated tokens lines followed by a single whitespace byte
+    let mut tokens = vec![];
+    let mut pos = 0;
+    while tokens.len() < 4 {
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(invalid("truncated header"));
+        }
+        tokens.push(String::from_utf8_lossy(&bytes[start..pos]).to_string());
+    }
+    pos += 1;
+
+    let expected_magic = if channels == 3 { "PF" } else { "Pf" };
+    if tokens[0] != expected_magic {
+        return Err(invalid(&format!("expected {} but found {}", expected_magic, tokens[0])));
+    }
+    let width: usize = tokens[1].parse().map_err(|_| invalid("bad width"))?;
+    let height: usize = tokens[2].parse().map_err(|_| invalid("bad height"))?;
+    let scale: f32 = tokens[3].parse().map_err(|_| invalid("bad scale"))?;
+
+    // The sizes come from the file, a huge one mustn't wrap around into a small one
+    let len = width.checked_mul(height).and_then(|pixels| pixels.checked_mul(channels)).ok_or_else(|| invalid("image too large"))?;
+    let end = len.checked_mul(4).and_then(|data_len| data_len.checked_add(pos)).ok_or_else(|| invalid("image too large"))?;
+    if len == 0 {
+        return Ok((width, height, vec![]));
+    }
+    if bytes.len() < end {
+        return Err(invalid("truncated pixel data"));
+    }
+
+    let mut values = vec![0.0; len];
+    let row_len = width * channels;
+    for (row, data) in bytes[pos..end].chunks(row_len * 4).enumerate() {
+        let i = height - 1 - row;
+        for (k, v) in data.chunks(4).enumerate() {
+            let v: [u8; 4] = v.try_into().unwrap();
+            values[i * row_len + k] = if scale < 0.0 { f32::from_le_bytes(v) } else { f32::from_be_bytes(v) };
+        }
+    }
+    Ok((width, height, values))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use na::{point, Vector3};
+    use crate::accumulator::{develop_bracket, Accumulator, RunningVariance};
+    use crate::camera::Camera;
+    use crate::Float;
+    use crate::image::{Image, PPM};
+    use crate::material::{Dielectric, Lambertian, Metal};
+    use crate::RGB;
+    use crate::scene::{Scene, Sphere};
+    use crate::tonemap::ToneMap;
+    use crate::utils::tolerance;
+
+    fn scene() -> Arc<Scene> {
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            center: point![0.0, -100.5, -1.0],
+            radius: 100.0,
+            material: Lambertian::new(RGB(0.8, 0.8, 0.0)).into()
+        });
+        scene.add(Sphere {
+            center: point![-0.5, 0.0, -1.0],
+            radius: 0.5,
+            material: Dielectric::new(1.5).into()
+        });
+        scene.add(Sphere {
+            center: point![0.5, 0.0, -1.0],
+            radius: 0.5,
+            material: Metal::new(RGB(0.8, 0.6, 0.2), 0.3).into()
+        });
+        Arc::new(scene)
+    }
+
+    fn camera(samples: u32) -> Camera {
+        Camera::builder()
+            .width(12)
+            .aspect_ratio(1.5)
+            .samples_per_pixel(samples)
+            .fov(60.0)
+            .look_from(point![0.0, 0.0, 1.0])
+            .look_at(point![0.0, 0.0, -1.0])
+            .defocus_angle(2.0)
+            .focus_dist(2.0)
+            .seed(7)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_merged_sample_ranges_match_single_render() {
+        let scene = scene();
+        let full = camera(20).renderer().render_samples(scene.clone(), 0..20);
+        let mut first = camera(10).renderer().render_samples(scene.clone(), 0..10);
+        let second = camera(10).renderer().render_samples(scene.clone(), 10..20);
+        first.merge(&second).unwrap();
+
+        for i in 0..full.height() {
+            for j in 0..full.width() {
+                assert_eq!(first.count(i, j), 20);
+                assert_eq!(full.count(i, j), 20);
+                assert!((first.sum(i, j) - full.sum(i, j)).norm() < tolerance(64.0) * full.sum(i, j).norm().max(1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_develop_bracket() {
+        let mut accumulator = Accumulator::new(8, 2);
+        for j in 0..8 {
+            // Two samples each, linear values from 0.1 to 0.45 in the top row and 0.6 to 0.95 below
+            let value = 0.1 + 0.05 * j as Float;
+            accumulator.add(0, j, Vector3::repeat(2.0 * value), 2);
+            accumulator.add(1, j, Vector3::repeat(2.0 * (value + 0.5)), 2);
+        }
+        let bracket = develop_bracket(&accumulator, &[-2.0, 0.0, 1.0], ToneMap::Clamp, 2.2);
+        assert_eq!(bracket.iter().map(|image| image.exposure_ev()).collect::<Vec<_>>(), vec![-2.0, 0.0, 1.0]);
+        for image in &bracket {
+            assert_eq!((image.tonemap(), image.gamma()), (ToneMap::Clamp, 2.2));
+            assert!(image.pixels().iter().zip(bracket[0].pixels()).all(|(a, b)| a.0 == b.0));
+        }
+
+        // Saved and read back with the gamma undone, +1 EV is twice the light where it isn't clipped
+        let linear: Vec<PPM> = bracket.iter().map(|image| {
+            let mut bytes = vec![];
+            image.save_binary(&mut bytes).unwrap();
+            PPM::load_with_gamma(&mut &bytes[..], 2.2).unwrap()
+        }).collect();
+        for j in 0..8 {
+            let (zero, plus) = (linear[1][(0, j)].0, linear[2][(0, j)].0);
+            assert!((plus / zero - 2.0).abs() < 0.1, "{}: {} {}", j, zero, plus);
+            assert!((linear[0][(0, j)].0 / zero - 0.25).abs() < 0.03, "{}", j);
+            // Everything in the bottom row is white at +1 EV
+            assert_eq!(linear[2][(1, j)].0, 1.0);
+        }
+    }
+
+    #[test]
+    fn test_running_variance() {
+        let mut stats = RunningVariance::default();
+        assert_eq!((stats.mean(), stats.variance(), stats.standard_error()), (0.0, 0.0, 0.0));
+        stats.add(3.0);
+        assert_eq!((stats.mean(), stats.variance()), (3.0, 0.0));
+
+        // Mean 5, squared differences add up to 32 over 8 - 1 degrees of freedom
+        let mut stats = RunningVariance::default();
+        for value in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+            stats.add(value);
+        }
+        assert_eq!(stats.count(), 8);
+        assert!((stats.mean() - 5.0).abs() < 1e-12);
+        assert!((stats.variance() - 32.0 / 7.0).abs() < 1e-12);
+        assert!((stats.standard_error() - (32.0 / 7.0 / 8.0 as Float).sqrt()).abs() < 1e-12);
+
+        // A large offset doesn't swallow a small spread
+        let mut stats = RunningVariance::default();
+        for value in [1e4 + 1.0, 1e4 + 2.0, 1e4 + 3.0] {
+            stats.add(value);
+        }
+        assert!((stats.variance() - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_render_equals_accumulator() {
+        let scene = scene();
+        let image = camera(4).renderer().render_parallel(scene.clone());
+        let developed = camera(4).renderer().render_samples(scene, 0..4).to_ppm();
+        assert_eq!(image.to_rgba8(), developed.to_rgba8());
+    }
+
+    #[test]
+    fn test_merge_dimension_check() {
+        let mut a = Accumulator::new(3, 2);
+        let b = Accumulator::new(2, 3);
+        let err = a.merge(&b).unwrap_err();
+        assert_eq!(err.expected, (3, 2));
+        assert_eq!(err.found, (2, 3));
+    }
+
+    #[test]
+    fn test_pfm_round_trip() {
+        let mut accumulator = Accumulator::new(3, 2);
+        accumulator.add(0, 0, Vector3::new(1.5, 0.25, 1e-3), 3);
+        accumulator.add(0, 2, Vector3::new(1e6, 7.0, 0.1), 12);
+        accumulator.add(1, 1, Vector3::new(-0.0, 2.5, 1e-20), 1);
+
+        let mut sums = vec![];
+        let mut counts = vec![];
+        accumulator.save_pfm(&mut sums).unwrap();
+        accumulator.save_counts(&mut counts).unwrap();
+        assert!(sums.starts_with(b"PF\n3 2\n-1.0\n"));
+        assert!(counts.starts_with(b"Pf\n3 2\n-1.0\n"));
+
+        let loaded = Accumulator::load_pfm(&mut &sums[..], &mut &counts[..]).unwrap();
+        for i in 0..2 {
+            for j in 0..3 {
+                #[allow(clippy::unnecessary_cast)]
+                let expected = accumulator.sum(i, j).map(|v| v as f32 as Float);
+                assert_eq!(loaded.sum(i, j), expected);
+                assert_eq!(loaded.count(i, j), accumulator.count(i, j));
+            }
+        }
+
+        let mut resaved = vec![];
+        loaded.save_pfm(&mut resaved).unwrap();
+        assert_eq!(resaved, sums);
+    }
+
+    #[test]
+    fn test_pfm_errors() {
+        let accumulator = Accumulator::new(2, 2);
+        let mut sums = vec![];
+        let mut counts = vec![];
+        accumulator.save_pfm(&mut sums).unwrap();
+        accumulator.save_counts(&mut counts).unwrap();
+
+        assert!(Accumulator::load_pfm(&mut &sums[..sums.len() - 1], &mut &counts[..]).is_err());
+        assert!(Accumulator::load_pfm(&mut &counts[..], &mut &sums[..]).is_err());
+        assert!(Accumulator::load_pfm(&mut &b"PF\n2"[..], &mut &counts[..]).is_err());
+
+        // Sizes that overflow when multiplied out
+        let huge = format!("PF\n{} {}\n-1.0\n", usize::MAX / 2, 3);
+        let err = Accumulator::load_pfm(&mut huge.as_bytes(), &mut &counts[..]).err().unwrap();
+        assert!(err.to_string().contains("too large"), "{}", err);
+    }
+
+    #[test]
+    fn test_pfm_empty() {
+        for (width, height) in [(0, 3), (4, 0)] {
+            let accumulator = Accumulator::new(width, height);
+            let mut sums = vec![];
+            let mut counts = vec![];
+            accumulator.save_pfm(&mut sums).unwrap();
+            accumulator.save_counts(&mut counts).unwrap();
+            let loaded = Accumulator::load_pfm(&mut &sums[..], &mut &counts[..]).unwrap();
+            assert_eq!((loaded.width(), loaded.height()), (width, height));
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use na::Point3;
+use crate::camera::{Camera, CameraBuilder, CameraError, Renderer};
+use crate::Float;
+use crate::image::PPM;
+use crate::scene::Scene;
+use crate::utils::hash_seed;
+
+// Camera placement at a moment of the animation
+#[derive(Copy, Clone, Debug)]
+pub struct Keyframe {
+    pub time: Float,
+    pub lookfrom: Point3<Float>,
+    pub lookat: Point3<Float>,
+    pub fov: Float,
+    pub focus_dist: Float,
+}
+
+// How the camera moves between two keyframes
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Easing {
+    #[default]
+    Linear,
+    // Starts and stops smoothly at every keyframe
+    SmoothStep,
+}
+
+// Camera path through keyframes, everything not animated comes from the camera builder
+pub struct Animation {
+    pub camera: CameraBuilder,
+    pub easing: Easing,
+    pub seed: u64, // Every frame gets its own seed derived from this one
+    keyframes: Vec<Keyframe>,
+}
+
+impl Animation {
+    pub fn new(camera: CameraBuilder, mut keyframes: Vec<Keyframe>) -> Self {
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Self { camera, easing: Easing::Linear, seed: 0, keyframes }
+    }
+
+    // Camera placement at any time, held constant before the first and after the last keyframe
+    pub fn keyframe_at(&self, time: Float) -> Option<Keyframe> {
+        let first = self.keyframes.first()?;
+        let last = self.keyframes.last()?;
+        if time <= first.time {
+            return Some(*first);
+        }
+        if time >= last.time {
+            return Some(*last);
+        }
+
+        let next = self.keyframes.partition_point(|k| k.time <= time);
+        let (a, b) = (self.keyframes[next - 1], self.keyframes[next]);
+        let mut s = (time - a.time) / (b.time - a.time);
+        if self.easing == Easing::SmoothStep {
+            s = s * s * (3.0 - 2.0 * s);
+        }
+        let lerp = |x: Float, y: Float| x + s * (y - x);
+        Some(Keyframe {
+            time,
+            lookfrom: a.lookfrom + s * (b.lookfrom - a.lookfrom),
+            lookat: a.lookat + s * (b.lookat - a.lookat),
+            fov: lerp(a.fov, b.fov),
+            focus_dist: lerp(a.focus_dist, b.focus_dist),
+        })
+    }
+
+    // Time of frame index out of frames, spread evenly from the first to the last keyframe
+    pub fn frame_time(&self, index: usize, frames: usize) -> Float {
+        let (Some(first), Some(last)) = (self.keyframes.first(), self.keyframes.last()) else {
+            return 0.0;
+        };
+        if frames < 2 {
+            return first.time;
+        }
+        first.time + (last.time - first.time) * index as Float / (frames - 1) as Float
+    }
+
+    // Fully initialized camera of a frame, which has its own seed so the noise changes between frames
+    pub fn camera(&self, index: usize, frames: usize) -> std::result::Result<Camera, CameraError> {
+        let mut camera = self.camera.clone().seed(hash_seed(&[self.seed, index as u64]));
+        if let Some(key) = self.keyframe_at(self.frame_time(index, frames)) {
+            camera = camera.look_from(key.lookfrom).look_at(key.lookat).fov(key.fov).focus_dist(key.focus_dist);
+        }
+        camera.build()
+    }
+
+    pub fn render_frame(&self, scene: Arc<Scene>, index: usize, frames: usize) -> Result<Box<PPM>> {
+        Ok(self.renderer(index, frames)?.render_parallel(scene))
+    }
+
+    fn renderer(&self, index: usize, frames: usize) -> Result<Renderer> {
+        let camera = self.camera(index, frames).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        Ok(camera.renderer())
+    }
+
+    // Renders frames PNG files, the run of '#' in the template becomes the frame number
+    // starting at 1, e.g. "out/frame_####.png" gives out/frame_0001.png, out/frame_0002.png, ...
+    pub fn render_frames(&self, scene: Arc<Scene>, frames: usize, template: &str) -> Result<Vec<PathBuf>> {
+        if self.keyframes.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "animation has no keyframes"));
+        }
+
+        // All frames have the same size and go through the same buffer
+        let mut paths = vec![];
+        let mut image: Option<PPM> = None;
+        for index in 0..frames {
+            let path = frame_path(template, index + 1)?;
+            let renderer = self.renderer(index, frames)?;
+            let image = image.get_or_insert_with(|| PPM::new(renderer.width(), renderer.height()));
+            renderer.render_into(scene.clone(), image).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            let mut file = std::fs::File::create(&path)?;
+            image.save_png(&mut file)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+pub fn frame_path(template: &str, number: usize) -> Result<PathBuf> {
+    let end = template.rfind('#')
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("no '#' in path template {}", template)))?;
+    let start = template[..end].rfind(|c| c != '#').map_or(0, |i| i + 1);
+    let width = end + 1 - start;
+    Ok(PathBuf::from(format!("{}{:0width$}{}", &template[..start], number, &template[end + 1..], width = width)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use na::point;
+    use crate::animation::{frame_path, Animation, Easing, Keyframe};
+    use crate::camera::Camera;
+    use crate::Float;
+    use crate::image::Image;
+    use crate::material::Lambertian;
+    use crate::RGB;
+    use crate::scene::{Scene, Sphere};
+
+    fn dolly() -> Animation {
+        let camera = Camera::builder().width(24).samples_per_pixel(4).max_bounces(2).transparent_background(true);
+        let key = |time: Float, z: Float| Keyframe {
+            time,
+            lookfrom: point![0.0, 0.0, z],
+            lookat: point![0.0, 0.0, -1.0],
+            fov: 40.0,
+            focus_dist: 1.0,
+        };
+        Animation::new(camera, vec![key(2.0, 1.0), key(0.0, 6.0)])
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let mut animation = dolly();
+        assert_eq!(animation.keyframe_at(-1.0).unwrap().lookfrom.z, 6.0);
+        assert_eq!(animation.keyframe_at(1.0).unwrap().lookfrom.z, 3.5);
+        assert_eq!(animation.keyframe_at(5.0).unwrap().lookfrom.z, 1.0);
+        assert_eq!(animation.frame_time(1, 5), 0.5);
+
+        animation.easing = Easing::SmoothStep;
+        assert_eq!(animation.keyframe_at(1.0).unwrap().lookfrom.z, 3.5);
+        assert!(animation.keyframe_at(0.5).unwrap().lookfrom.z > 6.0 - 5.0 * 0.25);
+    }
+
+    #[test]
+    fn test_dolly_frames() {
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            center: point![0.0, 0.0, -1.0],
+            radius: 0.5,
+            material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into()
+        });
+        let scene = Arc::new(scene);
+
+        let animation = dolly();
+        let mut footprints = vec![];
+        for index in 0..3 {
+            let image = animation.render_frame(scene.clone(), index, 3).unwrap();
+            let covered = (0..image.height())
+                .flat_map(|i| (0..image.width()).map(move |j| (i, j)))
+                .filter(|&(i, j)| image.alpha(i, j) > 0.5)
+                .count();
+            footprints.push(covered);
+        }
+        assert!(footprints[0] < footprints[1] && footprints[1] < footprints[2], "{:?}", footprints);
+
+        // Frames differ in noise
+        assert_ne!(animation.camera(0, 3).unwrap().seed(), animation.camera(1, 3).unwrap().seed());
+
+        let dir = std::env::temp_dir().join(format!("raytracer_animation_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let template = dir.join("frame_####.png");
+        let paths = animation.render_frames(scene, 3, template.to_str().unwrap()).unwrap();
+        assert_eq!(paths[2], dir.join("frame_0003.png"));
+        assert!(paths.iter().all(|path| path.exists()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_frame_path() {
+        assert_eq!(frame_path("frame_####.png", 1).unwrap().to_str().unwrap(), "frame_0001.png");
+        assert_eq!(frame_path("out#/f_##.png", 123).unwrap().to_str().unwrap(), "out#/f_123.png");
+        assert_eq!(frame_path("#", 7).unwrap().to_str().unwrap(), "7");
+        assert!(frame_path("frame.png", 1).is_err());
+    }
+}
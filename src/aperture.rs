@@ -0,0 +1,192 @@
+use crate::consts::PI;
+use std::sync::Arc;
+use na::{vector, Vector3};
+use crate::Float;
+use crate::utils::{degrees_to_radians, rand, rand_in_unit_disk};
+
+// Shape of the lens opening, out of focus highlights take this shape.
+// Samples are in the plane of the defocus disk, scaled so the shape fits the unit circle.
+#[derive(Clone, Debug, Default)]
+pub enum Aperture {
+    #[default]
+    Circle,
+    // Regular polygon with a corner at rotation degrees from the camera's right axis
+    Polygon { blades: u32, rotation: Float },
+    // Custom shape covering the square around the unit circle
+    Image(Arc<ApertureMask>),
+}
+
+impl Aperture {
+    pub fn sample(&self) -> Vector3<Float> {
+        match self {
+            Aperture::Circle => rand_in_unit_disk(),
+            Aperture::Polygon { blades, rotation } => sample_polygon(*blades, *rotation),
+            Aperture::Image(mask) => mask.sample(),
+        }
+    }
+}
+
+// Grayscale transmission mask, sampled with probability proportional to the pixel values
+#[derive(Debug)]
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    cdf: Vec<Float>, // Running sum of the values, row by row from the top
+}
+
+impl ApertureMask {
+    // Negative values count as zero, at least one value has to be positive
+    pub fn new(width: usize, height: usize, values: &[Float]) -> Option<Self> {
+        if width == 0 || height == 0 || values.len() != width * height {
+            return None;
+        }
+
+        let mut total = 0.0;
+        let cdf: Vec<Float> = values.iter().map(|v| {
+            total += v.max(0.0);
+            total
+        }).collect();
+        if total <= 0.0 {
+            return None;
+        }
+        Some(Self { width, height, cdf })
+    }
+
+    fn sample(&self) -> Vector3<Float> {
+        let target = rand() * self.cdf[self.cdf.len() - 1];
+        let idx = self.cdf.partition_point(|sum| *sum <= target).min(self.cdf.len() - 1);
+        let (row, column) = (idx / self.width, idx % self.width);
+
+        let x = (column as Float + rand()) / self.width as Float;
+        let y = (row as Float + rand()) / self.height as Float;
+        vector![2.0 * x - 1.0, 1.0 - 2.0 * y, 0.0]
+    }
+}
+
+fn polygon_corner(k: u32, blades: u32, rotation: Float) -> Vector3<Float> {
+    let angle = degrees_to_radians(rotation) + 2.0 * PI * k as Float / blades as Float;
+    vector![angle.cos(), angle.sin(), 0.0]
+}
+
+// Uniform over the polygon: all triangles of the fan from the center have the same area
+fn sample_polygon(blades: u32, rotation: Float) -> Vector3<Float> {
+    if blades < 3 {
+        return rand_in_unit_disk();
+    }
+
+    let k = ((rand() * blades as Float) as u32).min(blades - 1);
+    let a = polygon_corner(k, blades, rotation);
+    let b = polygon_corner(k + 1, blades, rotation);
+
+    // Uniform point in the triangle (0, a, b)
+    let (mut s, mut t) = (rand(), rand());
+    if s + t > 1.0 {
+        (s, t) = (1.0 - s, 1.0 - t);
+    }
+    s * a + t * b
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use na::{point, vector};
+    use crate::aperture::{polygon_corner, Aperture, ApertureMask};
+    use crate::camera::Camera;
+    use crate::Float;
+    use crate::material::DiffuseLight;
+    use crate::RGB;
+    use crate::scene::{Scene, Sphere};
+    use crate::utils::seed_rng;
+
+    #[test]
+    fn test_polygon_samples() {
+        seed_rng(1);
+        let (blades, rotation) = (5, 18.0);
+        let aperture = Aperture::Polygon { blades, rotation };
+        let samples: Vec<_> = (0..20_000).map(|_| aperture.sample()).collect();
+
+        // Inside of every edge
+        for k in 0..blades {
+            let a = polygon_corner(k, blades, rotation);
+            let b = polygon_corner(k + 1, blades, rotation);
+            let inward = vector![-(b - a).y, (b - a).x, 0.0];
+            assert!(samples.iter().all(|p| (p - a).dot(&inward) >= -1e-12));
+        }
+
+        // Close to every corner
+        for k in 0..blades {
+            let corner = polygon_corner(k, blades, rotation);
+            assert!(samples.iter().any(|p| (p - corner).norm() < 0.05), "corner {}", k);
+        }
+
+        // Uniform: the inner half-size polygon has a quarter of the area
+        let inner = samples.iter().filter(|p| {
+            (0..blades).all(|k| {
+                let a = 0.5 * polygon_corner(k, blades, rotation);
+                let b = 0.5 * polygon_corner(k + 1, blades, rotation);
+                (*p - a).dot(&vector![-(b - a).y, (b - a).x, 0.0]) >= 0.0
+            })
+        }).count();
+        let fraction = inner as Float / samples.len() as Float;
+        assert!((fraction - 0.25).abs() < 0.02, "{}", fraction);
+    }
+
+    #[test]
+    fn test_mask_samples() {
+        seed_rng(2);
+        // Only the top right quadrant is open
+        let mask = ApertureMask::new(2, 2, &[0.0, 1.0, 0.0, 0.0]).unwrap();
+        let aperture = Aperture::Image(Arc::new(mask));
+        for _ in 0..1000 {
+            let p = aperture.sample();
+            assert!(p.x >= 0.0 && p.x <= 1.0 && p.y >= 0.0 && p.y <= 1.0);
+        }
+
+        assert!(ApertureMask::new(2, 2, &[0.0; 4]).is_none());
+        assert!(ApertureMask::new(2, 2, &[1.0; 3]).is_none());
+    }
+
+    // Lit area of the highlight of a tiny light far in front of the focus plane, relative to the
+    // circle through its farthest lit pixel
+    fn bokeh_fill(aperture: Aperture) -> Float {
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            center: point![0.0, 0.0, -1.0],
+            radius: 0.035,
+            material: DiffuseLight::new(RGB(10.0, 10.0, 10.0)).into()
+        });
+
+        let size = 32;
+        let camera = Camera::builder()
+            .width(size)
+            .samples_per_pixel(1024)
+            .max_bounces(2)
+            .fov(60.0)
+            .defocus_angle(4.0)
+            .focus_dist(10.0)
+            .seed(3)
+            .background(RGB::default())
+            .transparent_background(true)
+            .aperture(aperture)
+            .build()
+            .unwrap();
+        let image = camera.renderer().render_parallel(Arc::new(scene));
+
+        let lit: Vec<(Float, Float)> = (0..size).flat_map(|i| (0..size).map(move |j| (i, j)))
+            .filter(|&(i, j)| image.alpha(i, j) > 0.0)
+            .map(|(i, j)| (i as Float + 0.5, j as Float + 0.5))
+            .collect();
+        let center = size as Float / 2.0;
+        let radius = lit.iter().map(|(y, x)| ((y - center).powi(2) + (x - center).powi(2)).sqrt()).fold(0.0, Float::max) + 0.5;
+        lit.len() as Float / (crate::consts::PI * radius * radius)
+    }
+
+    #[test]
+    fn test_pentagonal_bokeh() {
+        let circle = bokeh_fill(Aperture::Circle);
+        let pentagon = bokeh_fill(Aperture::Polygon { blades: 5, rotation: 90.0 });
+        // A regular pentagon covers 76% of its circumcircle, pixelation lowers both values a bit
+        assert!(circle > 0.83, "circle: {}, pentagon: {}", circle, pentagon);
+        assert!(pentagon < 0.8, "circle: {}, pentagon: {}", circle, pentagon);
+    }
+}
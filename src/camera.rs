@@ -1,232 +1,3728 @@
-use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
-use na::{Point3, vector, Vector3};
-use rayon::prelude::*;
-use crate::image::{PPM};
-use crate::ray::Ray;
+use std::error::Error;
+use crate::consts::PI;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use na::{point, Isometry3, Matrix3, Point3, Rotation3, Unit, UnitQuaternion, vector, Vector3};
+use crate::parallel::prelude::*;
+use crate::parallel::{current_num_threads, ThreadPool, ThreadPoolBuilder};
+use crate::aabb::Aabb;
+use crate::accumulator::{Accumulator, RunningVariance};
+use crate::aperture::Aperture;
+use crate::distortion::LensDistortion;
+use crate::filter::{Filter, MAX_FILTER_RADIUS};
+use crate::Float;
+use crate::image::{resize, DimensionMismatch, FloatImage, Image, PPM, ResizeFilter};
+use crate::interval::Interval;
+use crate::material::Material;
+use crate::path_trace::{write_paths, Bounce, BounceEvent, PathExport, PathTrace};
+use crate::photon::{PhotonMap, PhotonMapSettings};
+use crate::ray::{Ray, RayDifferentials};
 use crate::RGB;
-use crate::scene::{Hittable, Scene};
-use crate::utils::{degrees_to_radians, INF, rand, rand_in_unit_disk};
+use crate::scene::{HitRecord, Hittable, ObjectId, Scene, TraversalStats};
+use crate::scene::desc::{array, CameraDesc};
+use crate::spectral::{WavelengthSampling, Wavelengths};
+use crate::utils::{degrees_to_radians, hash_seed, INF, MIN_T, rand, seed_rng};
+#[cfg(feature = "packets")]
+use crate::ray::packet::{LANES, RayPacket4};
+#[cfg(feature = "packets")]
+use crate::utils::{restore_rng, save_rng};
 
+#[derive(Clone)]
+pub struct Renderer {
+    render_width: usize,
+    render_height: usize,
+    settings: RenderSettings,
+    camera: Arc<Camera>,
+    pool: Option<Arc<ThreadPool>>, // Built from settings.threads
+}
+
+// How an image is rendered, as opposed to the camera's optics which decide what it shows
+#[derive(Copy, Clone, Debug)]
+pub struct RenderSettings {
+    pub samples_per_pixel: u32,
+    pub max_bounces: u32,
+    pub background: Option<RGB>, // Radiance of rays leaving the scene, sky gradient if None
+    pub caustics: Option<PhotonMapSettings>, // Caustic photon map pre-pass, disabled if None
+    pub transparent_background: bool, // Camera rays that miss everything get zero alpha
+    pub aovs: AovFlags, // Extra buffers filled by Renderer::render_output
+    pub seed: Option<u64>, // Makes renders reproducible, random every run if None
+    pub sample_check: SampleCheck,
+    pub threads: Option<usize>, // Size of a pool of the renderer's own, see Renderer::threads
+    // Emitters are split into this many groups with a buffer each in RenderOutput, 0 to disable.
+    // Lights in higher groups than that count towards the last one.
+    pub light_groups: usize,
+    pub mode: RenderMode,
+    // Renderer::render_budgeted stops adding samples after this long, samples_per_pixel is still
+    // the most it renders
+    pub time_budget: Option<Duration>,
+    // Camera rays carry their neighbors in the next pixels, for the texture footprint at their
+    // first hit. Costs two extra plane tests and uv lookups per hit.
+    pub ray_differentials: bool,
+    // How samples are weighted into the pixels around them, the box keeps every sample to its pixel
+    pub filter: Filter,
+    // Paths carry wavelengths instead of RGB colors, drawn like this, see spectral::Wavelengths.
+    // Glass with dispersion splits light into colors, RGB colors are upsampled to spectra.
+    // RGB if None.
+    pub spectral: Option<WavelengthSampling>,
+}
+
+// What the pixels of a render show
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum RenderMode {
+    #[default]
+    Beauty,
+    // False color image of the work it took to find the first hit of one camera ray per pixel,
+    // see RGB::ramp. The ramp goes from the lowest count in the image to max, or to the highest
+    // count if None. Only the beauty image and alpha are filled, the AOVs stay empty.
+    Heatmap { metric: HeatmapMetric, max: Option<u32> },
+}
+
+// What a heatmap counts, see TraversalStats
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum HeatmapMetric {
+    #[default]
+    IntersectionTests,
+    NodeVisits,
+}
+
+impl HeatmapMetric {
+    pub fn count(&self, stats: &TraversalStats) -> u32 {
+        match self {
+            HeatmapMetric::IntersectionTests => stats.intersection_tests,
+            HeatmapMetric::NodeVisits => stats.node_visits,
+        }
+    }
+}
+
+// Each light group costs a buffer per pixel while rendering
+pub const MAX_LIGHT_GROUPS: usize = 16;
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            samples_per_pixel: 10,
+            max_bounces: 10,
+            background: None,
+            caustics: None,
+            transparent_background: false,
+            aovs: AovFlags::default(),
+            seed: None,
+            sample_check: SampleCheck::default(),
+            threads: None,
+            light_groups: 0,
+            mode: RenderMode::Beauty,
+            time_budget: None,
+            ray_differentials: false,
+            filter: Filter::Box,
+            spectral: None,
+        }
+    }
+}
+
+// Starts from the settings the camera was built with
+pub struct RendererBuilder {
+    camera: Camera,
+    settings: RenderSettings,
+}
+
+impl RendererBuilder {
+    pub fn settings(mut self, settings: RenderSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn build(self) -> Result<Renderer, RenderSettingsError> {
+        if self.settings.samples_per_pixel == 0 {
+            return Err(RenderSettingsError::ZeroSamples);
+        }
+        if self.settings.max_bounces == 0 {
+            return Err(RenderSettingsError::ZeroBounces);
+        }
+        if self.settings.light_groups > MAX_LIGHT_GROUPS {
+            return Err(RenderSettingsError::TooManyLightGroups(self.settings.light_groups));
+        }
+        if !self.settings.filter.is_valid() {
+            return Err(RenderSettingsError::InvalidFilter(self.settings.filter));
+        }
+        if self.settings.spectral.is_some() && self.settings.caustics.is_some() {
+            return Err(RenderSettingsError::SpectralCaustics);
+        }
+        let threads = self.settings.threads;
+        self.without_pool().threads(threads)
+    }
+
+    // For Camera::renderer, which has always taken any settings. Renders on the global pool if
+    // the threads can't be started.
+    fn build_unchecked(self) -> Renderer {
+        let threads = self.settings.threads;
+        let renderer = self.without_pool();
+        renderer.clone().threads(threads).unwrap_or(renderer)
+    }
+
+    fn without_pool(self) -> Renderer {
+        Renderer {
+            render_width: self.camera.render_width,
+            render_height: self.camera.render_height,
+            settings: self.settings,
+            camera: Arc::new(self.camera),
+            pool: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RenderSettingsError {
+    ZeroSamples,
+    ZeroBounces,
+    TooManyLightGroups(usize),
+    InvalidFilter(Filter),
+    SpectralCaustics,
+    ThreadPool(String), // The system refused to start the threads
+}
+
+impl Display for RenderSettingsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenderSettingsError::ZeroSamples => write!(f, "at least 1 sample per pixel is needed"),
+            RenderSettingsError::ZeroBounces => write!(f, "at least 1 bounce is needed, camera rays count as one"),
+            RenderSettingsError::TooManyLightGroups(count) => write!(f, "{} light groups requested, at most {} are supported", count, MAX_LIGHT_GROUPS),
+            RenderSettingsError::InvalidFilter(filter) => write!(f, "invalid filter {:?}, the radius goes up to {} pixels", filter, MAX_FILTER_RADIUS),
+            RenderSettingsError::SpectralCaustics => write!(f, "the caustic photon map is RGB only and can't be used with spectral rendering"),
+            RenderSettingsError::ThreadPool(e) => write!(f, "failed to start the render threads: {}", e),
+        }
+    }
+}
+
+impl Error for RenderSettingsError {}
+
+// Stops renders started with it from another thread, clones share the same flag
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Optional ways to look into a running render
 #[derive(Copy, Clone, Default)]
-struct Pixel {
-    i: usize,
-    j: usize,
+struct Hooks<'a> {
+    cancel: Option<&'a CancelToken>,
+    progress: Option<&'a (dyn Fn(usize, usize) + Sync)>,
+    // Pixels not started by then are skipped, the others are still returned
+    deadline: Option<Instant>,
+}
+
+// Auxiliary buffers filled from the first hit of every camera ray, e.g. as denoiser guides
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AovFlags {
+    pub normal: bool, // Shading normal facing the camera, zero for the background
+    pub depth: bool, // Distance along the view axis, Float::MAX for the background
+    pub albedo: bool, // Surface albedo, background color when nothing is hit
+    pub object_id: bool, // ObjectId::color of the object the first sample hit, black for the background
+    pub variance: Option<VarianceView>, // Noise in the samples of every pixel, in every channel
+}
+
+// How the variance AOV shows the spread of the luminance of the samples in a pixel
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum VarianceView {
+    // Sample variance
+    #[default]
+    Variance,
+    // Standard error of the pixel's mean, shrinks with the square root of the sample count
+    StandardError,
+    // Standard error over the mean, so bright and dark pixels compare. Black pixels are zero.
+    RelativeNoise,
+}
+
+impl VarianceView {
+    pub fn value(&self, stats: &RunningVariance) -> Float {
+        match self {
+            VarianceView::Variance => stats.variance(),
+            VarianceView::StandardError => stats.standard_error(),
+            VarianceView::RelativeNoise if stats.mean() == 0.0 => 0.0,
+            VarianceView::RelativeNoise => stats.standard_error() / stats.mean().abs(),
+        }
+    }
+}
+
+pub struct RenderOutput {
+    pub beauty: Box<PPM>,
+    pub normal: Option<FloatImage>,
+    pub depth: Option<FloatImage>,
+    pub albedo: Option<FloatImage>,
+    pub object_id: Option<FloatImage>,
+    pub variance: Option<FloatImage>,
+    // One image per light group, then one for the background, empty without light groups. They
+    // add up to the beauty image, except where SampleCheck::Highlight marked a pixel.
+    pub light_groups: Vec<FloatImage>,
+    pub stats: RenderStats,
+}
+
+// Counters over all pixels of a render
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RenderStats {
+    pub non_finite_samples: u64, // NaN or infinite samples caught by the sample check
+    pub threads: usize, // Size of the thread pool the render ran on
+}
+
+// Result of Renderer::render_budgeted, the accumulator has the samples of every pixel
+pub struct BudgetedRender {
+    pub image: Box<PPM>,
+    pub accumulator: Accumulator,
+    pub min_samples: u32, // Fewest and most samples of any pixel
+    pub max_samples: u32,
+    pub elapsed: Duration,
+}
+
+// Most samples per pixel in a pass of render_budgeted, more are checked against the time less often
+const MAX_BUDGET_PASS: u32 = 8;
+
+// First hit of the camera ray through the middle of a pixel, see Renderer::pick
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PickResult {
+    pub object_id: ObjectId,
+    pub t: Float,
+    pub point: Point3<Float>,
+    pub normal: Vector3<Float>, // Shading normal facing the camera
+}
+
+// Everything computed for a single pixel, AOVs are already averaged over the samples
+#[derive(Clone, Default)]
+struct PixelResult {
     color: RGB,
+    groups: Vec<RGB>, // Sample sums per light group and the background, like color
+    coverage: Float,
+    normal: RGB,
+    depth: Float,
+    albedo: RGB,
+    object: Option<ObjectId>,
+    variance: RunningVariance, // Luminance of the samples, only with the variance AOV
+    non_finite_samples: u32,
+    traversal: u32, // Heatmap count of the camera ray
+    rendered: bool, // False if the render stopped before the pixel
+    splats: Splats,
 }
 
-pub struct Renderer {
-    render_width: usize,
-    render_height: usize,
-    samples_per_pixel: u32,
-    max_bounces: u32,
-    camera: Arc<Camera>
+// Weighted samples of a pixel for itself and the pixels around it, with a filter wider than the
+// box. Cells are the square within the filter's reach, row by row.
+#[derive(Clone, Default)]
+struct Splats {
+    weights: Vec<Float>,
+    colors: Vec<RGB>,
+    groups: Vec<RGB>, // All group buffers of a cell, then the next cell
 }
 
 impl Renderer {
+    pub fn builder(camera: Camera) -> RendererBuilder {
+        RendererBuilder { settings: camera.settings, camera }
+    }
+
+    pub fn width(&self) -> usize {
+        self.render_width
+    }
+
+    pub fn height(&self) -> usize {
+        self.render_height
+    }
+
+    // Renders on a pool of their own with the given number of threads, all cores for 0.
+    // None uses the global rayon pool, or the pool the render is started from.
+    pub fn threads(mut self, threads: Option<usize>) -> Result<Self, RenderSettingsError> {
+        self.settings.threads = threads;
+        self.pool = match threads {
+            Some(threads) => {
+                let pool = ThreadPoolBuilder::new().num_threads(threads).build();
+                Some(Arc::new(pool.map_err(|e| RenderSettingsError::ThreadPool(e.to_string()))?))
+            },
+            None => None,
+        };
+        Ok(self)
+    }
+
+    // Threads that renders run on
+    pub fn thread_count(&self) -> usize {
+        self.pool.as_ref().map_or_else(current_num_threads, |pool| pool.current_num_threads())
+    }
+
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    // Runs work on the renderer's own pool, if it has one
+    fn install<R: Send>(&self, work: impl FnOnce() -> R + Send) -> R {
+        match &self.pool {
+            Some(pool) => pool.install(work),
+            None => work(),
+        }
+    }
+
     pub fn render_parallel(&self, scene: Arc<Scene>) -> Box<PPM> {
-        let mut image = Box::new(PPM::new(self.render_width, self.render_height, self.samples_per_pixel));
-        let counter = AtomicUsize::new(0);
-        let pixels: Vec<RGB> = (0..self.render_height).clone().into_par_iter().flat_map(|i| {
-            eprintln!("Scanlines remaining: {}", self.render_height - i);
-            let s = scene.clone();
-            (0..self.render_width).clone().into_par_iter().map(move |j| {
-                let mut sample_result = Vector3::<f64>::zeros();
-                for _ in 0..self.samples_per_pixel {
-                    let ray = self.camera.sample_ray(i, j);
-                    let color = ray_color(&ray, self.max_bounces, &s);
-                    sample_result += vector![color.0, color.1, color.2];
-                }
-
-                RGB::from(sample_result)
-            })
-        }).collect::<Vec<_>>();
+        let mut image = Box::new(PPM::new(self.render_width, self.render_height));
+        self.render_into(scene, &mut image).expect("the image is made to size");
+        image
+    }
 
-        (0..self.render_height).for_each(|i| {
-            (0..self.render_width).for_each(|j| {
-                image[(i, j)] = pixels[i * self.render_width + j];
-            });
+    // Renders into an image of the renderer's size, e.g. to reuse one buffer for all frames of an
+    // animation. Every pixel and the alpha are replaced, the display settings of the image stay.
+    pub fn render_into(&self, scene: Arc<Scene>, target: &mut PPM) -> Result<RenderStats, DimensionMismatch> {
+        self.check_size(target.width(), target.height())?;
+        let pixels = self.render_pixels(&scene, 0..self.settings.samples_per_pixel);
+        if !self.settings.transparent_background {
+            target.clear_alpha();
+        }
+        let mut stats = RenderStats { threads: self.thread_count(), ..RenderStats::default() };
+        for i in 0..self.render_height {
+            for j in 0..self.render_width {
+                let pixel = &pixels[i * self.render_width + j];
+                self.store_beauty(target, i, j, pixel);
+                stats.non_finite_samples += pixel.non_finite_samples as u64;
+            }
+        }
+        Ok(stats)
+    }
+
+    // Renders the image together with the AOVs requested by the camera
+    pub fn render_output(&self, scene: Arc<Scene>) -> RenderOutput {
+        let pixels = self.render_pixels(&scene, 0..self.settings.samples_per_pixel);
+        self.assemble(&pixels)
+    }
+
+    // Renders the given range of sample indices of every pixel without averaging them.
+    // With a seed set, rendering 0..10 and 10..20 and merging gives the same sums as 0..20.
+    pub fn render_samples(&self, scene: Arc<Scene>, samples: Range<u32>) -> Accumulator {
+        let mut accumulator = Accumulator::new(self.render_width, self.render_height);
+        self.render_samples_into(scene, samples, &mut accumulator, true).expect("the accumulator is made to size");
+        accumulator
+    }
+
+    // render_samples into an accumulator of the renderer's size. With accumulate the samples are
+    // added to the ones it holds, e.g. for progressive rendering, otherwise they replace them.
+    pub fn render_samples_into(
+        &self,
+        scene: Arc<Scene>,
+        samples: Range<u32>,
+        target: &mut Accumulator,
+        accumulate: bool
+    ) -> Result<(), DimensionMismatch> {
+        self.check_size(target.width(), target.height())?;
+        let count = samples.len() as u32;
+        let pixels = self.render_pixels(&scene, samples);
+        if !accumulate {
+            target.clear();
+        }
+        self.add_pixels(&pixels, count, target);
+        Ok(())
+    }
+
+    // Adds the samples to target like render_samples_into with accumulate, unless the token is
+    // cancelled first. Then target is left as it was and the result is false.
+    pub fn accumulate_cancellable(
+        &self,
+        scene: &Scene,
+        samples: Range<u32>,
+        target: &mut Accumulator,
+        cancel: &CancelToken
+    ) -> Result<bool, DimensionMismatch> {
+        self.check_size(target.width(), target.height())?;
+        let count = samples.len() as u32;
+        let Some(pixels) = self.render_pixels_until(scene, 0..self.render_height, samples, Hooks { cancel: Some(cancel), ..Hooks::default() }) else {
+            return Ok(false);
+        };
+        self.add_pixels(&pixels, count, target);
+        Ok(true)
+    }
+
+    fn add_pixels(&self, pixels: &[PixelResult], count: u32, target: &mut Accumulator) {
+        for i in 0..self.render_height {
+            for j in 0..self.render_width {
+                let color = pixels[i * self.render_width + j].color;
+                target.add(i, j, vector![color.0, color.1, color.2], count);
+            }
+        }
+    }
+
+    fn check_size(&self, width: usize, height: usize) -> Result<(), DimensionMismatch> {
+        if (width, height) != (self.render_width, self.render_height) {
+            return Err(DimensionMismatch { expected: (self.render_width, self.render_height), found: (width, height) });
+        }
+        Ok(())
+    }
+
+    // Like render_output, but gives up and returns None soon after the token is cancelled
+    pub fn render_cancellable(&self, scene: Arc<Scene>, cancel: &CancelToken) -> Option<RenderOutput> {
+        let pixels = self.render_pixels_until(&scene, 0..self.render_height, 0..self.settings.samples_per_pixel, Hooks { cancel: Some(cancel), ..Hooks::default() })?;
+        Some(self.assemble(&pixels))
+    }
+
+    // Renders passes over the whole image until it has samples_per_pixel or the time budget is
+    // used up. The first pass of one sample always finishes, so every pixel has at least one
+    // sample however small the budget. A later pass running out of time stops at the next pixel,
+    // so the pixels it reached have more samples than the others and every pixel is averaged
+    // over its own.
+    pub fn render_budgeted(&self, scene: &Scene) -> BudgetedRender {
+        let start = Instant::now();
+        let deadline = self.settings.time_budget.map(|budget| start + budget);
+        let mut accumulator = Accumulator::new(self.render_width, self.render_height);
+        let total = self.settings.samples_per_pixel;
+        let mut done = 0;
+        while done < total && (done == 0 || deadline.is_none_or(|deadline| Instant::now() < deadline)) {
+            // Short passes first, the budget only cuts passes after the first
+            let pass = done.clamp(1, MAX_BUDGET_PASS).min(total - done);
+            let pass_deadline = if done == 0 { None } else { deadline };
+            let pixels = self.render_pixels_until(scene, 0..self.render_height, done..done + pass, Hooks { deadline: pass_deadline, ..Hooks::default() }).unwrap();
+            for (idx, pixel) in pixels.iter().enumerate().filter(|(_, pixel)| pixel.rendered) {
+                let color = pixel.color;
+                accumulator.add(idx / self.render_width, idx % self.render_width, vector![color.0, color.1, color.2], pass);
+            }
+            done += pass;
+        }
+        let counts = (0..self.render_height).flat_map(|i| (0..self.render_width).map(move |j| (i, j)));
+        let (min_samples, max_samples) = counts.fold((u32::MAX, 0), |(low, high), (i, j)| {
+            let count = accumulator.count(i, j);
+            (low.min(count), high.max(count))
         });
+        BudgetedRender { image: Box::new(accumulator.to_ppm()), accumulator, min_samples, max_samples, elapsed: start.elapsed() }
+    }
 
-        image
+    // Like render_output, calls progress with the number of finished pixels and the total after
+    // every pixel. It's called from the render threads, so it should be quick.
+    pub fn render_with_progress(&self, scene: Arc<Scene>, progress: impl Fn(usize, usize) + Sync) -> RenderOutput {
+        let pixels = self.render_pixels_until(&scene, 0..self.render_height, 0..self.settings.samples_per_pixel, Hooks { progress: Some(&progress), ..Hooks::default() }).unwrap();
+        self.assemble(&pixels)
     }
-}
 
-#[derive(Default, Clone)]
-pub struct Camera {
-    pub render_width: usize,
-    pub aspect_ratio: f64,
-    pub samples_per_pixel: u32,
-    pub max_bounces: u32,
-    pub fov_degrees: f64,
-    pub lookfrom: Point3<f64>,
-    pub lookat: Point3<f64>,
-    pub vup: Vector3<f64>,
-    pub defocus_angle_degrees: f64,
-    pub focus_dist: f64,
+    fn render_pixels(&self, scene: &Scene, samples: Range<u32>) -> Vec<PixelResult> {
+        self.render_pixels_until(scene, 0..self.render_height, samples, Hooks::default()).unwrap()
+    }
 
-    render_height: usize, // Rendered image height
-    center: Point3<f64>, // Camera center
-    pixel00_loc: Point3<f64>, // Location of pixel (0, 0)
-    pixel_delta_u: Vector3<f64>, // Offset to pixel to the right
-    pixel_delta_v: Vector3<f64>, // Offset to pixel below
+    // The given samples of the given rows only, e.g. for a share of a frame split across machines.
+    // The accumulator covers just those rows. Heatmaps only span the rows too.
+    pub fn render_region(&self, scene: &Scene, rows: Range<usize>, samples: Range<u32>) -> Accumulator {
+        assert!(rows.end <= self.render_height, "rows {:?} are outside of the image", rows);
+        let count = samples.len() as u32;
+        let pixels = self.render_pixels_until(scene, rows.clone(), samples, Hooks::default()).unwrap();
+        let mut accumulator = Accumulator::new(self.render_width, rows.len());
+        for (idx, pixel) in pixels.iter().enumerate() {
+            let color = pixel.color;
+            accumulator.add(idx / self.render_width, idx % self.render_width, vector![color.0, color.1, color.2], count);
+        }
+        accumulator
+    }
 
-    // Camera frame basis vectors
-    u: Vector3<f64>, // right
-    v: Vector3<f64>, // up
-    w: Vector3<f64>, // backwards
+    // Pixels of the given rows, None if cancelled. Pixels that weren't started by then are skipped.
+    fn render_pixels_until(&self, scene: &Scene, rows: Range<usize>, samples: Range<u32>, hooks: Hooks) -> Option<Vec<PixelResult>> {
+        if self.camera.autofocus.is_some() {
+            return self.focused(scene).render_pixels_until(scene, rows, samples, hooks);
+        }
+        self.install(|| {
+            let cancelled = || hooks.cancel.is_some_and(|cancel| cancel.is_cancelled());
+            let total = self.render_width * rows.len();
+            let done = AtomicUsize::new(0);
+            let finished = || {
+                if let Some(progress) = hooks.progress {
+                    progress(done.fetch_add(1, Ordering::Relaxed) + 1, total);
+                }
+            };
+            let caustics = self.build_caustics(scene);
+            let integrator = Integrator::new(scene, self.settings.background, caustics.as_ref());
+            let pixels = rows.into_par_iter().flat_map(|i| {
+                let integrator = &integrator;
+                let samples = samples.clone();
+                let finished = &finished;
+                (0..self.render_width).clone().into_par_iter().map(move |j| {
+                    if cancelled() || hooks.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return PixelResult::default();
+                    }
+                    let pixel = self.render_pixel(integrator, i, j, samples.clone());
+                    finished();
+                    PixelResult { rendered: true, ..pixel }
+                })
+            }).collect::<Vec<_>>();
+            if cancelled() {
+                return None;
+            }
+            let mut pixels = pixels;
+            self.gather_splats(&mut pixels, samples.len() as u32);
+            self.color_heatmap(&mut pixels, samples.len() as u32);
+            Some(pixels)
+        })
+    }
 
-    defocus_disk_u: Vector3<f64>, // Defocus disk horizontal radius
-    defocus_disk_v: Vector3<f64> // Defocus disk vertical radius
-}
+    // Object under the middle of the pixel in column x and row y, e.g. for click to inspect. The
+    // ray leaves the middle of the lens without any jitter, None if it hits nothing.
+    pub fn pick(&self, scene: &Scene, x: usize, y: usize) -> Option<PickResult> {
+        let ray = self.camera.central_ray(x as Float + 0.5, y as Float + 0.5)?;
+        let hit = scene.hit(&ray, Interval::new(MIN_T, INF))?;
+        Some(PickResult { object_id: hit.object?, t: hit.t, point: hit.p, normal: hit.normal })
+    }
 
-impl Camera {
-    pub fn new(
-        width: usize,
-        aspect_ratio: f64,
+    // Follows one sample of the pixel in column x and row y and records every bounce. With a seed
+    // set, it's exactly the path that sample took in the rendered image.
+    pub fn trace_pixel(&self, scene: &Scene, x: usize, y: usize, sample: u32) -> PathTrace {
+        self.trace_samples(scene, &[(x, y, sample)]).remove(0)
+    }
+
+    // Traces samples 0..samples_per_pixel of every pixel in pixels, given as (x, y), and writes
+    // their paths as line geometry
+    pub fn export_paths(
+        &self,
+        scene: &Scene,
+        pixels: &[(usize, usize)],
         samples_per_pixel: u32,
-        max_bounces: u32,
-        fov: f64,
-        lookfrom: Point3<f64>,
-        lookat: Point3<f64>,
-        vup: Vector3<f64>,
-        defocus_angle_degrees: f64,
-        focus_dist: f64
-    ) -> Self {
-        Self {
-            render_width: width,
-            aspect_ratio,
-            samples_per_pixel,
-            max_bounces,
-            fov_degrees: fov,
-            lookfrom,
-            lookat,
-            vup,
-            defocus_angle_degrees,
-            focus_dist,
-            ..Default::default()
-        }
-    }
-
-    pub fn renderer(&mut self) -> Renderer {
-        self.initialize();
-        Renderer {
-            render_width: self.render_width,
-            render_height: self.render_height,
-            samples_per_pixel: self.samples_per_pixel,
-            max_bounces: self.max_bounces,
-            camera: Arc::new(self.clone())
+        options: PathExport,
+        writer: &mut dyn Write
+    ) -> std::io::Result<()> {
+        let samples: Vec<_> = pixels.iter().flat_map(|&(x, y)| (0..samples_per_pixel).map(move |sample| (x, y, sample))).collect();
+        write_paths(&self.trace_samples(scene, &samples), options, writer)
+    }
+
+    // trace_pixel for every (x, y, sample), the caustics are only built once
+    fn trace_samples(&self, scene: &Scene, samples: &[(usize, usize, u32)]) -> Vec<PathTrace> {
+        if self.camera.autofocus.is_some() {
+            return self.focused(scene).trace_samples(scene, samples);
         }
+        let caustics = self.build_caustics(scene);
+        samples.iter().map(|&(x, y, sample)| {
+            assert!(x < self.render_width && y < self.render_height, "pixel ({}, {}) is outside of the image", x, y);
+            let integrator = Integrator::new(scene, self.settings.background, caustics.as_ref()).tracing();
+            let (color, _, _) = self.trace_sample(&integrator, y, x, sample, &mut []);
+            let bounces = integrator.trace.map(|trace| trace.into_inner().unwrap()).unwrap_or_default();
+            PathTrace { x, y, sample, bounces, color }
+        }).collect()
     }
 
-    // TODO Remove mut and use interior mutability (RefCell)
-    pub fn render(&mut self, scene: &Scene) -> Box<PPM> {
-        self.initialize();
+    // Orbits the camera once around look_at and passes every rendered frame to on_frame,
+    // so only one frame is kept in memory at a time
+    pub fn turntable(
+        &self,
+        scene: Arc<Scene>,
+        frames: usize,
+        options: TurntableOptions,
+        mut on_frame: impl FnMut(usize, Box<PPM>)
+    ) {
+        for index in 0..frames {
+            let camera = self.camera.turntable_camera(index, frames, options);
+            on_frame(index, self.with_camera(camera).render_parallel(scene.clone()));
+        }
+    }
 
-        let mut image = Box::new(PPM::new(self.render_width, self.render_height, self.samples_per_pixel));
-        for i in 0..self.render_height {
-            eprintln!("Scanlines remaining: {}", self.render_height - i);
-            for j in 0..self.render_width {
-                let mut sample_result = Vector3::<f64>::zeros();
-                for _ in 0..self.samples_per_pixel {
-                    let ray = self.sample_ray(i, j);
-                    let color = ray_color(&ray, self.max_bounces, &scene);
-                    sample_result += vector![color.0, color.1, color.2];
+    // Renders factor times the width and height and shrinks the image back down with a lanczos
+    // filter, for clean edges in small images. Every pixel of the large image gets
+    // samples_per_pixel samples, so it takes factor² times as long as render_parallel.
+    pub fn render_supersampled(&self, scene: Arc<Scene>, factor: usize) -> Box<PPM> {
+        assert!(factor > 0, "the supersampling factor has to be at least 1");
+        let camera = self.camera.scaled(factor);
+        let large = Renderer { render_width: camera.render_width, render_height: camera.render_height, camera: Arc::new(camera), ..self.clone() }
+            .render_parallel(scene);
+        let (width, height) = (self.render_width, self.render_height);
+        let resized = resize(&*large, width, height, ResizeFilter::Lanczos3);
+        let mut image = Box::new(PPM::new(width, height));
+        for i in 0..height {
+            for j in 0..width {
+                image[(i, j)] = resized[(i, j)];
+            }
+        }
+        if large.has_alpha() {
+            let mut coverage = FloatImage::new(large.width(), large.height());
+            for i in 0..large.height() {
+                for j in 0..large.width() {
+                    let alpha = large.alpha(i, j);
+                    coverage[(i, j)] = RGB(alpha, alpha, alpha);
+                }
+            }
+            let coverage = resize(&coverage, width, height, ResizeFilter::Lanczos3);
+            for i in 0..height {
+                for j in 0..width {
+                    image.set_alpha(i, j, coverage[(i, j)].0.clamp(0.0, 1.0));
                 }
-                image[(i, j)] = sample_result.into();
             }
         }
         image
     }
 
-    fn sample_ray(&self, i: usize, j: usize) -> Ray {
-        // Get a randomly-sampled camera ray for the pixel at location i,j, originating from
-        // the camera defocus disk.
-        let pixel_center =
-            self.pixel00_loc + (j as f64 * self.pixel_delta_u) + (i as f64 * self.pixel_delta_v);
-        let pixel_sample = pixel_center + self.pixel_sample_square();
+    // Progressive preview: renders at 1/8, 1/4 and 1/2 of the width and height with one sample
+    // per pixel, then the full image with samples_per_pixel. on_level gets each image and its
+    // factor; a pixel covers factor × factor pixels of the full image, counted from the top left,
+    // and the last row and column can reach past it. Returns false if the token is cancelled
+    // before the full image is done.
+    pub fn render_coarse_to_fine(&self, scene: Arc<Scene>, cancel: &CancelToken, mut on_level: impl FnMut(Box<PPM>, usize)) -> bool {
+        for factor in [8, 4, 2, 1] {
+            let samples_per_pixel = if factor == 1 { self.settings.samples_per_pixel } else { 1 };
+            let level = Renderer {
+                render_width: self.render_width.div_ceil(factor),
+                render_height: self.render_height.div_ceil(factor),
+                settings: RenderSettings { samples_per_pixel, ..self.settings },
+                camera: Arc::new(self.camera.coarse(factor)),
+                ..self.clone()
+            };
+            if cancel.is_cancelled() {
+                return false;
+            }
+            let Some(output) = level.render_cancellable(scene.clone(), cancel) else {
+                return false;
+            };
+            on_level(output.beauty, factor);
+        }
+        true
+    }
 
-        let ray_origin = if self.defocus_angle_degrees <= 0.0 { self.center } else { self.defocus_disk_sample() };
-        let ray_direction = pixel_sample - ray_origin;
-        Ray::new(ray_origin, ray_direction)
+    // Left and right eye images, the cameras are ipd apart and otherwise identical
+    pub fn render_stereo(&self, scene: Arc<Scene>, ipd: Float, mode: StereoMode) -> (Box<PPM>, Box<PPM>) {
+        let left = self.with_camera(self.camera.eye_camera(-ipd / 2.0, mode)).render_parallel(scene.clone());
+        let right = self.with_camera(self.camera.eye_camera(ipd / 2.0, mode)).render_parallel(scene);
+        (left, right)
     }
 
-    fn defocus_disk_sample(&self) -> Point3<f64> {
-        let p = rand_in_unit_disk();
-        return self.center + (p.x * self.defocus_disk_u) + (p.y * self.defocus_disk_v)
+    fn focused(&self, scene: &Scene) -> Renderer {
+        self.with_camera(self.camera.focused(scene))
     }
 
-    fn pixel_sample_square(&self) -> Vector3<f64> {
-        let px = -0.5 + rand();
-        let py = -0.5 + rand();
-        return px * self.pixel_delta_u + py * self.pixel_delta_v
+    // Same settings and pool for a camera with the same image size
+    fn with_camera(&self, camera: Camera) -> Renderer {
+        Renderer { camera: Arc::new(camera), ..self.clone() }
     }
 
-    fn initialize(&mut self) {
-        self.render_height = (self.render_width as f64 / self.aspect_ratio) as usize;
-        if self.render_height < 1 {
-            self.render_height = 1;
+    fn build_caustics(&self, scene: &Scene) -> Option<PhotonMap> {
+        self.settings.caustics.map(|settings| {
+            PhotonMap::build(scene, PhotonMapSettings { seed: settings.seed.or(self.settings.seed), ..settings })
+        })
+    }
+
+    pub fn render(&self, scene: &Scene) -> Box<PPM> {
+        if self.camera.autofocus.is_some() {
+            return self.focused(scene).render(scene);
         }
-        println!("Image size: W:{}, H:{}", self.render_width, self.render_height);
-        self.center = self.lookfrom;
+        let caustics = self.build_caustics(scene);
+        let integrator = Integrator::new(scene, self.settings.background, caustics.as_ref());
+        let mut pixels = Vec::with_capacity(self.render_width * self.render_height);
+        for i in 0..self.render_height {
+            for j in 0..self.render_width {
+                pixels.push(self.render_pixel(&integrator, i, j, 0..self.settings.samples_per_pixel));
+            }
+        }
+        self.gather_splats(&mut pixels, self.settings.samples_per_pixel);
+        self.color_heatmap(&mut pixels, self.settings.samples_per_pixel);
+        self.assemble(&pixels).beauty
+    }
 
-        // Determine viewport dimensions.
-        let theta = degrees_to_radians(self.fov_degrees);
-        // height of camera field of view
-        let h = (theta / 2.0).tan();
-        let viewport_height = 2.0 * h * self.focus_dist;
-        let viewport_width = viewport_height * (self.render_width as f64) / (self.render_height as f64);
+    // Renders rows start_row..start_row + row_count on the calling thread into rgba, which holds
+    // the whole image laid out like to_rgba8. Rows past the bottom are skipped. Lets a caller that
+    // can't block for long, e.g. a browser, render a few rows per call. Returns the row to go on
+    // from. Caustics are traced again every call and heatmaps only span the rows of the call.
+    pub fn render_rows(&self, scene: &Scene, start_row: usize, row_count: usize, rgba: &mut [u8]) -> Result<usize, DimensionMismatch> {
+        let (width, height) = (self.render_width, self.render_height);
+        if rgba.len() != width * height * 4 {
+            return Err(DimensionMismatch { expected: (width, height), found: (width, rgba.len() / (width * 4)) });
+        }
+        if self.camera.autofocus.is_some() {
+            return self.focused(scene).render_rows(scene, start_row, row_count, rgba);
+        }
+        let rows = start_row.min(height)..start_row.saturating_add(row_count).min(height);
+        let caustics = self.build_caustics(scene);
+        let integrator = Integrator::new(scene, self.settings.background, caustics.as_ref());
+        let mut pixels = Vec::with_capacity(rows.len() * width);
+        for i in rows.clone() {
+            for j in 0..width {
+                pixels.push(self.render_pixel(&integrator, i, j, 0..self.settings.samples_per_pixel));
+            }
+        }
+        self.gather_splats(&mut pixels, self.settings.samples_per_pixel);
+        self.color_heatmap(&mut pixels, self.settings.samples_per_pixel);
+        let mut image = PPM::new(width, rows.len());
+        for (idx, pixel) in pixels.iter().enumerate() {
+            self.store_beauty(&mut image, idx / width, idx % width, pixel);
+        }
+        rgba[rows.start * width * 4..rows.end * width * 4].copy_from_slice(&image.to_rgba8());
+        Ok(rows.end)
+    }
 
-        // Calculate the u,v,w unit basis vectors for the camera coordinate frame
-        self.w = (self.lookfrom - self.lookat).normalize();
-        self.u = (self.vup.cross(&self.w)).normalize();
-        self.v = self.w.cross(&self.u);
+    // With a filter wider than the box, adds the splats of every pixel to the pixels they reach
+    // and divides by the weight each got. The colors are stored as sums over the given number of
+    // samples, like the box filter's. Splats reaching past the given pixels are dropped.
+    fn gather_splats(&self, pixels: &mut [PixelResult], samples: u32) {
+        let filter = self.settings.filter;
+        if filter == Filter::Box {
+            return;
+        }
+        let (width, height) = (self.render_width as isize, (pixels.len() / self.render_width) as isize);
+        let reach = filter.reach() as isize;
+        let side = 2 * reach + 1;
+        let buffers = self.group_buffers();
+        let mut weights = vec![0.0; pixels.len()];
+        let mut colors = vec![RGB::zeros(); pixels.len()];
+        let mut groups = vec![RGB::zeros(); pixels.len() * buffers];
+        for (idx, pixel) in pixels.iter().enumerate() {
+            let (i, j) = (idx as isize / width, idx as isize % width);
+            for (cell, weight) in pixel.splats.weights.iter().enumerate() {
+                let (ti, tj) = (i + cell as isize / side - reach, j + cell as isize % side - reach);
+                if ti < 0 || tj < 0 || ti >= height || tj >= width {
+                    continue;
+                }
+                let target = (ti * width + tj) as usize;
+                weights[target] += weight;
+                colors[target] += pixel.splats.colors[cell];
+                for (sum, group) in groups[target * buffers..(target + 1) * buffers].iter_mut().zip(&pixel.splats.groups[cell * buffers..]) {
+                    *sum += *group;
+                }
+            }
+        }
+        for (idx, pixel) in pixels.iter_mut().enumerate() {
+            // Heatmaps and pixels the render didn't get to have no splats
+            if pixel.splats.weights.is_empty() {
+                continue;
+            }
+            let scale = if weights[idx] > 0.0 { samples as Float / weights[idx] } else { 0.0 };
+            if !(self.settings.sample_check == SampleCheck::Highlight && pixel.non_finite_samples > 0) {
+                pixel.color = colors[idx] * scale;
+            }
+            for (sum, group) in pixel.groups.iter_mut().zip(&groups[idx * buffers..(idx + 1) * buffers]) {
+                *sum = *group * scale;
+            }
+            pixel.splats = Splats::default();
+        }
+    }
 
-        println!(
-            "Initialized viewport: W:{}, H:{}",
-            viewport_width, viewport_height
-        );
+    // Turns the counts of a heatmap into ramp colors, which need the range of the whole image.
+    // The colors are stored as sums over the given number of samples, like every other pixel.
+    fn color_heatmap(&self, pixels: &mut [PixelResult], samples: u32) {
+        let RenderMode::Heatmap { max, .. } = self.settings.mode else {
+            return;
+        };
+        let low = pixels.iter().map(|pixel| pixel.traversal).min().unwrap_or(0);
+        let high = max.unwrap_or_else(|| pixels.iter().map(|pixel| pixel.traversal).max().unwrap_or(0));
+        for pixel in pixels {
+            let t = if high > low { pixel.traversal.saturating_sub(low) as Float / (high - low) as Float } else { 0.0 };
+            pixel.color = RGB::ramp(t) * samples as Float;
+        }
+    }
 
-        // Calculate the vectors across the horizontal and down the vertical viewport edges
-        let viewport_u = viewport_width * self.u;
-        let viewport_v = viewport_height * -self.v;
+    fn new_output(&self) -> RenderOutput {
+        let aovs = self.settings.aovs;
+        let aov = |enabled: bool| if enabled { Some(FloatImage::new(self.render_width, self.render_height)) } else { None };
+        RenderOutput {
+            beauty: Box::new(PPM::new(self.render_width, self.render_height)),
+            normal: aov(aovs.normal),
+            depth: aov(aovs.depth),
+            albedo: aov(aovs.albedo),
+            object_id: aov(aovs.object_id),
+            variance: aov(aovs.variance.is_some()),
+            light_groups: (0..self.group_buffers()).map(|_| FloatImage::new(self.render_width, self.render_height)).collect(),
+            stats: RenderStats::default(),
+        }
+    }
 
-        // Calculate the horizontal and vertical delta vectors from pixel to pixel
-        self.pixel_delta_u = viewport_u / self.render_width as f64;
-        self.pixel_delta_v = viewport_v / self.render_height as f64;
+    fn assemble(&self, pixels: &[PixelResult]) -> RenderOutput {
+        let mut output = self.new_output();
+        output.stats.threads = self.thread_count();
+        (0..self.render_height).for_each(|i| {
+            (0..self.render_width).for_each(|j| {
+                self.store(&mut output, i, j, &pixels[i * self.render_width + j]);
+            });
+        });
+        output
+    }
 
-        // Calculate the location of the upper left pixel.
-        let viewport_upper_left =
-            self.center - self.focus_dist * self.w - viewport_u / 2.0 - viewport_v / 2.0;
-        self.pixel00_loc = viewport_upper_left + 0.5f64 * (self.pixel_delta_u + self.pixel_delta_v);
+    // Light groups and the background, none without light groups
+    fn group_buffers(&self) -> usize {
+        if self.settings.light_groups == 0 { 0 } else { self.settings.light_groups + 1 }
+    }
 
-        // Calculate the camera defocus disk basis vectors
-        let defocus_radius = self.focus_dist * (degrees_to_radians(self.defocus_angle_degrees / 2.0).tan());
-        self.defocus_disk_u = self.u * defocus_radius;
-        self.defocus_disk_v = self.v * defocus_radius;
+    // Averages the sample sum, so images hold the final linear color
+    fn store(&self, output: &mut RenderOutput, i: usize, j: usize, pixel: &PixelResult) {
+        self.store_beauty(&mut output.beauty, i, j, pixel);
+        for (image, sum) in output.light_groups.iter_mut().zip(&pixel.groups) {
+            image[(i, j)] = *sum * (1.0 / self.settings.samples_per_pixel as Float);
+        }
+        output.stats.non_finite_samples += pixel.non_finite_samples as u64;
+        if let Some(normal) = output.normal.as_mut() {
+            normal[(i, j)] = pixel.normal;
+        }
+        if let Some(depth) = output.depth.as_mut() {
+            depth[(i, j)] = RGB(pixel.depth, pixel.depth, pixel.depth);
+        }
+        if let Some(albedo) = output.albedo.as_mut() {
+            albedo[(i, j)] = pixel.albedo;
+        }
+        if let Some(object_id) = output.object_id.as_mut() {
+            object_id[(i, j)] = pixel.object.map_or(RGB::zeros(), |id| id.color());
+        }
+        if let (Some(variance), Some(view)) = (output.variance.as_mut(), self.settings.aovs.variance) {
+            let value = view.value(&pixel.variance);
+            variance[(i, j)] = RGB(value, value, value);
+        }
     }
-}
 
-fn ray_color(ray: &Ray, depth: u32, scene: &Scene) -> RGB {
-    if depth <= 0 {
-        return RGB::default();
+    fn store_beauty(&self, image: &mut PPM, i: usize, j: usize, pixel: &PixelResult) {
+        image[(i, j)] = pixel.color * (1.0 / self.settings.samples_per_pixel as Float);
+        if self.settings.transparent_background {
+            image.set_alpha(i, j, pixel.coverage);
+        }
     }
 
-    // Reduce the probability of falling inside the surface due to fp errors
-    let mint = 0.001;
-    if let Some(hit) = scene.hit(&ray, mint..INF) {
-        return match hit.material.scatter(&ray, &hit) {
-            Some((scattered, attenuation)) => {
-                attenuation * ray_color(&scattered, depth - 1, scene)
-            },
-            None => RGB::default()
+    // Sum of all samples of the pixel, the fraction of camera rays that hit an object and the AOVs
+    fn render_pixel(&self, integrator: &Integrator, i: usize, j: usize, samples: Range<u32>) -> PixelResult {
+        if let RenderMode::Heatmap { metric, .. } = self.settings.mode {
+            return self.heatmap_pixel(integrator.scene, i, j, samples.start, metric);
+        }
+        let aovs = self.settings.aovs;
+        let count = samples.len() as Float;
+        let weight = 1.0 / count;
+        let mut sample_result = RGB::zeros();
+        let buffers = self.group_buffers();
+        let mut pixel = PixelResult { groups: vec![RGB::zeros(); buffers], ..PixelResult::default() };
+        let filter = self.settings.filter;
+        if filter != Filter::Box {
+            let side = 2 * filter.reach() + 1;
+            let cells = side * side;
+            pixel.splats = Splats { weights: vec![0.0; cells], colors: vec![RGB::zeros(); cells], groups: vec![RGB::zeros(); cells * buffers] };
+        }
+        let mut hits = 0;
+        let mut first = true;
+        let mut add_sample = |mut color: RGB, hit: Option<HitRecord>, groups: &[RGB], (x, y): (Float, Float)| {
+            let sanitized = self.settings.sample_check != SampleCheck::Off && !color.is_finite();
+            if sanitized {
+                pixel.non_finite_samples += 1;
+                color = RGB::zeros();
+            }
+            // With a transparent background the sky only shows up through reflections
+            let counted = hit.is_some() || !self.settings.transparent_background;
+            if aovs.variance.is_some() {
+                pixel.variance.add(if counted { color.luminance() } else { 0.0 });
+            }
+            if counted {
+                sample_result += color;
+                if !sanitized {
+                    pixel.groups.iter_mut().zip(groups).for_each(|(sum, group)| *sum += *group);
+                }
+            }
+            if filter != Filter::Box {
+                // Samples left out still weigh in, like they count toward the average of the box
+                let splats = &mut pixel.splats;
+                for (cell, weight) in filter.splat_weights(x, y).enumerate() {
+                    splats.weights[cell] += weight;
+                    if counted && weight != 0.0 {
+                        splats.colors[cell] += color * weight;
+                        if !sanitized {
+                            let cell_groups = &mut splats.groups[cell * buffers..(cell + 1) * buffers];
+                            cell_groups.iter_mut().zip(groups).for_each(|(sum, group)| *sum += *group * weight);
+                        }
+                    }
+                }
+            }
+
+            // AOV samples are weighted on the way in, a sum of Float::MAX depths would overflow
+            match hit {
+                Some(hit) => {
+                    hits += 1;
+                    if aovs.normal {
+                        pixel.normal += RGB::from(hit.normal) * weight;
+                    }
+                    if aovs.depth {
+                        pixel.depth += (hit.p - self.camera.center).dot(&-self.camera.w) * weight;
+                    }
+                    if aovs.albedo {
+                        pixel.albedo += hit.material.albedo() * weight;
+                    }
+                    // Ids can't be averaged, edges get the object of the first sample
+                    if aovs.object_id && first {
+                        pixel.object = hit.object;
+                    }
+                },
+                None => {
+                    if aovs.depth {
+                        pixel.depth += Float::MAX * weight;
+                    }
+                    if aovs.albedo {
+                        pixel.albedo += color * weight;
+                    }
+                }
+            }
+            first = false;
+        };
+
+        // Whole packets of samples first, the rest one by one
+        #[cfg(feature = "packets")]
+        let samples = {
+            let packed = samples.start + samples.len() as u32 / LANES as u32 * LANES as u32;
+            let mut lane_groups = vec![RGB::zeros(); buffers * LANES];
+            for start in (samples.start..packed).step_by(LANES) {
+                lane_groups.fill(RGB::zeros());
+                let results = self.trace_packet(integrator, i, j, start, &mut lane_groups);
+                for (lane, (color, hit, position)) in results.into_iter().enumerate() {
+                    add_sample(color, hit, &lane_groups[lane * buffers..(lane + 1) * buffers], position);
+                }
+            }
+            packed..samples.end
+        };
+        let mut sample_groups = vec![RGB::zeros(); buffers];
+        for sample in samples {
+            sample_groups.fill(RGB::zeros());
+            let (color, hit, position) = self.trace_sample(integrator, i, j, sample, &mut sample_groups);
+            add_sample(color, hit, &sample_groups, position);
+        }
+
+        pixel.color = sample_result;
+        if self.settings.sample_check == SampleCheck::Highlight && pixel.non_finite_samples > 0 {
+            // Stored as a sum like every other pixel
+            pixel.color = RGB(1.0, 0.0, 1.0) * count;
+        }
+        pixel.coverage = hits as Float * weight;
+        pixel
+    }
+
+    // Count of the first sample's camera ray, colored later by color_heatmap
+    fn heatmap_pixel(&self, scene: &Scene, i: usize, j: usize, sample: u32, metric: HeatmapMetric) -> PixelResult {
+        self.seed_sample(i, j, sample);
+        let mut stats = TraversalStats::default();
+        let hit = self.camera.sample_ray(i, j, self.settings.filter.sample_position(), false).and_then(|ray| scene.hit_counted(&ray, Interval::new(MIN_T, INF), &mut stats));
+        let coverage = if hit.is_some() { 1.0 } else { 0.0 };
+        PixelResult { traversal: metric.count(&stats), coverage, ..PixelResult::default() }
+    }
+
+    // Every sample gets its own random sequence, independent of the thread rendering it
+    fn seed_sample(&self, i: usize, j: usize, sample: u32) {
+        if let Some(seed) = self.settings.seed {
+            seed_rng(hash_seed(&[seed, i as u64, j as u64, sample as u64]));
         }
     }
 
-    // Sky
-    let unit = ray.dir.normalize();
-    let a = 0.5 * (unit.y + 1.0);
-    let blue = vector![0.5, 0.7, 1.0];
-    let white = vector![1.0, 1.0, 1.0];
-    white.lerp(&blue, a).into()
+    // Color of one sample of the pixel, the first hit of its camera ray and where in the pixel
+    // it was taken. The color is also added to groups split by light group, if there are any.
+    fn trace_sample<'a>(
+        &self,
+        integrator: &'a Integrator,
+        i: usize,
+        j: usize,
+        sample: u32,
+        groups: &mut [RGB]
+    ) -> (RGB, Option<HitRecord<'a>>, (Float, Float)) {
+        self.seed_sample(i, j, sample);
+        let position = self.settings.filter.sample_position();
+        let ray = self.camera.sample_ray(i, j, position, self.settings.ray_differentials);
+        let hit = ray.as_ref().and_then(|ray| integrator.camera_hit(ray, self.settings.max_bounces));
+        let (color, hit) = self.sample_color(integrator, i, j, ray.as_ref(), hit, groups);
+        (color, hit, position)
+    }
+
+    // trace_sample for the LANES samples from start on, with one packet test for the camera rays.
+    // Shading continues every lane's random sequence where its camera ray left it, so seeded
+    // renders match trace_sample exactly. Unseeded ones draw the numbers in a different order.
+    // groups holds the light groups of every lane one after another.
+    #[cfg(feature = "packets")]
+    fn trace_packet<'a>(
+        &self,
+        integrator: &'a Integrator,
+        i: usize,
+        j: usize,
+        start: u32,
+        groups: &mut [RGB]
+    ) -> [(RGB, Option<HitRecord<'a>>, (Float, Float)); LANES] {
+        let mut states: [_; LANES] = Default::default();
+        let mut positions = [(0.0, 0.0); LANES];
+        let rays = std::array::from_fn(|lane| {
+            self.seed_sample(i, j, start + lane as u32);
+            positions[lane] = self.settings.filter.sample_position();
+            let ray = self.camera.sample_ray(i, j, positions[lane], self.settings.ray_differentials);
+            states[lane] = self.settings.seed.map(|_| save_rng());
+            ray
+        });
+        let packet = RayPacket4::new(rays);
+        let mut hits = integrator.camera_hit4(&packet, self.settings.max_bounces);
+        let buffers = groups.len() / LANES;
+        std::array::from_fn(|lane| {
+            if let Some(state) = states[lane].take() {
+                restore_rng(state);
+            }
+            let groups = &mut groups[lane * buffers..(lane + 1) * buffers];
+            let (color, hit) = self.sample_color(integrator, i, j, packet.ray(lane), hits[lane].take(), groups);
+            (color, hit, positions[lane])
+        })
+    }
+
+    // Color of a camera ray given its first hit, None for a point outside of the fisheye image circle
+    fn sample_color<'a>(
+        &self,
+        integrator: &Integrator,
+        i: usize,
+        j: usize,
+        ray: Option<&Ray>,
+        hit: Option<HitRecord<'a>>,
+        groups: &mut [RGB]
+    ) -> (RGB, Option<HitRecord<'a>>) {
+        match ray {
+            Some(ray) => {
+                let spectral = self.settings.spectral.map(|sampling| Ray { wavelengths: Some(Wavelengths::sample(sampling)), ..ray.clone() });
+                let ray = spectral.as_ref().unwrap_or(ray);
+                let weight = self.camera.vignetting_weight(ray, i, j);
+                let mut split = GroupSplit::new(groups, weight);
+                let (color, hit) = integrator.camera_hit_color(ray, hit, self.settings.max_bounces, &mut split);
+                // Spectral paths come back as radiance at their wavelengths
+                let color = match ray.wavelengths {
+                    Some(wavelengths) => {
+                        groups.iter_mut().for_each(|group| *group = wavelengths.to_rgb(*group));
+                        wavelengths.to_rgb(color)
+                    },
+                    None => color
+                };
+                (color * weight, hit)
+            },
+            None if self.camera.fill_outside_image_circle => {
+                let background = integrator.background(&self.camera.forward_ray());
+                GroupSplit::new(groups, 1.0).add_background(background);
+                (background, None)
+            },
+            None => (RGB::default(), None)
+        }
+    }
+}
+
+// How directions around the camera map to the image
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Projection {
+    #[default]
+    Perspective,
+    // Equidistant fisheye: the angle to the view axis grows linearly with the distance from the
+    // image center. fov_degrees spans the diameter of the image circle and can go up to 360.
+    Fisheye,
+    // Full 360x180 degree panorama with longitude along x and latitude along y, the view
+    // direction is in the middle of the image. Ignores fov, aspect ratio (always 2:1) and defocus.
+    Equirectangular,
+}
+
+// Darkening towards the edges of the image, a weight on every primary sample
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Vignetting {
+    // cos^4 of the angle between the ray and the view axis. With a barrel length, a second
+    // opening as large as the defocus disk that far in front of it (in defocus disk radii) also
+    // cuts off oblique rays from the edge of the lens. That part needs a defocus angle.
+    Natural { barrel: Option<Float> },
+    // 1 - strength * r^exponent, r goes from 0 in the image center to 1 in the corners
+    Radial { strength: Float, exponent: Float },
+}
+
+// What happens to samples whose color came out NaN or infinite, e.g. from a degenerate normal
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum SampleCheck {
+    // They count as black and are counted in RenderStats
+    #[default]
+    Sanitize,
+    // Like Sanitize, and the whole pixel turns magenta to show where they are
+    Highlight,
+    // Left in, a single one spoils the pixel
+    Off,
+}
+
+// How the eyes of a stereo pair are oriented
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum StereoMode {
+    // Both eyes look at look_at
+    #[default]
+    Converged,
+    // Both eyes look in the camera's view direction
+    Parallel,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TurntableOptions {
+    pub clockwise: bool, // Seen from above, i.e. looking down against vup
+}
+
+// Point of the image whose first hit sets the focus distance right before rendering
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Autofocus {
+    // Center of the image, which is where look_at is
+    Center,
+    // Position in pixels from the top left corner of the image
+    Pixel { x: Float, y: Float },
+}
+
+#[derive(Clone)]
+pub struct Camera {
+    render_width: usize,
+    aspect_ratio: Float, // Requested, the image height is rounded down from it
+    exact_height: Option<usize>, // Replaces aspect_ratio if set
+    settings: RenderSettings, // Defaults for renderers of this camera
+    fov_degrees: Float,
+    lookfrom: Point3<Float>,
+    lookat: Point3<Float>,
+    vup: Vector3<Float>,
+    pose: Option<Isometry3<Float>>, // Replaces lookfrom, lookat and vup if set
+    defocus_angle_degrees: Float,
+    focus_dist: Float,
+    lens_tilt_degrees: (Float, Float), // Rotation of the focus plane about u and v
+    autofocus: Option<Autofocus>,
+    shutter: (Float, Float), // Open and close time, rays get a random time in between
+    projection: Projection,
+    fill_outside_image_circle: bool, // Fisheye corners show the background instead of black
+    aperture: Aperture, // Shape of the defocus disk
+    vignetting: Option<Vignetting>,
+    distortion: LensDistortion, // Radial distortion of perspective images
+    block: usize, // Image pixels across each rendered pixel, above 1 for coarse previews
+
+    render_height: usize, // Rendered image height
+    center: Point3<Float>, // Camera center
+    pixel00_loc: Point3<Float>, // Location of pixel (0, 0)
+    pixel_delta_u: Vector3<Float>, // Offset to pixel to the right
+    pixel_delta_v: Vector3<Float>, // Offset to pixel below
+
+    // Camera frame basis vectors
+    u: Vector3<Float>, // right
+    v: Vector3<Float>, // up
+    w: Vector3<Float>, // backwards
+
+    defocus_disk_u: Vector3<Float>, // Defocus disk horizontal radius
+    defocus_disk_v: Vector3<Float>, // Defocus disk vertical radius
+    focus_plane_normal: Vector3<Float> // Equals w unless the lens is tilted
+}
+
+// Camera with all builder defaults
+impl Default for Camera {
+    fn default() -> Self {
+        CameraBuilder::default().build().unwrap()
+    }
+}
+
+impl Camera {
+    pub fn builder() -> CameraBuilder {
+        CameraBuilder::default()
+    }
+
+    // Builder for a camera placed by a world transform, e.g. one exported from a modeling tool
+    pub fn from_isometry(pose: Isometry3<Float>, width: usize, fov: Float) -> CameraBuilder {
+        CameraBuilder::default().pose(pose).width(width).fov(fov)
+    }
+
+    pub fn width(&self) -> usize {
+        self.render_width
+    }
+
+    pub fn height(&self) -> usize {
+        self.render_height
+    }
+
+    // Aspect ratio of the pixel grid, which can differ a bit from the requested one
+    pub fn aspect_ratio(&self) -> Float {
+        self.render_width as Float / self.render_height as Float
+    }
+
+    pub fn focus_dist(&self) -> Float {
+        self.focus_dist
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.settings.seed
+    }
+
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    // World transform of the camera, looking down its -Z axis with Y up
+    pub fn pose(&self) -> Isometry3<Float> {
+        let rotation = Rotation3::from_matrix_unchecked(Matrix3::from_columns(&[self.u, self.v, self.w]));
+        Isometry3::from_parts(self.center.coords.into(), UnitQuaternion::from_rotation_matrix(&rotation))
+    }
+
+    // Renderer with the settings the camera was built with, see Renderer::builder
+    pub fn renderer(&self) -> Renderer {
+        Renderer::builder(self.clone()).build_unchecked()
+    }
+
+    pub fn render(&self, scene: &Scene) -> Box<PPM> {
+        self.renderer().render(scene)
+    }
+
+    fn sample_ray(&self, i: usize, j: usize, (jitter_x, jitter_y): (Float, Float), differentials: bool) -> Option<Ray> {
+        // Get a camera ray through the given position of the pixel at location i,j, originating
+        // from the camera defocus disk. There is no ray outside of the fisheye image circle.
+        // With differentials, the same sample is also traced dx, dy = 1 pixels further.
+        // Coarse copies address the top left image pixel of a block and jitter over all of it.
+        let scale = self.block as Float;
+        let (i, j, jitter_x, jitter_y) = (i * self.block, j * self.block, jitter_x * scale, jitter_y * scale);
+        let (x, y) = (j as Float + jitter_x, i as Float + jitter_y);
+        if let Projection::Equirectangular = self.projection {
+            let direction = |dx: Float, dy: Float| self.equirectangular_direction(x + dx, y + dy);
+            let mut ray = Ray::new_at_time(self.center, direction(0.0, 0.0), self.sample_time());
+            if differentials {
+                ray.differentials = Some(RayDifferentials {
+                    x_orig: self.center,
+                    x_dir: direction(scale, 0.0),
+                    y_orig: self.center,
+                    y_dir: direction(0.0, scale),
+                });
+            }
+            return Some(ray);
+        }
+        let pixel_sample = |dx: Float, dy: Float| {
+            let point = match self.projection {
+                Projection::Perspective => {
+                    let pixel_center =
+                        self.pixel00_loc + (j as Float * self.pixel_delta_u) + (i as Float * self.pixel_delta_v);
+                    let offset = (-0.5 + jitter_x + dx) * self.pixel_delta_u + (-0.5 + jitter_y + dy) * self.pixel_delta_v;
+                    self.undistort(pixel_center + offset)
+                },
+                _ => self.center + self.focus_dist * self.fisheye_direction(x + dx, y + dy)?,
+            };
+            Some(self.tilted_focus(point))
+        };
+
+        let target = pixel_sample(0.0, 0.0)?;
+        let ray_origin = if self.defocus_angle_degrees <= 0.0 { self.center } else { self.defocus_disk_sample() };
+        let mut ray = Ray::new_at_time(ray_origin, target - ray_origin, self.sample_time());
+        if differentials {
+            // Neighbors outside of the fisheye image circle have none
+            if let (Some(x_target), Some(y_target)) = (pixel_sample(scale, 0.0), pixel_sample(0.0, scale)) {
+                ray.differentials = Some(RayDifferentials {
+                    x_orig: ray_origin,
+                    x_dir: x_target - ray_origin,
+                    y_orig: ray_origin,
+                    y_dir: y_target - ray_origin,
+                });
+            }
+        }
+        Some(ray)
+    }
+
+    // Point on the focus plane whose ray the lens bends into the given point of the viewport
+    fn undistort(&self, viewport_point: Point3<Float>) -> Point3<Float> {
+        if self.distortion.is_identity() {
+            return viewport_point;
+        }
+        let viewport_center = self.center - self.focus_dist * self.w;
+        let offset = (viewport_point - viewport_center) / self.focus_dist;
+        let ideal = self.distortion.undistort(vector![offset.dot(&self.u), offset.dot(&self.v)]);
+        viewport_center + self.focus_dist * (ideal.x * self.u + ideal.y * self.v)
+    }
+
+    // Where the central ray through a point of the untilted focus plane meets the tilted one
+    // (Scheimpflug principle). Rays almost parallel to the tilted plane keep the untilted point.
+    fn tilted_focus(&self, pixel_sample: Point3<Float>) -> Point3<Float> {
+        if self.lens_tilt_degrees == (0.0, 0.0) {
+            return pixel_sample;
+        }
+        let direction = pixel_sample - self.center;
+        let denominator = self.focus_plane_normal.dot(&direction);
+        if denominator.abs() < 1e-9 {
+            return pixel_sample;
+        }
+        let t = self.focus_plane_normal.dot(&(-self.focus_dist * self.w)) / denominator;
+        if t <= 0.0 { pixel_sample } else { self.center + t * direction }
+    }
+
+    // Share of the light of a primary ray that makes it through the lens
+    fn vignetting_weight(&self, ray: &Ray, i: usize, j: usize) -> Float {
+        match self.vignetting {
+            None => 1.0,
+            Some(Vignetting::Natural { barrel }) => {
+                let forward = ray.dir.dot(&-self.w);
+                let cos = (forward / ray.dir.norm()).max(0.0);
+                if let Some(barrel) = barrel {
+                    let radius = self.defocus_disk_u.norm();
+                    if radius > 0.0 && forward > 0.0 {
+                        // Where the ray crosses the plane of the second opening, in defocus disk radii
+                        let offset = ray.orig - self.center;
+                        let x = offset.dot(&self.u) / radius + barrel * ray.dir.dot(&self.u) / forward;
+                        let y = offset.dot(&self.v) / radius + barrel * ray.dir.dot(&self.v) / forward;
+                        if x * x + y * y > 1.0 {
+                            return 0.0;
+                        }
+                    }
+                }
+                cos.powi(4)
+            },
+            Some(Vignetting::Radial { strength, exponent }) => {
+                let scale = self.block as Float;
+                let dx = (j as Float + 0.5) * scale - self.render_width as Float / 2.0;
+                let dy = (i as Float + 0.5) * scale - self.render_height as Float / 2.0;
+                let corner = (self.render_width as Float).hypot(self.render_height as Float) / 2.0;
+                (1.0 - strength * (dx.hypot(dy) / corner).powf(exponent)).max(0.0)
+            }
+        }
+    }
+
+    // Uniform over the shutter interval
+    fn sample_time(&self) -> Float {
+        let (open, close) = self.shutter;
+        if open == close { open } else { open + rand() * (close - open) }
+    }
+
+    fn forward_ray(&self) -> Ray {
+        Ray::new(self.center, -self.w)
+    }
+
+    // Unit direction through the point (x, y) of the image, measured in pixels from the top left
+    // corner, or None if the point is outside of the image circle
+    fn fisheye_direction(&self, x: Float, y: Float) -> Option<Vector3<Float>> {
+        let radius = self.render_width.min(self.render_height) as Float / 2.0;
+        let dx = (x - self.render_width as Float / 2.0) / radius;
+        let dy = (self.render_height as Float / 2.0 - y) / radius;
+        let r = (dx * dx + dy * dy).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+
+        // Rotate the forward axis by theta towards the azimuth phi
+        let theta = r * degrees_to_radians(self.fov_degrees) / 2.0;
+        let phi = dy.atan2(dx);
+        Some(theta.sin() * (phi.cos() * self.u + phi.sin() * self.v) - theta.cos() * self.w)
+    }
+
+    fn defocus_disk_sample(&self) -> Point3<Float> {
+        let p = self.aperture.sample();
+        self.center + (p.x * self.defocus_disk_u) + (p.y * self.defocus_disk_v)
+    }
+
+    // Unit direction through the point (x, y) of the panorama, measured in pixels from the top left
+    // corner. Both angles come straight from the continuous position, so jitter across the seam
+    // or over a pole wraps around smoothly.
+    fn equirectangular_direction(&self, x: Float, y: Float) -> Vector3<Float> {
+        let longitude = (x / self.render_width as Float - 0.5) * 2.0 * PI;
+        let latitude = (0.5 - y / self.render_height as Float) * PI;
+        let horizontal = longitude.sin() * self.u - longitude.cos() * self.w;
+        latitude.cos() * horizontal + latitude.sin() * self.v
+    }
+
+    fn initialize(&mut self) {
+        self.init_basis();
+        self.init_viewport();
+    }
+
+    // Image size and camera frame, everything autofocus needs to cast a ray
+    fn init_basis(&mut self) {
+        self.render_height = match self.exact_height {
+            Some(height) if self.projection != Projection::Equirectangular => height,
+            _ => {
+                let aspect_ratio = if self.projection == Projection::Equirectangular { 2.0 } else { self.aspect_ratio };
+                ((self.render_width as Float / aspect_ratio) as usize).max(1)
+            }
+        };
+        println!("Image size: W:{}, H:{}", self.render_width, self.render_height);
+
+        // Calculate the u,v,w unit basis vectors for the camera coordinate frame
+        match self.pose {
+            Some(pose) => {
+                // The camera looks down -Z of the pose
+                self.center = pose.translation.vector.into();
+                self.u = pose.rotation * Vector3::x();
+                self.v = pose.rotation * Vector3::y();
+                self.w = pose.rotation * Vector3::z();
+            },
+            None => {
+                self.center = self.lookfrom;
+                self.w = (self.lookfrom - self.lookat).normalize();
+                self.u = (self.vup.cross(&self.w)).normalize();
+                self.v = self.w.cross(&self.u);
+            }
+        }
+    }
+
+    // Viewport and defocus disk, which depend on the focus distance
+    fn init_viewport(&mut self) {
+        // Determine viewport dimensions.
+        let theta = degrees_to_radians(self.fov_degrees);
+        // height of camera field of view
+        let h = (theta / 2.0).tan();
+        let viewport_height = 2.0 * h * self.focus_dist;
+        let viewport_width = viewport_height * (self.render_width as Float) / (self.render_height as Float);
+
+        println!(
+            "Initialized viewport: W:{}, H:{}",
+            viewport_width, viewport_height
+        );
+
+        // Calculate the vectors across the horizontal and down the vertical viewport edges
+        let viewport_u = viewport_width * self.u;
+        let viewport_v = viewport_height * -self.v;
+
+        // Calculate the horizontal and vertical delta vectors from pixel to pixel
+        self.pixel_delta_u = viewport_u / self.render_width as Float;
+        self.pixel_delta_v = viewport_v / self.render_height as Float;
+
+        // Calculate the location of the upper left pixel.
+        let viewport_upper_left =
+            self.center - self.focus_dist * self.w - viewport_u / 2.0 - viewport_v / 2.0;
+        self.pixel00_loc = viewport_upper_left + (0.5 as Float) * (self.pixel_delta_u + self.pixel_delta_v);
+
+        // Calculate the camera defocus disk basis vectors
+        let defocus_radius = self.focus_dist * (degrees_to_radians(self.defocus_angle_degrees / 2.0).tan());
+        self.defocus_disk_u = self.u * defocus_radius;
+        self.defocus_disk_v = self.v * defocus_radius;
+
+        // The focus plane keeps going through the point focus_dist in front of the camera
+        let (about_u, about_v) = self.lens_tilt_degrees;
+        let tilt = Rotation3::from_axis_angle(&Unit::new_normalize(self.u), degrees_to_radians(about_u))
+            * Rotation3::from_axis_angle(&Unit::new_normalize(self.v), degrees_to_radians(about_v));
+        self.focus_plane_normal = tilt * self.w;
+    }
+
+    // Copy of the camera rotated by index / frames of a full turn around look_at. The axis is vup,
+    // so elevation, distance and fov stay the same. Frame 0 and frame `frames` are the same pose.
+    pub fn turntable_camera(&self, index: usize, frames: usize, options: TurntableOptions) -> Camera {
+        let mut camera = self.clone();
+        camera.replace_pose();
+
+        let axis = camera.vup.normalize();
+        let offset = camera.lookfrom - camera.lookat;
+        let height = offset.dot(&axis);
+        let radial = offset - height * axis;
+
+        let direction = if options.clockwise { -1.0 } else { 1.0 };
+        let angle = direction * 2.0 * PI * index as Float / frames.max(1) as Float;
+        let rotated = radial * angle.cos() + axis.cross(&radial) * angle.sin();
+        camera.lookfrom = camera.lookat + rotated + height * axis;
+        camera.initialize();
+        camera
+    }
+
+    // Copy of the camera moved by offset along u, the view direction stays parallel or turns to
+    // keep look_at in the middle of the image
+    pub fn eye_camera(&self, offset: Float, mode: StereoMode) -> Camera {
+        let mut camera = self.clone();
+        camera.replace_pose();
+        let shift = offset * self.u;
+        camera.lookfrom += shift;
+        if mode == StereoMode::Parallel {
+            camera.lookat += shift;
+        }
+        camera.initialize();
+        camera
+    }
+
+    // Copy of the camera with factor times the image width and height and the same view
+    fn scaled(&self, factor: usize) -> Camera {
+        let mut camera = self.clone();
+        camera.render_width = self.render_width * factor;
+        camera.exact_height = Some(self.render_height * factor);
+        camera.initialize();
+        camera
+    }
+
+    // Copy of the camera for an image factor times smaller, rounded up. Each of its pixels covers a
+    // factor × factor block of this camera's pixels, so the view and the pixel grid stay the same.
+    fn coarse(&self, factor: usize) -> Camera {
+        Camera { block: self.block * factor, ..self.clone() }
+    }
+
+    // Settings as plain data for saving, a pose is saved as lookfrom, lookat and vup. Only covers
+    // what CameraDesc has fields for.
+    pub fn to_desc(&self) -> CameraDesc {
+        let mut camera = self.clone();
+        camera.replace_pose();
+        CameraDesc {
+            width: Some(self.render_width),
+            height: Some(self.render_height),
+            aspect_ratio: None,
+            samples: Some(self.settings.samples_per_pixel),
+            max_bounces: Some(self.settings.max_bounces),
+            fov: Some(self.fov_degrees),
+            lookfrom: Some(camera.lookfrom.coords.into()),
+            lookat: Some(camera.lookat.coords.into()),
+            vup: Some(camera.vup.into()),
+            defocus_angle: Some(self.defocus_angle_degrees),
+            focus_dist: Some(self.focus_dist),
+            shutter: Some([self.shutter.0, self.shutter.1]),
+            seed: self.settings.seed,
+            background: self.settings.background.map(array),
+        }
+    }
+
+    fn frame(&mut self, bounds: &Aabb, direction: Vector3<Float>) {
+        // The bounding sphere of the box has to fit in the narrower of the two fields of view.
+        // Empty boxes and single points get a unit sphere so the distance stays finite.
+        let (center, radius) = match bounds.extent().norm() / 2.0 {
+            radius if radius > 0.0 => (bounds.center(), radius),
+            _ if bounds.is_empty() => (Point3::origin(), 1.0),
+            _ => (bounds.center(), 1.0),
+        };
+        let half_angle = match self.projection {
+            Projection::Perspective => {
+                let aspect_ratio = match self.exact_height {
+                    Some(height) => self.render_width as Float / height as Float,
+                    None => self.aspect_ratio,
+                };
+                let half_height = degrees_to_radians(self.fov_degrees / 2.0).tan();
+                half_height.min(half_height * aspect_ratio).atan()
+            },
+            Projection::Fisheye | Projection::Equirectangular => degrees_to_radians(self.fov_degrees.min(180.0) / 2.0),
+        };
+        let direction = if direction.norm() > 0.0 { direction.normalize() } else { Vector3::z() };
+        let distance = FRAMING_MARGIN * radius / half_angle.sin();
+
+        self.pose = None;
+        self.lookat = center;
+        self.lookfrom = center + distance * direction;
+        self.focus_dist = distance;
+    }
+
+    // Turns a pose into the equivalent lookfrom, lookat and vup, with lookat on the focus plane
+    fn replace_pose(&mut self) {
+        if self.pose.take().is_some() {
+            self.lookfrom = self.center;
+            self.lookat = self.center - self.focus_dist * self.w;
+            self.vup = self.v;
+        }
+    }
+
+    // Copy of the camera focused on what the autofocus point sees, the copy has autofocus off.
+    // Cameras without autofocus are returned unchanged.
+    pub fn focused(&self, scene: &Scene) -> Camera {
+        let mut camera = self.clone();
+        if let Some(autofocus) = camera.autofocus.take() {
+            camera.focus_dist = self.autofocus_distance(autofocus, scene);
+            camera.init_viewport();
+        }
+        camera
+    }
+
+    // Ray from the middle of the lens through the point (x, y) of the image in pixels, (0, 0) is
+    // the top left corner. It starts at the shutter open time. None outside of the fisheye image
+    // circle.
+    fn central_ray(&self, x: Float, y: Float) -> Option<Ray> {
+        let direction = match self.projection {
+            Projection::Perspective => {
+                let viewport_point = self.pixel00_loc + (x - 0.5) * self.pixel_delta_u + (y - 0.5) * self.pixel_delta_v;
+                self.undistort(viewport_point) - self.center
+            },
+            Projection::Fisheye => self.fisheye_direction(x, y)?,
+            Projection::Equirectangular => self.equirectangular_direction(x, y),
+        };
+        Some(Ray::new_at_time(self.center, direction, self.shutter.0))
+    }
+
+    // Where a ray leaving the middle of the lens in the given direction lands on the image, in
+    // pixels from the top left corner. The inverse of central_ray, e.g. to find the pixel a light
+    // path connecting to the camera adds to. Lens tilt is left out. None if it misses the image.
+    pub fn raster_position(&self, direction: &Vector3<Float>) -> Option<(Float, Float)> {
+        let (width, height) = (self.render_width as Float, self.render_height as Float);
+        let (right, up, forward) = (direction.dot(&self.u), direction.dot(&self.v), -direction.dot(&self.w));
+        let (x, y) = match self.projection {
+            Projection::Perspective => {
+                if forward <= 0.0 {
+                    return None;
+                }
+                let viewport = self.distortion.distort(vector![right / forward, up / forward]) * self.focus_dist;
+                (width / 2.0 + viewport.x / self.pixel_delta_u.norm(), height / 2.0 - viewport.y / self.pixel_delta_v.norm())
+            },
+            Projection::Fisheye => {
+                let theta = right.hypot(up).atan2(forward);
+                let r = theta / (degrees_to_radians(self.fov_degrees) / 2.0);
+                if r > 1.0 {
+                    return None;
+                }
+                let radius = width.min(height) / 2.0;
+                let phi = up.atan2(right);
+                (width / 2.0 + r * phi.cos() * radius, height / 2.0 - r * phi.sin() * radius)
+            },
+            Projection::Equirectangular => {
+                let longitude = right.atan2(forward);
+                let latitude = up.atan2(right.hypot(forward));
+                ((longitude / (2.0 * PI) + 0.5) * width % width, (0.5 - latitude / PI) * height)
+            },
+        };
+        let inside = x >= 0.0 && y >= 0.0 && x < width && y < height;
+        inside.then_some((x, y))
+    }
+
+    // Distance of the focus plane through the first hit, look_at is in focus if nothing is hit
+    fn autofocus_distance(&self, autofocus: Autofocus, scene: &Scene) -> Float {
+        let fallback = if self.pose.is_some() { self.focus_dist } else { (self.lookat - self.lookfrom).norm() };
+        let (x, y) = match autofocus {
+            Autofocus::Center => (self.render_width as Float / 2.0, self.render_height as Float / 2.0),
+            Autofocus::Pixel { x, y } => (x, y),
+        };
+        let Some(ray) = self.central_ray(x, y) else {
+            return fallback;
+        };
+
+        match scene.hit(&ray, Interval::new(MIN_T, INF)) {
+            // The focus plane is perpendicular to the view axis
+            Some(hit) => (hit.p - self.center).dot(&-self.w),
+            None => fallback
+        }
+    }
+}
+
+// Settings rejected by CameraBuilder::build
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraError {
+    ZeroWidth,
+    ZeroHeight,
+    InvalidAspectRatio(Float),
+    InvalidFov(Float),
+    NonPositiveFocusDistance(Float),
+    LookFromEqualsLookAt,
+    VupParallelToViewDirection,
+}
+
+impl Display for CameraError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CameraError::ZeroWidth => write!(f, "image width must be at least 1 pixel"),
+            CameraError::ZeroHeight => write!(f, "image height must be at least 1 pixel"),
+            CameraError::InvalidAspectRatio(ratio) => write!(f, "aspect ratio must be positive, got {}", ratio),
+            CameraError::InvalidFov(fov) => write!(
+                f,
+                "field of view must be above 0 and below 180 degrees (up to 360 for fisheye), got {}",
+                fov
+            ),
+            CameraError::NonPositiveFocusDistance(dist) => write!(f, "focus distance must be positive, got {}", dist),
+            CameraError::LookFromEqualsLookAt => write!(f, "look_from and look_at are the same point"),
+            CameraError::VupParallelToViewDirection => {
+                write!(f, "vup is parallel to the view direction, the camera roll is undefined")
+            }
+        }
+    }
+}
+
+impl Error for CameraError {}
+
+// Named settings for a Camera, anything not set keeps the default below
+#[derive(Clone)]
+pub struct CameraBuilder {
+    camera: Camera,
+    framing: Option<(Aabb, Vector3<Float>)>, // Resolved in build, once fov and image size are final
+}
+
+impl Default for CameraBuilder {
+    fn default() -> Self {
+        Self {
+            camera: Camera {
+                render_width: 100,
+                aspect_ratio: 1.0,
+                exact_height: None,
+                settings: RenderSettings::default(),
+                fov_degrees: 90.0,
+                lookfrom: Point3::origin(),
+                lookat: point![0.0, 0.0, -1.0],
+                vup: vector![0.0, 1.0, 0.0],
+                pose: None,
+                defocus_angle_degrees: 0.0,
+                focus_dist: 10.0,
+                lens_tilt_degrees: (0.0, 0.0),
+                autofocus: None,
+                shutter: (0.0, 0.0),
+                projection: Projection::default(),
+                fill_outside_image_circle: false,
+                aperture: Aperture::default(),
+                vignetting: None,
+                distortion: LensDistortion::default(),
+                block: 1,
+                render_height: 0,
+                center: Point3::origin(),
+                pixel00_loc: Point3::origin(),
+                pixel_delta_u: Vector3::zeros(),
+                pixel_delta_v: Vector3::zeros(),
+                u: Vector3::zeros(),
+                v: Vector3::zeros(),
+                w: Vector3::zeros(),
+                defocus_disk_u: Vector3::zeros(),
+                defocus_disk_v: Vector3::zeros(),
+                focus_plane_normal: Vector3::zeros()
+            },
+            framing: None,
+        }
+    }
+}
+
+impl CameraBuilder {
+    pub fn width(mut self, width: usize) -> Self {
+        self.camera.render_width = width;
+        self
+    }
+
+    // Height is width / aspect_ratio rounded down, replaces an earlier height
+    pub fn aspect_ratio(mut self, aspect_ratio: Float) -> Self {
+        self.camera.aspect_ratio = aspect_ratio;
+        self.camera.exact_height = None;
+        self
+    }
+
+    // Exact image height, replaces an earlier aspect ratio. Panoramas are always 2:1 and ignore it.
+    pub fn height(mut self, height: usize) -> Self {
+        self.camera.exact_height = Some(height);
+        self
+    }
+
+    pub fn dimensions(self, width: usize, height: usize) -> Self {
+        self.width(width).height(height)
+    }
+
+    pub fn samples_per_pixel(mut self, samples: u32) -> Self {
+        self.camera.settings.samples_per_pixel = samples;
+        self
+    }
+
+    pub fn max_bounces(mut self, max_bounces: u32) -> Self {
+        self.camera.settings.max_bounces = max_bounces;
+        self
+    }
+
+    // Vertical field of view in degrees, or the diameter of the image circle for fisheye
+    pub fn fov(mut self, degrees: Float) -> Self {
+        self.camera.fov_degrees = degrees;
+        self
+    }
+
+    pub fn look_from(mut self, lookfrom: Point3<Float>) -> Self {
+        self.camera.lookfrom = lookfrom;
+        self
+    }
+
+    pub fn look_at(mut self, lookat: Point3<Float>) -> Self {
+        self.camera.lookat = lookat;
+        self
+    }
+
+    pub fn vup(mut self, vup: Vector3<Float>) -> Self {
+        self.camera.vup = vup;
+        self
+    }
+
+    // Places the camera with a world transform instead of look_from, look_at and vup.
+    // Forward is -Z of the pose and up is +Y.
+    pub fn pose(mut self, pose: Isometry3<Float>) -> Self {
+        self.camera.pose = Some(pose);
+        self
+    }
+
+    // Variation angle of rays through each pixel, 0 disables defocus blur
+    pub fn defocus_angle(mut self, degrees: Float) -> Self {
+        self.camera.defocus_angle_degrees = degrees;
+        self
+    }
+
+    // Distance from look_from to the plane of perfect focus
+    pub fn focus_dist(mut self, focus_dist: Float) -> Self {
+        self.camera.focus_dist = focus_dist;
+        self
+    }
+
+    // Tilts the plane of sharp focus about the camera's right and up axes, it still goes through
+    // the point focus_dist in front of the camera
+    pub fn lens_tilt(mut self, about_u_degrees: Float, about_v_degrees: Float) -> Self {
+        self.camera.lens_tilt_degrees = (about_u_degrees, about_v_degrees);
+        self
+    }
+
+    // Looks at the center of bounds from the given direction, far enough away for the whole box
+    // to fit in the image with a small margin, and focuses there. Replaces look_from, look_at,
+    // focus_dist and pose, vup stays.
+    pub fn frame(mut self, bounds: Aabb, direction: Vector3<Float>) -> Self {
+        self.framing = Some((bounds, direction));
+        self
+    }
+
+    pub fn frame_scene(self, scene: &Scene, direction: Vector3<Float>) -> Self {
+        self.frame(scene.bounds(), direction)
+    }
+
+    // Replaces focus_dist with the distance to the object at the given point of the image
+    pub fn autofocus(mut self, autofocus: Autofocus) -> Self {
+        self.camera.autofocus = Some(autofocus);
+        self
+    }
+
+    // Time interval the shutter is open, moving objects blur along their path during it
+    pub fn shutter(mut self, open: Float, close: Float) -> Self {
+        self.camera.shutter = (open, close);
+        self
+    }
+
+    // Radiance of rays leaving the scene instead of the sky gradient
+    pub fn background(mut self, background: RGB) -> Self {
+        self.camera.settings.background = Some(background);
+        self
+    }
+
+    // Enables the caustic photon map pre-pass
+    pub fn caustics(mut self, settings: PhotonMapSettings) -> Self {
+        self.camera.settings.caustics = Some(settings);
+        self
+    }
+
+    // Camera rays that miss everything get zero alpha
+    pub fn transparent_background(mut self, transparent: bool) -> Self {
+        self.camera.settings.transparent_background = transparent;
+        self
+    }
+
+    // Checks every sample for NaN and infinite colors, sanitizes them by default
+    pub fn sample_check(mut self, check: SampleCheck) -> Self {
+        self.camera.settings.sample_check = check;
+        self
+    }
+
+    // Extra buffers filled by Renderer::render_output
+    pub fn aovs(mut self, aovs: AovFlags) -> Self {
+        self.camera.settings.aovs = aovs;
+        self
+    }
+
+    pub fn light_groups(mut self, count: usize) -> Self {
+        self.camera.settings.light_groups = count;
+        self
+    }
+
+    pub fn mode(mut self, mode: RenderMode) -> Self {
+        self.camera.settings.mode = mode;
+        self
+    }
+
+    pub fn time_budget(mut self, budget: Duration) -> Self {
+        self.camera.settings.time_budget = Some(budget);
+        self
+    }
+
+    pub fn ray_differentials(mut self, enabled: bool) -> Self {
+        self.camera.settings.ray_differentials = enabled;
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.camera.settings.filter = filter;
+        self
+    }
+
+    pub fn spectral(mut self, sampling: WavelengthSampling) -> Self {
+        self.camera.settings.spectral = Some(sampling);
+        self
+    }
+
+    // Makes renders reproducible, without a seed they are random every run
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.camera.settings.seed = Some(seed);
+        self
+    }
+
+    pub fn projection(mut self, projection: Projection) -> Self {
+        self.camera.projection = projection;
+        self
+    }
+
+    // Fisheye corners show the background instead of black
+    pub fn fill_outside_image_circle(mut self, fill: bool) -> Self {
+        self.camera.fill_outside_image_circle = fill;
+        self
+    }
+
+    // Shape of the defocus disk
+    pub fn aperture(mut self, aperture: Aperture) -> Self {
+        self.camera.aperture = aperture;
+        self
+    }
+
+    // Brown-Conrady coefficients, only perspective images are distorted
+    pub fn lens_distortion(mut self, k1: Float, k2: Float) -> Self {
+        self.camera.distortion = LensDistortion::new(k1, k2);
+        self
+    }
+
+    // Darker image edges, off by default
+    pub fn vignetting(mut self, vignetting: Vignetting) -> Self {
+        self.camera.vignetting = Some(vignetting);
+        self
+    }
+
+    pub fn build(self) -> Result<Camera, CameraError> {
+        let mut camera = self.camera;
+        if camera.render_width == 0 {
+            return Err(CameraError::ZeroWidth);
+        }
+        match camera.exact_height {
+            Some(0) => return Err(CameraError::ZeroHeight),
+            Some(_) => {},
+            None if camera.aspect_ratio.is_nan() || camera.aspect_ratio <= 0.0 => {
+                return Err(CameraError::InvalidAspectRatio(camera.aspect_ratio));
+            },
+            None => {}
+        }
+        let max_fov = match camera.projection {
+            Projection::Perspective => Some(180.0),
+            Projection::Fisheye => Some(360.0),
+            Projection::Equirectangular => None,
+        };
+        if let Some(max_fov) = max_fov {
+            let fov = camera.fov_degrees;
+            let valid = fov > 0.0 && (fov < max_fov || (fov == max_fov && camera.projection == Projection::Fisheye));
+            if !valid {
+                return Err(CameraError::InvalidFov(fov));
+            }
+        }
+        if let Some((bounds, direction)) = self.framing {
+            camera.frame(&bounds, direction);
+        }
+        if camera.focus_dist.is_nan() || camera.focus_dist <= 0.0 {
+            return Err(CameraError::NonPositiveFocusDistance(camera.focus_dist));
+        }
+
+        if camera.pose.is_none() {
+            let view = camera.lookfrom - camera.lookat;
+            if view.norm() == 0.0 {
+                return Err(CameraError::LookFromEqualsLookAt);
+            }
+            if camera.vup.cross(&view.normalize()).norm() < 1e-9 {
+                return Err(CameraError::VupParallelToViewDirection);
+            }
+        }
+
+        camera.initialize();
+        Ok(camera)
+    }
+}
+
+// Room left around framed bounds, relative to their bounding sphere
+const FRAMING_MARGIN: Float = 1.05;
+
+// Kind of the last bounce, needed to avoid counting caustics twice
+#[derive(Copy, Clone, PartialEq)]
+enum PathState {
+    Primary,
+    Diffuse,
+    // At least one specular bounce right after a diffuse one
+    Caustic,
+}
+
+// Radiance of one sample split by light group while its path is traced, see
+// RenderSettings::light_groups. Without any sums to add to it does nothing.
+struct GroupSplit<'s> {
+    sums: &'s mut [RGB], // One per light group, then the background
+    throughput: RGB, // Attenuation along the path up to the current bounce
+}
+
+impl<'s> GroupSplit<'s> {
+    fn new(sums: &'s mut [RGB], weight: Float) -> Self {
+        Self { sums, throughput: RGB::white() * weight }
+    }
+
+    fn add_light(&mut self, group: usize, radiance: RGB) {
+        if let Some(last_light) = self.sums.len().checked_sub(2) {
+            self.sums[group.min(last_light)] += self.throughput * radiance;
+        }
+    }
+
+    fn add_background(&mut self, radiance: RGB) {
+        if let Some(background) = self.sums.last_mut() {
+            *background += self.throughput * radiance;
+        }
+    }
+
+    fn add_caustics(&mut self, caustics: &PhotonMap, p: &Point3<Float>, albedo: RGB) {
+        if let Some(last_light) = self.sums.len().checked_sub(2) {
+            let (sums, throughput) = (&mut *self.sums, self.throughput);
+            caustics.estimate_groups(p, albedo, |group, radiance| sums[group.min(last_light)] += throughput * radiance);
+        }
+    }
+}
+
+// Attenuation of a scatter at the wavelengths of a spectral path, which the scattered ray keeps.
+// Once they split up only the hero goes on.
+fn spectral_scatter((mut scattered, attenuation): (Ray, RGB), wavelengths: Wavelengths) -> (Ray, RGB) {
+    let next = *scattered.wavelengths.get_or_insert(wavelengths);
+    let attenuation = wavelengths.upsample(attenuation);
+    if wavelengths.secondary && !next.secondary {
+        (scattered, attenuation * Wavelengths::termination_weight())
+    } else {
+        (scattered, attenuation)
+    }
+}
+
+struct Integrator<'a> {
+    scene: &'a Scene,
+    background: Option<RGB>,
+    caustics: Option<&'a PhotonMap>,
+    trace: Option<Mutex<Vec<Bounce>>>, // Every bounce gets recorded here if set
+}
+
+impl<'a> Integrator<'a> {
+    fn new(scene: &'a Scene, background: Option<RGB>, caustics: Option<&'a PhotonMap>) -> Self {
+        Self { scene, background, caustics, trace: None }
+    }
+
+    fn tracing(mut self) -> Self {
+        self.trace = Some(Mutex::default());
+        self
+    }
+
+    fn record(&self, ray: &Ray, event: impl FnOnce() -> BounceEvent) {
+        if let Some(trace) = &self.trace {
+            let bounce = Bounce { origin: ray.orig, direction: ray.dir, event: event() };
+            trace.lock().unwrap().push(bounce);
+        }
+    }
+
+    fn escaped(&self, ray: &Ray, groups: &mut GroupSplit) -> RGB {
+        let background = match ray.wavelengths {
+            Some(wavelengths) => wavelengths.upsample(self.background(ray)),
+            None => self.background(ray)
+        };
+        self.record(ray, || BounceEvent::Escaped { background });
+        groups.add_background(background);
+        background
+    }
+
+    // First hit of a ray leaving the camera, there is none without any bounces left
+    fn camera_hit(&self, ray: &Ray, depth: u32) -> Option<HitRecord<'_>> {
+        if depth == 0 {
+            return None;
+        }
+        self.scene.hit(ray, Interval::new(MIN_T, INF))
+    }
+
+    #[cfg(feature = "packets")]
+    fn camera_hit4(&self, packet: &RayPacket4, depth: u32) -> [Option<HitRecord<'_>>; LANES] {
+        if depth == 0 {
+            return Default::default();
+        }
+        self.scene.hit4(packet, [Interval::new(MIN_T, INF); LANES])
+    }
+
+    // Color of a ray leaving the camera given its first hit from camera_hit, which is passed on
+    fn camera_hit_color<'b>(
+        &self,
+        ray: &Ray,
+        hit: Option<HitRecord<'b>>,
+        depth: u32,
+        groups: &mut GroupSplit
+    ) -> (RGB, Option<HitRecord<'b>>) {
+        if depth == 0 {
+            return (RGB::default(), None);
+        }
+
+        match hit {
+            Some(hit) => (self.shade(ray, &hit, depth, PathState::Primary, groups), Some(hit)),
+            None => (self.escaped(ray, groups), None)
+        }
+    }
+
+    fn ray_color(&self, ray: &Ray, depth: u32, state: PathState, groups: &mut GroupSplit) -> RGB {
+        if depth == 0 {
+            return RGB::default();
+        }
+
+        match self.scene.hit(ray, Interval::new(MIN_T, INF)) {
+            Some(hit) => self.shade(ray, &hit, depth, state, groups),
+            None => self.escaped(ray, groups)
+        }
+    }
+
+    fn shade(&self, ray: &Ray, hit: &HitRecord, depth: u32, state: PathState, groups: &mut GroupSplit) -> RGB {
+        // Light reaching a diffuse surface through specular bounces is in the photon map already
+        let emitted = if state == PathState::Caustic && self.caustics.is_some() {
+            RGB::default()
+        } else if let Some(wavelengths) = &ray.wavelengths {
+            hit.material.emitted_spectral(wavelengths)
+        } else {
+            hit.material.emitted()
+        };
+
+        let scatter = hit.material.scatter(ray, hit).map(|scatter| match ray.wavelengths {
+            Some(wavelengths) => spectral_scatter(scatter, wavelengths),
+            None => scatter
+        });
+        self.record(ray, || BounceEvent::Hit {
+            object: hit.object.expect("hits found by a scene have an object"),
+            t: hit.t,
+            point: hit.p,
+            normal: hit.normal,
+            front: hit.front,
+            emitted,
+            specular: hit.material.is_specular(),
+            attenuation: scatter.as_ref().map(|(_, attenuation)| *attenuation),
+        });
+        groups.add_light(hit.material.light_group(), emitted);
+        match scatter {
+            Some((scattered, attenuation)) => {
+                let next = match state {
+                    _ if !hit.material.is_specular() => PathState::Diffuse,
+                    PathState::Primary => PathState::Primary,
+                    _ => PathState::Caustic,
+                };
+                let throughput = groups.throughput;
+                groups.throughput = throughput * attenuation;
+                let color = emitted + attenuation * self.ray_color(&scattered, depth - 1, next, groups);
+                groups.throughput = throughput;
+                match self.caustics {
+                    Some(caustics) if !hit.material.is_specular() => {
+                        groups.add_caustics(caustics, &hit.p, attenuation);
+                        color + caustics.estimate(&hit.p, attenuation)
+                    },
+                    _ => color
+                }
+            },
+            None => emitted
+        }
+    }
+
+    fn background(&self, ray: &Ray) -> RGB {
+        if let Some(background) = self.background {
+            return background;
+        }
+
+        // Sky
+        let unit = ray.dir.normalize();
+        let a = 0.5 * (unit.y + 1.0);
+        let blue = vector![0.5, 0.7, 1.0];
+        let white = vector![1.0, 1.0, 1.0];
+        white.lerp(&blue, a).into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ops::Range;
+    use std::sync::Arc;
+    use na::{point, vector, Isometry3, Point3, Vector3};
+    use crate::aabb::Aabb;
+    use crate::accumulator::Accumulator;
+    use crate::camera::{AovFlags, Autofocus, Camera, CameraBuilder, CameraError, CancelToken, HeatmapMetric, RenderMode, RenderSettings, RenderSettingsError, Renderer, SampleCheck, Projection, StereoMode, TurntableOptions, VarianceView, Vignetting};
+    use crate::film::Film;
+    use crate::filter::Filter;
+    use crate::Float;
+    use crate::image::compare::compare;
+    use crate::image::{FloatImage, Image, PPM};
+    use crate::interval::Interval;
+    use crate::material::{Dielectric, DiffuseLight, Lambertian, Material, MaterialKind};
+    use crate::photon::PhotonMapSettings;
+    use crate::ray::Ray;
+    use crate::RGB;
+    use crate::scene::{HitRecord, Hittable, Scene, Sphere, Triangle};
+    use crate::scene::desc::{HittableDesc, SceneDesc};
+    use crate::scene::generators::RandomSpheres;
+    use crate::scene::sphere_list::SphereList;
+    use crate::path_trace::{BounceEvent, PathExport, PathFormat};
+    use crate::scenes::{final_scene, setup_scene};
+    use crate::spectral::{WavelengthSampling, Wavelengths};
+    use crate::utils::{seed_rng, tolerance, INF, MIN_T};
+
+    fn single_sphere() -> Arc<Scene> {
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            center: point![0.0, 0.0, -1.0],
+            radius: 0.5,
+            material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into()
+        });
+        Arc::new(scene)
+    }
+
+    fn camera(width: usize, samples: u32) -> CameraBuilder {
+        Camera::builder()
+            .width(width)
+            .samples_per_pixel(samples)
+            .focus_dist(1.0)
+    }
+
+    #[test]
+    fn test_alpha_coverage() {
+        let camera = camera(21, 16).transparent_background(true).build().unwrap();
+        let image = camera.renderer().render_parallel(single_sphere());
+        assert!(image.has_alpha());
+
+        assert_eq!(image.alpha(10, 10), 1.0);
+        assert_eq!(image.alpha(0, 0), 0.0);
+        assert_eq!(image.alpha(10, 0), 0.0);
+        // Sky is still visible in the sphere through bounces
+        assert!(image[(10, 10)].2 > 0.0);
+        assert_eq!(image[(0, 0)].2, 0.0);
+
+        let edge = (0..21).map(|j| image.alpha(10, j)).filter(|a| *a > 0.0 && *a < 1.0).count();
+        assert!(edge >= 2);
+    }
+
+    #[test]
+    fn test_opaque_by_default() {
+        let image = camera(8, 2).build().unwrap().renderer().render_parallel(single_sphere());
+        assert!(!image.has_alpha());
+        assert_eq!(image.alpha(0, 0), 1.0);
+        assert!(image[(0, 0)].2 > 0.0);
+    }
+
+    #[test]
+    fn test_aovs() {
+        let camera = camera(21, 4)
+            .background(RGB(0.1, 0.2, 0.3))
+            .aovs(AovFlags { normal: true, depth: true, albedo: true, object_id: false, variance: None })
+            .build()
+            .unwrap();
+        let output = camera.renderer().render_output(single_sphere());
+        let normal = output.normal.unwrap();
+        let depth = output.depth.unwrap();
+        let albedo = output.albedo.unwrap();
+
+        // The center of the sphere faces the camera
+        let n = normal[(10, 10)];
+        assert!(n.2 > 0.99 && n.0.abs() < 0.1 && n.1.abs() < 0.1);
+        assert!((depth[(10, 10)].0 - 0.5).abs() < 0.01);
+        assert!((albedo[(10, 10)].0 - 0.5).abs() < 1e-12);
+
+        // Depth grows toward the silhouette
+        assert!(depth[(10, 12)].0 > depth[(10, 11)].0);
+        assert!(depth[(10, 11)].0 > depth[(10, 10)].0);
+
+        // Background values
+        let corner = normal[(0, 0)];
+        assert_eq!((corner.0, corner.1, corner.2), (0.0, 0.0, 0.0));
+        assert_eq!(depth[(0, 0)].0, Float::MAX);
+        assert_eq!(albedo[(0, 0)].2, 0.3);
+    }
+
+    #[test]
+    fn test_variance_aov() {
+        seed_rng(11);
+        let scene = final_scene();
+        let view = camera(48, 16)
+            .aspect_ratio(16.0 / 9.0)
+            .fov(20.0)
+            .look_from(point![13.0, 2.0, 3.0])
+            .look_at(point![0.0, 0.0, 0.0])
+            .defocus_angle(0.6)
+            .focus_dist(10.0)
+            .aovs(AovFlags { variance: Some(VarianceView::Variance), ..AovFlags::default() })
+            .seed(3)
+            .build()
+            .unwrap();
+        let variance = view.renderer().render_output(scene).variance.unwrap();
+        let average = |pixels: &[(usize, usize)]| pixels.iter().map(|&px| variance[px].0).sum::<Float>() / pixels.len() as Float;
+
+        // The sky above everything is the same for every sample
+        let sky = average(&(0..2).flat_map(|i| (0..48).map(move |j| (i, j))).collect::<Vec<_>>());
+        assert!(sky < 1e-4, "{}", sky);
+        // The silhouette of the glass ball in the middle of the scene mixes light from all over
+        let glass = point![0.0, 1.0, 0.0];
+        let edges: Vec<_> = [-view.u, view.v].iter().flat_map(|side| {
+            let (x, y) = view.raster_position(&(glass + side.normalize() - view.center)).unwrap();
+            (0..9).map(move |k| ((y as usize + k / 3).saturating_sub(1), (x as usize + k % 3).saturating_sub(1)))
+        }).collect();
+        let glass_edges = average(&edges);
+        assert!(glass_edges > 1e-2 && glass_edges > 1000.0 * sky, "{} {}", glass_edges, sky);
+
+        // The other views follow from the variance and the mean
+        let render = |view: VarianceView| {
+            let camera = camera(16, 8).aovs(AovFlags { variance: Some(view), ..AovFlags::default() }).seed(2).build().unwrap();
+            let output = camera.renderer().render_output(single_sphere());
+            (output.beauty, output.variance.unwrap())
+        };
+        let (beauty, variance) = render(VarianceView::Variance);
+        let (_, standard_error) = render(VarianceView::StandardError);
+        let (_, relative) = render(VarianceView::RelativeNoise);
+        assert!(variance.pixels().iter().any(|px| px.0 > 0.0));
+        for (i, j) in (0..beauty.height()).flat_map(|i| (0..16).map(move |j| (i, j))) {
+            let se = standard_error[(i, j)].0;
+            assert!((se * se * 8.0 - variance[(i, j)].0).abs() < 1e-6);
+            assert!((relative[(i, j)].0 * beauty[(i, j)].luminance() - se).abs() < 1e-6);
+        }
+        assert!(camera(4, 2).build().unwrap().renderer().render_output(single_sphere()).variance.is_none());
+    }
+
+    fn fisheye(width: usize, fov: Float) -> CameraBuilder {
+        camera(width, 1).projection(Projection::Fisheye).fov(fov)
+    }
+
+    fn assert_direction(actual: Option<Vector3<Float>>, expected: Vector3<Float>) {
+        let actual = actual.unwrap();
+        assert!((actual - expected).norm() < tolerance(16.0), "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn test_fisheye_directions() {
+        let camera = fisheye(20, 180.0).build().unwrap();
+        // The camera looks down -z with y up
+        assert_direction(camera.fisheye_direction(10.0, 10.0), vector![0.0, 0.0, -1.0]);
+        assert_direction(camera.fisheye_direction(20.0, 10.0), vector![1.0, 0.0, 0.0]);
+        assert_direction(camera.fisheye_direction(0.0, 10.0), vector![-1.0, 0.0, 0.0]);
+        assert_direction(camera.fisheye_direction(10.0, 0.0), vector![0.0, 1.0, 0.0]);
+        assert_direction(camera.fisheye_direction(15.0, 10.0), vector![(0.5 as Float).sqrt(), 0.0, -((0.5 as Float).sqrt())]);
+        for (x, y) in [(0.0, 0.0), (20.0, 0.0), (0.0, 20.0), (20.0, 20.0)] {
+            assert!(camera.fisheye_direction(x, y).is_none());
+        }
+
+        // At 360 degrees the edge of the circle looks backwards
+        let camera = fisheye(20, 360.0).build().unwrap();
+        assert_direction(camera.fisheye_direction(20.0, 10.0), vector![0.0, 0.0, 1.0]);
+        assert_direction(camera.fisheye_direction(10.0, 5.0), vector![0.0, 1.0, 0.0]);
+    }
+
+    fn sphere_grid() -> Arc<Scene> {
+        let mut scene = Scene::new();
+        let mut add = |center: Point3<Float>, radius: Float| scene.add(Sphere {
+            center,
+            radius,
+            material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into()
+        });
+        add(point![0.0, 0.0, -3.0], 1.0);
+        for (x, y) in [(-2.0, -2.0), (-2.0, 2.0), (2.0, -2.0), (2.0, 2.0)] {
+            add(point![x, y, -3.0], 0.5);
+        }
+        // 90 degrees off the view axis
+        add(point![3.0, 0.0, 0.0], 0.5);
+        add(point![-3.0, 0.0, 0.0], 0.5);
+        add(point![0.0, 3.0, 0.0], 0.5);
+        Arc::new(scene)
+    }
+
+    #[test]
+    fn test_fisheye_render() {
+        let camera = fisheye(41, 180.0).samples_per_pixel(16).transparent_background(true).build().unwrap();
+        let image = camera.renderer().render_parallel(sphere_grid());
+        let covered = |i: usize, j: usize| image.alpha(i, j) > 0.5;
+
+        // The sphere in the middle stays round, its angular radius is asin(1 / 3)
+        let expected = (1.0 as Float / 3.0).asin().to_degrees() / 90.0 * 20.5 * 2.0;
+        let width = (0..41).filter(|&j| covered(20, j)).filter(|&j| (10..31).contains(&j)).count();
+        let height = (10..31).filter(|&i| covered(i, 20)).count();
+        assert!(width.abs_diff(height) <= 1, "{} x {}", width, height);
+        assert!((width as Float - expected).abs() <= 1.5, "{} vs {}", width, expected);
+
+        // Spheres to the sides and above are at the rim of the image circle
+        assert!(covered(20, 40) && covered(20, 0) && covered(0, 20));
+        assert!(!covered(40, 20));
+        assert_eq!(image.alpha(0, 0), 0.0);
+    }
+
+    #[test]
+    fn test_fisheye_outside_circle() {
+        let camera = fisheye(9, 180.0).background(RGB(0.1, 0.2, 0.3));
+        let image = camera.clone().build().unwrap().renderer().render_parallel(single_sphere());
+        assert_eq!(image[(0, 0)].2, 0.0);
+        assert_eq!(image[(4, 1)].2, 0.3);
+
+        let image = camera.fill_outside_image_circle(true).build().unwrap().renderer().render_parallel(single_sphere());
+        assert_eq!(image[(0, 0)].2, 0.3);
+    }
+
+    fn panorama(width: usize) -> CameraBuilder {
+        Camera::builder()
+            .width(width)
+            .aspect_ratio(16.0 / 9.0)
+            .samples_per_pixel(2)
+            .max_bounces(4)
+            .fov(20.0)
+            .look_from(point![12.0, 2.0, 3.0])
+            .look_at(point![0.0, 0.0, 0.0])
+            .defocus_angle(0.6)
+            .projection(Projection::Equirectangular)
+    }
+
+    #[test]
+    fn test_equirectangular_directions() {
+        let camera = panorama(8).build().unwrap();
+        assert_eq!(camera.render_height, 4);
+
+        let forward = -camera.w;
+        assert_direction(Some(camera.equirectangular_direction(4.0, 2.0)), forward);
+        assert_direction(Some(camera.equirectangular_direction(0.0, 2.0)), -forward);
+        assert_direction(Some(camera.equirectangular_direction(8.0, 2.0)), -forward);
+        assert_direction(Some(camera.equirectangular_direction(6.0, 2.0)), camera.u);
+        assert_direction(Some(camera.equirectangular_direction(3.0, 0.0)), camera.v);
+        assert_direction(Some(camera.equirectangular_direction(5.0, 4.0)), -camera.v);
+    }
+
+    #[test]
+    fn test_equirectangular_render() {
+        seed_rng(11);
+        let scene = final_scene();
+        let camera = panorama(256).seed(5).build().unwrap();
+        let image = camera.renderer().render_parallel(scene).to_float_image();
+        assert_eq!((image.width(), image.height()), (256, 128));
+
+        // The left and right columns look in the same direction
+        for rows in (0..128).step_by(16) {
+            let mean = |j: usize| (rows..rows + 16).map(|i| image[(i, j)].luminance()).sum::<Float>() / 16.0;
+            let (left, right) = (mean(0), mean(255));
+            assert!((left - right).abs() < 0.05, "rows {}: {} vs {}", rows, left, right);
+        }
+
+        // Straight up is the top of the sky gradient
+        for j in 0..256 {
+            let px = image[(0, j)];
+            assert!((px.0 - 0.5).abs() < 0.01 && (px.1 - 0.7).abs() < 0.01 && (px.2 - 1.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        let build = |builder: CameraBuilder| builder.build().err();
+        assert_eq!(build(Camera::builder().width(0)), Some(CameraError::ZeroWidth));
+        assert_eq!(build(Camera::builder().aspect_ratio(0.0)), Some(CameraError::InvalidAspectRatio(0.0)));
+        assert_eq!(build(Camera::builder().height(0)), Some(CameraError::ZeroHeight));
+        assert_eq!(build(Camera::builder().dimensions(0, 10)), Some(CameraError::ZeroWidth));
+        // The last of height and aspect ratio wins
+        assert!(Camera::builder().aspect_ratio(0.0).height(10).build().is_ok());
+        assert_eq!(build(Camera::builder().height(10).aspect_ratio(-1.0)), Some(CameraError::InvalidAspectRatio(-1.0)));
+        assert_eq!(build(Camera::builder().fov(-10.0)), Some(CameraError::InvalidFov(-10.0)));
+        assert_eq!(build(Camera::builder().fov(180.0)), Some(CameraError::InvalidFov(180.0)));
+        assert_eq!(build(Camera::builder().focus_dist(0.0)), Some(CameraError::NonPositiveFocusDistance(0.0)));
+        assert_eq!(build(Camera::builder().focus_dist(-1.0)), Some(CameraError::NonPositiveFocusDistance(-1.0)));
+        assert_eq!(build(Camera::builder().look_at(point![0.0, 0.0, 0.0])), Some(CameraError::LookFromEqualsLookAt));
+        assert_eq!(
+            build(Camera::builder().look_at(point![0.0, -2.0, 0.0])),
+            Some(CameraError::VupParallelToViewDirection)
+        );
+
+        // Limits depend on the projection
+        assert!(fisheye(10, 360.0).build().is_ok());
+        assert_eq!(build(fisheye(10, 361.0)), Some(CameraError::InvalidFov(361.0)));
+        assert!(panorama(10).fov(-1.0).build().is_ok());
+
+        let message = CameraError::VupParallelToViewDirection.to_string();
+        assert!(message.contains("vup"));
+    }
+
+    #[test]
+    fn test_builder_matches_hand_constructed_camera() {
+        let mut manual = Camera {
+            render_width: 32,
+            aspect_ratio: 16.0 / 9.0,
+            settings: RenderSettings { samples_per_pixel: 4, max_bounces: 10, seed: Some(9), ..RenderSettings::default() },
+            fov_degrees: 20.0,
+            lookfrom: point![12.0, 2.0, 3.0],
+            lookat: point![0.0, 0.0, 0.0],
+            vup: vector![0.0, 1.0, 0.0],
+            defocus_angle_degrees: 0.6,
+            focus_dist: 10.0,
+            ..Camera::builder().camera
+        };
+        manual.initialize();
+        let built = final_camera().build().unwrap();
+        assert_eq!((built.width(), built.height()), (32, 18));
+
+        seed_rng(4);
+        let scene = final_scene();
+        let expected = manual.renderer().render_parallel(scene.clone());
+        let image = built.renderer().render_parallel(scene);
+        assert_eq!(image.to_rgba8(), expected.to_rgba8());
+    }
+
+    fn assert_square_pixels(camera: &Camera) {
+        let (du, dv) = (camera.pixel_delta_u.norm(), camera.pixel_delta_v.norm());
+        assert!((du - dv).abs() < tolerance(4.0) * du, "{} x {}", du, dv);
+    }
+
+    #[test]
+    fn test_exact_dimensions() {
+        for (width, height) in [(1920, 1080), (1366, 768), (675, 1200), (1, 7), (7, 1)] {
+            let camera = Camera::builder().dimensions(width, height).build().unwrap();
+            assert_eq!((camera.width(), camera.height()), (width, height));
+            assert_eq!((camera.renderer().width(), camera.renderer().height()), (width, height));
+            assert_eq!(camera.aspect_ratio(), width as Float / height as Float);
+            assert_square_pixels(&camera);
+        }
+
+        // Heights from awkward aspect ratios are rounded down, pixels stay square
+        for (width, aspect_ratio, height) in [(1200, 16.0 / 9.0, 675), (1366, 16.0 / 9.0, 768), (100, 2.39, 41), (3, 1.0 / 3.0, 9)] {
+            let camera = Camera::builder().width(width).aspect_ratio(aspect_ratio).build().unwrap();
+            assert_eq!(camera.height(), height);
+            assert_eq!(camera.aspect_ratio(), width as Float / height as Float);
+            assert_square_pixels(&camera);
+        }
+
+        // Copies of the camera keep the exact size
+        let camera = Camera::builder().dimensions(1366, 768).build().unwrap();
+        let turned = camera.turntable_camera(1, 4, TurntableOptions::default());
+        assert_eq!((turned.width(), turned.height()), (1366, 768));
+    }
+
+    fn final_camera() -> CameraBuilder {
+        Camera::builder()
+            .width(32)
+            .aspect_ratio(16.0 / 9.0)
+            .samples_per_pixel(4)
+            .fov(20.0)
+            .look_from(point![12.0, 2.0, 3.0])
+            .look_at(point![0.0, 0.0, 0.0])
+            .defocus_angle(0.6)
+            .seed(9)
+    }
+
+    fn assert_same_basis(a: &Camera, b: &Camera) {
+        assert!((a.center - b.center).norm() < tolerance(64.0) * a.center.coords.norm().max(1.0));
+        assert!((a.u - b.u).norm() < tolerance(64.0), "{:?} != {:?}", a.u, b.u);
+        assert!((a.v - b.v).norm() < tolerance(64.0), "{:?} != {:?}", a.v, b.v);
+        assert!((a.w - b.w).norm() < tolerance(64.0), "{:?} != {:?}", a.w, b.w);
+    }
+
+    #[test]
+    fn test_pose_round_trip() {
+        let camera = final_camera().build().unwrap();
+
+        // The view transform of a right-handed look-at camera is the inverse of its pose
+        let pose = Isometry3::look_at_rh(&point![12.0, 2.0, 3.0], &point![0.0, 0.0, 0.0], &vector![0.0, 1.0, 0.0]).inverse();
+        let from_pose = final_camera().pose(pose).build().unwrap();
+        assert_same_basis(&camera, &from_pose);
+
+        let from_own_pose = final_camera().pose(camera.pose()).build().unwrap();
+        assert_same_basis(&camera, &from_own_pose);
+
+        let isometry = Camera::from_isometry(pose, 32, 20.0).build().unwrap();
+        assert_same_basis(&camera, &isometry);
+
+        seed_rng(4);
+        let scene = final_scene();
+        let expected = camera.renderer().render_parallel(scene.clone());
+        let image = from_pose.renderer().render_parallel(scene);
+        let result = compare(&image.to_float_image(), &expected.to_float_image()).unwrap();
+        assert!(result.psnr > 60.0, "{:?}", result);
+    }
+
+    #[test]
+    fn test_pose_ignores_look_at() {
+        // A pose makes look_at irrelevant, even when it would be invalid
+        let camera = Camera::builder().look_at(point![0.0, 0.0, 0.0]).pose(Isometry3::identity()).build().unwrap();
+        assert_eq!(camera.w, vector![0.0, 0.0, 1.0]);
+        assert_eq!(camera.u, vector![1.0, 0.0, 0.0]);
+    }
+
+    fn sphere_at(distance: Float) -> Arc<Scene> {
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            center: point![0.0, 0.0, -distance],
+            radius: 1.0,
+            material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into()
+        });
+        Arc::new(scene)
+    }
+
+    #[test]
+    fn test_autofocus_center() {
+        let camera = camera(20, 1).fov(60.0).look_at(point![0.0, 0.0, -2.0]).autofocus(Autofocus::Center).build().unwrap();
+        let focused = camera.focused(&sphere_at(5.0));
+        assert!((focused.focus_dist() - 4.0).abs() < 1e-9, "{}", focused.focus_dist());
+        assert!(focused.autofocus.is_none());
+
+        // The viewport moved to the new focus plane
+        let expected = camera_at_focus(4.0);
+        assert!((focused.pixel00_loc - expected.pixel00_loc).norm() < 1e-9);
+        assert!((focused.defocus_disk_u - expected.defocus_disk_u).norm() < 1e-9);
+
+        // Nothing hit: look_at is in focus
+        let focused = camera.focused(&Scene::new());
+        assert!((focused.focus_dist() - 2.0).abs() < 1e-12);
+
+        // The renderer focuses before rendering
+        let renderer = camera.renderer();
+        assert!((renderer.focused(&sphere_at(5.0)).camera.focus_dist - 4.0).abs() < 1e-9);
+    }
+
+    fn camera_at_focus(focus_dist: Float) -> Camera {
+        camera(20, 1).fov(60.0).look_at(point![0.0, 0.0, -2.0]).focus_dist(focus_dist).build().unwrap()
+    }
+
+    #[test]
+    fn test_autofocus_pixel() {
+        let (x, y) = (11.5, 8.0);
+        let camera = camera(20, 1).fov(60.0).autofocus(Autofocus::Pixel { x, y }).build().unwrap();
+        let focused = camera.focused(&sphere_at(5.0));
+
+        // Analytic hit of the ray through (x, y) with the sphere
+        let h = (30.0 as Float).to_radians().tan();
+        let dir = vector![(x / 20.0 - 0.5) * 2.0 * h, (0.5 - y / 20.0) * 2.0 * h, -1.0].normalize();
+        let oc = vector![0.0, 0.0, 5.0];
+        let b = dir.dot(&oc);
+        let t = -b - (b * b - (oc.norm_squared() - 1.0)).sqrt();
+        let expected = t * -dir.z;
+        assert!((focused.focus_dist() - expected).abs() < 1e-9, "{} vs {}", focused.focus_dist(), expected);
+        assert!(expected > 4.0);
+    }
+
+    fn sphere_side(image: &PPM) -> Float {
+        let (mut sum, mut count) = (0.0, 0.0);
+        for i in 0..image.height() {
+            for j in 0..image.width() {
+                sum += image.alpha(i, j) * j as Float;
+                count += image.alpha(i, j);
+            }
+        }
+        sum / count - image.width() as Float / 2.0
+    }
+
+    #[test]
+    fn test_turntable() {
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            center: point![1.0, 0.0, 1.0],
+            radius: 0.5,
+            material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into()
+        });
+        let scene = Arc::new(scene);
+        let camera = camera(24, 2)
+            .fov(60.0)
+            .look_from(point![0.0, 0.0, 5.0])
+            .look_at(point![0.0, 0.0, 0.0])
+            .transparent_background(true)
+            .build()
+            .unwrap();
+
+        for (clockwise, expected) in [(false, [1.0, -1.0, -1.0, 1.0]), (true, [1.0, 1.0, -1.0, -1.0])] {
+            let mut sides = vec![];
+            camera.renderer().turntable(scene.clone(), 4, TurntableOptions { clockwise }, |index, image| {
+                assert_eq!(index, sides.len());
+                sides.push(sphere_side(&image));
+            });
+            for (side, expected) in sides.iter().zip(expected) {
+                assert!(side * expected > 2.0, "clockwise: {}, {:?}", clockwise, sides);
+            }
+        }
+
+        // Full circle
+        let options = TurntableOptions::default();
+        let first = camera.turntable_camera(0, 7, options);
+        let last = camera.turntable_camera(7, 7, options);
+        assert_same_basis(&first, &last);
+        assert_same_basis(&first, &camera);
+        let quarter = camera.turntable_camera(1, 4, options);
+        assert!((quarter.center - point![5.0, 0.0, 0.0]).norm() < tolerance(64.0) * 5.0);
+    }
+
+    // Horizontal center of coverage in the given rows
+    fn coverage_center(image: &PPM, rows: Range<usize>) -> Float {
+        let (mut sum, mut count) = (0.0, 0.0);
+        for i in rows {
+            for j in 0..image.width() {
+                sum += image.alpha(i, j) * j as Float;
+                count += image.alpha(i, j);
+            }
+        }
+        sum / count
+    }
+
+    #[test]
+    fn test_stereo_disparity() {
+        let mut scene = Scene::new();
+        let material = MaterialKind::from(Lambertian::new(RGB(0.5, 0.5, 0.5)));
+        // Near sphere in the bottom half of the image, far sphere in the top half
+        scene.add(Sphere { center: point![0.0, -0.5, -2.0], radius: 0.3, material: material.clone() });
+        scene.add(Sphere { center: point![0.0, 2.0, -8.0], radius: 0.8, material });
+        let scene = Arc::new(scene);
+        // Converging between the spheres
+        let camera = camera(40, 4).fov(60.0).look_at(point![0.0, 0.0, -4.0]).transparent_background(true).seed(3).build().unwrap();
+
+        for mode in [StereoMode::Parallel, StereoMode::Converged] {
+            let (left, right) = camera.renderer().render_stereo(scene.clone(), 0.2, mode);
+            let near = coverage_center(&left, 20..40) - coverage_center(&right, 20..40);
+            let far = coverage_center(&left, 0..20) - coverage_center(&right, 0..20);
+            // Near objects move left in the right eye
+            assert!(near > 1.0 && near > far + 1.0, "{:?}: near {}, far {}", mode, near, far);
+        }
+
+        // Nothing but the position changes
+        let left = camera.eye_camera(-0.1, StereoMode::Parallel);
+        assert!((left.center - point![-0.1, 0.0, 0.0]).norm() < 1e-12);
+        assert!((left.w - camera.w).norm() < 1e-12);
+        assert_eq!(left.seed(), camera.seed());
+    }
+
+    // Mean row of a thin horizontal bar above the center, per column
+    fn bar_rows(k1: Float) -> Vec<Option<Float>> {
+        let mut scene = Scene::new();
+        let material = MaterialKind::from(Lambertian::new(RGB(0.5, 0.5, 0.5)));
+        for x in -25..=25 {
+            scene.add(Sphere { center: point![x as Float * 0.04, 0.41, -1.0], radius: 0.025, material: material.clone() });
+        }
+        let camera = camera(80, 2).max_bounces(1).lens_distortion(k1, 0.0).transparent_background(true).seed(2).build().unwrap();
+        let image = camera.renderer().render_parallel(Arc::new(scene));
+
+        (0..80).map(|j| {
+            let coverage: Float = (0..80).map(|i| image.alpha(i, j)).sum();
+            let row: Float = (0..80).map(|i| image.alpha(i, j) * i as Float).sum();
+            if coverage > 0.0 { Some(row / coverage) } else { None }
+        }).collect()
+    }
+
+    #[test]
+    fn test_lens_distortion_bends_lines() {
+        let straight = bar_rows(0.0);
+        // How far the bar moved down in the middle and near the left edge of the image
+        let shift = |rows: Vec<Option<Float>>| {
+            (rows[40].unwrap() - straight[40].unwrap(), rows[8].unwrap() - straight[8].unwrap())
+        };
+
+        // Pincushion pulls the ends of the bar away from the center of the image
+        let (middle, edge) = shift(bar_rows(0.15));
+        assert!(edge < middle - 0.5, "middle {}, edge {}", middle, edge);
+
+        // Barrel bends them back towards the center, the bar bows outwards
+        let (middle, edge) = shift(bar_rows(-0.15));
+        assert!(edge > middle + 0.5, "middle {}, edge {}", middle, edge);
+    }
+
+    #[test]
+    fn test_zero_lens_distortion_changes_nothing() {
+        let camera = camera(16, 2).seed(4);
+        let plain = camera.clone().build().unwrap().renderer().render_parallel(single_sphere());
+        let distorted = camera.lens_distortion(0.0, 0.0).build().unwrap().renderer().render_parallel(single_sphere());
+        assert_eq!(compare(&plain.to_float_image(), &distorted.to_float_image()).unwrap().mean_absolute_error, 0.0);
+    }
+
+    // Glowing spheres on the focus plane tilted by 45 degrees about v, receding to the right.
+    // They are evenly spaced across the image and equally large in it.
+    fn receding_row() -> (Arc<Scene>, Vec<Point3<Float>>) {
+        let mut scene = Scene::new();
+        let mut centers = vec![];
+        for k in -2..=2 {
+            let x = k as Float * 0.22;
+            let distance = 5.0 / (1.0 - x * (45.0 as Float).to_radians().tan());
+            let center = point![x * distance, 0.0, -distance];
+            scene.add(Sphere { center, radius: 0.04 * distance, material: DiffuseLight::new(RGB::white()).into() });
+            centers.push(center);
+        }
+        (Arc::new(scene), centers)
+    }
+
+    // Gradient energy in a window around where the point is in the image
+    fn sharpness(image: &PPM, center: &Point3<Float>) -> Float {
+        let half_width = image.width() as Float / 2.0;
+        let x = (half_width + center.x / -center.z / (30.0 as Float).to_radians().tan() * half_width) as usize;
+        let y = image.height() / 2;
+        let luminance = |i: usize, j: usize| image[(i, j)].0;
+        let mut energy = 0.0;
+        for i in y - 7..y + 7 {
+            for j in x - 7..x + 7 {
+                energy += (luminance(i, j + 1) - luminance(i, j)).powi(2) + (luminance(i + 1, j) - luminance(i, j)).powi(2);
+            }
+        }
+        energy
+    }
+
+    #[test]
+    fn test_lens_tilt_focus_band() {
+        let (scene, centers) = receding_row();
+        let camera = camera(80, 16)
+            .fov(60.0)
+            .focus_dist(5.0)
+            .defocus_angle(10.0)
+            .background(RGB::default())
+            .max_bounces(1)
+            .seed(5);
+        let flat = camera.clone().build().unwrap().renderer().render_parallel(scene.clone());
+        let tilted = camera.lens_tilt(0.0, 45.0).build().unwrap().renderer().render_parallel(scene);
+
+        let ratios: Vec<Float> = centers.iter().map(|c| sharpness(&tilted, c) / sharpness(&flat, c)).collect();
+        // The middle sphere is in focus either way, the ones in front and behind only with the tilt
+        assert!((ratios[2] - 1.0).abs() < 0.2, "{:?}", ratios);
+        for &ratio in [ratios[0], ratios[1], ratios[3], ratios[4]].iter() {
+            assert!(ratio > 1.5, "{:?}", ratios);
+        }
+    }
+
+    #[test]
+    fn test_zero_lens_tilt_changes_nothing() {
+        let (scene, _) = receding_row();
+        let camera = camera(16, 2).defocus_angle(6.0).focus_dist(5.0).seed(4);
+        let plain = camera.clone().build().unwrap().renderer().render_parallel(scene.clone());
+        let tilted = camera.lens_tilt(0.0, 0.0).build().unwrap().renderer().render_parallel(scene);
+        assert_eq!(compare(&plain.to_float_image(), &tilted.to_float_image()).unwrap().mean_absolute_error, 0.0);
+    }
+
+    // Average of a pixel rendered against a white sky
+    fn vignetted(vignetting: Vignetting, defocus_angle: Float) -> (Float, Float) {
+        let camera = camera(41, 64)
+            .background(RGB::white())
+            .vignetting(vignetting)
+            .defocus_angle(defocus_angle)
+            .seed(6)
+            .build()
+            .unwrap();
+        let image = camera.renderer().render_parallel(Arc::new(Scene::new()));
+        (image[(20, 20)].0, image[(0, 0)].0)
+    }
+
+    #[test]
+    fn test_natural_vignetting() {
+        let (center, corner) = vignetted(Vignetting::Natural { barrel: None }, 0.0);
+        assert!((center - 1.0).abs() < 0.01, "{}", center);
+        // The corner pixel is (20 / 20.5) of the viewport half width off axis in both directions
+        let tan: Float = 20.0 / 20.5;
+        let expected = (1.0 / (1.0 + 2.0 * tan * tan)).powi(2);
+        assert!((corner / expected - 1.0).abs() < 0.03, "{} vs {}", corner, expected);
+
+        // A long lens barrel blocks part of the defocus disk in the corners only
+        // Rays from the edge of the 20 degree defocus disk are a bit oblique even in the center
+        let (center, clipped) = vignetted(Vignetting::Natural { barrel: Some(1.0) }, 20.0);
+        assert!(center > 0.95, "{}", center);
+        assert!(clipped < 0.8 * corner, "{} vs {}", clipped, corner);
+    }
+
+    #[test]
+    fn test_radial_vignetting() {
+        let (center, corner) = vignetted(Vignetting::Radial { strength: 0.5, exponent: 2.0 }, 0.0);
+        assert!((center - 1.0).abs() < 1e-3, "{}", center);
+        // The corner pixel center is 20 / 20.5 of the way to the corner
+        assert!((corner - (1.0 - 0.5 * (20.0 as Float / 20.5).powi(2))).abs() < 1e-3, "{}", corner);
+    }
+
+    #[test]
+    fn test_aovs_off_by_default() {
+        let output = camera(4, 1).build().unwrap().renderer().render_output(single_sphere());
+        assert!(output.normal.is_none() && output.depth.is_none() && output.albedo.is_none());
+        assert!(output.light_groups.is_empty());
+    }
+
+    // Two lights in groups 0 and 1 over a gray floor, under the sky
+    fn two_lights(first: RGB) -> Arc<Scene> {
+        let mut scene = Scene::new();
+        scene.add_sphere(point![0.0, -100.5, -1.0], 100.0, Lambertian::new(RGB(0.5, 0.5, 0.5)));
+        scene.add_sphere(point![-0.6, 0.0, -1.0], 0.4, DiffuseLight::new(first));
+        scene.add_sphere(point![0.6, 0.0, -1.0], 0.4, DiffuseLight::new(RGB(1.0, 2.0, 4.0)).group(1));
+        Arc::new(scene)
+    }
+
+    #[test]
+    fn test_heatmap() {
+        // A dense block of small spheres in the middle of an otherwise empty view
+        let mut cluster = SphereList::new();
+        let material = cluster.add_material(Lambertian::new(RGB(0.5, 0.5, 0.5)));
+        for (x, y, z) in (0..125).map(|idx| (idx % 5, idx / 5 % 5, idx / 25)) {
+            cluster.push(point![x as Float * 0.2 - 0.4, y as Float * 0.2 - 0.4, z as Float * 0.2 - 3.4], 0.08, material);
+        }
+        let mut scene = Scene::new();
+        scene.add(Arc::new(cluster));
+        let scene = Arc::new(scene);
+
+        let heatmap = |max: Option<u32>| {
+            let mode = RenderMode::Heatmap { metric: HeatmapMetric::IntersectionTests, max };
+            camera(32, 4).dimensions(32, 32).mode(mode).seed(1).build().unwrap().renderer().render_output(scene.clone())
+        };
+        let output = heatmap(None);
+        let close = |a: RGB, b: RGB| (a - b).0.abs().max((a - b).1.abs()).max((a - b).2.abs()) < 1e-12;
+        let (center, corner) = (output.beauty[(16, 16)], output.beauty[(0, 0)]);
+        assert!(center.luminance() > corner.luminance());
+        // Rays missing the block skip all of its spheres and get the bottom of the ramp, the
+        // ones through it test every sphere and get the top
+        assert!(close(corner, RGB::ramp(0.0)));
+        assert!(close(center, RGB::ramp(1.0)));
+        assert_eq!(output.beauty.alpha(0, 0), 1.0);
+
+        // Counts above a fixed max are clipped, below it they come out darker
+        assert!(close(heatmap(Some(100)).beauty[(16, 16)], RGB::ramp(1.0)));
+        let darker = heatmap(Some(250)).beauty[(16, 16)];
+        assert!(close(darker, RGB::ramp(0.5)), "{:?}", darker);
+        // The serial render colors the same way
+        let serial = camera(32, 4).dimensions(32, 32).mode(RenderMode::Heatmap { metric: HeatmapMetric::NodeVisits, max: None }).seed(1).build().unwrap().render(&scene);
+        assert!(close(serial[(16, 16)], RGB::ramp(0.0)) && close(serial[(0, 0)], RGB::ramp(0.0)));
+    }
+
+    #[test]
+    fn test_light_groups() {
+        let renderer = camera(24, 8).seed(4).light_groups(2).build().unwrap().renderer();
+        let output = renderer.render_output(two_lights(RGB(4.0, 2.0, 1.0)));
+        assert_eq!(output.light_groups.len(), 3);
+        let close = |a: RGB, b: RGB| (a - b).0.abs().max((a - b).1.abs()).max((a - b).2.abs()) < tolerance(64.0) * b.0.max(b.1).max(b.2).max(1.0);
+
+        // Without the first light the same paths only pick up the rest
+        let unlit = renderer.render_output(two_lights(RGB::zeros()));
+        for i in 0..output.beauty.height() {
+            for j in 0..output.beauty.width() {
+                let [first, second, sky] = [0, 1, 2].map(|group| output.light_groups[group][(i, j)]);
+                assert!(close(first + second + sky, output.beauty[(i, j)]), "({}, {})", i, j);
+                assert!(close(second + sky, unlit.beauty[(i, j)]), "({}, {})", i, j);
+            }
+        }
+        // Both lights and the sky show up somewhere
+        for group in &output.light_groups {
+            assert!(group.pixels().iter().any(|px| px.luminance() > 0.1));
+        }
+    }
+
+    // Pixel coordinates where p shows up in a perspective image
+    fn project(camera: &Camera, p: Point3<Float>) -> (Float, Float) {
+        let d = p - camera.center;
+        let on_plane = camera.center + d * (camera.focus_dist / d.dot(&-camera.w));
+        let offset = on_plane - camera.pixel00_loc;
+        (
+            offset.dot(&camera.pixel_delta_u) / camera.pixel_delta_u.norm_squared() + 0.5,
+            offset.dot(&camera.pixel_delta_v) / camera.pixel_delta_v.norm_squared() + 0.5,
+        )
+    }
+
+    #[test]
+    fn test_frame_scene() {
+        let mut scene = Scene::new();
+        scene.add_sphere(Point3::origin(), 1.0, Lambertian::new(RGB(0.5, 0.5, 0.5)));
+        let bounds = scene.bounds();
+        assert_eq!((bounds.min, bounds.max), (point![-1.0, -1.0, -1.0], point![1.0, 1.0, 1.0]));
+
+        // Landscape is limited by the height, portrait by the width
+        for (width, height) in [(160, 90), (90, 160)] {
+            let camera = Camera::builder()
+                .dimensions(width, height)
+                .fov(40.0)
+                .look_from(point![5.0, 5.0, 5.0])
+                .frame_scene(&scene, vector![1.0, 0.5, 2.0])
+                .build()
+                .unwrap();
+            assert_eq!(camera.lookat, Point3::origin());
+            assert!(((camera.lookfrom - camera.lookat).norm() - camera.focus_dist).abs() < tolerance(16.0) * camera.focus_dist);
+            assert!((camera.lookfrom.coords.normalize() - vector![1.0, 0.5, 2.0].normalize()).norm() < tolerance(16.0));
+
+            let mut largest: Float = 0.0;
+            for corner in bounds.corners() {
+                let (x, y) = project(&camera, corner);
+                assert!(x > 0.0 && x < width as Float && y > 0.0 && y < height as Float, "{} x {}: {:?}", width, height, (x, y));
+                largest = largest.max((x - width as Float / 2.0).abs() / width as Float).max((y - height as Float / 2.0).abs() / height as Float);
+            }
+            // Not needlessly far away either
+            assert!(largest > 0.3, "{}", largest);
+        }
+
+        // Nothing to frame, a single point and no direction still give a usable camera
+        let camera = Camera::builder().frame_scene(&Scene::new(), vector![0.0, 0.0, 1.0]).build().unwrap();
+        assert!(camera.focus_dist.is_finite() && camera.focus_dist > 0.0);
+        let point = Aabb::new(point![1.0, 2.0, 3.0], point![1.0, 2.0, 3.0]);
+        let camera = Camera::builder().frame(point, Vector3::zeros()).build().unwrap();
+        assert_eq!(camera.lookat, point![1.0, 2.0, 3.0]);
+        assert!(camera.focus_dist.is_finite() && camera.focus_dist > 0.0);
+    }
+
+    #[test]
+    fn test_coarse_to_fine() {
+        let renderer = camera(256, 4).dimensions(256, 128).fov(60.0).transparent_background(true).build().unwrap().renderer();
+        // Coverage of each level blown back up to full size, summed over a few previews since a
+        // single sample per pixel leaves the coarse edges noisy
+        let mut coverage = vec![vec![0.0; 256 * 128]; 4];
+        let mut sizes = Vec::new();
+        for run in 0..16 {
+            let cancel = CancelToken::new();
+            renderer.render_coarse_to_fine(single_sphere(), &cancel, |image, factor| {
+                sizes.push((image.width(), image.height(), factor));
+                let level = &mut coverage[factor.trailing_zeros() as usize];
+                for (idx, alpha) in level.iter_mut().enumerate() {
+                    *alpha += image.alpha(idx / 256 / factor, idx % 256 / factor);
+                }
+                // Only the first run goes on to the full image
+                if run > 0 && factor == 2 {
+                    cancel.cancel();
+                }
+            });
+        }
+        assert_eq!(sizes[..4], [(32, 16, 8), (64, 32, 4), (128, 64, 2), (256, 128, 1)]);
+        assert_eq!(sizes.len(), 4 + 15 * 3);
+
+        let centroid = |level: &[Float]| {
+            let total: Float = level.iter().sum();
+            let x: Float = level.iter().enumerate().map(|(idx, alpha)| alpha * ((idx % 256) as Float + 0.5)).sum();
+            let y: Float = level.iter().enumerate().map(|(idx, alpha)| alpha * ((idx / 256) as Float + 0.5)).sum();
+            (x / total, y / total)
+        };
+        let (x, y) = centroid(&coverage[0]);
+        assert!((x - 128.0).abs() < 0.5 && (y - 64.0).abs() < 0.5, "{:?}", (x, y));
+        for (idx, level) in coverage.iter().enumerate().skip(1) {
+            let (level_x, level_y) = centroid(level);
+            assert!((level_x - x).abs() < 1.0 && (level_y - y).abs() < 1.0, "1/{}: {:?}", 1 << idx, (level_x, level_y));
+        }
+
+        // Stops between levels
+        let cancel = CancelToken::new();
+        let mut count = 0;
+        let finished = renderer.render_coarse_to_fine(single_sphere(), &cancel, |_, _| {
+            count += 1;
+            cancel.cancel();
+        });
+        assert!(!finished);
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_coarse_framing() {
+        // A coarse pixel spans a block of full pixels, in every projection and with odd sizes
+        for projection in [Projection::Perspective, Projection::Fisheye, Projection::Equirectangular] {
+            let full = camera(45, 1).dimensions(45, 27).fov(100.0).projection(projection).build().unwrap();
+            for factor in [2, 4, 8] {
+                let coarse = full.coarse(factor);
+                for (i, j) in [(0, 0), (1, 2), (3, 5)] {
+                    for (jitter_x, jitter_y) in [(0.0, 0.0), (0.5, 0.5), (0.25, 0.75)] {
+                        let (x, y) = (jitter_x * factor as Float, jitter_y * factor as Float);
+                        let expected = full.sample_ray(i * factor + y as usize, j * factor + x as usize, (x.fract(), y.fract()), false);
+                        let found = coarse.sample_ray(i, j, (jitter_x, jitter_y), false);
+                        match (expected, found) {
+                            (Some(expected), Some(found)) => {
+                                assert!((expected.dir.normalize() - found.dir.normalize()).norm() < 1e-5, "{:?} {} {:?}", projection, factor, (i, j));
+                                assert!((expected.orig - found.orig).norm() < 1e-5);
+                            },
+                            (expected, found) => assert_eq!(expected.is_some(), found.is_some()),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_render_cancellable() {
+        let renderer = camera(8, 2).dimensions(8, 4).build().unwrap().renderer();
+        let cancel = CancelToken::new();
+        let output = renderer.render_cancellable(single_sphere(), &cancel).unwrap();
+        assert_eq!((output.beauty.width(), output.beauty.height()), (8, 4));
+
+        // Clones share the flag
+        cancel.clone().cancel();
+        assert!(cancel.is_cancelled());
+        assert!(renderer.render_cancellable(single_sphere(), &cancel).is_none());
+    }
+
+    #[test]
+    fn test_images_hold_averages() {
+        let camera = camera(12, 6).seed(5).build().unwrap();
+        let renderer = camera.renderer();
+        let scene = Arc::new(RandomSpheres::new().seed(3).generate());
+        let parallel = renderer.render_parallel(scene.clone());
+        let serial = renderer.render(&scene);
+        // The accumulator still sums the samples and divides when asked for the average
+        let accumulator = renderer.render_samples(scene, 0..6);
+        for i in 0..parallel.height() {
+            for j in 0..parallel.width() {
+                let (px, average) = (parallel[(i, j)], accumulator.average(i, j));
+                assert_eq!((px.0, px.1, px.2), (average.0, average.1, average.2));
+                assert_eq!((serial[(i, j)].0, serial[(i, j)].1, serial[(i, j)].2), (px.0, px.1, px.2));
+            }
+        }
+
+        let save = |image: &PPM| {
+            let mut bytes = vec![];
+            image.save_binary(&mut bytes).unwrap();
+            bytes
+        };
+        assert_eq!(save(&parallel), save(&accumulator.to_ppm()));
+    }
+
+    #[test]
+    fn test_render_with_progress() {
+        let renderer = camera(8, 2).dimensions(8, 4).build().unwrap().renderer();
+        let reported = std::sync::Mutex::new(vec![]);
+        renderer.render_with_progress(single_sphere(), |done, total| reported.lock().unwrap().push((done, total)));
+        let mut reported = reported.into_inner().unwrap();
+        reported.sort();
+        assert_eq!(reported, (1..=32).map(|done| (done, 32)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_thread_pools() {
+        let scene = Arc::new(RandomSpheres::new().seed(3).generate());
+        let renderer = camera(12, 4).look_from(point![13.0, 2.0, 3.0]).look_at(point![0.0, 0.0, 0.0]).seed(7).build().unwrap().renderer();
+        let one = renderer.clone().threads(Some(1)).unwrap().render_output(scene.clone());
+        let two = renderer.clone().threads(Some(2)).unwrap().render_output(scene.clone());
+        assert_eq!((one.stats.threads, two.stats.threads), (1, 2));
+        let values = |image: &PPM| image.pixels().iter().map(|px| (px.0, px.1, px.2)).collect::<Vec<_>>();
+        assert_eq!(values(&one.beauty), values(&two.beauty));
+
+        // Without a pool of its own it runs on the global one, 0 threads is one per core
+        let global = renderer.render_output(scene);
+        assert_eq!(global.stats.threads, crate::parallel::current_num_threads());
+        assert_eq!(values(&global.beauty), values(&one.beauty));
+        assert!(camera(4, 1).build().unwrap().renderer().threads(Some(0)).unwrap().thread_count() >= 1);
+    }
+
+    #[test]
+    fn test_renderer_builder() {
+        let scene = Arc::new(RandomSpheres::new().seed(3).generate());
+        let optics = || camera(12, 1).look_from(point![13.0, 2.0, 3.0]).look_at(point![0.0, 0.0, 0.0]);
+        let settings = RenderSettings {
+            samples_per_pixel: 3,
+            max_bounces: 4,
+            background: Some(RGB(0.2, 0.3, 0.4)),
+            aovs: AovFlags { normal: true, ..AovFlags::default() },
+            seed: Some(9),
+            ..RenderSettings::default()
+        };
+        let built = Renderer::builder(optics().build().unwrap()).settings(settings).build().unwrap();
+        let wrapped = optics()
+            .samples_per_pixel(3)
+            .max_bounces(4)
+            .background(RGB(0.2, 0.3, 0.4))
+            .aovs(AovFlags { normal: true, ..AovFlags::default() })
+            .seed(9)
+            .build()
+            .unwrap()
+            .renderer();
+        assert_eq!(built.settings().samples_per_pixel, wrapped.settings().samples_per_pixel);
+
+        let (built, wrapped) = (built.render_output(scene.clone()), wrapped.render_output(scene));
+        let values = |image: &PPM| image.pixels().iter().map(|px| (px.0, px.1, px.2)).collect::<Vec<_>>();
+        assert_eq!(values(&built.beauty), values(&wrapped.beauty));
+        let normals = |image: &FloatImage| image.pixels().iter().map(|px| (px.0, px.1, px.2)).collect::<Vec<_>>();
+        assert_eq!(normals(built.normal.as_ref().unwrap()), normals(wrapped.normal.as_ref().unwrap()));
+
+        // The builder starts from the camera's settings
+        let camera = optics().samples_per_pixel(5).build().unwrap();
+        assert_eq!(Renderer::builder(camera).build().unwrap().settings().samples_per_pixel, 5);
+    }
+
+    #[test]
+    fn test_renderer_builder_validation() {
+        let build = |settings: RenderSettings| Renderer::builder(camera(4, 1).build().unwrap()).settings(settings).build().err();
+        assert_eq!(build(RenderSettings { samples_per_pixel: 0, ..RenderSettings::default() }), Some(RenderSettingsError::ZeroSamples));
+        assert_eq!(build(RenderSettings { max_bounces: 0, ..RenderSettings::default() }), Some(RenderSettingsError::ZeroBounces));
+        assert_eq!(build(RenderSettings { light_groups: 100, ..RenderSettings::default() }), Some(RenderSettingsError::TooManyLightGroups(100)));
+        assert_eq!(build(RenderSettings::default()), None);
+        assert!(RenderSettingsError::ZeroSamples.to_string().contains("sample"));
+
+        // Camera::renderer stays as lenient as before
+        assert_eq!(camera(4, 0).build().unwrap().renderer().settings().samples_per_pixel, 0);
+    }
+
+    #[test]
+    fn test_render_into() {
+        let values = |image: &PPM| image.pixels().iter().map(|px| (px.0, px.1, px.2)).collect::<Vec<_>>();
+        let spheres = Arc::new(RandomSpheres::new().seed(3).generate());
+        let optics = || camera(12, 2).look_from(point![13.0, 2.0, 3.0]).look_at(point![0.0, 0.0, 0.0]).seed(4);
+        let transparent = optics().transparent_background(true).build().unwrap().renderer();
+        let opaque = optics().build().unwrap().renderer();
+
+        let mut image = PPM::new(opaque.width(), opaque.height());
+        image.set_exposure_ev(1.5);
+        transparent.render_into(spheres.clone(), &mut image).unwrap();
+        assert!(image.has_alpha());
+        assert_eq!(values(&image), values(&transparent.render_parallel(spheres.clone())));
+
+        // A different scene into the same buffer leaves nothing of the first one
+        let stats = opaque.render_into(single_sphere(), &mut image).unwrap();
+        assert_eq!(values(&image), values(&opaque.render_parallel(single_sphere())));
+        assert!(!image.has_alpha());
+        assert_eq!(stats.threads, opaque.thread_count());
+        assert_eq!(image.exposure_ev(), 1.5);
+
+        let err = opaque.render_into(spheres, &mut PPM::new(opaque.width() + 1, opaque.height())).unwrap_err();
+        assert_eq!(err.expected, (opaque.width(), opaque.height()));
+        assert_eq!(err.found, (opaque.width() + 1, opaque.height()));
+    }
+
+    #[test]
+    fn test_render_samples_into() {
+        let scene = single_sphere();
+        let renderer = camera(8, 3).seed(2).build().unwrap().renderer();
+        let mut accumulator = renderer.render_samples(scene.clone(), 0..3);
+        renderer.render_samples_into(scene.clone(), 3..6, &mut accumulator, true).unwrap();
+        let all = renderer.render_samples(scene.clone(), 0..6);
+        for i in 0..renderer.height() {
+            for j in 0..renderer.width() {
+                assert_eq!(accumulator.count(i, j), 6);
+                assert!((accumulator.sum(i, j) - all.sum(i, j)).norm() < tolerance(64.0) * all.sum(i, j).norm().max(1.0));
+            }
+        }
+
+        // Without accumulate the earlier samples are gone
+        renderer.render_samples_into(scene.clone(), 3..6, &mut accumulator, false).unwrap();
+        let later = renderer.render_samples(scene.clone(), 3..6);
+        assert_eq!(accumulator.count(2, 2), 3);
+        assert_eq!(accumulator.sum(2, 2), later.sum(2, 2));
+
+        let mut small = Accumulator::new(renderer.width(), renderer.height() - 1);
+        assert!(renderer.render_samples_into(scene, 0..1, &mut small, true).is_err());
+    }
+
+    #[test]
+    fn test_render_budgeted() {
+        let scene = single_sphere();
+        // Without a budget it's a normal render
+        let renderer = camera(12, 6).seed(4).build().unwrap().renderer();
+        let budgeted = renderer.render_budgeted(&scene);
+        assert_eq!((budgeted.min_samples, budgeted.max_samples), (6, 6));
+        assert_eq!(budgeted.image.to_rgba8(), renderer.render_parallel(scene.clone()).to_rgba8());
+
+        let budgeted = |budget: std::time::Duration| {
+            camera(16, 1_000_000).seed(4).time_budget(budget).build().unwrap().renderer().render_budgeted(&scene)
+        };
+        // A budget used up before the first pass still gets the one sample everywhere
+        let short = budgeted(std::time::Duration::from_nanos(1));
+        assert_eq!((short.min_samples, short.max_samples), (1, 1));
+        let long = budgeted(std::time::Duration::from_millis(200));
+        assert!(long.min_samples >= 1 && long.max_samples < 1_000_000);
+        // Pixels the last pass didn't reach are averaged over fewer samples
+        for i in 0..long.image.height() {
+            for j in 0..long.image.width() {
+                let count = long.accumulator.count(i, j);
+                assert!(count == long.min_samples || count == long.max_samples);
+                let (px, average) = (long.image[(i, j)], long.accumulator.average(i, j));
+                assert_eq!((px.0, px.1, px.2), (average.0, average.1, average.2));
+            }
+        }
+    }
+
+    #[test]
+    fn test_render_rows() {
+        let scene = single_sphere();
+        let renderer = camera(12, 4).seed(5).transparent_background(true).build().unwrap().renderer();
+        let expected = renderer.render_parallel(scene.clone()).to_rgba8();
+
+        // Uneven chunks, the last one runs past the bottom
+        let mut rgba = vec![0; expected.len()];
+        let mut row = 0;
+        while row < renderer.height() {
+            row = renderer.render_rows(&scene, row, 2, &mut rgba).unwrap();
+        }
+        assert_eq!(rgba, expected);
+        assert_eq!(renderer.render_rows(&scene, renderer.height(), 2, &mut rgba).unwrap(), renderer.height());
+
+        // Only the asked rows are written
+        let mut rgba = vec![0; expected.len()];
+        assert_eq!(renderer.render_rows(&scene, 1, 1, &mut rgba).unwrap(), 2);
+        let row_bytes = renderer.width() * 4;
+        assert!(rgba[..row_bytes].iter().chain(&rgba[2 * row_bytes..]).all(|&b| b == 0));
+        assert_eq!(rgba[row_bytes..2 * row_bytes], expected[row_bytes..2 * row_bytes]);
+
+        assert!(renderer.render_rows(&scene, 0, 1, &mut rgba[4..]).is_err());
+    }
+
+    #[test]
+    fn test_ray_differentials() {
+        use crate::interval::Interval;
+        use crate::scene::Hittable;
+
+        let mut scene = Scene::new();
+        scene.add(Sphere { center: point![0.0, 0.0, -3.0], radius: 2.5, material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into() });
+        let camera = camera(41, 1).ray_differentials(true).build().unwrap();
+        let (i, j) = (camera.renderer().height() / 2, 20);
+        let uv = |i: usize, j: usize, differentials: bool| {
+            // The same position in the next pixel is exactly one pixel over
+            let ray = camera.sample_ray(i, j, (0.3, 0.6), differentials).unwrap();
+            let hit = scene.hit(&ray, Interval::new(0.001, Float::INFINITY)).unwrap();
+            (hit.u, hit.v, hit.uv_derivatives)
+        };
+        let (u, v, derivatives) = uv(i, j, true);
+        let derivatives = derivatives.unwrap();
+        let (right_u, right_v, _) = uv(i, j + 1, false);
+        let (below_u, below_v, _) = uv(i + 1, j, false);
+        let close = |a: Float, b: Float| (a - b).abs() <= 0.05 * b.abs() + 1e-9;
+        assert!(close(derivatives.du_dx, right_u - u), "{:?} {}", derivatives, right_u - u);
+        assert!(close(derivatives.dv_dy, below_v - v), "{:?} {}", derivatives, below_v - v);
+        assert!(derivatives.du_dx.abs() > 1e-4 && derivatives.dv_dy.abs() > 1e-4);
+        // At the center, x runs along the longitude and y along the latitude
+        let small = 0.1 * derivatives.du_dx.abs();
+        assert!(derivatives.dv_dx.abs() < small && (right_v - v).abs() < small && (below_u - u).abs() < small);
+
+        // Off by default
+        assert!(uv(i, j, false).2.is_none());
+        assert!(!RenderSettings::default().ray_differentials);
+    }
+
+    // A thin bright line at a shallow angle on black, rendered with the filter
+    fn render_line(filter: Filter) -> Box<PPM> {
+        let mut scene = Scene::new();
+        let (along, across) = (vector![1.0, 0.2, 0.0].normalize(), vector![-0.2, 1.0, 0.0].normalize() * 0.02);
+        let center = point![0.0, 0.0, -1.0];
+        let corners = [center - along - across, center + along - across, center + along + across, center - along + across];
+        for [a, b, c] in [[0, 1, 2], [0, 2, 3]] {
+            scene.add(Triangle { vertices: [corners[a], corners[b], corners[c]], normals: None, uvs: None, material: DiffuseLight::new(RGB(8.0, 8.0, 8.0)).into() });
+        }
+        let camera = camera(32, 64).aspect_ratio(1.0).background(RGB::zeros()).filter(filter).seed(2).build().unwrap();
+        camera.renderer().render_parallel(Arc::new(scene))
+    }
+
+    #[test]
+    fn test_filters() {
+        let column_peaks = |image: &PPM| (4..28).map(|j| (0..32).map(|i| image[(i, j)].0).fold(0.0, Float::max)).collect::<Vec<_>>();
+        // How much the brightest pixel of a column changes as the line moves across the rows
+        let stairs = |image: &PPM| {
+            let peaks = column_peaks(image);
+            let mean = peaks.iter().sum::<Float>() / peaks.len() as Float;
+            (peaks.iter().map(|peak| (peak - mean).powi(2)).sum::<Float>() / peaks.len() as Float).sqrt() / mean
+        };
+        let lowest = |image: &PPM| image.pixels().iter().map(|px| px.0).fold(Float::MAX, Float::min);
+        let sharpness = |image: &PPM| column_peaks(image).iter().sum::<Float>();
+        let [boxed, tent, gaussian, mitchell] = [Filter::Box, Filter::tent(), Filter::gaussian(), Filter::mitchell()].map(render_line);
+
+        // Wider filters smooth the steps, the gaussian the most
+        assert!(stairs(&tent) < 0.85 * stairs(&boxed));
+        assert!(stairs(&mitchell) < 0.85 * stairs(&boxed));
+        assert!(stairs(&gaussian) < stairs(&tent) && stairs(&gaussian) < stairs(&mitchell));
+        // The mitchell filter keeps the line sharper than the gaussian, but rings below black next to it
+        assert!(sharpness(&mitchell) > sharpness(&gaussian));
+        assert!(lowest(&mitchell) < 0.0);
+        assert_eq!([&boxed, &tent, &gaussian].map(|image| lowest(image)), [0.0; 3]);
+
+        // The box is the default
+        assert_eq!(render_line(Filter::default()).to_rgba8(), boxed.to_rgba8());
+        let invalid = Filter::Gaussian { radius: 8.0, alpha: 2.0 };
+        assert_eq!(Renderer::builder(camera(4, 1).filter(invalid).build().unwrap()).build().err(), Some(RenderSettingsError::InvalidFilter(invalid)));
+    }
+
+    #[test]
+    fn test_raster_position() {
+        let cameras = [
+            camera(40, 1).aspect_ratio(1.5).look_from(point![1.0, 2.0, 3.0]).look_at(point![0.0, 0.0, -1.0]).lens_distortion(-0.2, 0.05),
+            fisheye(40, 180.0).aspect_ratio(1.5),
+            panorama(40),
+        ].map(|camera| camera.build().unwrap());
+        for camera in &cameras {
+            for (x, y) in [(20.0, 13.0), (0.5, 0.5), (33.25, 7.75), (12.0, 18.5)] {
+                let Some(ray) = camera.central_ray(x, y) else {
+                    continue;
+                };
+                let (rx, ry) = camera.raster_position(&(ray.dir * 3.0)).unwrap();
+                assert!((rx - x).abs() < tolerance(1e3) * x.max(1.0) && (ry - y).abs() < tolerance(1e3) * y.max(1.0), "{:?} ({}, {}) -> ({}, {})", camera.projection, x, y, rx, ry);
+            }
+        }
+        // Behind the camera and outside of the fisheye circle
+        let [perspective, fisheye, panorama] = &cameras;
+        assert_eq!(perspective.raster_position(&perspective.w), None);
+        assert!(perspective.raster_position(&(perspective.u - 0.1 * perspective.w)).is_none());
+        assert_eq!(fisheye.raster_position(&fisheye.w), None);
+        assert!(panorama.raster_position(&panorama.w).is_some());
+
+        // A light path reaching the camera splats where the camera ray through that point starts
+        let film = Film::new(perspective.width(), perspective.height());
+        let ray = perspective.central_ray(10.5, 7.5).unwrap();
+        let (x, y) = perspective.raster_position(&ray.dir).unwrap();
+        film.add_splat(x, y, RGB::white());
+        let image = film.develop(1);
+        assert!((image[(7, 10)].0 - 1.0).abs() < tolerance(1e3));
+    }
+
+    #[test]
+    fn test_render_supersampled() {
+        let scene = single_sphere();
+        let renderer = camera(15, 4).seed(6).transparent_background(true).build().unwrap().renderer();
+        // Lanczos weights are zero at whole pixels, so the same size stays the same
+        let same = renderer.render_supersampled(scene.clone(), 1);
+        assert_eq!(same.to_rgba8(), renderer.render_parallel(scene.clone()).to_rgba8());
+
+        let image = renderer.render_supersampled(scene.clone(), 3);
+        assert_eq!((image.width(), image.height()), (renderer.width(), renderer.height()));
+        // Nine times the samples per pixel, close to a render with as many
+        let reference = camera(15, 36).seed(6).transparent_background(true).build().unwrap().renderer().render_parallel(scene);
+        let comparison = compare(&image.to_float_image(), &reference.to_float_image()).unwrap();
+        assert!(comparison.psnr > 25.0, "{:?}", comparison);
+        assert!(image.alpha(image.height() / 2, image.width() / 2) > 0.99 && image.alpha(0, 0) < 0.01);
+    }
+
+    // Glass prism along z with a triangular cross section, its tip pointing up
+    fn prism(dispersion: Float) -> Scene {
+        let glass: MaterialKind = Dielectric::new(1.5).dispersion(dispersion).into();
+        let corners = [point![-0.5, -1.0], point![0.5, -1.0], point![0.0, 1.5]];
+        let mut scene = Scene::new();
+        for k in 0..3 {
+            let (a, b) = (corners[k], corners[(k + 1) % 3]);
+            let [a0, a1, b0, b1] = [(a, -5.0), (a, 5.0), (b, -5.0), (b, 5.0)].map(|(p, z)| point![p.x, p.y, z]);
+            // Wound so the normals point out of the prism
+            for vertices in [[a0, b0, b1], [a0, b1, a1]] {
+                scene.add(Triangle { vertices, normals: None, uvs: None, material: glass.clone() });
+            }
+        }
+        scene
+    }
+
+    // A ray with the wavelengths through the prism from the left, tried again until it makes it
+    // through without being reflected on the way
+    fn refracted_path(scene: &Scene, lambda: [Float; 3]) -> Ray {
+        seed_rng(4);
+        let start = Ray { wavelengths: Some(Wavelengths { lambda, secondary: true }), ..Ray::new(point![-3.0, 0.1, 0.0], vector![1.0, 0.0, 0.0]) };
+        (0..100).find_map(|_| {
+            let mut ray = start.clone();
+            while let Some(hit) = scene.hit(&ray, Interval::new(MIN_T, INF)) {
+                let (scattered, _) = hit.material.scatter(&ray, &hit)?;
+                if scattered.dir.dot(&hit.normal) > 0.0 {
+                    return None;
+                }
+                ray = scattered;
+            }
+            Some(ray)
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_prism_dispersion() {
+        let scene = prism(0.05);
+        let (blue, red) = (refracted_path(&scene, [450.0; 3]), refracted_path(&scene, [650.0; 3]));
+        // Both bend towards the base, blue more than red
+        let bend = |ray: &Ray| -ray.dir.normalize().y;
+        assert!(bend(&red) > 0.1, "{:?}", red.dir);
+        assert!(bend(&blue) > bend(&red) + 0.01, "{:?} {:?}", blue.dir, red.dir);
+        // Only the hero goes on after dispersive glass
+        assert!(!refracted_path(&scene, [450.0, 550.0, 650.0]).wavelengths.unwrap().secondary);
+        let plain = refracted_path(&prism(0.0), [450.0, 550.0, 650.0]);
+        assert!(plain.wavelengths.unwrap().secondary);
+        assert_eq!(refracted_path(&prism(0.0), [650.0; 3]).dir, plain.dir);
+
+        // Rendered, the white light coming through the prism picks up colors
+        let render = |dispersion: Float| {
+            let mut scene = prism(dispersion);
+            scene.add(Sphere { center: point![-20.0, 0.1, 0.0], radius: 3.0, material: DiffuseLight::new(RGB(4.0, 4.0, 4.0)).into() });
+            let renderer = camera(16, 64)
+                .aspect_ratio(1.0)
+                .look_from(point![6.0, -1.5, 0.0])
+                .look_at(point![0.0, -0.7, 0.0])
+                .fov(40.0)
+                .background(RGB::zeros())
+                .spectral(WavelengthSampling::Hero)
+                .seed(5)
+                .build()
+                .unwrap()
+                .renderer();
+            let image = renderer.render_parallel(Arc::new(scene));
+            // Share of the light that is colored
+            let chroma = image.pixels().iter().map(|px| px.0.max(px.1).max(px.2) - px.0.min(px.1).min(px.2)).sum::<Float>();
+            let total = image.pixels().iter().map(|px| px.0.max(px.1).max(px.2)).sum::<Float>();
+            chroma / total
+        };
+        assert!(render(0.05) > 2.0 * render(0.0));
+    }
+
+    #[test]
+    fn test_spectral_furnace() {
+        // A gray ball under a white sky, everything stays gray in spectral renders
+        let scene = single_sphere();
+        for sampling in [WavelengthSampling::Hero, WavelengthSampling::Uniform] {
+            let renderer = camera(16, 256).aspect_ratio(1.0).background(RGB::white()).spectral(sampling).seed(3).build().unwrap().renderer();
+            let image = renderer.render_parallel(scene.clone());
+            let mean = image.pixels().iter().fold(RGB::zeros(), |sum, px| sum + *px) * (1.0 / image.pixels().len() as Float);
+            assert!((mean.0 - mean.1).abs() < 0.01 && (mean.1 - mean.2).abs() < 0.01, "{:?} {:?}", sampling, mean);
+            let average = |pixels: &[(usize, usize)]| pixels.iter().fold(RGB::zeros(), |sum, &px| sum + image[px]) * (1.0 / pixels.len() as Float);
+            let (sky, ball) = (average(&(0..16).map(|j| (0, j)).collect::<Vec<_>>()), average(&[(7, 7), (7, 8), (8, 7), (8, 8)]));
+            assert!([sky.0, sky.1, sky.2].iter().all(|c| (c - 1.0).abs() < 0.05), "{:?}", sky);
+            assert!([ball.0, ball.1, ball.2].iter().all(|c| (c - 0.5).abs() < 0.05), "{:?}", ball);
+        }
+
+        // RGB stays the default, and the photon map doesn't know about wavelengths
+        assert_eq!(RenderSettings::default().spectral, None);
+        let settings = RenderSettings { spectral: Some(WavelengthSampling::Hero), caustics: Some(PhotonMapSettings::default()), ..RenderSettings::default() };
+        assert_eq!(Renderer::builder(camera(4, 1).build().unwrap()).settings(settings).build().err(), Some(RenderSettingsError::SpectralCaustics));
+
+        // Black body lights are red when cool and blue when hot
+        let lit = |kelvin: Float| {
+            let mut scene = Scene::new();
+            scene.add(Sphere { center: point![0.0, 0.0, -1.0], radius: 0.5, material: DiffuseLight::new(RGB::white()).blackbody(kelvin).into() });
+            let renderer = camera(4, 64).aspect_ratio(1.0).background(RGB::zeros()).spectral(WavelengthSampling::Hero).seed(1).build().unwrap().renderer();
+            renderer.render_parallel(Arc::new(scene))[(2, 2)]
+        };
+        let (candle, sky) = (lit(2000.0), lit(12000.0));
+        assert!(candle.0 > candle.2 && sky.2 > sky.0, "{:?} {:?}", candle, sky);
+    }
+
+    // Scatters with NaN attenuation, like a material dividing by a zero length normal would
+    struct NanMaterial;
+
+    impl Material for NanMaterial {
+        fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, RGB)> {
+            Some((Ray::new(hit.p, ray.dir), RGB(Float::NAN, 0.5, 0.5)))
+        }
+    }
+
+    #[test]
+    fn test_sample_check() {
+        let mut scene = Scene::new();
+        scene.add(Sphere { center: point![0.0, 0.0, -1.0], radius: 0.5, material: Arc::new(NanMaterial).into() });
+        let scene = Arc::new(scene);
+        let render = |check: SampleCheck| camera(9, 4).sample_check(check).seed(1).build().unwrap().renderer().render_output(scene.clone());
+
+        let sanitized = render(SampleCheck::default());
+        assert!(sanitized.stats.non_finite_samples > 0);
+        assert!(sanitized.beauty.pixels().iter().all(|px| px.is_finite()));
+        // The sphere is black where it was hit, the sky is untouched
+        assert_eq!(sanitized.beauty[(4, 4)].luminance(), 0.0);
+        assert!(sanitized.beauty[(0, 0)].luminance() > 0.0);
+
+        let highlighted = render(SampleCheck::Highlight);
+        assert_eq!(highlighted.stats, sanitized.stats);
+        let center = highlighted.beauty[(4, 4)];
+        assert_eq!((center.0, center.1, center.2), (1.0, 0.0, 1.0));
+        assert_eq!(highlighted.beauty[(0, 0)].1, sanitized.beauty[(0, 0)].1);
+
+        let unchecked = render(SampleCheck::Off);
+        assert_eq!(unchecked.stats.non_finite_samples, 0);
+        assert!(unchecked.beauty[(4, 4)].has_nan());
+    }
+
+    #[test]
+    #[cfg(feature = "packets")]
+    fn test_packets_match_scalar_samples() {
+        use crate::camera::Integrator;
+
+        // Fisheye packets have inactive lanes outside of the image circle
+        let scene = RandomSpheres::new().seed(3).generate();
+        let integrator = Integrator::new(&scene, None, None);
+        for projection in [Projection::Perspective, Projection::Fisheye] {
+            let renderer = camera(16, 8)
+                .look_from(point![13.0, 2.0, 3.0])
+                .look_at(point![0.0, 0.0, 0.0])
+                .projection(projection)
+                .fill_outside_image_circle(true)
+                .seed(2)
+                .build()
+                .unwrap()
+                .renderer();
+            for i in 0..renderer.height() {
+                for j in 0..renderer.width() {
+                    let packet = renderer.trace_packet(&integrator, i, j, 4, &mut []);
+                    for (lane, (color, hit, position)) in packet.into_iter().enumerate() {
+                        let (expected, expected_hit, expected_position) = renderer.trace_sample(&integrator, i, j, 4 + lane as u32, &mut []);
+                        assert_eq!((color.0, color.1, color.2), (expected.0, expected.1, expected.2));
+                        assert_eq!(position, expected_position);
+                        assert_eq!(hit.map(|hit| (hit.t, hit.p)), expected_hit.map(|hit| (hit.t, hit.p)));
+                    }
+                }
+            }
+        }
+    }
+
+    // final_scene and its camera with every length multiplied by factor
+    fn scaled_final_scene(factor: Float) -> (Arc<Scene>, Camera) {
+        let desc = RandomSpheres::new().seed(3).generate().to_desc().unwrap();
+        let objects = desc.objects.into_iter().map(|object| match object {
+            HittableDesc::Sphere { center, radius, material } => {
+                HittableDesc::Sphere { center: center.map(|x| x * factor), radius: radius * factor, material }
+            },
+            other => other,
+        }).collect();
+        let camera = Camera::builder()
+            .width(160)
+            .aspect_ratio(16.0 / 9.0)
+            .samples_per_pixel(8)
+            .fov(20.0)
+            .look_from(point![12.0, 2.0, 3.0] * factor)
+            .look_at(point![0.0, 0.0, 0.0])
+            .defocus_angle(0.6)
+            .focus_dist(10.0 * factor)
+            .seed(1)
+            .build()
+            .unwrap();
+        (Arc::new(SceneDesc { objects }.build()), camera)
+    }
+
+    #[test]
+    fn test_scale_invariance() {
+        let render = |factor: Float| {
+            let (scene, camera) = scaled_final_scene(factor);
+            camera.renderer().render_parallel(scene).to_float_image()
+        };
+        // Without the scaled offset the small scene lost its contact shadows at about 24 dB
+        let reference = render(1.0);
+        // Contact shadows under the three large spheres, where acne and detached shadows show
+        let crop = |image: &FloatImage, (x0, y0): (usize, usize)| {
+            let mut crop = FloatImage::new(24, 12);
+            for y in 0..12 {
+                for x in 0..24 {
+                    crop[(y, x)] = image[(y0 + y, x0 + x)];
+                }
+            }
+            crop
+        };
+        // Rounding of the scaled coordinates alone costs f32 a few dB
+        let min_psnr = if cfg!(feature = "f32") { 50.0 } else { 60.0 };
+        for factor in [1000.0, 0.001] {
+            let image = render(factor);
+            let result = compare(&reference, &image).unwrap();
+            assert!(result.psnr > min_psnr, "x{}: {:?}", factor, result);
+            for corner in [(20, 52), (52, 50), (90, 64)] {
+                let result = compare(&crop(&reference, corner), &crop(&image, corner)).unwrap();
+                assert!(result.psnr > min_psnr, "x{} crop {:?}: {:?}", factor, corner, result);
+            }
+        }
+    }
+
+    #[test]
+    fn test_trace_pixel() {
+        let scene = Arc::new(setup_scene());
+        // The metal sphere on the right, added last
+        let mirror = scene.objects().last().unwrap().0;
+        let camera = camera(21, 4).look_at(point![1.0, 0.0, -1.0]).seed(9).build().unwrap();
+        let renderer = camera.renderer();
+
+        // The center ray bounces straight back off the mirror, past the camera into the sky
+        let trace = renderer.trace_pixel(&scene, 10, 10, 0);
+        assert_eq!(trace.bounces.len(), 2);
+        match trace.bounces[0].event {
+            BounceEvent::Hit { object, front, specular, attenuation, .. } => {
+                assert_eq!(object, mirror);
+                assert!(front && specular);
+                assert_eq!(attenuation.unwrap().0, 0.8);
+            },
+            _ => panic!("the camera ray missed the mirror"),
+        }
+        assert!(trace.escaped());
+        assert!(trace.bounces[1].direction.z > 0.0);
+        let text = trace.to_string();
+        assert!(text.contains("scattered (specular)") && text.contains("escaped"), "{}", text);
+
+        // Same colors as the render, for a pixel with a longer path too
+        let image = renderer.render_parallel(scene.clone());
+        for (x, y) in [(10, 10), (3, 17)] {
+            let sum = (0..4).fold(RGB::zeros(), |sum, sample| sum + renderer.trace_pixel(&scene, x, y, sample).color);
+            let (pixel, expected) = (image[(y, x)], sum * 0.25);
+            assert!((pixel.0 - expected.0).abs() < 1e-12 && (pixel.2 - expected.2).abs() < 1e-12, "pixel ({}, {})", x, y);
+        }
+        let ground = renderer.trace_pixel(&scene, 3, 17, 1);
+        assert!(ground.bounces.len() > 1, "{}", ground);
+    }
+
+    #[test]
+    fn test_export_paths() {
+        let scene = setup_scene();
+        let camera = camera(21, 4).look_at(point![-1.0, 0.0, -1.0]).seed(4).build().unwrap();
+        let renderer = camera.renderer();
+        let pixels = [(10, 10), (10, 12)];
+        let mut obj = vec![];
+        renderer.export_paths(&scene, &pixels, 3, PathExport::default(), &mut obj).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+
+        // One object per path, with the vertices of its polyline and a segment between each two
+        let traces: Vec<_> = pixels.iter().flat_map(|&(x, y)| (0..3).map(move |sample| (x, y, sample)))
+            .map(|(x, y, sample)| renderer.trace_pixel(&scene, x, y, sample))
+            .collect();
+        let lines = |prefix: &str| obj.lines().filter(|line| line.starts_with(prefix)).count();
+        let vertices: usize = traces.iter().map(|trace| trace.polyline(5.0).len()).sum();
+        assert_eq!(lines("o "), 6);
+        assert!(obj.contains("o pixel_10_12_sample_2\n"));
+        assert_eq!(lines("v "), vertices);
+        assert_eq!(lines("l "), vertices - 6);
+        let parsed: Vec<Vec<Float>> = obj.lines().filter(|line| line.starts_with("v ")).map(|line| {
+            line.split_whitespace().skip(1).map(|x| x.parse().unwrap()).collect()
+        }).collect();
+        assert!(parsed.iter().all(|v| v.len() == 3));
+        assert!(obj.lines().filter_map(|line| line.strip_prefix("l ")).flat_map(|line| line.split(' '))
+            .all(|idx| (1..=vertices).contains(&idx.parse().unwrap())));
+
+        // Through the glass sphere: in, out and on
+        let through = &traces[0];
+        assert!(through.bounces.len() >= 3, "{}", through);
+        let last = *through.polyline(5.0).last().unwrap();
+        if through.escaped() {
+            let origin = through.bounces.last().unwrap().origin;
+            assert!(((last - origin).norm() - 5.0).abs() < 1e-9);
+        }
+
+        let mut svg = vec![];
+        let options = PathExport { format: PathFormat::Svg, escape_length: 2.0 };
+        renderer.export_paths(&scene, &pixels, 3, options, &mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.starts_with("<svg") && svg.trim_end().ends_with("</svg>"));
+        assert_eq!(svg.matches("<polyline").count(), 6);
+    }
+
+    #[test]
+    fn test_pick() {
+        let scene = Arc::new(setup_scene());
+        let ids: Vec<_> = scene.objects().map(|(id, _)| id).collect();
+        let camera = camera(21, 4).aspect_ratio(21.0 / 11.0).aovs(AovFlags { object_id: true, ..AovFlags::default() }).build().unwrap();
+        let renderer = camera.renderer();
+
+        // The center sphere of radius 0.5 at z = -1 is straight ahead
+        let pick = renderer.pick(&scene, 10, 5).unwrap();
+        assert_eq!(pick.object_id, ids[1]);
+        assert!((pick.point - point![0.0, 0.0, -0.5]).norm() < tolerance(16.0));
+        assert!((pick.normal - vector![0.0, 0.0, 1.0]).norm() < tolerance(16.0));
+        assert!((camera.central_ray(10.5, 5.5).unwrap().at(pick.t) - pick.point).norm() < tolerance(16.0));
+        // Open sky above, the ground and the glass sphere below and to the left
+        assert_eq!(renderer.pick(&scene, 10, 0), None);
+        assert_eq!(renderer.pick(&scene, 10, 10).unwrap().object_id, ids[0]);
+        assert_eq!(renderer.pick(&scene, 4, 5).unwrap().object_id, ids[2]);
+
+        let output = renderer.render_output(scene.clone());
+        let object_id = output.object_id.unwrap();
+        let rgb = |c: RGB| (c.0, c.1, c.2);
+        assert_eq!(rgb(object_id[(5, 10)]), rgb(ids[1].color()));
+        assert_eq!(rgb(object_id[(0, 10)]), (0.0, 0.0, 0.0));
+        assert!((0..4).all(|a| (a + 1..4).all(|b| rgb(ids[a].color()) != rgb(ids[b].color()))));
+    }
 }
@@ -0,0 +1,339 @@
+// One frame split across machines. Every machine renders a chunk, some rows and a share of the
+// samples, and saves it. merge_chunks adds the chunk files back up into the whole frame.
+//
+// Every sample is seeded from the render seed, its pixel and its index, so a chunk comes out the
+// same on any machine and the merged frame is the one a single machine would have rendered.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::ops::Range;
+use na::Vector3;
+use serde::{Deserialize, Serialize};
+use crate::accumulator::Accumulator;
+use crate::camera::Renderer;
+use crate::Float;
+use crate::scene::Scene;
+
+const MAGIC: &str = "RTCHUNK";
+// Three f64 sums and a u32 count
+const PIXEL_BYTES: usize = 28;
+
+// What a chunk covers, e.g. rows 200..400 or the second of three shares of the samples
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkSpec {
+    pub rows: Option<Range<usize>>, // All rows if None
+    pub share: u32, // This share of shares, counted from 0
+    pub shares: u32,
+}
+
+impl ChunkSpec {
+    // All samples of the rows
+    pub fn rows(rows: Range<usize>) -> Self {
+        Self { rows: Some(rows), share: 0, shares: 1 }
+    }
+
+    // Share index of count of the samples of every pixel
+    pub fn share(index: u32, count: u32) -> Self {
+        Self { rows: None, share: index, shares: count }
+    }
+
+    // Samples of the share, the ranges of all shares add up to 0..samples_per_pixel
+    pub fn samples(&self, samples_per_pixel: u32) -> Range<u32> {
+        let bound = |share: u32| (samples_per_pixel as u64 * share as u64 / self.shares as u64) as u32;
+        bound(self.share)..bound(self.share + 1)
+    }
+}
+
+// The render a chunk belongs to and the part of it it holds
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ChunkHeader {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: u32,
+    pub seed: u64,
+    pub rows: Range<usize>,
+    pub samples: Range<u32>,
+}
+
+impl ChunkHeader {
+    fn same_render(&self, other: &ChunkHeader) -> bool {
+        (self.width, self.height, self.samples_per_pixel, self.seed) == (other.width, other.height, other.samples_per_pixel, other.seed)
+    }
+}
+
+// Sample sums of the rows of the header, as many rows as it has
+pub struct Chunk {
+    pub header: ChunkHeader,
+    pub accumulator: Accumulator,
+}
+
+#[derive(Debug)]
+pub enum ChunkError {
+    Io(std::io::Error),
+    // Without a seed, chunks couldn't be rendered again or matched to each other
+    Unseeded,
+    InvalidSpec(String),
+    NoChunks,
+    // The chunk at the index is of another render than the first one
+    OtherRender(usize),
+    // The first row that has these samples more than once, or not at all
+    Overlap { row: usize, samples: Range<u32> },
+    Missing { row: usize, samples: Range<u32> },
+}
+
+impl Display for ChunkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkError::Io(e) => write!(f, "{}", e),
+            ChunkError::Unseeded => write!(f, "chunks need a seed to be rendered reproducibly"),
+            ChunkError::InvalidSpec(message) => write!(f, "invalid chunk: {}", message),
+            ChunkError::NoChunks => write!(f, "no chunks to merge"),
+            ChunkError::OtherRender(idx) => write!(f, "chunk {} is from another render than the first one", idx),
+            ChunkError::Overlap { row, samples } => {
+                write!(f, "samples {}..{} of row {} are in more than one chunk", samples.start, samples.end, row)
+            },
+            ChunkError::Missing { row, samples } => {
+                write!(f, "samples {}..{} of row {} are in no chunk", samples.start, samples.end, row)
+            },
+        }
+    }
+}
+
+impl Error for ChunkError {}
+
+impl From<std::io::Error> for ChunkError {
+    fn from(e: std::io::Error) -> Self {
+        ChunkError::Io(e)
+    }
+}
+
+// Renders the part of the frame the spec asks for, the renderer needs a seed
+pub fn render_chunk(renderer: &Renderer, scene: &Scene, spec: &ChunkSpec) -> Result<Chunk, ChunkError> {
+    let settings = renderer.settings();
+    let seed = settings.seed.ok_or(ChunkError::Unseeded)?;
+    let rows = spec.rows.clone().unwrap_or(0..renderer.height());
+    if rows.is_empty() || rows.end > renderer.height() {
+        return Err(ChunkError::InvalidSpec(format!("rows {}..{} of an image {} high", rows.start, rows.end, renderer.height())));
+    }
+    if spec.share >= spec.shares {
+        return Err(ChunkError::InvalidSpec(format!("share {} of {}", spec.share, spec.shares)));
+    }
+    let samples = spec.samples(settings.samples_per_pixel);
+    let header = ChunkHeader {
+        width: renderer.width(),
+        height: renderer.height(),
+        samples_per_pixel: settings.samples_per_pixel,
+        seed,
+        rows: rows.clone(),
+        samples: samples.clone(),
+    };
+    let accumulator = renderer.render_region(scene, rows, samples);
+    Ok(Chunk { header, accumulator })
+}
+
+impl Chunk {
+    // A line with the magic, a line of header JSON, then every pixel as three f64 sums and a u32
+    // count, little endian
+    pub fn save(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        let mut contents = BufWriter::new(writer);
+        writeln!(contents, "{}", MAGIC)?;
+        serde_json::to_writer(&mut contents, &self.header)?;
+        writeln!(contents)?;
+        for i in 0..self.accumulator.height() {
+            for j in 0..self.accumulator.width() {
+                for v in self.accumulator.sum(i, j).iter() {
+                    #[allow(clippy::unnecessary_cast)] // Float is f64 already without the f32 feature
+                    contents.write_all(&(*v as f64).to_le_bytes())?;
+                }
+                contents.write_all(&self.accumulator.count(i, j).to_le_bytes())?;
+            }
+        }
+        contents.flush()
+    }
+
+    pub fn load(reader: &mut dyn Read) -> std::io::Result<Chunk> {
+        let invalid = |message: String| std::io::Error::new(ErrorKind::InvalidData, message);
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if line.trim_end() != MAGIC {
+            return Err(invalid("not a chunk file".to_string()));
+        }
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header: ChunkHeader = serde_json::from_str(&line).map_err(|e| invalid(format!("invalid chunk header: {}", e)))?;
+        if header.rows.start > header.rows.end || header.samples.start > header.samples.end {
+            return Err(invalid("the chunk has backwards rows or samples".to_string()));
+        }
+        if header.rows.end > header.height || header.samples.end > header.samples_per_pixel {
+            return Err(invalid("the chunk doesn't fit in its image".to_string()));
+        }
+
+        // The size comes from the file, so it's checked against the data before allocating
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        let expected = header.width.checked_mul(header.rows.len()).and_then(|pixels| pixels.checked_mul(PIXEL_BYTES));
+        if expected.is_none_or(|expected| data.len() < expected) {
+            return Err(invalid(format!("the chunk has {} bytes of pixels, too few for {} x {}", data.len(), header.width, header.rows.len())));
+        }
+
+        let mut accumulator = Accumulator::new(header.width, header.rows.len());
+        for (idx, pixel) in data.chunks_exact(PIXEL_BYTES).take(header.width * header.rows.len()).enumerate() {
+            let value = |k: usize| f64::from_le_bytes(pixel[k * 8..k * 8 + 8].try_into().unwrap()) as Float;
+            let count = u32::from_le_bytes(pixel[24..].try_into().unwrap());
+            accumulator.add(idx / header.width, idx % header.width, Vector3::new(value(0), value(1), value(2)), count);
+        }
+        Ok(Chunk { header, accumulator })
+    }
+}
+
+// Adds the chunks up into the whole frame. Every sample of every pixel has to be in exactly one
+// chunk, and all chunks have to be of the same render.
+pub fn merge_chunks(chunks: &[Chunk]) -> Result<Accumulator, ChunkError> {
+    let first = &chunks.first().ok_or(ChunkError::NoChunks)?.header;
+    if let Some(idx) = chunks.iter().position(|chunk| !chunk.header.same_render(first)) {
+        return Err(ChunkError::OtherRender(idx));
+    }
+
+    for row in 0..first.height {
+        let mut samples: Vec<_> = chunks.iter()
+            .map(|chunk| &chunk.header)
+            .filter(|header| header.rows.contains(&row) && !header.samples.is_empty())
+            .map(|header| header.samples.clone())
+            .collect();
+        samples.sort_by_key(|samples| samples.start);
+        let mut covered = 0;
+        for range in samples {
+            if range.start < covered {
+                return Err(ChunkError::Overlap { row, samples: range.start..covered.min(range.end) });
+            }
+            if range.start > covered {
+                return Err(ChunkError::Missing { row, samples: covered..range.start });
+            }
+            covered = range.end;
+        }
+        if covered < first.samples_per_pixel {
+            return Err(ChunkError::Missing { row, samples: covered..first.samples_per_pixel });
+        }
+    }
+
+    let mut frame = Accumulator::new(first.width, first.height);
+    for chunk in chunks {
+        for i in 0..chunk.accumulator.height() {
+            for j in 0..chunk.accumulator.width() {
+                frame.add(chunk.header.rows.start + i, j, chunk.accumulator.sum(i, j), chunk.accumulator.count(i, j));
+            }
+        }
+    }
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod test {
+    use na::point;
+    use crate::camera::{Camera, Renderer};
+    use crate::chunk::{merge_chunks, render_chunk, Chunk, ChunkError, ChunkSpec};
+    use crate::material::{Lambertian, Metal};
+    use crate::RGB;
+    use crate::scene::{Scene, Sphere};
+
+    fn scene() -> Scene {
+        let mut scene = Scene::new();
+        scene.add(Sphere { center: point![0.0, -100.5, -1.0], radius: 100.0, material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into() });
+        scene.add(Sphere { center: point![0.0, 0.0, -1.0], radius: 0.5, material: Metal::new(RGB(0.8, 0.6, 0.2), 0.3).into() });
+        scene
+    }
+
+    fn renderer() -> Renderer {
+        Camera::builder()
+            .dimensions(10, 7)
+            .samples_per_pixel(6)
+            .look_from(point![0.0, 0.0, 1.0])
+            .look_at(point![0.0, 0.0, -1.0])
+            .seed(11)
+            .build()
+            .unwrap()
+            .renderer()
+    }
+
+    // Through a file and back, like chunks coming from other machines
+    fn saved(chunk: Chunk) -> Chunk {
+        let mut bytes = vec![];
+        chunk.save(&mut bytes).unwrap();
+        Chunk::load(&mut &bytes[..]).unwrap()
+    }
+
+    #[test]
+    fn test_merge_matches_single_render() {
+        let renderer = renderer();
+        let expected = renderer.render_samples(std::sync::Arc::new(scene()), 0..6);
+        let scene = scene();
+        let rows = [0..3, 3..5, 5..7].map(|rows| saved(render_chunk(&renderer, &scene, &ChunkSpec::rows(rows)).unwrap()));
+        let shares = [0, 1].map(|share| saved(render_chunk(&renderer, &scene, &ChunkSpec::share(share, 2)).unwrap()));
+        assert_eq!(shares[1].header.samples, 3..6);
+
+        for chunks in [&rows[..], &shares[..]] {
+            let merged = merge_chunks(chunks).unwrap();
+            for i in 0..7 {
+                for j in 0..10 {
+                    assert_eq!(merged.count(i, j), 6);
+                    assert!((merged.sum(i, j) - expected.sum(i, j)).norm() < 1e-4, "{} {}", i, j);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_errors() {
+        let (renderer, scene) = (renderer(), scene());
+        let chunk = |spec: ChunkSpec| render_chunk(&renderer, &scene, &spec).unwrap();
+
+        let overlap = merge_chunks(&[chunk(ChunkSpec::rows(0..4)), chunk(ChunkSpec::rows(3..7))]);
+        assert!(matches!(overlap, Err(ChunkError::Overlap { row: 3, samples }) if samples == (0..6)));
+        let gap = merge_chunks(&[chunk(ChunkSpec::rows(0..3)), chunk(ChunkSpec::rows(4..7))]);
+        assert!(matches!(gap, Err(ChunkError::Missing { row: 3, samples }) if samples == (0..6)));
+        let share = merge_chunks(&[chunk(ChunkSpec::share(0, 3)), chunk(ChunkSpec::share(2, 3))]);
+        assert!(matches!(share, Err(ChunkError::Missing { row: 0, samples }) if samples == (2..4)));
+        // Rows and shares mix as long as every sample is there once
+        let mut half = ChunkSpec::share(1, 2);
+        half.rows = Some(0..7);
+        assert!(merge_chunks(&[chunk(ChunkSpec::share(0, 2)), chunk(half)]).is_ok());
+
+        let mut other = chunk(ChunkSpec::rows(3..7));
+        other.header.seed = 12;
+        assert!(matches!(merge_chunks(&[chunk(ChunkSpec::rows(0..3)), other]), Err(ChunkError::OtherRender(1))));
+        assert!(matches!(merge_chunks(&[]), Err(ChunkError::NoChunks)));
+
+        assert!(matches!(render_chunk(&renderer, &scene, &ChunkSpec::rows(5..8)), Err(ChunkError::InvalidSpec(_))));
+        assert!(matches!(render_chunk(&renderer, &scene, &ChunkSpec::share(2, 2)), Err(ChunkError::InvalidSpec(_))));
+        let unseeded = Camera::builder().dimensions(4, 4).build().unwrap().renderer();
+        assert!(matches!(render_chunk(&unseeded, &scene, &ChunkSpec::share(0, 2)), Err(ChunkError::Unseeded)));
+        assert!(Chunk::load(&mut &b"RTCHUNK\n{}\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_header() {
+        let load = |header: &str, pixels: usize| {
+            let mut bytes = format!("RTCHUNK\n{}\n", header).into_bytes();
+            bytes.resize(bytes.len() + pixels * 28, 0);
+            Chunk::load(&mut &bytes[..]).err().map(|e| (e.kind(), e.to_string()))
+        };
+        let header = |width: usize, rows: (usize, usize)| format!(
+            r#"{{"width":{},"height":4,"samples_per_pixel":2,"seed":1,"rows":{{"start":{},"end":{}}},"samples":{{"start":0,"end":2}}}}"#,
+            width, rows.0, rows.1
+        );
+        assert_eq!(load(&header(3, (1, 3)), 6), None);
+
+        for (header, pixels, message) in [
+            (header(3, (1, 3)), 5, "too few"),
+            (header(usize::MAX / 2, (0, 4)), 1, "too few"),
+            (header(usize::MAX, (0, 1)), 1, "too few"),
+            (header(3, (3, 1)), 0, "backwards"),
+        ] {
+            let (kind, error) = load(&header, pixels).unwrap();
+            assert_eq!(kind, std::io::ErrorKind::InvalidData);
+            assert!(error.contains(message), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,495 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use na::point;
+use crate::camera::{Camera, CameraBuilder, HeatmapMetric, RenderMode};
+use crate::filter::Filter;
+use crate::image::{Image, PPM};
+use crate::scene::Scene;
+use crate::scene::generators::RandomSpheres;
+use crate::scenes::{setup_scene, setup_scene2};
+use crate::spectral::WavelengthSampling;
+use crate::Float;
+
+pub const USAGE: &str = "\
+Usage: raytracer [OPTIONS]
+
+Options:
+  --width <pixels>       Image width, the height follows from the scene's aspect ratio [default: 1200]
+  --samples <count>      Samples per pixel [default: 50]
+  --max-bounces <count>  Longest path traced from the camera [default: 10]
+                         Scene files can set their own defaults for these three
+  --seed <number>        Makes the render and the random scenes reproducible, random every run if not set
+  --output <path>        Image file, the format comes from the extension: png, bmp, ppm or hdr [default: image.png]
+  --scene <name|path>    setup_scene, setup_scene2, final_scene, bouncing_spheres or a .json or .toml scene file
+                         [default: final_scene]
+  --threads <count>      Render threads, all cores for 0 or if not set
+  --time <seconds>       Stop adding samples after this long and save what's there, --samples is still the most
+  --filter <name>        Pixel reconstruction filter: box, tent, gaussian or mitchell [default: box]
+  --spectral <sampling>  Trace wavelengths instead of RGB, drawn hero (evenly spaced) or uniform
+  --bracket <evs>        Develop the render at each of these exposures in stops, e.g. -2,0,2, into
+                         <output>_ev-2.png, <output>_ev0.png and so on
+  --metadata             Also write the render settings to <output>.meta.json
+  --heatmap <metric>     Render the cost of finding the first hits instead, tests (intersection tests) or
+                         visits (objects and bounding boxes)
+  --watch                Render again whenever the scene file changes, needs a scene file
+  --serve <address>      Render scenes sent over HTTP, e.g. 0.0.0.0:8080
+  --preview              Show the image in a window while it renders, Esc stops early and saves what's there
+  -h, --help             Print this help";
+
+// Scenes compiled into the binary, each with the camera it was made for
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BuiltinScene {
+    Simple,
+    TwoSpheres,
+    Final,
+    Bouncing,
+}
+
+impl BuiltinScene {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "setup_scene" => Some(BuiltinScene::Simple),
+            "setup_scene2" => Some(BuiltinScene::TwoSpheres),
+            "final_scene" => Some(BuiltinScene::Final),
+            "bouncing_spheres" => Some(BuiltinScene::Bouncing),
+            _ => None,
+        }
+    }
+
+    // The seed fixes the random sphere fields, they are different every run without one
+    pub fn scene(&self, seed: Option<u64>) -> Arc<Scene> {
+        let random_spheres = |bouncing: bool| {
+            let generator = RandomSpheres::new().bouncing(bouncing);
+            let generator = match seed {
+                Some(seed) => generator.seed(seed),
+                None => generator,
+            };
+            Arc::new(generator.generate())
+        };
+        match self {
+            BuiltinScene::Simple => Arc::new(setup_scene()),
+            BuiltinScene::TwoSpheres => Arc::new(setup_scene2()),
+            BuiltinScene::Final => random_spheres(false),
+            BuiltinScene::Bouncing => random_spheres(true),
+        }
+    }
+
+    pub fn camera(&self) -> CameraBuilder {
+        let builder = Camera::builder().width(1200).aspect_ratio(16.0 / 9.0).samples_per_pixel(50).max_bounces(10);
+        match self {
+            BuiltinScene::Simple | BuiltinScene::TwoSpheres => builder.focus_dist(1.0),
+            BuiltinScene::Final | BuiltinScene::Bouncing => {
+                let builder = builder
+                    .fov(20.0)
+                    .look_from(point![12.0, 2.0, 3.0])
+                    .look_at(point![0.0, 0.0, 0.0])
+                    .defocus_angle(0.6)
+                    .focus_dist(10.0);
+                if *self == BuiltinScene::Bouncing { builder.shutter(0.0, 1.0) } else { builder }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SceneChoice {
+    Builtin(BuiltinScene),
+    File(PathBuf),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    Bmp,
+    Ppm, // Binary P6
+    Hdr, // Radiance RGBE, without tone mapping
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(OutputFormat::Png),
+            "bmp" => Some(OutputFormat::Bmp),
+            "ppm" => Some(OutputFormat::Ppm),
+            "hdr" => Some(OutputFormat::Hdr),
+            _ => None,
+        }
+    }
+
+    pub fn save(&self, image: &PPM, writer: &mut dyn Write) -> std::io::Result<()> {
+        match self {
+            OutputFormat::Png => image.save_png(writer),
+            OutputFormat::Bmp => image.save_bmp(writer),
+            OutputFormat::Ppm => image.save_binary(writer),
+            OutputFormat::Hdr => image.to_float_image().save(writer),
+        }
+    }
+}
+
+// Everything the binary needs to know about a render. Settings left at None come from the scene.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub width: Option<usize>,
+    pub samples: Option<u32>,
+    pub max_bounces: Option<u32>,
+    pub seed: Option<u64>,
+    pub output: PathBuf,
+    pub format: OutputFormat, // Follows the extension of output
+    pub scene: SceneChoice,
+    pub threads: Option<usize>, // Render threads, one per core for 0 or None
+    pub metadata: bool, // Write a JSON sidecar next to the image
+    pub watch: bool, // Keep running and render again when the scene file changes
+    pub serve: Option<SocketAddr>, // Run the HTTP render service instead of rendering once
+    pub heatmap: Option<HeatmapMetric>, // Render a heatmap instead of the image
+    pub preview: bool, // Show the render in a window while it refines
+    pub time_budget: Option<Duration>, // Render for this long at most
+    pub filter: Option<Filter>, // Reconstruction filter over the scene's
+    pub spectral: Option<WavelengthSampling>, // Spectral rendering, RGB if None
+    pub bracket: Option<Vec<Float>>, // Exposures in EV to save the image at instead of once
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            width: None,
+            samples: None,
+            max_bounces: None,
+            seed: None,
+            output: PathBuf::from("image.png"),
+            format: OutputFormat::Png,
+            scene: SceneChoice::Builtin(BuiltinScene::Final),
+            threads: None,
+            metadata: false,
+            watch: false,
+            serve: None,
+            heatmap: None,
+            preview: false,
+            time_budget: None,
+            filter: None,
+            spectral: None,
+            bracket: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CliError {
+    HelpRequested,
+    UnknownArgument(String),
+    MissingValue(String),
+    InvalidValue { option: String, value: String },
+    UnknownFormat(PathBuf),
+    WatchWithoutSceneFile,
+    ServeNotBuilt, // Built without the serve feature
+    PreviewNotBuilt, // Built without the preview feature
+    BracketWithHdr, // HDR output isn't tone mapped, so it has no exposures to bracket
+}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::HelpRequested => write!(f, "{}", USAGE),
+            CliError::UnknownArgument(argument) => write!(f, "unknown argument '{}'", argument),
+            CliError::MissingValue(option) => write!(f, "{} needs a value", option),
+            CliError::InvalidValue { option, value } => write!(f, "invalid value '{}' for {}", value, option),
+            CliError::UnknownFormat(path) => write!(
+                f,
+                "can't tell the image format of '{}', use a .png, .bmp, .ppm or .hdr extension",
+                path.display()
+            ),
+            CliError::WatchWithoutSceneFile => write!(f, "--watch needs a scene file, built-in scenes never change"),
+            CliError::ServeNotBuilt => write!(f, "--serve needs a build with the serve feature"),
+            CliError::PreviewNotBuilt => write!(f, "--preview needs a build with the preview feature"),
+            CliError::BracketWithHdr => write!(f, "--bracket needs a png, bmp or ppm output, hdr keeps the linear values"),
+        }
+    }
+}
+
+impl Error for CliError {}
+
+impl Config {
+    // Arguments without the program name, options take their value as the next argument
+    // or after '='
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Config, CliError> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Err(CliError::HelpRequested);
+            }
+            // Flags without a value
+            match arg.as_str() {
+                "--metadata" => {
+                    config.metadata = true;
+                    continue;
+                },
+                "--watch" => {
+                    config.watch = true;
+                    continue;
+                },
+                "--preview" => {
+                    if !cfg!(feature = "preview") {
+                        return Err(CliError::PreviewNotBuilt);
+                    }
+                    config.preview = true;
+                    continue;
+                },
+                _ => {}
+            }
+            let (option, inline_value) = match arg.split_once('=') {
+                Some((option, value)) => (option.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+            if !matches!(
+                option.as_str(),
+                "--width" | "--samples" | "--max-bounces" | "--seed" | "--output" | "--scene" | "--threads"
+                    | "--serve" | "--heatmap" | "--time" | "--filter" | "--spectral"
+                    | "--bracket"
+            ) {
+                return Err(CliError::UnknownArgument(arg));
+            }
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(CliError::MissingValue(option)),
+            };
+
+            match option.as_str() {
+                "--width" => config.width = Some(positive(&option, &value)?),
+                "--samples" => config.samples = Some(positive(&option, &value)?),
+                "--max-bounces" => config.max_bounces = Some(number(&option, &value)?),
+                "--seed" => config.seed = Some(number(&option, &value)?),
+                "--threads" => config.threads = Some(number(&option, &value)?),
+                "--time" => {
+                    let seconds: f64 = positive(&option, &value)?;
+                    config.time_budget = Some(Duration::try_from_secs_f64(seconds).map_err(|_| CliError::InvalidValue { option, value })?);
+                },
+                "--serve" => {
+                    if !cfg!(feature = "serve") {
+                        return Err(CliError::ServeNotBuilt);
+                    }
+                    config.serve = Some(number(&option, &value)?);
+                },
+                "--filter" => {
+                    config.filter = Some(Filter::from_name(&value).ok_or(CliError::InvalidValue { option, value })?);
+                },
+                "--spectral" => {
+                    config.spectral = Some(match value.as_str() {
+                        "hero" => WavelengthSampling::Hero,
+                        "uniform" => WavelengthSampling::Uniform,
+                        _ => return Err(CliError::InvalidValue { option, value }),
+                    });
+                },
+                "--bracket" => {
+                    let evs: Option<Vec<Float>> = value.split(',').map(|ev| ev.trim().parse().ok().filter(|ev: &Float| ev.is_finite())).collect();
+                    config.bracket = Some(evs.ok_or(CliError::InvalidValue { option, value })?);
+                },
+                "--heatmap" => {
+                    config.heatmap = Some(match value.as_str() {
+                        "tests" => HeatmapMetric::IntersectionTests,
+                        "visits" => HeatmapMetric::NodeVisits,
+                        _ => return Err(CliError::InvalidValue { option, value }),
+                    });
+                },
+                "--output" => {
+                    let output = PathBuf::from(value);
+                    config.format = OutputFormat::from_path(&output).ok_or(CliError::UnknownFormat(output.clone()))?;
+                    config.output = output;
+                },
+                _ => {
+                    config.scene = match BuiltinScene::from_name(&value) {
+                        Some(scene) => SceneChoice::Builtin(scene),
+                        None => SceneChoice::File(PathBuf::from(value)),
+                    };
+                }
+            }
+        }
+        if config.watch && !matches!(config.scene, SceneChoice::File(_)) {
+            return Err(CliError::WatchWithoutSceneFile);
+        }
+        if config.bracket.is_some() && config.format == OutputFormat::Hdr {
+            return Err(CliError::BracketWithHdr);
+        }
+        Ok(config)
+    }
+
+    // Puts the settings given on the command line over the ones from the scene
+    pub fn apply(&self, mut camera: CameraBuilder) -> CameraBuilder {
+        if let Some(width) = self.width {
+            camera = camera.width(width);
+        }
+        if let Some(samples) = self.samples {
+            camera = camera.samples_per_pixel(samples);
+        }
+        if let Some(max_bounces) = self.max_bounces {
+            camera = camera.max_bounces(max_bounces);
+        }
+        if let Some(seed) = self.seed {
+            camera = camera.seed(seed);
+        }
+        if let Some(budget) = self.time_budget {
+            camera = camera.time_budget(budget);
+        }
+        if let Some(filter) = self.filter {
+            camera = camera.filter(filter);
+        }
+        if let Some(sampling) = self.spectral {
+            camera = camera.spectral(sampling);
+        }
+        if let Some(metric) = self.heatmap {
+            camera = camera.mode(RenderMode::Heatmap { metric, max: None });
+        }
+        camera
+    }
+}
+
+// The output path with the exposure before the extension, image.png at -2 EV is image_ev-2.png
+pub fn bracket_path(output: &Path, ev: Float) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let sign = if ev > 0.0 { "+" } else { "" };
+    let mut name = format!("{}_ev{}{}", stem, sign, ev);
+    if let Some(extension) = output.extension() {
+        name = format!("{}.{}", name, extension.to_string_lossy());
+    }
+    output.with_file_name(name)
+}
+
+fn number<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, CliError> {
+    value.parse().map_err(|_| CliError::InvalidValue { option: option.to_string(), value: value.to_string() })
+}
+
+fn positive<T: std::str::FromStr + PartialEq + Default>(option: &str, value: &str) -> Result<T, CliError> {
+    let number: T = number(option, value)?;
+    if number == T::default() {
+        return Err(CliError::InvalidValue { option: option.to_string(), value: value.to_string() });
+    }
+    Ok(number)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use crate::camera::HeatmapMetric;
+    use crate::cli::{bracket_path, BuiltinScene, CliError, Config, OutputFormat, SceneChoice};
+    use crate::filter::Filter;
+    use crate::spectral::WavelengthSampling;
+
+    fn parse(args: &[&str]) -> Result<Config, CliError> {
+        Config::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(parse(&[]).unwrap(), Config::default());
+        let config = Config::default();
+        assert_eq!((config.width, config.samples, config.max_bounces), (None, None, None));
+        assert_eq!(config.output, PathBuf::from("image.png"));
+        assert_eq!(config.scene, SceneChoice::Builtin(BuiltinScene::Final));
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = parse(&[
+            "--width", "640", "--samples=8", "--max-bounces", "0", "--seed", "42",
+            "--output", "out/frame.HDR", "--scene", "setup_scene2", "--threads", "3", "--metadata"
+        ]).unwrap();
+        assert_eq!(config, Config {
+            width: Some(640),
+            samples: Some(8),
+            max_bounces: Some(0),
+            seed: Some(42),
+            output: PathBuf::from("out/frame.HDR"),
+            format: OutputFormat::Hdr,
+            scene: SceneChoice::Builtin(BuiltinScene::TwoSpheres),
+            threads: Some(3),
+            metadata: true,
+            watch: false,
+            serve: None,
+            heatmap: None,
+            preview: false,
+            time_budget: None,
+            filter: None,
+            spectral: None,
+            bracket: None,
+        });
+
+        // Anything that isn't a built-in scene is a file
+        let config = parse(&["--scene", "scenes/room.json"]).unwrap();
+        assert_eq!(config.scene, SceneChoice::File(PathBuf::from("scenes/room.json")));
+        // The last value wins
+        assert_eq!(parse(&["--width", "10", "--width", "20"]).unwrap().width, Some(20));
+        assert_eq!(parse(&["--heatmap", "visits"]).unwrap().heatmap, Some(HeatmapMetric::NodeVisits));
+        assert_eq!(parse(&["--time", "2.5"]).unwrap().time_budget, Some(Duration::from_millis(2500)));
+        assert_eq!(parse(&["--filter", "mitchell"]).unwrap().filter, Some(Filter::mitchell()));
+        assert_eq!(parse(&["--spectral", "uniform"]).unwrap().spectral, Some(WavelengthSampling::Uniform));
+        assert_eq!(parse(&["--bracket", "-2,0,2"]).unwrap().bracket, Some(vec![-2.0, 0.0, 2.0]));
+        assert_eq!(parse(&["--bracket=0.5"]).unwrap().bracket, Some(vec![0.5]));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse(&["--help"]), Err(CliError::HelpRequested));
+        assert_eq!(parse(&["--width", "10", "-h"]), Err(CliError::HelpRequested));
+        assert_eq!(parse(&["--frobnicate"]), Err(CliError::UnknownArgument("--frobnicate".to_string())));
+        assert_eq!(parse(&["640"]), Err(CliError::UnknownArgument("640".to_string())));
+        assert_eq!(parse(&["--samples"]), Err(CliError::MissingValue("--samples".to_string())));
+
+        let invalid = |option: &str, value: &str| Err(CliError::InvalidValue { option: option.to_string(), value: value.to_string() });
+        assert_eq!(parse(&["--width", "wide"]), invalid("--width", "wide"));
+        assert_eq!(parse(&["--width", "0"]), invalid("--width", "0"));
+        assert_eq!(parse(&["--samples", "-4"]), invalid("--samples", "-4"));
+        assert_eq!(parse(&["--threads=0"]).unwrap().threads, Some(0));
+        assert_eq!(parse(&["--threads", "-1"]), invalid("--threads", "-1"));
+        assert_eq!(parse(&["--seed", "1.5"]), invalid("--seed", "1.5"));
+        assert_eq!(parse(&["--heatmap", "bvh"]), invalid("--heatmap", "bvh"));
+        assert_eq!(parse(&["--time", "0"]), invalid("--time", "0"));
+        assert_eq!(parse(&["--time", "-1"]), invalid("--time", "-1"));
+        assert_eq!(parse(&["--filter", "lanczos"]), invalid("--filter", "lanczos"));
+        assert_eq!(parse(&["--spectral", "rgb"]), invalid("--spectral", "rgb"));
+        assert_eq!(parse(&["--bracket", "-2,,2"]), invalid("--bracket", "-2,,2"));
+        assert_eq!(parse(&["--bracket", "inf"]), invalid("--bracket", "inf"));
+        assert_eq!(parse(&["--bracket", "0", "--output", "image.hdr"]), Err(CliError::BracketWithHdr));
+        assert_eq!(parse(&["--output", "image.jpg"]), Err(CliError::UnknownFormat(PathBuf::from("image.jpg"))));
+        assert_eq!(parse(&["--output", "image"]), Err(CliError::UnknownFormat(PathBuf::from("image"))));
+        assert_eq!(parse(&["--watch"]), Err(CliError::WatchWithoutSceneFile));
+        assert!(parse(&["--watch", "--scene", "room.toml"]).unwrap().watch);
+        if cfg!(feature = "serve") {
+            assert_eq!(parse(&["--serve", "127.0.0.1:8080"]).unwrap().serve, Some("127.0.0.1:8080".parse().unwrap()));
+            assert_eq!(parse(&["--serve", "8080"]), invalid("--serve", "8080"));
+        } else {
+            assert_eq!(parse(&["--serve", "127.0.0.1:8080"]), Err(CliError::ServeNotBuilt));
+        }
+        if cfg!(feature = "preview") {
+            assert!(parse(&["--preview"]).unwrap().preview);
+        } else {
+            assert_eq!(parse(&["--preview"]), Err(CliError::PreviewNotBuilt));
+        }
+
+        assert!(CliError::MissingValue("--seed".to_string()).to_string().contains("--seed"));
+        assert!(CliError::HelpRequested.to_string().contains("--threads"));
+    }
+
+    #[test]
+    fn test_bracket_path() {
+        let output = PathBuf::from("out/image.png");
+        assert_eq!(bracket_path(&output, -2.0), PathBuf::from("out/image_ev-2.png"));
+        assert_eq!(bracket_path(&output, 0.0), PathBuf::from("out/image_ev0.png"));
+        assert_eq!(bracket_path(&output, 2.0), PathBuf::from("out/image_ev+2.png"));
+        assert_eq!(bracket_path(&output, 0.5), PathBuf::from("out/image_ev+0.5.png"));
+        assert_eq!(bracket_path(&PathBuf::from("frame.ppm"), -1.5), PathBuf::from("frame_ev-1.5.ppm"));
+    }
+
+    #[test]
+    fn test_builtin_cameras_build() {
+        for scene in [BuiltinScene::Simple, BuiltinScene::TwoSpheres, BuiltinScene::Final, BuiltinScene::Bouncing] {
+            let camera = scene.camera().build().unwrap();
+            assert_eq!((camera.width(), camera.height()), (1200, 675));
+        }
+    }
+}
@@ -1,16 +1,21 @@
 use nalgebra::{Vector3, clamp};
 use std::convert::From;
 use std::io::{Result, Write};
-use std::ops::Mul;
+use std::ops::{Add, AddAssign, Div, Mul, Sub};
+use crate::Float;
 use crate::utils::{gamma_correct, rand, rand_range};
 
 #[derive(Copy, Clone, Debug, Default)]
-pub struct RGB(pub f64, pub f64, pub f64);
+pub struct RGB(pub Float, pub Float, pub Float);
 
 unsafe impl Sync for RGB {}
 unsafe impl Send for RGB {}
 
 impl RGB {
+    pub fn zeros() -> Self {
+        Self(0.0, 0.0, 0.0)
+    }
+
     pub fn white() -> Self {
         Self(1.0, 1.0, 1.0)
     }
@@ -19,35 +24,131 @@ impl RGB {
         Self(rand(), rand(), rand())
     }
 
-    pub fn rand_range(min: f64, max: f64) -> Self {
+    pub fn rand_range(min: Float, max: Float) -> Self {
         Self(rand_range(min, max), rand_range(min, max), rand_range(min, max))
     }
 
-    pub fn write(&self, samples_per_pixel: u32, writer: &mut dyn Write) -> Result<()> {
-        let (r, g, b) = (self.0, self.1, self.2);
-        let scale = 1.0 / samples_per_pixel as f64;
+    // Fully saturated color of a random hue, see from_hsv
+    pub fn random_hue(saturation: Float, value: Float) -> Self {
+        Self::from_hsv(rand_range(0.0, 360.0), saturation, value)
+    }
+
+    // Hue in degrees, wrapped into [0, 360), saturation and value in [0, 1]. The result is used
+    // as a linear color as it is, without any gamma.
+    pub fn from_hsv(hue: Float, saturation: Float, value: Float) -> Self {
+        let hue = hue.rem_euclid(360.0) / 60.0;
+        let chroma = value * saturation;
+        let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+        let (r, g, b) = match hue as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let m = value - chroma;
+        Self(r + m, g + m, b + m)
+    }
+
+    // (hue, saturation, value) with the conventions of from_hsv. Grays have hue and saturation 0.
+    pub fn to_hsv(&self) -> (Float, Float, Float) {
+        let max = self.0.max(self.1).max(self.2);
+        let min = self.0.min(self.1).min(self.2);
+        let chroma = max - min;
+        if chroma <= 0.0 {
+            return (0.0, 0.0, max);
+        }
+        let hue = if max == self.0 {
+            ((self.1 - self.2) / chroma).rem_euclid(6.0)
+        } else if max == self.1 {
+            (self.2 - self.0) / chroma + 2.0
+        } else {
+            (self.0 - self.1) / chroma + 4.0
+        };
+        // rem_euclid can round up to exactly 6 for tiny negative values
+        ((hue * 60.0) % 360.0, chroma / max, max)
+    }
+
+    // Viridis-like false color ramp from dark purple at t = 0 over blue and green to yellow at
+    // t = 1, t is clamped. Linear, so the ramp shows up as such once gamma corrected.
+    pub fn ramp(t: Float) -> Self {
+        const STOPS: [RGB; 5] = [
+            RGB(0.267, 0.005, 0.329),
+            RGB(0.229, 0.322, 0.546),
+            RGB(0.128, 0.567, 0.551),
+            RGB(0.369, 0.789, 0.383),
+            RGB(0.993, 0.906, 0.144),
+        ];
+        let position = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as Float;
+        let index = (position as usize).min(STOPS.len() - 2);
+        let display = STOPS[index].lerp(STOPS[index + 1], position - index as Float);
+        display * display
+    }
+
+    // self at t = 0, other at t = 1
+    pub fn lerp(&self, other: RGB, t: Float) -> Self {
+        *self + (other - *self) * t
+    }
+
+    // Moves the color the given fraction of the way to white
+    pub fn lighten(&self, amount: Float) -> Self {
+        self.lerp(Self::white(), amount)
+    }
+
+    // Moves the color the given fraction of the way to black
+    pub fn darken(&self, amount: Float) -> Self {
+        self.lerp(Self::zeros(), amount)
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.0.is_finite() && self.1.is_finite() && self.2.is_finite()
+    }
+
+    pub fn has_nan(&self) -> bool {
+        self.0.is_nan() || self.1.is_nan() || self.2.is_nan()
+    }
+
+    // Relative luminance of a linear color with Rec. 709 primaries
+    pub fn luminance(&self) -> Float {
+        0.2126 * self.0 + 0.7152 * self.1 + 0.0722 * self.2
+    }
 
-        let result_r = gamma_correct(r * scale);
-        let result_g = gamma_correct(g * scale);
-        let result_b = gamma_correct(b * scale);
+    // Gamma corrects an averaged color and quantizes it to 8 bits per channel
+    pub fn quantize(&self) -> [u8; 3] {
+        self.quantize_gamma(2.0)
+    }
+
+    // Like quantize with value^(1 / gamma) as the gamma correction, 2 is quantize's square root
+    pub fn quantize_gamma(&self, gamma: Float) -> [u8; 3] {
+        let encode = |linear: Float| if gamma == 2.0 { gamma_correct(linear) } else { linear.max(0.0).powf(1.0 / gamma) };
+        let result_r = encode(self.0);
+        let result_g = encode(self.1);
+        let result_b = encode(self.2);
 
         let rint = (256.0 * clamp(result_r, 0.0, 0.999)) as u8;
         let gint = (256.0 * clamp(result_g, 0.0, 0.999)) as u8;
         let bint = (256.0 * clamp(result_b, 0.0, 0.999)) as u8;
-        write!(writer, "{} {} {}\n", rint, gint, bint)
+        [rint, gint, bint]
+    }
+
+    // Writes an averaged color as a line of a P3 file
+    pub fn write(&self, writer: &mut dyn Write) -> Result<()> {
+        let [rint, gint, bint] = self.quantize();
+        writeln!(writer, "{} {} {}", rint, gint, bint)
     }
 }
 
-impl From<Vector3<f64>> for RGB {
-    fn from(point: Vector3<f64>) -> Self {
+impl From<Vector3<Float>> for RGB {
+    fn from(point: Vector3<Float>) -> Self {
         Self(point.x, point.y, point.z)
     }
 }
 
-impl Mul<f64> for RGB {
+impl Mul<Float> for RGB {
     type Output = RGB;
 
-    fn mul(self, rhs: f64) -> Self::Output {
+    fn mul(self, rhs: Float) -> Self::Output {
         Self(rhs * self.0, rhs * self.1, rhs * self.2)
     }
 }
@@ -58,4 +159,197 @@ impl Mul for RGB {
     fn mul(self, rhs: Self) -> Self::Output {
         Self(self.0 * rhs.0, self.1 * rhs.1, self.2 * rhs.2)
     }
-}
\ No newline at end of file
+}
+
+impl Add for RGB {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self(self.0 + rhs.0, self.1 + rhs.1, self.2 + rhs.2)
+    }
+}
+
+impl Mul<Float> for &RGB {
+    type Output = RGB;
+
+    fn mul(self, rhs: Float) -> Self::Output {
+        *self * rhs
+    }
+}
+
+impl Mul<&RGB> for &RGB {
+    type Output = RGB;
+
+    fn mul(self, rhs: &RGB) -> Self::Output {
+        *self * *rhs
+    }
+}
+
+impl Add<&RGB> for &RGB {
+    type Output = RGB;
+
+    fn add(self, rhs: &RGB) -> Self::Output {
+        *self + *rhs
+    }
+}
+
+impl AddAssign for RGB {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl AddAssign<&RGB> for RGB {
+    fn add_assign(&mut self, rhs: &RGB) {
+        *self = *self + *rhs;
+    }
+}
+
+impl Sub for RGB {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self(self.0 - rhs.0, self.1 - rhs.1, self.2 - rhs.2)
+    }
+}
+
+impl Sub<&RGB> for &RGB {
+    type Output = RGB;
+
+    fn sub(self, rhs: &RGB) -> Self::Output {
+        *self - *rhs
+    }
+}
+
+impl Div<Float> for RGB {
+    type Output = Self;
+
+    fn div(self, rhs: Float) -> Self::Output {
+        Self(self.0 / rhs, self.1 / rhs, self.2 / rhs)
+    }
+}
+
+impl Div<Float> for &RGB {
+    type Output = RGB;
+
+    fn div(self, rhs: Float) -> Self::Output {
+        *self / rhs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Float;
+    use crate::RGB;
+    use crate::utils::tolerance;
+
+    fn assert_rgb(actual: RGB, expected: RGB) {
+        assert_eq!((actual.0, actual.1, actual.2), (expected.0, expected.1, expected.2));
+    }
+
+    #[test]
+    #[allow(clippy::op_ref)] // The reference impls are what's being tested
+    fn test_operators() {
+        let a = RGB(1.0, 2.0, 4.0);
+        let b = RGB(0.5, 0.25, 2.0);
+        assert_rgb(a + b, RGB(1.5, 2.25, 6.0));
+        assert_rgb(&a + &b, a + b);
+        assert_rgb(a - b, RGB(0.5, 1.75, 2.0));
+        assert_rgb(&a - &b, a - b);
+        assert_rgb(a * b, RGB(0.5, 0.5, 8.0));
+        assert_rgb(&a * &b, a * b);
+        assert_rgb(a * 2.0, RGB(2.0, 4.0, 8.0));
+        assert_rgb(&a * 2.0, a * 2.0);
+        assert_rgb(a / 4.0, RGB(0.25, 0.5, 1.0));
+        assert_rgb(&a / 4.0, a / 4.0);
+
+        let mut sum = RGB::zeros();
+        sum += a;
+        sum += &b;
+        assert_rgb(sum, a + b);
+        assert_rgb(RGB::zeros(), RGB::default());
+    }
+
+    #[test]
+    fn test_hsv_round_trip() {
+        let near = |a: RGB, b: RGB, eps: Float| (a.0 - b.0).abs() < eps && (a.1 - b.1).abs() < eps && (a.2 - b.2).abs() < eps;
+        let close = |a: RGB, b: RGB| near(a, b, tolerance(16.0));
+        assert_rgb(RGB::from_hsv(0.0, 1.0, 1.0), RGB(1.0, 0.0, 0.0));
+        assert_rgb(RGB::from_hsv(120.0, 1.0, 1.0), RGB(0.0, 1.0, 0.0));
+        assert_rgb(RGB::from_hsv(240.0, 1.0, 0.5), RGB(0.0, 0.0, 0.5));
+        assert_rgb(RGB::from_hsv(30.0, 0.5, 1.0), RGB(1.0, 0.75, 0.5));
+        // Hue wraps around
+        assert!(close(RGB::from_hsv(360.0, 1.0, 1.0), RGB::from_hsv(0.0, 1.0, 1.0)));
+        assert!(close(RGB::from_hsv(-90.0, 0.7, 0.9), RGB::from_hsv(270.0, 0.7, 0.9)));
+        assert!(near(RGB::from_hsv(359.999_999_999, 1.0, 1.0), RGB(1.0, 0.0, 0.0), 1e-9));
+
+        for step in 0..720 {
+            let hue = step as Float * 0.5;
+            for (saturation, value) in [(1.0, 1.0), (0.3, 0.8), (0.05, 0.2), (1.0, 0.01)] {
+                let color = RGB::from_hsv(hue, saturation, value);
+                let (h, s, v) = color.to_hsv();
+                assert!((0.0..360.0).contains(&h), "{}", h);
+                assert!(close(RGB::from_hsv(h, s, v), color), "{} {} {}", hue, saturation, value);
+                assert!((s - saturation).abs() < tolerance(16.0) && (v - value).abs() < tolerance(16.0));
+                // The hue itself comes back unless it's right at the wrap
+                let dh = (h - hue).abs();
+                assert!(dh < tolerance(16.0) * 360.0 || (360.0 - dh) < tolerance(16.0) * 360.0, "{} became {}", hue, h);
+            }
+        }
+
+        // Grays have no hue
+        for value in [0.0, 0.18, 1.0] {
+            assert_eq!(RGB(value, value, value).to_hsv(), (0.0, 0.0, value));
+            assert!(close(RGB::from_hsv(123.0, 0.0, value), RGB(value, value, value)));
+        }
+    }
+
+    #[test]
+    fn test_lerp_lighten_darken() {
+        let a = RGB(0.2, 0.4, 0.8);
+        assert_rgb(a.lerp(RGB(1.0, 0.0, 0.0), 0.0), a);
+        assert_rgb(a.lerp(RGB(1.0, 0.0, 0.0), 1.0), RGB(1.0, 0.0, 0.0));
+        assert_rgb(a.lerp(RGB(0.4, 0.0, 0.0), 0.5), RGB(0.2 + 0.1, 0.2, 0.4));
+        assert_rgb(a.lighten(1.0), RGB::white());
+        assert_rgb(a.darken(1.0), RGB::zeros());
+        assert_rgb(a.darken(0.5), a * 0.5);
+        assert_eq!(a.lighten(0.25).to_hsv().0, a.to_hsv().0);
+
+        for _ in 0..100 {
+            let (h, s, v) = RGB::random_hue(0.8, 0.6).to_hsv();
+            assert!((0.0..360.0).contains(&h));
+            assert!((s - 0.8).abs() < 1e-9 && (v - 0.6).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_finite_checks() {
+        assert!(RGB(0.0, 1e30, -2.0).is_finite());
+        assert!(!RGB(0.0, Float::INFINITY, 0.0).is_finite());
+        assert!(!RGB(Float::NAN, 0.0, 0.0).is_finite());
+        assert!(RGB(0.0, 0.0, Float::NAN).has_nan());
+        assert!(!RGB(Float::NEG_INFINITY, 0.0, 0.0).has_nan());
+    }
+
+    #[test]
+    fn test_luminance_and_write() {
+        assert!((RGB::white().luminance() - 1.0).abs() < 1e-12);
+        assert_eq!(RGB(0.0, 1.0, 0.0).luminance(), 0.7152);
+        assert_eq!(RGB::zeros().luminance(), 0.0);
+
+        // Averaged colors are written as they are, no sample count needed
+        let mut line = vec![];
+        RGB(1.0, 0.25, 0.0).write(&mut line).unwrap();
+        assert_eq!(line, b"255 128 0\n");
+    }
+
+    #[test]
+    fn test_ramp() {
+        assert_rgb(RGB::ramp(-1.0), RGB::ramp(0.0));
+        assert_rgb(RGB::ramp(2.0), RGB::ramp(1.0));
+        assert_eq!(RGB::ramp(1.0).quantize(), [254, 231, 36]);
+        // Gets brighter all the way
+        let luminance: Vec<Float> = (0..=20).map(|step| RGB::ramp(step as Float / 20.0).luminance()).collect();
+        assert!(luminance.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", luminance);
+    }
+}
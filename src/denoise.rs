@@ -0,0 +1,196 @@
+use crate::parallel::prelude::*;
+use crate::Float;
+use crate::image::{DimensionMismatch, FloatImage, Image};
+use crate::RGB;
+
+const TILE_SIZE: usize = 16;
+
+#[derive(Copy, Clone, Debug)]
+pub struct DenoiseParams {
+    pub radius: usize, // Filter footprint is (2 * radius + 1)^2 pixels
+    pub color_sigma: Float, // Tolerated difference of the noisy colors
+    pub normal_sigma: Float, // Tolerated distance between unit normals
+    pub depth_sigma: Float, // Tolerated depth difference relative to the nearer depth
+    pub albedo_sigma: Float, // Tolerated albedo difference, keeps texture and material edges
+}
+
+impl Default for DenoiseParams {
+    fn default() -> Self {
+        Self { radius: 4, color_sigma: 0.75, normal_sigma: 0.2, depth_sigma: 0.05, albedo_sigma: 0.1 }
+    }
+}
+
+// Joint bilateral filter: neighbours are weighted by distance and by how similar they are
+// in the noisy color and in the noise-free guide buffers, so only edges visible in the
+// guides stop the smoothing. Any guide can be left out.
+pub fn denoise(
+    beauty: &FloatImage,
+    normal: Option<&FloatImage>,
+    depth: Option<&FloatImage>,
+    albedo: Option<&FloatImage>,
+    params: &DenoiseParams
+) -> Result<FloatImage, DimensionMismatch> {
+    let (width, height) = (beauty.width(), beauty.height());
+    for guide in [normal, depth, albedo].into_iter().flatten() {
+        if (guide.width(), guide.height()) != (width, height) {
+            return Err(DimensionMismatch { expected: (width, height), found: (guide.width(), guide.height()) });
+        }
+    }
+
+    let tiles: Vec<(usize, usize)> = (0..height).step_by(TILE_SIZE)
+        .flat_map(|y| (0..width).step_by(TILE_SIZE).map(move |x| (x, y)))
+        .collect();
+    let filtered: Vec<Vec<(usize, usize, RGB)>> = tiles.into_par_iter().map(|(x0, y0)| {
+        let mut tile = vec![];
+        for y in y0..(y0 + TILE_SIZE).min(height) {
+            for x in x0..(x0 + TILE_SIZE).min(width) {
+                tile.push((x, y, filter_pixel(beauty, normal, depth, albedo, params, x, y)));
+            }
+        }
+        tile
+    }).collect();
+
+    let mut result = FloatImage::new(width, height);
+    for (x, y, px) in filtered.into_iter().flatten() {
+        result[(y, x)] = px;
+    }
+    Ok(result)
+}
+
+fn filter_pixel(
+    beauty: &FloatImage,
+    normal: Option<&FloatImage>,
+    depth: Option<&FloatImage>,
+    albedo: Option<&FloatImage>,
+    params: &DenoiseParams,
+    x: usize,
+    y: usize
+) -> RGB {
+    let radius = params.radius as isize;
+    let spatial_sigma = (params.radius as Float / 2.0).max(0.5);
+    let center = beauty.pixel(x, y);
+    if !center.is_finite() {
+        return center;
+    }
+
+    let mut sum = RGB::default();
+    let mut total_weight = 0.0;
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let (nx, ny) = (x as isize + dx, y as isize + dy);
+            if nx < 0 || ny < 0 || nx >= beauty.width() as isize || ny >= beauty.height() as isize {
+                continue;
+            }
+            let (nx, ny) = (nx as usize, ny as usize);
+            let px = beauty.pixel(nx, ny);
+            if !px.is_finite() {
+                continue;
+            }
+
+            let mut exponent = (dx * dx + dy * dy) as Float / (2.0 * spatial_sigma * spatial_sigma);
+            exponent += distance2(center, px) / (2.0 * params.color_sigma * params.color_sigma);
+            if let Some(normal) = normal {
+                exponent += distance2(normal.pixel(x, y), normal.pixel(nx, ny))
+                    / (2.0 * params.normal_sigma * params.normal_sigma);
+            }
+            if let Some(depth) = depth {
+                let (a, b) = (depth.pixel(x, y).0.min(Float::MAX), depth.pixel(nx, ny).0.min(Float::MAX));
+                let relative = if a == b { 0.0 } else { (a - b).abs() / a.min(b).max(1e-9) };
+                exponent += relative * relative / (2.0 * params.depth_sigma * params.depth_sigma);
+            }
+            if let Some(albedo) = albedo {
+                exponent += distance2(albedo.pixel(x, y), albedo.pixel(nx, ny))
+                    / (2.0 * params.albedo_sigma * params.albedo_sigma);
+            }
+
+            let weight = (-exponent).exp();
+            sum += px * weight;
+            total_weight += weight;
+        }
+    }
+    // The center pixel always has weight 1
+    sum * (1.0 / total_weight)
+}
+
+fn distance2(a: RGB, b: RGB) -> Float {
+    (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2) + (a.2 - b.2).powi(2)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::denoise::{denoise, DenoiseParams};
+    use crate::Float;
+    use crate::image::FloatImage;
+    use crate::RGB;
+    use crate::utils::{rand, seed_rng};
+
+    const SIZE: usize = 32;
+    const EDGE: usize = 16;
+
+    // Two flat regions with a vertical edge between columns EDGE - 1 and EDGE, with the edge
+    // present in the guides and noise only in the beauty buffer
+    fn noisy_edge() -> (FloatImage, FloatImage, FloatImage, FloatImage) {
+        seed_rng(3);
+        let mut beauty = FloatImage::new(SIZE, SIZE);
+        let mut normal = FloatImage::new(SIZE, SIZE);
+        let mut depth = FloatImage::new(SIZE, SIZE);
+        let mut albedo = FloatImage::new(SIZE, SIZE);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let left = x < EDGE;
+                let base = if left { 0.2 } else { 0.8 };
+                let v = base + (rand() - 0.5) * 0.4;
+                beauty[(y, x)] = RGB(v, v, v);
+                normal[(y, x)] = if left { RGB(1.0, 0.0, 0.0) } else { RGB(0.0, 1.0, 0.0) };
+                depth[(y, x)] = RGB(2.0, 2.0, 2.0);
+                albedo[(y, x)] = if left { RGB(0.3, 0.3, 0.3) } else { RGB(0.9, 0.9, 0.9) };
+            }
+        }
+        (beauty, normal, depth, albedo)
+    }
+
+    fn variance(image: &FloatImage, columns: std::ops::Range<usize>) -> Float {
+        let values: Vec<Float> = (0..SIZE).flat_map(|y| columns.clone().map(move |x| (x, y)))
+            .map(|(x, y)| image[(y, x)].0)
+            .collect();
+        let mean = values.iter().sum::<Float>() / values.len() as Float;
+        values.iter().map(|v| (v - mean).powi(2)).sum::<Float>() / values.len() as Float
+    }
+
+    #[test]
+    fn test_smooths_flat_region_and_keeps_edge() {
+        let (beauty, normal, depth, albedo) = noisy_edge();
+        let params = DenoiseParams::default();
+        let result = denoise(&beauty, Some(&normal), Some(&depth), Some(&albedo), &params).unwrap();
+
+        // Away from the image border, inside the left region
+        let before = variance(&beauty, 4..12);
+        let after = variance(&result, 4..12);
+        assert!(after * 10.0 < before, "before: {}, after: {}", before, after);
+
+        // The 0.5 crossing of every row stays at the edge
+        for y in 0..SIZE {
+            let crossing = (0..SIZE).find(|&x| result[(y, x)].0 > 0.5).unwrap();
+            assert!(crossing.abs_diff(EDGE) <= 1, "row {} crosses at {}", y, crossing);
+            assert!(result[(y, EDGE - 1)].0 < 0.35 && result[(y, EDGE)].0 > 0.65);
+        }
+    }
+
+    #[test]
+    fn test_without_guides_blurs_edge() {
+        let (beauty, _, _, _) = noisy_edge();
+        let params = DenoiseParams { color_sigma: 10.0, ..Default::default() };
+        let result = denoise(&beauty, None, None, None, &params).unwrap();
+        let step = result[(SIZE / 2, EDGE)].0 - result[(SIZE / 2, EDGE - 1)].0;
+        assert!(step < 0.3, "{}", step);
+    }
+
+    #[test]
+    fn test_guide_dimension_mismatch() {
+        let beauty = FloatImage::new(8, 8);
+        let normal = FloatImage::new(8, 4);
+        let err = denoise(&beauty, Some(&normal), None, None, &DenoiseParams::default()).unwrap_err();
+        assert_eq!(err.expected, (8, 8));
+        assert_eq!(err.found, (8, 4));
+    }
+}
@@ -0,0 +1,95 @@
+use na::Vector2;
+use crate::Float;
+
+// Brown-Conrady radial lens distortion. Points are normalized image coordinates, the offset from
+// the optical axis on the image plane at distance 1. The forward model moves an ideal pinhole
+// point p to p * (1 + k1 * r^2 + k2 * r^4). Positive k1 pushes the edges outwards (pincushion),
+// negative pulls them in (barrel).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LensDistortion {
+    pub k1: Float,
+    pub k2: Float,
+}
+
+// Newton steps are stopped once the radius changes by less than this
+const TOLERANCE: Float = 1e-15;
+const MAX_ITERATIONS: usize = 50;
+
+impl LensDistortion {
+    pub fn new(k1: Float, k2: Float) -> Self {
+        Self { k1, k2 }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.k1 == 0.0 && self.k2 == 0.0
+    }
+
+    fn scale(&self, r2: Float) -> Float {
+        1.0 + self.k1 * r2 + self.k2 * r2 * r2
+    }
+
+    pub fn distort(&self, p: Vector2<Float>) -> Vector2<Float> {
+        p * self.scale(p.norm_squared())
+    }
+
+    // Ideal point that distort maps to p. The model only changes the radius, so this solves
+    // r * (1 + k1 * r^2 + k2 * r^4) = |p| for r with Newton's method, starting at |p|.
+    pub fn undistort(&self, p: Vector2<Float>) -> Vector2<Float> {
+        let target = p.norm();
+        if target == 0.0 || self.is_identity() {
+            return p;
+        }
+
+        let mut r = target;
+        for _ in 0..MAX_ITERATIONS {
+            let r2 = r * r;
+            let f = r * self.scale(r2) - target;
+            let df = 1.0 + 3.0 * self.k1 * r2 + 5.0 * self.k2 * r2 * r2;
+            // Past the fold of the model there is no inverse, keep the best guess
+            if df <= 0.0 {
+                break;
+            }
+            let step = f / df;
+            r -= step;
+            if step.abs() < TOLERANCE {
+                break;
+            }
+        }
+        p * (r / target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na::vector;
+    use crate::distortion::LensDistortion;
+    use crate::Float;
+    use crate::utils::tolerance;
+
+    #[test]
+    fn test_round_trip() {
+        // Covers the image of a 60 degree, 16:9 camera
+        let half_height = (30.0 as Float).to_radians().tan();
+        let half_width = half_height * 16.0 / 9.0;
+        for (k1, k2) in [(0.1, 0.0), (-0.1, 0.0), (0.2, 0.05), (-0.15, 0.02), (0.05, -0.01)] {
+            let distortion = LensDistortion::new(k1, k2);
+            for i in 0..=20 {
+                for j in 0..=20 {
+                    let p = vector![(j as Float / 10.0 - 1.0) * half_width, (i as Float / 10.0 - 1.0) * half_height];
+                    let round_trip = distortion.distort(distortion.undistort(p));
+                    assert!((round_trip - p).norm() < tolerance(64.0), "k1 {}, k2 {}, {:?} -> {:?}", k1, k2, p, round_trip);
+                    let round_trip = distortion.undistort(distortion.distort(p));
+                    assert!((round_trip - p).norm() < tolerance(64.0), "k1 {}, k2 {}, {:?} -> {:?}", k1, k2, p, round_trip);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_identity() {
+        let distortion = LensDistortion::default();
+        let p = vector![0.3, -0.7];
+        assert_eq!(distortion.distort(p), p);
+        assert_eq!(distortion.undistort(p), p);
+    }
+}
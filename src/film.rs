@@ -0,0 +1,180 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::Float;
+use crate::image::PPM;
+use crate::RGB;
+
+// Image plane that many threads can add to at once. Samples go to the pixel they were taken in
+// and are averaged by their weights. Splats land anywhere on the image, e.g. where a light path
+// connects to the camera, and are spread over the four nearest pixel centers. They are kept
+// apart from the samples and scaled by the number of light paths when the film is developed.
+// Positions are in pixels from the top left corner of the image, pixel (i, j) covers
+// j..j + 1 across and i..i + 1 down.
+pub struct Film {
+    width: usize,
+    height: usize,
+    samples: Vec<[AtomicFloat; 4]>, // Weighted color and the total weight of every pixel
+    splats: Vec<[AtomicFloat; 3]>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            samples: (0..width * height).map(|_| Default::default()).collect(),
+            splats: (0..width * height).map(|_| Default::default()).collect(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Adds color with the given weight to the pixel the position lies in, positions outside of
+    // the image are dropped
+    pub fn add_sample(&self, x: Float, y: Float, color: RGB, weight: Float) {
+        let Some(idx) = self.index(x.floor(), y.floor()) else {
+            return;
+        };
+        let pixel = &self.samples[idx];
+        pixel[0].add(color.0 * weight);
+        pixel[1].add(color.1 * weight);
+        pixel[2].add(color.2 * weight);
+        pixel[3].add(weight);
+    }
+
+    // Adds color to the splats of the four pixels whose centers surround the position, each with
+    // the bilinear weight of its center. The share of pixels outside of the image is dropped.
+    pub fn add_splat(&self, x: Float, y: Float, color: RGB) {
+        let (x, y) = (x - 0.5, y - 0.5);
+        let (left, top) = (x.floor(), y.floor());
+        let (tx, ty) = (x - left, y - top);
+        for (dx, dy, weight) in [(0.0, 0.0, (1.0 - tx) * (1.0 - ty)), (1.0, 0.0, tx * (1.0 - ty)), (0.0, 1.0, (1.0 - tx) * ty), (1.0, 1.0, tx * ty)] {
+            if let (Some(idx), true) = (self.index(left + dx, top + dy), weight > 0.0) {
+                let splat = &self.splats[idx];
+                splat[0].add(color.0 * weight);
+                splat[1].add(color.1 * weight);
+                splat[2].add(color.2 * weight);
+            }
+        }
+    }
+
+    // Weighted average of the samples of every pixel plus its splats over the number of light
+    // paths they came from. Pixels without samples only have their splats, no paths means no
+    // splats. The film keeps its contents, e.g. to develop it again after more paths.
+    pub fn develop(&self, splat_paths: u64) -> PPM {
+        let splat_scale = if splat_paths == 0 { 0.0 } else { 1.0 / splat_paths as f64 };
+        let mut image = PPM::new(self.width, self.height);
+        for (idx, (sample, splat)) in self.samples.iter().zip(&self.splats).enumerate() {
+            let weight = sample[3].get();
+            let scale = if weight != 0.0 { 1.0 / weight } else { 0.0 };
+            let channel = |c: usize| (sample[c].get() * scale + splat[c].get() * splat_scale) as Float;
+            image[(idx / self.width, idx % self.width)] = RGB(channel(0), channel(1), channel(2));
+        }
+        image
+    }
+
+    // Index of the pixel in column x and row y, None outside of the image
+    fn index(&self, x: Float, y: Float) -> Option<usize> {
+        let inside = x >= 0.0 && y >= 0.0 && x < self.width as Float && y < self.height as Float;
+        inside.then(|| y as usize * self.width + x as usize)
+    }
+}
+
+// An f64 that threads add to with compare and swap, independent of the Float type so
+// small contributions to large sums keep their precision
+#[derive(Default)]
+struct AtomicFloat(AtomicU64);
+
+impl AtomicFloat {
+    fn add(&self, value: Float) {
+        #[allow(clippy::unnecessary_cast)] // Float is f64 already without the f32 feature
+        let update = |bits: u64| Some((f64::from_bits(bits) + value as f64).to_bits());
+        self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, update).unwrap();
+    }
+
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::film::Film;
+    use crate::Float;
+    use crate::image::Image;
+    use crate::parallel::prelude::*;
+    use crate::RGB;
+    use crate::utils::tolerance;
+
+    fn assert_close(actual: RGB, expected: RGB) {
+        let close = |a: Float, b: Float| (a - b).abs() < tolerance(64.0) * b.abs().max(1.0);
+        assert!(close(actual.0, expected.0) && close(actual.1, expected.1) && close(actual.2, expected.2), "{:?} {:?}", actual, expected);
+    }
+
+    #[test]
+    fn test_samples() {
+        let film = Film::new(3, 2);
+        film.add_sample(0.2, 0.9, RGB(1.0, 2.0, 3.0), 1.0);
+        film.add_sample(0.7, 0.1, RGB(3.0, 2.0, 1.0), 3.0);
+        film.add_sample(2.99, 1.5, RGB(0.5, 0.5, 0.5), 0.25);
+        // Outside of the image
+        film.add_sample(3.0, 0.5, RGB::white(), 1.0);
+        film.add_sample(-0.1, 0.5, RGB::white(), 1.0);
+
+        let image = film.develop(0);
+        assert_close(image[(0, 0)], RGB(2.5, 2.0, 1.5));
+        assert_close(image[(1, 2)], RGB(0.5, 0.5, 0.5));
+        assert_close(image[(0, 1)], RGB::zeros());
+        assert_close(image[(1, 0)], RGB::zeros());
+    }
+
+    #[test]
+    fn test_splats() {
+        let film = Film::new(4, 3);
+        // A quarter pixel right of the centers in column 1, halfway between rows 0 and 1
+        film.add_splat(1.75, 1.0, RGB(8.0, 4.0, 0.0));
+        let image = film.develop(2);
+        // Four scaled by the paths, 3/4 and 1/4 across, 1/2 and 1/2 down
+        assert_close(image[(0, 1)], RGB(1.5, 0.75, 0.0));
+        assert_close(image[(0, 2)], RGB(0.5, 0.25, 0.0));
+        assert_close(image[(1, 1)], RGB(1.5, 0.75, 0.0));
+        assert_close(image[(1, 2)], RGB(0.5, 0.25, 0.0));
+        let total = image.pixels().iter().fold(RGB::zeros(), |sum, px| sum + *px);
+        assert_close(total, RGB(4.0, 2.0, 0.0));
+
+        // On a pixel center it all goes to that pixel
+        let film = Film::new(4, 3);
+        film.add_splat(3.5, 0.5, RGB::white());
+        film.add_sample(3.2, 0.7, RGB(0.5, 0.0, 0.0), 2.0);
+        let image = film.develop(1);
+        assert_close(image[(0, 3)], RGB(1.5, 1.0, 1.0));
+        assert_eq!(image.pixels().iter().filter(|px| px.0 != 0.0).count(), 1);
+        assert_close(film.develop(0)[(0, 3)], RGB(0.5, 0.0, 0.0));
+
+        // Past the corner only the share on the image stays
+        let film = Film::new(4, 3);
+        film.add_splat(0.25, 0.25, RGB::white());
+        assert_close(film.develop(1)[(0, 0)], RGB(0.5625, 0.5625, 0.5625));
+    }
+
+    #[test]
+    fn test_concurrent_splats() {
+        let film = Film::new(8, 8);
+        (0..10_000).into_par_iter().for_each(|k| {
+            let (x, y) = ((k % 7) as Float + 1.0 + 0.25, (k % 5) as Float + 1.0 + 0.5);
+            film.add_splat(x, y, RGB(1.0, 0.5, 0.25));
+            film.add_sample(x, y, RGB::white(), 1.0);
+        });
+        let splats = film.develop(10_000);
+        let total = splats.pixels().iter().fold(RGB::zeros(), |sum, px| sum + *px);
+        // Every pixel some sample fell into develops to white, plus the splats
+        let sampled = film.develop(0).pixels().iter().filter(|px| px.0 == 1.0).count();
+        assert_eq!(sampled, 7 * 5);
+        assert_close(total, RGB(1.0, 1.0, 1.0) * sampled as Float + RGB(1.0, 0.5, 0.25));
+    }
+}
@@ -0,0 +1,219 @@
+use crate::Float;
+use crate::utils::rand;
+
+// Largest filter radius in pixels, keeps the splats of every pixel small
+pub const MAX_FILTER_RADIUS: Float = 4.0;
+
+// Pixel reconstruction filter. The box averages the samples inside each pixel. Wider filters
+// spread the samples of a pixel over a square of radius pixels around its center, and every
+// sample counts toward all the pixels whose center it is closer to than radius, weighted by the
+// kernel. Pixels are the weighted sums over the total weight.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Filter {
+    #[default]
+    Box,
+    // Weight falling off linearly to zero at radius
+    Tent { radius: Float },
+    // exp(-alpha x²) less its value at radius, larger alpha is narrower
+    Gaussian { radius: Float, alpha: Float },
+    // Mitchell-Netravali cubic, sharper than the gaussian at the cost of slightly negative lobes
+    // around edges. b = c = 1/3 is the usual choice.
+    Mitchell { radius: Float, b: Float, c: Float },
+}
+
+impl Filter {
+    // The kernels with their common sizes
+    pub fn tent() -> Filter {
+        Filter::Tent { radius: 1.0 }
+    }
+
+    pub fn gaussian() -> Filter {
+        Filter::Gaussian { radius: 1.5, alpha: 2.0 }
+    }
+
+    pub fn mitchell() -> Filter {
+        Filter::Mitchell { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 }
+    }
+
+    // Kernels by name, with their common sizes
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "box" => Some(Filter::Box),
+            "tent" => Some(Filter::tent()),
+            "gaussian" => Some(Filter::gaussian()),
+            "mitchell" => Some(Filter::mitchell()),
+            _ => None,
+        }
+    }
+
+    pub fn radius(&self) -> Float {
+        match *self {
+            Filter::Box => 0.5,
+            Filter::Tent { radius } | Filter::Gaussian { radius, .. } | Filter::Mitchell { radius, .. } => radius,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let radius = self.radius();
+        let parameters_valid = match *self {
+            Filter::Gaussian { alpha, .. } => alpha.is_finite() && alpha > 0.0,
+            Filter::Mitchell { b, c, .. } => b.is_finite() && c.is_finite(),
+            _ => true,
+        };
+        radius > 0.0 && radius <= MAX_FILTER_RADIUS && parameters_valid
+    }
+
+    // Position of a new sample relative to its pixel, whose corners are (0, 0) and (1, 1)
+    pub fn sample_position(&self) -> (Float, Float) {
+        match self {
+            Filter::Box => (rand(), rand()),
+            _ => {
+                let radius = self.radius();
+                (0.5 + radius * (2.0 * rand() - 1.0), 0.5 + radius * (2.0 * rand() - 1.0))
+            },
+        }
+    }
+
+    // Pixels a sample reaches on every side of its own one
+    pub fn reach(&self) -> usize {
+        ((2.0 * self.radius()).ceil() as usize).saturating_sub(1)
+    }
+
+    // Weight of a sample dx, dy pixels from the center of a pixel
+    pub fn weight(&self, dx: Float, dy: Float) -> Float {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    // Weights of a sample at x, y of its pixel for the square of pixels within reach around it,
+    // row by row from the top left
+    pub fn splat_weights(&self, x: Float, y: Float) -> impl Iterator<Item = Float> + '_ {
+        let reach = self.reach() as isize;
+        (-reach..=reach).flat_map(move |di| {
+            (-reach..=reach).map(move |dj| self.weight(dj as Float + 0.5 - x, di as Float + 0.5 - y))
+        })
+    }
+
+    fn weight_1d(&self, x: Float) -> Float {
+        let radius = self.radius();
+        let x = x.abs();
+        if x >= radius {
+            return 0.0;
+        }
+        match *self {
+            Filter::Box => 1.0,
+            Filter::Tent { .. } => radius - x,
+            Filter::Gaussian { alpha, .. } => Float::exp(-alpha * x * x) - Float::exp(-alpha * radius * radius),
+            Filter::Mitchell { b, c, .. } => mitchell(2.0 * x / radius, b, c),
+        }
+    }
+}
+
+// The Mitchell-Netravali cubic on 0..2
+fn mitchell(x: Float, b: Float, c: Float) -> Float {
+    let (x2, x3) = (x * x, x * x * x);
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b)) / 6.0
+    } else {
+        ((-b - 6.0 * c) * x3 + (6.0 * b + 30.0 * c) * x2 + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::filter::Filter;
+    use crate::Float;
+    use crate::utils::tolerance;
+
+    const FILTERS: [Filter; 4] = [
+        Filter::Box,
+        Filter::Tent { radius: 1.0 },
+        Filter::Gaussian { radius: 1.5, alpha: 2.0 },
+        Filter::Mitchell { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 },
+    ];
+
+    // Filters an image that is one in the middle pixel and zero everywhere else, from samples on a
+    // fine grid over every pixel, and resolves it like the renderer does
+    fn filtered_delta(filter: Filter) -> Vec<Vec<Float>> {
+        let (size, steps) = (9, 16);
+        let reach = filter.reach() as isize;
+        let side = 2 * reach + 1;
+        let mut sums = vec![vec![0.0; size]; size];
+        let mut weights = vec![vec![0.0; size]; size];
+        for i in 0..size {
+            for j in 0..size {
+                for (si, sj) in (0..steps * steps).map(|k| (k / steps, k % steps)) {
+                    // Grid over the square the filter draws samples from
+                    let spread = 2.0 * filter.radius();
+                    let x = 0.5 + spread * ((sj as Float + 0.5) / steps as Float - 0.5);
+                    let y = 0.5 + spread * ((si as Float + 0.5) / steps as Float - 0.5);
+                    // The sample is only in the middle pixel if it lies inside of it
+                    let (px, py) = (j as Float + x, i as Float + y);
+                    let inside = px.floor() as usize == size / 2 && py.floor() as usize == size / 2;
+                    let value = if inside { 1.0 } else { 0.0 };
+                    for (cell, weight) in filter.splat_weights(x, y).enumerate() {
+                        let ti = i as isize + cell as isize / side - reach;
+                        let tj = j as isize + cell as isize % side - reach;
+                        if (0..size as isize).contains(&ti) && (0..size as isize).contains(&tj) {
+                            sums[ti as usize][tj as usize] += weight * value;
+                            weights[ti as usize][tj as usize] += weight;
+                        }
+                    }
+                }
+            }
+        }
+        (0..size).map(|i| (0..size).map(|j| sums[i][j] / weights[i][j]).collect()).collect()
+    }
+
+    #[test]
+    fn test_weights() {
+        for filter in FILTERS {
+            assert!(filter.is_valid());
+            let radius = filter.radius();
+            assert!(filter.weight(0.0, 0.0) > 0.0);
+            assert_eq!(filter.weight(radius, 0.0), 0.0);
+            assert_eq!(filter.weight(0.0, -radius - 0.1), 0.0);
+            assert_eq!(filter.weight(0.3, -0.2), filter.weight(-0.3, 0.2));
+            // Every pixel the sample can reach is in the splat
+            let reach = filter.reach();
+            assert_eq!(filter.splat_weights(0.5, 0.5).count(), (2 * reach + 1) * (2 * reach + 1));
+            assert!((reach as Float) < 2.0 * radius && reach as Float + 1.0 >= 2.0 * radius);
+        }
+        assert_eq!(FILTERS.map(|filter| filter.reach()), [0, 1, 2, 3]);
+        // Only the mitchell filter goes negative
+        assert!(Filter::mitchell().weight(1.5, 0.0) < 0.0);
+        assert!((0..40).all(|k| Filter::gaussian().weight(k as Float / 20.0, 0.0) >= 0.0));
+
+        assert!(!Filter::Tent { radius: 0.0 }.is_valid());
+        assert!(!Filter::Tent { radius: 10.0 }.is_valid());
+        assert!(!Filter::Gaussian { radius: 1.0, alpha: -1.0 }.is_valid());
+        assert!(!Filter::Mitchell { radius: 2.0, b: Float::NAN, c: 0.0 }.is_valid());
+        assert_eq!(Filter::from_name("gaussian"), Some(Filter::gaussian()));
+        assert_eq!(Filter::from_name("lanczos"), None);
+    }
+
+    #[test]
+    fn test_delta_image() {
+        for filter in FILTERS {
+            let image = filtered_delta(filter);
+            // The total stays the same, the filters only move it around
+            let total: Float = image.iter().flatten().sum();
+            assert!((total - 1.0).abs() < 0.02, "{:?} {}", filter, total);
+            // Symmetric around the middle
+            for i in 0..9 {
+                for j in 0..9 {
+                    assert!((image[i][j] - image[8 - i][j]).abs() < tolerance(16.0) && (image[i][j] - image[i][8 - j]).abs() < tolerance(16.0));
+                }
+            }
+        }
+
+        // The box keeps the pixel to itself, the others blur it into the neighbors
+        let [boxed, tent, gaussian, mitchell] = FILTERS.map(filtered_delta);
+        assert_eq!(boxed[4][4], 1.0);
+        assert_eq!(boxed[4][3], 0.0);
+        assert!(tent[4][4] < 1.0 && tent[4][3] > 0.0 && tent[4][2] == 0.0);
+        assert!(gaussian[4][4] < 1.0 && gaussian[4][3] > 0.0 && gaussian[4][2] == 0.0);
+        // The mitchell filter keeps more of the peak and rings below zero
+        assert!(mitchell[4][4] > gaussian[4][4]);
+        assert!(mitchell[4][2] < 0.0);
+    }
+}
@@ -1,18 +1,65 @@
+use crate::Float;
+use crate::png::{self, ColorType};
 use crate::RGB;
-use std::io::{Cursor, Result, Write};
+use crate::tonemap::{ToneMap, WhiteBalance};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{BufWriter, Result, Write};
 use std::ops::{Index, IndexMut};
 
+pub mod compare;
+mod load;
+mod resize;
+
+pub use load::{LoadError, WRITER_GAMMA};
+pub use resize::{resize, ResizeFilter};
+
+// Two images or buffers that had to be the same size were not, sizes are (width, height)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DimensionMismatch {
+    pub expected: (usize, usize),
+    pub found: (usize, usize),
+}
+
+impl Display for DimensionMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expected a {}x{} image but got {}x{}",
+            self.expected.0, self.expected.1, self.found.0, self.found.1
+        )
+    }
+}
+
+impl Error for DimensionMismatch {}
+
 pub trait Image {
     fn width(&self) -> usize;
     fn height(&self) -> usize;
     fn save(&self, writer: &mut dyn Write) -> Result<()>;
+
+    // Stored value of the pixel in column x and row y
+    fn pixel(&self, x: usize, y: usize) -> RGB {
+        self.pixels()[y * self.width() + x]
+    }
+
+    // All stored pixels, row by row from the top
+    fn pixels(&self) -> &[RGB];
+
+    // Display-ready bytes with the same conversion as the writers and straight alpha
+    fn to_rgba8(&self) -> Vec<u8>;
 }
 
 pub struct PPM {
     width: usize,
     height: usize,
-    samples_per_pixel: u32,
+    white_balance: WhiteBalance,
+    exposure_ev: Float,
+    tonemap: ToneMap,
+    gamma: Float,
     data: Vec<RGB>,
+    // Coverage of every pixel, the image is opaque if None
+    alpha: Option<Vec<Float>>,
 }
 
 impl Index<(usize, usize)> for PPM {
@@ -31,15 +78,296 @@ impl IndexMut<(usize, usize)> for PPM {
     }
 }
 
+// Averaged linear radiance per pixel, kept in full float precision for HDR output
+#[derive(Clone, Debug)]
+pub struct FloatImage {
+    width: usize,
+    height: usize,
+    data: Vec<RGB>,
+}
+
+impl Index<(usize, usize)> for FloatImage {
+    type Output = RGB;
+
+    fn index(&self, idx: (usize, usize)) -> &Self::Output {
+        let (y, x) = idx;
+        &self.data[y * self.width + x]
+    }
+}
+
+impl IndexMut<(usize, usize)> for FloatImage {
+    fn index_mut(&mut self, idx: (usize, usize)) -> &mut Self::Output {
+        let (y, x) = idx;
+        &mut self.data[y * self.width + x]
+    }
+}
+
+impl FloatImage {
+    pub fn new(w: usize, h: usize) -> Self {
+        Self {
+            width: w,
+            height: h,
+            data: vec![RGB::default(); w * h],
+        }
+    }
+}
+
+impl FloatImage {
+    // Exposure that brings the geometric mean luminance to middle gray (18%).
+    // Black and non-finite pixels are left out, so a background can't dominate the key value.
+    pub fn auto_exposure(&self) -> Float {
+        let mut log_sum = 0.0;
+        let mut count = 0;
+        for px in &self.data {
+            let luminance = px.luminance();
+            if luminance.is_finite() && luminance > 0.0 {
+                log_sum += luminance.ln();
+                count += 1;
+            }
+        }
+        if count == 0 {
+            return 0.0;
+        }
+
+        let key = (log_sum / count as Float).exp();
+        (0.18 / key).log2()
+    }
+}
+
+impl Image for FloatImage {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn pixels(&self) -> &[RGB] {
+        &self.data
+    }
+
+    fn to_rgba8(&self) -> Vec<u8> {
+        self.data.iter().flat_map(|px| {
+            let [r, g, b] = px.quantize();
+            [r, g, b, 255]
+        }).collect()
+    }
+
+    // Radiance .hdr with flat (not run-length encoded) RGBE scanlines
+    fn save(&self, writer: &mut dyn Write) -> Result<()> {
+        let mut contents = BufWriter::new(writer);
+        write!(contents, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", self.height, self.width)?;
+        for px in &self.data {
+            contents.write_all(&rgbe(*px))?;
+        }
+        contents.flush()
+    }
+}
+
+// Shared exponent encoding: three 8-bit mantissas scaled by 2^(e - 128)
+fn rgbe(px: RGB) -> [u8; 4] {
+    let (r, g, b) = (px.0.max(0.0), px.1.max(0.0), px.2.max(0.0));
+    let v = r.max(g).max(b);
+    if !v.is_finite() || v < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+
+    let mut exponent = v.log2().floor() as i32 + 1;
+    // Keep the mantissa of the largest channel in [0.5, 1) despite log2 rounding
+    if v / Float::powi(2.0, exponent) >= 1.0 {
+        exponent += 1;
+    } else if v / Float::powi(2.0, exponent) < 0.5 {
+        exponent -= 1;
+    }
+    let scale = 256.0 / Float::powi(2.0, exponent);
+    [(r * scale) as u8, (g * scale) as u8, (b * scale) as u8, (exponent + 128) as u8]
+}
+
 impl PPM {
-    pub fn new(w: usize, h: usize, samples: u32) -> Self {
+    pub fn new(w: usize, h: usize) -> Self {
         Self {
             width: w,
             height: h,
-            samples_per_pixel: samples,
+            white_balance: WhiteBalance::default(),
+            exposure_ev: 0.0,
+            tonemap: ToneMap::default(),
+            gamma: WRITER_GAMMA,
             data: vec![RGB::default(); w * h],
+            alpha: None,
         }
     }
+
+    // Operator used by all writers, can be changed to save the same render differently
+    pub fn set_tonemap(&mut self, tonemap: ToneMap) {
+        self.tonemap = tonemap;
+    }
+
+    pub fn tonemap(&self) -> ToneMap {
+        self.tonemap
+    }
+
+    // Exposure in stops, every +1 doubles the linear color before tone mapping
+    pub fn set_exposure_ev(&mut self, exposure_ev: Float) {
+        self.exposure_ev = exposure_ev;
+    }
+
+    pub fn exposure_ev(&self) -> Float {
+        self.exposure_ev
+    }
+
+    pub fn set_white_balance(&mut self, white_balance: WhiteBalance) {
+        self.white_balance = white_balance;
+    }
+
+    pub fn white_balance(&self) -> WhiteBalance {
+        self.white_balance
+    }
+
+    // Writers store value^(1 / gamma) of the tone mapped colors, WRITER_GAMMA unless set
+    pub fn set_gamma(&mut self, gamma: Float) {
+        self.gamma = gamma;
+    }
+
+    pub fn gamma(&self) -> Float {
+        self.gamma
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        self.alpha.is_some()
+    }
+
+    pub fn alpha(&self, i: usize, j: usize) -> Float {
+        self.alpha.as_ref().map_or(1.0, |alpha| alpha[i * self.width + j])
+    }
+
+    // Pixel colors are premultiplied by alpha, i.e. as if composited over black
+    pub fn set_alpha(&mut self, i: usize, j: usize, alpha: Float) {
+        let width = self.width;
+        let data = self.alpha.get_or_insert_with(|| vec![1.0; self.data.len()]);
+        data[i * width + j] = alpha;
+    }
+
+    // Makes the image opaque again
+    pub fn clear_alpha(&mut self) {
+        self.alpha = None;
+    }
+
+    // Undoes the alpha premultiplication for formats storing straight alpha
+    fn display_rgba(&self, idx: usize) -> [u8; 4] {
+        let alpha = self.alpha.as_ref().map_or(1.0, |alpha| alpha[idx]);
+        let color = if alpha > 0.0 { self.data[idx] * (1.0 / alpha) } else { self.data[idx] };
+        let [r, g, b] = self.display_bytes(&color);
+        [r, g, b, (255.0 * alpha.clamp(0.0, 1.0)).round() as u8]
+    }
+
+    // White balance, exposure and tone mapping all happen on linear color, gamma comes last
+    fn display_bytes(&self, px: &RGB) -> [u8; 3] {
+        let balanced = self.white_balance.apply(*px);
+        let exposed = balanced * self.exposure_ev.exp2();
+        self.tonemap.apply(exposed).quantize_gamma(self.gamma)
+    }
+
+    // Linear colors without any gamma correction or clamping
+    pub fn to_float_image(&self) -> FloatImage {
+        FloatImage {
+            width: self.width,
+            height: self.height,
+            data: self.data.clone(),
+        }
+    }
+
+    // Binary P6 variant of the PPM format, a third of the size of the ASCII one
+    pub fn save_binary(&self, writer: &mut dyn Write) -> Result<()> {
+        let mut contents = BufWriter::new(writer);
+        write!(contents, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for px in &self.data {
+            contents.write_all(&self.display_bytes(px))?;
+        }
+        contents.flush()
+    }
+
+    // 24-bit uncompressed BMP: rows are stored bottom-up in BGR order, each padded to 4 bytes
+    pub fn save_bmp(&self, writer: &mut dyn Write) -> Result<()> {
+        let row_size = (self.width * 3).div_ceil(4) * 4;
+        let pixel_bytes = row_size * self.height;
+        let header_size = 14 + 40;
+
+        let mut contents = BufWriter::new(writer);
+        // BITMAPFILEHEADER
+        contents.write_all(b"BM")?;
+        contents.write_all(&((header_size + pixel_bytes) as u32).to_le_bytes())?;
+        contents.write_all(&[0; 4])?;
+        contents.write_all(&(header_size as u32).to_le_bytes())?;
+        // BITMAPINFOHEADER
+        contents.write_all(&40u32.to_le_bytes())?;
+        contents.write_all(&(self.width as i32).to_le_bytes())?;
+        contents.write_all(&(self.height as i32).to_le_bytes())?;
+        contents.write_all(&1u16.to_le_bytes())?; // planes
+        contents.write_all(&24u16.to_le_bytes())?; // bits per pixel
+        contents.write_all(&0u32.to_le_bytes())?; // no compression
+        contents.write_all(&(pixel_bytes as u32).to_le_bytes())?;
+        contents.write_all(&2835i32.to_le_bytes())?; // 72 DPI
+        contents.write_all(&2835i32.to_le_bytes())?;
+        contents.write_all(&[0; 8])?; // palette sizes
+
+        let padding = vec![0; row_size - self.width * 3];
+        for i in (0..self.height).rev() {
+            for j in 0..self.width {
+                let [r, g, b] = self.display_bytes(&self[(i, j)]);
+                contents.write_all(&[b, g, r])?;
+            }
+            contents.write_all(&padding)?;
+        }
+        contents.flush()
+    }
+
+    // Same pixel conversion as the PPM writer, so both formats look identical.
+    // Images with coverage information are written with an alpha channel.
+    pub fn save_png(&self, writer: &mut dyn Write) -> Result<()> {
+        if self.has_alpha() {
+            let rgba = self.to_rgba8();
+            return png::encode(self.width, self.height, ColorType::Rgba, &rgba, writer);
+        }
+
+        let rgb: Vec<u8> = self.data.iter().flat_map(|px| self.display_bytes(px)).collect();
+        png::encode(self.width, self.height, ColorType::Rgb, &rgb, writer)
+    }
+}
+
+// Puts two images of the same height next to each other, e.g. the eyes of a stereo pair.
+// Display settings come from the left image.
+pub fn stitch_side_by_side(left: &PPM, right: &PPM) -> std::result::Result<PPM, DimensionMismatch> {
+    if left.height != right.height {
+        return Err(DimensionMismatch { expected: (right.width, left.height), found: (right.width, right.height) });
+    }
+
+    let width = left.width + right.width;
+    let mut image = PPM::new(width, left.height);
+    image.white_balance = left.white_balance;
+    image.exposure_ev = left.exposure_ev;
+    image.tonemap = left.tonemap;
+    image.gamma = left.gamma;
+    for i in 0..left.height {
+        for j in 0..left.width {
+            image[(i, j)] = left[(i, j)];
+        }
+        for j in 0..right.width {
+            image[(i, left.width + j)] = right[(i, j)];
+        }
+    }
+
+    if left.has_alpha() || right.has_alpha() {
+        for i in 0..left.height {
+            for j in 0..left.width {
+                image.set_alpha(i, j, left.alpha(i, j));
+            }
+            for j in 0..right.width {
+                image.set_alpha(i, left.width + j, right.alpha(i, j));
+            }
+        }
+    }
+    Ok(image)
 }
 
 impl Image for PPM {
@@ -51,16 +379,385 @@ impl Image for PPM {
         self.height
     }
 
+    // Average of all samples of every pixel
+    fn pixels(&self) -> &[RGB] {
+        &self.data
+    }
+
+    fn to_rgba8(&self) -> Vec<u8> {
+        (0..self.data.len()).flat_map(|idx| self.display_rgba(idx)).collect()
+    }
+
     fn save(&self, writer: &mut dyn Write) -> Result<()> {
-        let mut contents = Cursor::new(vec![]);
+        let mut contents = BufWriter::new(writer);
         write!(contents, "P3\n{} {}\n255\n", self.width, self.height)?;
         for i in 0..self.height {
             for j in 0..self.width {
-                let idx = (i * self.width + j) as usize;
+                let idx = i * self.width + j;
                 let px = self.data[idx];
-                px.write(self.samples_per_pixel, &mut contents)?
+                let [r, g, b] = self.display_bytes(&px);
+                writeln!(contents, "{} {} {}", r, g, b)?
+            }
+        }
+        contents.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Error, ErrorKind, Write};
+    use crate::Float;
+    use crate::image::{stitch_side_by_side, DimensionMismatch, FloatImage, Image, PPM};
+    use crate::png;
+    use crate::RGB;
+    use crate::tonemap::{ToneMap, WhiteBalance};
+    use crate::utils::tolerance;
+
+    // Accepts at most a few bytes per call like a congested pipe would
+    struct ShortWriter {
+        data: Vec<u8>,
+        calls: usize,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+            let n = buf.len().min(3);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    type Saver = fn(&PPM, &mut dyn Write) -> std::io::Result<()>;
+
+    struct FailingWriter {
+        remaining: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(Error::new(ErrorKind::BrokenPipe, "closed"));
+            }
+            let n = buf.len().min(self.remaining);
+            self.remaining -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn gradient(w: usize, h: usize) -> PPM {
+        let mut image = PPM::new(w, h);
+        for i in 0..h {
+            for j in 0..w {
+                image[(i, j)] = RGB(j as Float / w as Float, i as Float / h as Float, 0.25);
+            }
+        }
+        image
+    }
+
+    fn ppm_pixel_bytes(image: &PPM) -> Vec<u8> {
+        let mut out = vec![];
+        image.save(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        text.split_whitespace().skip(4).map(|v| v.parse::<u8>().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_binary_ppm_matches_ascii() {
+        let image = gradient(9, 4);
+        let mut bytes = vec![];
+        image.save_binary(&mut bytes).unwrap();
+
+        let header = b"P6\n9 4\n255\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(&bytes[header.len()..], &ppm_pixel_bytes(&image)[..]);
+    }
+
+    #[test]
+    fn test_png_matches_ppm_pixels() {
+        let image = gradient(7, 5);
+        let mut bytes = vec![];
+        image.save_png(&mut bytes).unwrap();
+
+        let (w, h, pixels) = png::test::decode(&bytes);
+        assert_eq!((w, h), (7, 5));
+        assert_eq!(pixels, ppm_pixel_bytes(&image));
+    }
+
+    #[test]
+    fn test_png_header() {
+        let image = gradient(300, 2);
+        let mut bytes = vec![];
+        image.save_png(&mut bytes).unwrap();
+
+        let chunks = png::test::read_chunks(&bytes);
+        assert_eq!(&chunks[0].kind, b"IHDR");
+        assert_eq!(&chunks[0].data[0..8], &[0, 0, 1, 44, 0, 0, 0, 2]);
+        assert_eq!(&chunks[0].data[8..], &[8, 2, 0, 0, 0]);
+    }
+
+    fn decode_hdr(bytes: &[u8]) -> FloatImage {
+        let header_end = bytes.windows(2).position(|w| w == b"\n\n").unwrap() + 2;
+        let size_end = header_end + bytes[header_end..].iter().position(|&b| b == b'\n').unwrap();
+        let size = std::str::from_utf8(&bytes[header_end..size_end]).unwrap();
+        let parts: Vec<&str> = size.split_whitespace().collect();
+        assert_eq!((parts[0], parts[2]), ("-Y", "+X"));
+        let (h, w): (usize, usize) = (parts[1].parse().unwrap(), parts[3].parse().unwrap());
+
+        let mut image = FloatImage::new(w, h);
+        for (idx, px) in bytes[size_end + 1..].chunks(4).enumerate() {
+            let scale = if px[3] == 0 { 0.0 } else { Float::powi(2.0, px[3] as i32 - 136) };
+            // Decode to the middle of the quantization step
+            let channel = |v: u8| if px[3] == 0 { 0.0 } else { (v as Float + 0.5) * scale };
+            image[(idx / w, idx % w)] = RGB(channel(px[0]), channel(px[1]), channel(px[2]));
+        }
+        image
+    }
+
+    #[test]
+    fn test_hdr_round_trip() {
+        let mut image = PPM::new(4, 3);
+        let values = [0.0, 1.0 / 1024.0, 0.003, 0.5, 1.0, 1.7, 3.25, 100.0, 6.5e4, 1.0 / 300.0, 12.0, 0.9999];
+        for (idx, v) in values.iter().enumerate() {
+            image[(idx / 4, idx % 4)] = RGB(*v, v * 0.5, v * 0.25);
+        }
+
+        let float = image.to_float_image();
+        let mut bytes = vec![];
+        float.save(&mut bytes).unwrap();
+        assert!(bytes.starts_with(b"#?RADIANCE\n"));
+        let decoded = decode_hdr(&bytes);
+        assert_eq!((decoded.width(), decoded.height()), (4, 3));
+
+        for (idx, v) in values.iter().enumerate() {
+            let px = decoded[(idx / 4, idx % 4)];
+            assert_eq!(float[(idx / 4, idx % 4)].0, *v);
+            // The largest channel keeps at least 7 significant bits of mantissa
+            if *v == 0.0 {
+                assert_eq!(px.0, 0.0);
+            } else {
+                assert!((px.0 - v).abs() / v < 1.0 / 128.0, "{} decoded as {}", v, px.0);
+                assert!((px.1 - 0.5 * v).abs() / v < 1.0 / 128.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bmp_layout() {
+        let mut image = PPM::new(3, 2);
+        image[(0, 0)] = RGB(1.0, 0.0, 0.0);
+        image[(0, 2)] = RGB(0.0, 1.0, 0.0);
+        image[(1, 0)] = RGB(0.0, 0.0, 1.0);
+        image[(1, 2)] = RGB(1.0, 1.0, 1.0);
+        image[(1, 1)] = RGB(0.25, 0.25, 0.25);
+        let mut bytes = vec![];
+        image.save_bmp(&mut bytes).unwrap();
+
+        // 3 pixels are 9 bytes, padded to 12 per row
+        assert_eq!(bytes.len(), 54 + 2 * 12);
+        assert_eq!(&bytes[0..2], b"BM");
+        assert_eq!(&bytes[2..6], &78u32.to_le_bytes());
+        assert_eq!(&bytes[10..14], &54u32.to_le_bytes());
+        assert_eq!(&bytes[14..18], &40u32.to_le_bytes());
+        assert_eq!(&bytes[18..22], &3i32.to_le_bytes());
+        assert_eq!(&bytes[22..26], &2i32.to_le_bytes());
+        assert_eq!(&bytes[28..30], &24u16.to_le_bytes());
+        assert_eq!(&bytes[34..38], &24u32.to_le_bytes());
+
+        // Bottom row first: blue, gray, white, then the top row: red, black, green
+        assert_eq!(&bytes[54..66], &[255, 0, 0, 128, 128, 128, 255, 255, 255, 0, 0, 0]);
+        assert_eq!(&bytes[66..78], &[0, 0, 255, 0, 0, 0, 0, 255, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_bmp_rows_without_padding() {
+        let image = gradient(4, 3);
+        let mut bytes = vec![];
+        image.save_bmp(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 54 + 3 * 12);
+
+        let top_left = image[(0, 0)].quantize();
+        let last_row = 54 + 2 * 12;
+        assert_eq!(&bytes[last_row..last_row + 3], &[top_left[2], top_left[1], top_left[0]]);
+    }
+
+    #[test]
+    fn test_short_writes_deliver_whole_file() {
+        let image = gradient(33, 17);
+        let savers: [Saver; 4] = [
+            |image, writer| image.save(writer),
+            |image, writer| image.save_binary(writer),
+            |image, writer| image.save_bmp(writer),
+            |image, writer| image.save_png(writer),
+        ];
+
+        for save in savers {
+            let mut expected = vec![];
+            save(&image, &mut expected).unwrap();
+
+            let mut writer = ShortWriter { data: vec![], calls: 0 };
+            save(&image, &mut writer).unwrap();
+            assert_eq!(writer.data, expected);
+            assert!(writer.calls > 1);
+        }
+    }
+
+    #[test]
+    fn test_write_errors_are_returned() {
+        let image = gradient(40, 30);
+        let mut writer = FailingWriter { remaining: 1000 };
+        let result = image.save(&mut writer);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::BrokenPipe);
+
+        let mut writer = FailingWriter { remaining: 100 };
+        assert!(image.to_float_image().save(&mut writer).is_err());
+    }
+
+    #[test]
+    fn test_rgba8_matches_ppm_writer() {
+        let mut image = PPM::new(3, 2);
+        for i in 0..2 {
+            for j in 0..3 {
+                image[(i, j)] = RGB(0.7, 0.2, 1.3);
             }
         }
-        writer.write(&contents.into_inner()).map(|_| ())
+        let rgba = image.to_rgba8();
+        assert_eq!(rgba.len(), 3 * 2 * 4);
+
+        let ppm = ppm_pixel_bytes(&image);
+        for (px, rgb) in rgba.chunks(4).zip(ppm.chunks(3)) {
+            assert_eq!(&px[..3], rgb);
+            assert_eq!(px[3], 255);
+        }
+    }
+
+    #[test]
+    fn test_pixel_access() {
+        let image = gradient(5, 4);
+        assert_eq!(image.pixels().len(), 20);
+        assert_eq!(image.pixel(3, 1).0, image[(1, 3)].0);
+        assert_eq!(image.pixel(3, 1).1, image[(1, 3)].1);
+
+        let float = image.to_float_image();
+        assert_eq!(float.pixel(3, 1).0, image[(1, 3)].0);
+        assert_eq!(float.to_rgba8(), image.to_rgba8());
+    }
+
+    #[test]
+    fn test_tonemap_selectable_after_render() {
+        let mut image = PPM::new(2, 1);
+        image[(0, 0)] = RGB(8.0, 8.0, 8.0);
+        image[(0, 1)] = RGB(0.5, 0.5, 0.5);
+        let clamped = ppm_pixel_bytes(&image);
+        assert_eq!(clamped[0], 255);
+
+        image.set_tonemap(ToneMap::AcesApprox);
+        let mapped = ppm_pixel_bytes(&image);
+        assert!(mapped[0] < 255);
+        assert_ne!(mapped[3], clamped[3]);
+        assert_eq!(&image.to_rgba8()[..3], &mapped[..3]);
+    }
+
+    #[test]
+    fn test_exposure_doubles_linear_color() {
+        let mut bright = PPM::new(16, 1);
+        let mut exposed = PPM::new(16, 1);
+        for j in 0..16 {
+            let v = j as Float / 32.0;
+            bright[(0, j)] = RGB(2.0 * v, 2.0 * v * 0.5, 2.0 * v * 0.25);
+            exposed[(0, j)] = RGB(v, v * 0.5, v * 0.25);
+        }
+        exposed.set_exposure_ev(1.0);
+        assert_eq!(exposed.to_rgba8(), bright.to_rgba8());
+
+        exposed.set_exposure_ev(0.0);
+        assert_ne!(exposed.to_rgba8(), bright.to_rgba8());
+    }
+
+    #[test]
+    fn test_auto_exposure() {
+        // Geometric mean of 0.09 and 0.36 is 0.18, so no correction is needed
+        let mut image = FloatImage::new(4, 1);
+        image[(0, 0)] = RGB(0.09, 0.09, 0.09);
+        image[(0, 1)] = RGB(0.36, 0.36, 0.36);
+        image[(0, 2)] = RGB(Float::NAN, 0.0, 0.0);
+        image[(0, 3)] = RGB::default();
+        assert!(image.auto_exposure().abs() < tolerance(16.0));
+
+        // A dim image with key 0.045 needs two more stops
+        let mut dim = FloatImage::new(3, 2);
+        for i in 0..2 {
+            for j in 0..3 {
+                dim[(i, j)] = RGB(0.045, 0.045, 0.045);
+            }
+        }
+        dim[(1, 2)] = RGB(Float::INFINITY, 1.0, 1.0);
+        assert!((dim.auto_exposure() - 2.0).abs() < tolerance(16.0) * 2.0);
+
+        assert_eq!(FloatImage::new(2, 2).auto_exposure(), 0.0);
+    }
+
+    #[test]
+    fn test_white_balance_on_save() {
+        let mut image = PPM::new(2, 1);
+        image[(0, 0)] = RGB(0.8, 0.5, 0.2);
+        image[(0, 1)] = RGB::white() * 0.5;
+        let plain = image.to_rgba8();
+
+        image.set_white_balance(WhiteBalance::Temperature(6500.0));
+        assert_eq!(image.to_rgba8(), plain);
+
+        image.set_white_balance(WhiteBalance::GrayPick(RGB(0.8, 0.5, 0.2)));
+        image.set_exposure_ev(1.0);
+        let rgba = image.to_rgba8();
+        assert_eq!(rgba[0], rgba[1]);
+        assert_eq!(rgba[1], rgba[2]);
+    }
+
+    #[test]
+    fn test_png_with_alpha() {
+        let mut image = PPM::new(2, 1);
+        image[(0, 0)] = RGB(0.5, 0.25, 0.0);
+        image.set_alpha(0, 0, 0.5);
+        image.set_alpha(0, 1, 0.0);
+        let mut bytes = vec![];
+        image.save_png(&mut bytes).unwrap();
+
+        let (w, h, pixels) = png::test::decode(&bytes);
+        assert_eq!((w, h), (2, 1));
+        // Half of the samples hit a surface of color (1, 0.5, 0)
+        let expected = RGB(1.0, 0.5, 0.0).quantize();
+        assert_eq!(&pixels[0..4], &[expected[0], expected[1], expected[2], 128]);
+        assert_eq!(pixels[7], 0);
+        assert_eq!(image.to_rgba8(), pixels);
+    }
+
+    #[test]
+    fn test_stitch_side_by_side() {
+        let mut left = PPM::new(2, 2);
+        left[(1, 1)] = RGB(2.0, 0.0, 0.0);
+        left.set_exposure_ev(1.0);
+        let mut right = PPM::new(3, 2);
+        right[(0, 2)] = RGB(0.0, 4.0, 0.0);
+        right.set_alpha(1, 0, 0.5);
+
+        let image = stitch_side_by_side(&left, &right).unwrap();
+        assert_eq!((image.width(), image.height()), (5, 2));
+        assert_eq!(image[(1, 1)].0, 2.0);
+        assert_eq!(image[(0, 4)].1, 4.0);
+        assert_eq!(image.alpha(1, 2), 0.5);
+        assert_eq!(image.alpha(0, 0), 1.0);
+        assert_eq!(image.exposure_ev, 1.0);
+
+        let short = PPM::new(3, 1);
+        assert_eq!(stitch_side_by_side(&left, &short).err(), Some(DimensionMismatch { expected: (3, 2), found: (3, 1) }));
     }
 }
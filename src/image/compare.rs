@@ -0,0 +1,229 @@
+use crate::Float;
+use crate::image::{DimensionMismatch, FloatImage, Image};
+use crate::RGB;
+
+// Metrics are computed on linear values with 1.0 as the peak signal.
+// Pixels that are NaN in either image are left out and counted in nan_pixels instead.
+#[derive(Copy, Clone, Debug)]
+pub struct Comparison {
+    pub mean_absolute_error: Float,
+    pub psnr: Float, // In dB, infinite for identical images
+    pub ssim: Float,
+    pub nan_pixels: usize,
+}
+
+const SSIM_WINDOW: usize = 8;
+
+pub fn compare(a: &FloatImage, b: &FloatImage) -> Result<Comparison, DimensionMismatch> {
+    Ok(Comparison {
+        mean_absolute_error: mean_absolute_error(a, b)?,
+        psnr: psnr(a, b)?,
+        ssim: ssim(a, b)?,
+        nan_pixels: nan_pixels(a, b)?,
+    })
+}
+
+pub fn mean_absolute_error(a: &FloatImage, b: &FloatImage) -> Result<Float, DimensionMismatch> {
+    let (sum, count) = valid_pairs(a, b)?.fold((0.0, 0), |(sum, count), (pa, pb)| {
+        let d = difference(pa, pb);
+        (sum + (d.0.abs() + d.1.abs() + d.2.abs()) / 3.0, count + 1)
+    });
+    Ok(if count == 0 { 0.0 } else { sum / count as Float })
+}
+
+pub fn psnr(a: &FloatImage, b: &FloatImage) -> Result<Float, DimensionMismatch> {
+    let (sum, count) = valid_pairs(a, b)?.fold((0.0, 0), |(sum, count), (pa, pb)| {
+        let d = difference(pa, pb);
+        (sum + (d.0 * d.0 + d.1 * d.1 + d.2 * d.2) / 3.0, count + 1)
+    });
+    if count == 0 || sum == 0.0 {
+        return Ok(Float::INFINITY);
+    }
+    Ok(-10.0 * (sum / count as Float).log10())
+}
+
+// Mean SSIM of the luminance over non-overlapping 8x8 windows
+pub fn ssim(a: &FloatImage, b: &FloatImage) -> Result<Float, DimensionMismatch> {
+    check_dimensions(a, b)?;
+    let (c1, c2) = (Float::powi(0.01, 2), Float::powi(0.03, 2));
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y0 in (0..a.height).step_by(SSIM_WINDOW) {
+        for x0 in (0..a.width).step_by(SSIM_WINDOW) {
+            let mut values = vec![];
+            for y in y0..(y0 + SSIM_WINDOW).min(a.height) {
+                for x in x0..(x0 + SSIM_WINDOW).min(a.width) {
+                    let (pa, pb) = (a.pixel(x, y), b.pixel(x, y));
+                    if !pa.has_nan() && !pb.has_nan() {
+                        values.push((pa.luminance(), pb.luminance()));
+                    }
+                }
+            }
+            if values.is_empty() {
+                continue;
+            }
+
+            let n = values.len() as Float;
+            let mean_a = values.iter().map(|v| v.0).sum::<Float>() / n;
+            let mean_b = values.iter().map(|v| v.1).sum::<Float>() / n;
+            let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+            for (la, lb) in &values {
+                var_a += (la - mean_a).powi(2) / n;
+                var_b += (lb - mean_b).powi(2) / n;
+                cov += (la - mean_a) * (lb - mean_b) / n;
+            }
+            total += ((2.0 * mean_a * mean_b + c1) * (2.0 * cov + c2))
+                / ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2));
+            windows += 1;
+        }
+    }
+    Ok(if windows == 0 { 1.0 } else { total / windows as Float })
+}
+
+pub fn nan_pixels(a: &FloatImage, b: &FloatImage) -> Result<usize, DimensionMismatch> {
+    check_dimensions(a, b)?;
+    Ok(a.data.iter().zip(&b.data).filter(|(pa, pb)| pa.has_nan() || pb.has_nan()).count())
+}
+
+// False-color error visualization, from dark blue for no error to red for the largest one.
+// NaN pixels are magenta.
+pub fn diff_image(a: &FloatImage, b: &FloatImage) -> Result<FloatImage, DimensionMismatch> {
+    check_dimensions(a, b)?;
+    let errors: Vec<Option<Float>> = a.data.iter().zip(&b.data).map(|(pa, pb)| {
+        if pa.has_nan() || pb.has_nan() {
+            return None;
+        }
+        let d = difference(*pa, *pb);
+        Some((d.0.abs() + d.1.abs() + d.2.abs()) / 3.0)
+    }).collect();
+    let max_error = errors.iter().flatten().fold(0.0, |max: Float, e| max.max(*e));
+
+    let mut diff = FloatImage::new(a.width, a.height);
+    for (px, error) in diff.data.iter_mut().zip(errors) {
+        *px = match error {
+            None => RGB(1.0, 0.0, 1.0),
+            Some(error) => heat(if max_error > 0.0 { error / max_error } else { 0.0 }),
+        };
+    }
+    Ok(diff)
+}
+
+fn check_dimensions(a: &FloatImage, b: &FloatImage) -> Result<(), DimensionMismatch> {
+    if (a.width, a.height) != (b.width, b.height) {
+        return Err(DimensionMismatch { expected: (a.width, a.height), found: (b.width, b.height) });
+    }
+    Ok(())
+}
+
+fn valid_pairs<'a>(
+    a: &'a FloatImage,
+    b: &'a FloatImage
+) -> Result<impl Iterator<Item = (RGB, RGB)> + 'a, DimensionMismatch> {
+    check_dimensions(a, b)?;
+    Ok(a.data.iter().zip(&b.data).map(|(pa, pb)| (*pa, *pb)).filter(|(pa, pb)| !pa.has_nan() && !pb.has_nan()))
+}
+
+fn difference(a: RGB, b: RGB) -> RGB {
+    RGB(a.0 - b.0, a.1 - b.1, a.2 - b.2)
+}
+
+// Jet colormap for t in [0, 1]
+fn heat(t: Float) -> RGB {
+    let channel = |center: Float| (1.5 - (4.0 * t - center).abs()).clamp(0.0, 1.0);
+    RGB(channel(3.0), channel(2.0), channel(1.0))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Float;
+    use crate::image::compare::{compare, diff_image, mean_absolute_error, psnr, ssim};
+    use crate::image::FloatImage;
+    use crate::RGB;
+    use crate::utils::tolerance;
+
+    fn gradient(w: usize, h: usize) -> FloatImage {
+        let mut image = FloatImage::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let v = (x + y) as Float / (w + h) as Float;
+                image[(y, x)] = RGB(v, 0.5 * v, 1.0 - v);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn test_identical_images() {
+        let image = gradient(20, 12);
+        let result = compare(&image, &image).unwrap();
+        assert_eq!(result.mean_absolute_error, 0.0);
+        assert_eq!(result.psnr, Float::INFINITY);
+        assert!((result.ssim - 1.0).abs() < 1e-12);
+        assert_eq!(result.nan_pixels, 0);
+    }
+
+    #[test]
+    fn test_known_error() {
+        let a = FloatImage::new(4, 4);
+        let mut b = FloatImage::new(4, 4);
+        for px in b.data.iter_mut() {
+            *px = RGB(0.1, 0.1, 0.1);
+        }
+        assert!((mean_absolute_error(&a, &b).unwrap() - 0.1).abs() < tolerance(16.0));
+        // MSE of 0.01 is 20 dB
+        assert!((psnr(&a, &b).unwrap() - 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ssim_drops_with_noise() {
+        let a = gradient(32, 32);
+        let mut b = gradient(32, 32);
+        for (idx, px) in b.data.iter_mut().enumerate() {
+            let noise = if idx % 2 == 0 { 0.2 } else { -0.2 };
+            *px = RGB(px.0 + noise, px.1 + noise, px.2 + noise);
+        }
+        let value = ssim(&a, &b).unwrap();
+        assert!(value < 0.9, "{}", value);
+        assert!(value > -1.0);
+    }
+
+    #[test]
+    fn test_dimension_mismatch() {
+        let err = compare(&FloatImage::new(4, 3), &FloatImage::new(3, 4)).unwrap_err();
+        assert_eq!(err.expected, (4, 3));
+        assert_eq!(err.found, (3, 4));
+        assert!(diff_image(&FloatImage::new(4, 3), &FloatImage::new(4, 4)).is_err());
+    }
+
+    #[test]
+    fn test_nan_pixels_are_reported_separately() {
+        let a = gradient(16, 16);
+        let mut b = gradient(16, 16);
+        b[(3, 5)] = RGB(Float::NAN, 0.0, 0.0);
+        b[(9, 1)] = RGB(0.0, 0.0, Float::NAN);
+
+        let result = compare(&a, &b).unwrap();
+        assert_eq!(result.nan_pixels, 2);
+        assert_eq!(result.mean_absolute_error, 0.0);
+        assert_eq!(result.psnr, Float::INFINITY);
+        assert!(result.ssim.is_finite());
+
+        let diff = diff_image(&a, &b).unwrap();
+        let nan = diff[(3, 5)];
+        assert_eq!((nan.0, nan.1, nan.2), (1.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn test_diff_image_highlights_error() {
+        let a = gradient(8, 8);
+        let mut b = gradient(8, 8);
+        b[(2, 2)] = RGB(5.0, 5.0, 5.0);
+
+        let diff = diff_image(&a, &b).unwrap();
+        let hot = diff[(2, 2)];
+        let cold = diff[(0, 0)];
+        assert!(hot.0 >= 0.5 && hot.1 == 0.0 && hot.2 == 0.0);
+        assert!(cold.2 > 0.4 && cold.0 == 0.0);
+    }
+}
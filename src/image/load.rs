@@ -0,0 +1,321 @@
+use crate::Float;
+use crate::image::PPM;
+use crate::png::{self, DecodeError};
+use crate::RGB;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::Read;
+
+// Unless set otherwise, writers store the square root of linear values, see gamma_correct
+pub const WRITER_GAMMA: Float = 2.0;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    // Neither P3 nor P6
+    UnknownFormat,
+    // A header field is missing or not a number, with the name of the field
+    BadHeader(&'static str),
+    ZeroMaxval,
+    // Samples are at most two bytes
+    MaxvalTooLarge(u64),
+    // The pixel data stops after found of the expected samples
+    Truncated { expected: usize, found: usize },
+    // A sample that is not a number up to maxval, with its position in the pixel data
+    BadSample(usize),
+    Png(DecodeError),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "could not read the image: {}", err),
+            LoadError::UnknownFormat => write!(f, "not a PPM file, expected P3 or P6 at the start"),
+            LoadError::BadHeader(field) => write!(f, "missing or invalid {} in the PPM header", field),
+            LoadError::ZeroMaxval => write!(f, "the PPM maximum value is 0"),
+            LoadError::MaxvalTooLarge(maxval) => write!(f, "the PPM maximum value {} is above 65535", maxval),
+            LoadError::Truncated { expected, found } => {
+                write!(f, "the pixel data ends after {} of {} samples", found, expected)
+            },
+            LoadError::BadSample(idx) => write!(f, "sample {} is not a number up to the maximum value", idx),
+            LoadError::Png(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(err) => Some(err),
+            LoadError::Png(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(err: std::io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+impl From<DecodeError> for LoadError {
+    fn from(err: DecodeError) -> Self {
+        LoadError::Png(err)
+    }
+}
+
+impl PPM {
+    // Reads a P3 or P6 file with linear colors as the writers saved them. Display settings are
+    // the defaults, tone mapping of the writer is not undone.
+    pub fn load(reader: &mut dyn Read) -> Result<PPM, LoadError> {
+        PPM::load_with_gamma(reader, WRITER_GAMMA)
+    }
+
+    // Stored values are raised to gamma to get linear colors, 1 keeps them as they are. The image
+    // is saved with the same gamma.
+    pub fn load_with_gamma(reader: &mut dyn Read, gamma: Float) -> Result<PPM, LoadError> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        let binary = match bytes.get(..2) {
+            Some(b"P3") => false,
+            Some(b"P6") => true,
+            _ => return Err(LoadError::UnknownFormat),
+        };
+        if bytes.get(2).is_some_and(|&b| !is_whitespace(b) && b != b'#') {
+            return Err(LoadError::UnknownFormat);
+        }
+        let mut pos = 2;
+        let width = header_number(&bytes, &mut pos, "width")?;
+        let height = header_number(&bytes, &mut pos, "height")?;
+        let maxval = header_number(&bytes, &mut pos, "maximum value")?;
+        if maxval == 0 {
+            return Err(LoadError::ZeroMaxval);
+        }
+        if maxval > 65535 {
+            return Err(LoadError::MaxvalTooLarge(maxval));
+        }
+        let expected = usize::try_from(width)
+            .ok()
+            .zip(usize::try_from(height).ok())
+            .and_then(|(width, height)| width.checked_mul(height)?.checked_mul(3))
+            .ok_or(LoadError::BadHeader("image size"))?;
+
+        let samples = if binary {
+            // A single whitespace byte separates the header from the pixels
+            pos += 1;
+            let data = bytes.get(pos..).unwrap_or_default();
+            let size = if maxval > 255 { 2 } else { 1 };
+            if data.len() / size < expected {
+                return Err(LoadError::Truncated { expected, found: data.len() / size });
+            }
+            let samples: Vec<u64> = match size {
+                1 => data[..expected].iter().map(|&b| b as u64).collect(),
+                _ => data[..2 * expected].chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u64).collect(),
+            };
+            if let Some(idx) = samples.iter().position(|&sample| sample > maxval) {
+                return Err(LoadError::BadSample(idx));
+            }
+            samples
+        } else {
+            let mut samples = Vec::with_capacity(expected.min(bytes.len()));
+            while samples.len() < expected {
+                skip_whitespace(&bytes, &mut pos);
+                let token = read_token(&bytes, &mut pos);
+                if token.is_empty() {
+                    return Err(LoadError::Truncated { expected, found: samples.len() });
+                }
+                match parse_number(token) {
+                    Some(sample) if sample <= maxval => samples.push(sample),
+                    _ => return Err(LoadError::BadSample(samples.len())),
+                }
+            }
+            samples
+        };
+
+        let mut image = PPM::new(width as usize, height as usize);
+        image.gamma = gamma;
+        let linear = |sample: u64| (sample as Float / maxval as Float).powf(gamma);
+        for (px, rgb) in image.data.iter_mut().zip(samples.chunks_exact(3)) {
+            *px = RGB(linear(rgb[0]), linear(rgb[1]), linear(rgb[2]));
+        }
+        Ok(image)
+    }
+
+    // Reads a PNG like load reads a PPM. Gray is spread to all channels, and an alpha channel
+    // becomes the coverage with the colors premultiplied.
+    pub fn load_png(reader: &mut dyn Read) -> Result<PPM, LoadError> {
+        PPM::load_png_with_gamma(reader, WRITER_GAMMA)
+    }
+
+    pub fn load_png_with_gamma(reader: &mut dyn Read, gamma: Float) -> Result<PPM, LoadError> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        let decoded = png::decode(&bytes)?;
+        let mut image = PPM::new(decoded.width, decoded.height);
+        image.gamma = gamma;
+        let max = decoded.max as Float;
+        let linear = |sample: u16| (sample as Float / max).powf(gamma);
+        let has_alpha = decoded.channels % 2 == 0;
+        for (idx, px) in decoded.samples.chunks_exact(decoded.channels).enumerate() {
+            let color = if decoded.channels < 3 {
+                RGB(linear(px[0]), linear(px[0]), linear(px[0]))
+            } else {
+                RGB(linear(px[0]), linear(px[1]), linear(px[2]))
+            };
+            let (i, j) = (idx / decoded.width, idx % decoded.width);
+            if has_alpha {
+                let alpha = px[decoded.channels - 1] as Float / max;
+                image[(i, j)] = color * alpha;
+                image.set_alpha(i, j, alpha);
+            } else {
+                image[(i, j)] = color;
+            }
+        }
+        Ok(image)
+    }
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c)
+}
+
+// Comments run from # to the end of the line
+fn skip_whitespace(bytes: &[u8], pos: &mut usize) {
+    while let Some(&byte) = bytes.get(*pos) {
+        if byte == b'#' {
+            while bytes.get(*pos).is_some_and(|&b| b != b'\n' && b != b'\r') {
+                *pos += 1;
+            }
+        } else if is_whitespace(byte) {
+            *pos += 1;
+        } else {
+            break;
+        }
+    }
+}
+
+fn read_token<'a>(bytes: &'a [u8], pos: &mut usize) -> &'a [u8] {
+    let start = *pos;
+    while bytes.get(*pos).is_some_and(|&b| !is_whitespace(b) && b != b'#') {
+        *pos += 1;
+    }
+    &bytes[start..*pos]
+}
+
+fn parse_number(token: &[u8]) -> Option<u64> {
+    if token.is_empty() || token.len() > 10 || !token.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some(token.iter().fold(0, |value, &digit| value * 10 + (digit - b'0') as u64))
+}
+
+fn header_number(bytes: &[u8], pos: &mut usize, field: &'static str) -> Result<u64, LoadError> {
+    skip_whitespace(bytes, pos);
+    parse_number(read_token(bytes, pos)).ok_or(LoadError::BadHeader(field))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Float;
+    use crate::image::{Image, LoadError, PPM};
+    use crate::RGB;
+    use std::io::Write;
+
+    type Saver = fn(&PPM, &mut dyn Write) -> std::io::Result<()>;
+
+    fn gradient(w: usize, h: usize) -> PPM {
+        let mut image = PPM::new(w, h);
+        for i in 0..h {
+            for j in 0..w {
+                image[(i, j)] = RGB(j as Float / (w - 1) as Float, i as Float / (h - 1) as Float, 0.01 * (i * w + j) as Float);
+            }
+        }
+        image
+    }
+
+    // Every channel within one 8-bit step of the original after gamma correction
+    fn assert_quantized(loaded: &PPM, original: &PPM) {
+        assert_eq!((loaded.width(), loaded.height()), (original.width(), original.height()));
+        for (a, b) in loaded.pixels().iter().zip(original.pixels()) {
+            for (a, b) in [(a.0, b.0), (a.1, b.1), (a.2, b.2)] {
+                assert!((a.sqrt() - b.sqrt()).abs() <= 1.0 / 255.0, "{} {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let image = gradient(9, 7);
+        for save in [<PPM as Image>::save as Saver, PPM::save_binary, PPM::save_png] {
+            let mut bytes = vec![];
+            save(&image, &mut bytes).unwrap();
+            let loaded = if bytes.starts_with(b"P") { PPM::load(&mut &bytes[..]) } else { PPM::load_png(&mut &bytes[..]) };
+            let loaded = loaded.unwrap();
+            assert!(!loaded.has_alpha());
+            assert_quantized(&loaded, &image);
+        }
+
+        // Coverage comes back from PNG with the colors premultiplied again
+        let mut image = gradient(4, 3);
+        image.set_alpha(1, 2, 0.5);
+        image[(1, 2)] = RGB(0.2, 0.1, 0.05);
+        let mut bytes = vec![];
+        image.save_png(&mut bytes).unwrap();
+        let loaded = PPM::load_png(&mut &bytes[..]).unwrap();
+        assert!((loaded.alpha(1, 2) - 0.5).abs() <= 1.0 / 255.0);
+        assert_eq!(loaded.alpha(0, 0), 1.0);
+        assert!((loaded[(1, 2)].0 - 0.2).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_header_variants() {
+        // Comments anywhere in the header, any whitespace, other maximum values
+        let text = b"P3 # made by hand\n2\t1\r\n# maximum\n1000 1000 0 500\n\n250   1000\t0";
+        let image = PPM::load_with_gamma(&mut &text[..], 1.0).unwrap();
+        assert_eq!((image.width(), image.height()), (2, 1));
+        assert_eq!((image[(0, 0)].0, image[(0, 0)].1, image[(0, 0)].2), (1.0, 0.0, 0.5));
+        assert_eq!((image[(0, 1)].0, image[(0, 1)].1, image[(0, 1)].2), (0.25, 1.0, 0.0));
+
+        // Two bytes per sample above 255, big endian
+        let mut binary = b"P6\n1 1 #\n65535\n".to_vec();
+        binary.extend_from_slice(&[0xff, 0xff, 0x80, 0x00, 0x00, 0x00]);
+        let image = PPM::load(&mut &binary[..]).unwrap();
+        assert_eq!(image[(0, 0)].0, 1.0);
+        assert!((image[(0, 0)].1 - 0.25).abs() < 1e-4);
+        assert_eq!(image[(0, 0)].2, 0.0);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let corpus: [(&[u8], &str); 14] = [
+            (b"", "UnknownFormat"),
+            (b"P5\n1 1\n255\n\0", "UnknownFormat"),
+            (b"P61 1\n255\n", "UnknownFormat"),
+            (b"P6", "BadHeader(\"width\")"),
+            (b"P6\n1\n", "BadHeader(\"height\")"),
+            (b"P6\nx 1 255\n", "BadHeader(\"width\")"),
+            (b"P3\n1 -1 255\n", "BadHeader(\"height\")"),
+            (b"P6\n1 1 25x5\n", "BadHeader(\"maximum value\")"),
+            (b"P6\n1 1\n0\n\0\0\0", "ZeroMaxval"),
+            (b"P6\n1 1\n70000\n", "MaxvalTooLarge(70000)"),
+            (b"P6\n2 1\n255\n\0\0\0\0\0", "Truncated { expected: 6, found: 5 }"),
+            (b"P6\n1 1\n255", "Truncated { expected: 3, found: 0 }"),
+            (b"P3\n1 1\n255\n1 2", "Truncated { expected: 3, found: 2 }"),
+            (b"P3\n1 1\n255\n1 2 256", "BadSample(2)"),
+        ];
+        for (bytes, expected) in corpus {
+            let err = PPM::load(&mut &bytes[..]).err().unwrap();
+            assert_eq!(format!("{:?}", err), expected, "{}", String::from_utf8_lossy(bytes));
+            assert!(!err.to_string().is_empty());
+        }
+        let err = PPM::load(&mut &b"P6\n1 1\n100\n\x01\x02\xff"[..]).err().unwrap();
+        assert!(matches!(err, LoadError::BadSample(2)));
+        assert_eq!(err.to_string(), "sample 2 is not a number up to the maximum value");
+        assert_eq!(
+            LoadError::Truncated { expected: 6, found: 5 }.to_string(),
+            "the pixel data ends after 5 of 6 samples"
+        );
+        assert!(matches!(PPM::load_png(&mut &b"P6\n1 1\n255\n"[..]), Err(LoadError::Png(_))));
+    }
+}
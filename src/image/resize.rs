@@ -0,0 +1,156 @@
+use crate::consts::PI;
+use crate::Float;
+use crate::image::{FloatImage, Image};
+use crate::RGB;
+
+// Kernel of resize. Both filter the stored linear values, so a mix of black and white comes out
+// as linear middle gray and not the darker gray of averaging gamma encoded bytes.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ResizeFilter {
+    // Area average, every new pixel is the mean of the source area it covers. Source pixels it
+    // covers only partly count by the part it covers.
+    Box,
+    // Windowed sinc with three lobes, sharper than the box. When shrinking it's stretched over
+    // the source pixels a new pixel covers. Can overshoot a little next to hard edges.
+    #[default]
+    Lanczos3,
+}
+
+// Resamples the pixels of src to new_width x new_height, sizes don't have to divide each other.
+// Rows are filtered first, then columns.
+pub fn resize(src: &dyn Image, new_width: usize, new_height: usize, filter: ResizeFilter) -> FloatImage {
+    let (width, height) = (src.width(), src.height());
+    let mut resized = FloatImage::new(new_width, new_height);
+    if width == 0 || height == 0 {
+        return resized;
+    }
+
+    let columns = axis_weights(width, new_width, filter);
+    let mut rows = vec![RGB::zeros(); new_width * height];
+    for i in 0..height {
+        let source = &src.pixels()[i * width..(i + 1) * width];
+        for (j, (start, weights)) in columns.iter().enumerate() {
+            rows[i * new_width + j] = weighted_sum(weights, |k| source[start + k]);
+        }
+    }
+    for (i, (start, weights)) in axis_weights(height, new_height, filter).iter().enumerate() {
+        for j in 0..new_width {
+            resized[(i, j)] = weighted_sum(weights, |k| rows[(start + k) * new_width + j]);
+        }
+    }
+    resized
+}
+
+fn weighted_sum(weights: &[Float], pixel: impl Fn(usize) -> RGB) -> RGB {
+    weights.iter().enumerate().fold(RGB::zeros(), |sum, (k, weight)| sum + pixel(k) * *weight)
+}
+
+// For every new pixel along an axis, the first source pixel it takes from and the weights of
+// that one and the following ones, adding up to one
+fn axis_weights(length: usize, new_length: usize, filter: ResizeFilter) -> Vec<(usize, Vec<Float>)> {
+    let scale = length as Float / new_length as Float;
+    // Shrinking stretches the lanczos kernel over the source pixels
+    let stretch = scale.max(1.0);
+    (0..new_length).map(|n| {
+        // Source pixels cover k..k + 1, the new one low..high
+        let (low, high) = (n as Float * scale, (n + 1) as Float * scale);
+        let center = (low + high) / 2.0;
+        let (first, last) = match filter {
+            ResizeFilter::Box => (low.floor(), high.ceil()),
+            ResizeFilter::Lanczos3 => ((center - 3.0 * stretch).floor(), (center + 3.0 * stretch).ceil()),
+        };
+        let weight = |k: Float| match filter {
+            ResizeFilter::Box => (k + 1.0).min(high) - k.max(low),
+            ResizeFilter::Lanczos3 => lanczos3((k + 0.5 - center) / stretch),
+        };
+        // Pixels past the edges are left out, the others weigh more
+        let start = (first.max(0.0) as usize).min(length - 1);
+        let end = (last.max(0.0) as usize).clamp(start + 1, length);
+        let mut weights: Vec<Float> = (start..end).map(|k| weight(k as Float)).collect();
+        let total: Float = weights.iter().sum();
+        weights.iter_mut().for_each(|weight| *weight /= total);
+        (start, weights)
+    }).collect()
+}
+
+fn lanczos3(x: Float) -> Float {
+    if x == 0.0 {
+        return 1.0;
+    }
+    // Zero at whole numbers, sin(PI * x) only comes close
+    if x.abs() >= 3.0 || x.fract() == 0.0 {
+        return 0.0;
+    }
+    let px = PI * x;
+    3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::image::{resize, FloatImage, Image, ResizeFilter};
+    use crate::RGB;
+    use crate::utils::tolerance;
+
+    fn checkerboard(width: usize, height: usize) -> FloatImage {
+        let mut image = FloatImage::new(width, height);
+        for i in 0..height {
+            for j in 0..width {
+                if (i + j) % 2 == 0 {
+                    image[(i, j)] = RGB::white();
+                }
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn test_checkerboard_to_gray() {
+        let image = checkerboard(8, 8);
+        let boxed = resize(&image, 4, 4, ResizeFilter::Box);
+        assert!(boxed.pixels().iter().all(|px| (px.0, px.1, px.2) == (0.5, 0.5, 0.5)));
+        let lanczos = resize(&image, 4, 4, ResizeFilter::Lanczos3);
+        assert_eq!((lanczos.width(), lanczos.height()), (4, 4));
+        assert!(lanczos.pixels().iter().all(|px| (px.0 - 0.5).abs() < 0.02), "{:?}", lanczos.pixels());
+
+        // Sizes that don't divide each other are filtered rather than cut off
+        for filter in [ResizeFilter::Box, ResizeFilter::Lanczos3] {
+            let odd = resize(&checkerboard(27, 17), 5, 3, filter);
+            assert!(odd.pixels().iter().all(|px| (px.0 - 0.5).abs() < 0.05), "{:?} {:?}", filter, odd.pixels());
+        }
+    }
+
+    #[test]
+    fn test_linear_mix() {
+        let mut image = FloatImage::new(2, 1);
+        image[(0, 1)] = RGB::white();
+        for filter in [ResizeFilter::Box, ResizeFilter::Lanczos3] {
+            let mixed = resize(&image, 1, 1, filter);
+            assert!((mixed[(0, 0)].0 - 0.5).abs() < 1e-9, "{:?}", mixed[(0, 0)]);
+            // Linear middle gray is well above the middle byte once gamma encoded
+            assert_eq!(mixed.to_rgba8()[0], RGB(0.5, 0.5, 0.5).quantize()[0]);
+            assert!(mixed.to_rgba8()[0] > 160);
+        }
+    }
+
+    #[test]
+    fn test_constant_stays() {
+        let mut image = FloatImage::new(7, 5);
+        for idx in 0..7 * 5 {
+            image[(idx / 7, idx % 7)] = RGB(0.25, 0.5, 2.0);
+        }
+        for filter in [ResizeFilter::Box, ResizeFilter::Lanczos3] {
+            for (width, height) in [(3, 2), (7, 5), (16, 9), (1, 1)] {
+                let resized = resize(&image, width, height, filter);
+                assert_eq!((resized.width(), resized.height()), (width, height));
+                let close = |px: &RGB| (px.0 - 0.25).abs() < tolerance(64.0) && (px.1 - 0.5).abs() < tolerance(64.0) && (px.2 - 2.0).abs() < tolerance(128.0);
+                assert!(resized.pixels().iter().all(close), "{:?} {}x{}", filter, width, height);
+            }
+        }
+        // The same size is a copy
+        let checker = checkerboard(5, 3);
+        let same = resize(&checker, 5, 3, ResizeFilter::Lanczos3);
+        assert!(same.pixels().iter().zip(checker.pixels()).all(|(a, b)| (a.0 - b.0).abs() < 1e-9));
+        assert_eq!(resize(&checker, 0, 2, ResizeFilter::Box).pixels().len(), 0);
+        assert_eq!(resize(&FloatImage::new(0, 0), 2, 2, ResizeFilter::Box).pixels().len(), 4);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::Float;
+use crate::utils::INF;
+
+// Span of ray parameters or coordinates between min and max. It is empty if min is above max.
+//
+// contains treats the interval as closed, both ends are inside. surrounds treats it as open,
+// both ends are outside. Hit tests use surrounds, so a hit exactly at the near end (the
+// self-intersection offset) or exactly at the far end (the closest hit so far) doesn't count.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Interval {
+    pub min: Float,
+    pub max: Float,
+}
+
+impl Default for Interval {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+impl Interval {
+    pub const EMPTY: Interval = Interval { min: INF, max: -INF };
+    pub const UNIVERSE: Interval = Interval { min: -INF, max: INF };
+
+    pub const fn new(min: Float, max: Float) -> Self {
+        Self { min, max }
+    }
+
+    // Negative for empty intervals
+    pub fn size(&self) -> Float {
+        self.max - self.min
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min > self.max
+    }
+
+    // min <= x <= max
+    pub fn contains(&self, x: Float) -> bool {
+        self.min <= x && x <= self.max
+    }
+
+    // min < x < max
+    pub fn surrounds(&self, x: Float) -> bool {
+        self.min < x && x < self.max
+    }
+
+    // Nearest value inside, unlike Float::clamp it doesn't panic on empty intervals
+    pub fn clamp(&self, x: Float) -> Float {
+        if x < self.min {
+            self.min
+        } else if x > self.max {
+            self.max
+        } else {
+            x
+        }
+    }
+
+    // Grows the interval by delta in total, half of it at each end
+    pub fn expand(&self, delta: Float) -> Self {
+        let padding = delta / 2.0;
+        Self::new(self.min - padding, self.max + padding)
+    }
+
+    // Same start, ending at max. Used to narrow the search to hits before the closest so far.
+    pub fn with_max(&self, max: Float) -> Self {
+        Self::new(self.min, max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::interval::Interval;
+
+    #[test]
+    fn test_boundaries() {
+        let interval = Interval::new(1.0, 3.0);
+        assert!(interval.contains(1.0) && interval.contains(3.0) && interval.contains(2.0));
+        assert!(!interval.surrounds(1.0) && !interval.surrounds(3.0) && interval.surrounds(2.0));
+        assert!(!interval.contains(0.999) && !interval.contains(3.001));
+        assert_eq!(interval.size(), 2.0);
+        assert_eq!(interval.with_max(2.5), Interval::new(1.0, 2.5));
+
+        assert_eq!(interval.clamp(-5.0), 1.0);
+        assert_eq!(interval.clamp(2.5), 2.5);
+        assert_eq!(interval.clamp(7.0), 3.0);
+
+        assert_eq!(interval.expand(1.0), Interval::new(0.5, 3.5));
+        assert_eq!(interval.expand(-2.0), Interval::new(2.0, 2.0));
+    }
+
+    #[test]
+    fn test_empty_and_universe() {
+        assert!(Interval::EMPTY.is_empty());
+        assert_eq!(Interval::default(), Interval::EMPTY);
+        assert!(Interval::EMPTY.size() < 0.0);
+        for x in [-1e30, 0.0, 1e30] {
+            assert!(!Interval::EMPTY.contains(x) && !Interval::EMPTY.surrounds(x));
+            assert!(Interval::UNIVERSE.contains(x) && Interval::UNIVERSE.surrounds(x));
+        }
+        assert!(!Interval::UNIVERSE.is_empty());
+        // A single point is not empty, but nothing lies strictly inside it
+        let point = Interval::new(2.0, 2.0);
+        assert!(!point.is_empty() && point.contains(2.0) && !point.surrounds(2.0));
+        assert_eq!(Interval::EMPTY.clamp(1.0), Interval::EMPTY.min);
+    }
+}
@@ -0,0 +1,55 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod aabb;
+pub mod accumulator;
+pub mod animation;
+pub mod aperture;
+pub mod cli;
+pub mod color;
+pub mod denoise;
+pub mod distortion;
+pub mod film;
+pub mod filter;
+pub mod image;
+pub mod interval;
+pub mod ray;
+pub mod scene;
+pub mod scenes;
+pub mod utils;
+pub mod camera;
+pub mod chunk;
+pub mod material;
+pub mod metadata;
+pub mod onb;
+pub mod parallel;
+pub mod path_trace;
+pub mod photon;
+pub mod png;
+pub mod preview;
+pub mod queue;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod spectral;
+pub mod testing;
+pub mod texture;
+pub mod tonemap;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+pub mod watch;
+
+extern crate nalgebra as na;
+
+// Scalar type of all the rendering math, f32 with the f32 feature for less memory traffic
+#[cfg(not(feature = "f32"))]
+pub type Float = f64;
+#[cfg(feature = "f32")]
+pub type Float = f32;
+
+// Mathematical constants of Float
+#[cfg(not(feature = "f32"))]
+pub use std::f64::consts;
+#[cfg(feature = "f32")]
+pub use std::f32::consts;
+
+pub use color::RGB;
+pub use ray::Ray;
@@ -1,186 +1,170 @@
-mod color;
-mod image;
-mod ray;
-mod scene;
-mod utils;
-mod camera;
-mod material;
-
-use std::f64::consts::PI;
-use color::RGB;
-use image::{Image};
-use ray::Ray;
-use scene::{Sphere};
-use material::{Lambertian};
-
-extern crate nalgebra as na;
-use na::{point, vector};
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use std::sync::Arc;
-use crate::camera::{Camera};
-use crate::material::{Dielectric, Metal};
-use crate::scene::Scene;
-use crate::utils::{rand, rand_range};
+use std::time::{Duration, Instant};
+use raytracer::accumulator::{develop_bracket, Accumulator};
+use raytracer::camera::{RenderSettings, Renderer};
+use raytracer::cli::{bracket_path, CliError, Config, SceneChoice, USAGE};
+use raytracer::image::WRITER_GAMMA;
+use raytracer::metadata::RenderMetadata;
+use raytracer::scene::loader::load_file;
+use raytracer::tonemap::ToneMap;
+use raytracer::watch::{poll_changes, watch_loop, WatchEvent};
 
 fn main() -> Result<()> {
-    let aspect_ratio = 16.0 / 9.0;
-    let w = 1200;
-    let samples = 50;
-    let max_bounces= 10;
+    let config = match Config::parse(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(CliError::HelpRequested) => {
+            println!("{}", USAGE);
+            return Ok(());
+        },
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    // Watching and serving make their renderers inside and share the global pool
+    #[cfg(feature = "parallel")]
+    if let Some(threads) = config.threads.filter(|_| config.watch || config.serve.is_some()) {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .map_err(Error::other)?;
+    }
 
-    let scene = final_scene();
-    let mut camera = Camera::new(
-        w,
-        aspect_ratio,
-        samples,
-        max_bounces,
-        20.0,
-        point![12.0, 2.0, 3.0],
-        point![0.0, 0.0, 0.0],
-        vector![0.0, 1.0, 0.0],
-        0.6,
-        10.0
-    );
+    if let Some(address) = config.serve {
+        return serve(address);
+    }
+
+    if config.watch {
+        let SceneChoice::File(path) = &config.scene else {
+            unreachable!("parse makes sure --watch comes with a scene file")
+        };
+        let load = || {
+            let loaded = load_file(path, None).map_err(|e| e.to_string())?;
+            let camera = config.apply(loaded.camera).build().map_err(|e| e.to_string())?;
+            Ok((Arc::new(loaded.scene), camera))
+        };
+        let report = Arc::new(|event: &WatchEvent| match event {
+            WatchEvent::Started { generation } => eprintln!("Rendering version {}", generation),
+            WatchEvent::Cancelled { generation } => eprintln!("Scene changed, stopping version {}", generation),
+            WatchEvent::Saved { path, .. } => eprintln!("Saved {}", path.display()),
+            WatchEvent::LoadFailed(e) => eprintln!("error: {}, keeping the last image", e),
+            WatchEvent::SaveFailed(e) => eprintln!("error: {}", e),
+        });
+        eprintln!("Watching {}, Ctrl-C to stop", path.display());
+        watch_loop(load, &config.output, config.format, poll_changes(path, Duration::from_millis(250)), report);
+        return Ok(());
+    }
+
+    let (scene, camera) = match &config.scene {
+        SceneChoice::Builtin(builtin) => (builtin.scene(config.seed), builtin.camera()),
+        SceneChoice::File(path) => {
+            let loaded = load_file(path, None).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            (Arc::new(loaded.scene), loaded.camera)
+        }
+    };
+    let camera = config.apply(camera);
+    let camera = camera.build().map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
 
     // Render
-    let renderer = camera.renderer();
-    let image = renderer.render_parallel(scene.clone());
+    let settings = RenderSettings { threads: config.threads, ..*camera.settings() };
+    let renderer = Renderer::builder(camera.clone()).settings(settings).build().map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    if config.preview {
+        return preview(&renderer, scene, &config);
+    }
+    if config.time_budget.is_some() {
+        let budgeted = renderer.render_budgeted(&scene);
+        eprintln!("Done, {} to {} samples per pixel", budgeted.min_samples, budgeted.max_samples);
+        match &config.bracket {
+            Some(evs) => save_bracket(&budgeted.accumulator, evs, &config)?,
+            None => config.format.save(&budgeted.image, &mut std::fs::File::create(&config.output)?)?,
+        }
+        if config.metadata {
+            let mut metadata = RenderMetadata::new(&camera, budgeted.elapsed);
+            metadata.stats.insert("min_samples".to_string(), budgeted.min_samples as u64);
+            metadata.stats.insert("max_samples".to_string(), budgeted.max_samples as u64);
+            metadata.save_sidecar(&config.output)?;
+        }
+        return Ok(());
+    }
+    let start = Instant::now();
+    if let Some(evs) = &config.bracket {
+        let accumulator = renderer.render_region(&scene, 0..renderer.height(), 0..settings.samples_per_pixel);
+        eprintln!("Done");
+        save_bracket(&accumulator, evs, &config)?;
+        if config.metadata {
+            RenderMetadata::new(&camera, start.elapsed()).save_sidecar(&config.output)?;
+        }
+        return Ok(());
+    }
+    let width = renderer.width();
+    let output = renderer.render_with_progress(scene, |done, total| {
+        if done.is_multiple_of(width) {
+            eprintln!("Scanlines remaining: {}", (total - done) / width);
+        }
+    });
+    let render_time = start.elapsed();
     eprintln!("Done");
-    let mut file = std::fs::File::create("image.ppm")?;
-    let _ = image.save(&mut file).unwrap();
+    let stats = output.stats;
+    if stats.non_finite_samples > 0 {
+        eprintln!("{} samples were NaN or infinite and counted as black", stats.non_finite_samples);
+    }
+    let mut file = std::fs::File::create(&config.output)?;
+    config.format.save(&output.beauty, &mut file)?;
+    if config.metadata {
+        let mut metadata = RenderMetadata::new(&camera, render_time);
+        metadata.stats.insert("non_finite_samples".to_string(), stats.non_finite_samples);
+        metadata.stats.insert("threads".to_string(), stats.threads as u64);
+        metadata.save_sidecar(&config.output)?;
+    }
     Ok(())
 }
 
-fn setup_scene() -> Scene {
-    let mut scene = Scene::new();
-    let material_ground = Arc::new(Lambertian::new(RGB(0.8, 0.8, 0.0)));
-    let material_center = Arc::new(Lambertian::new(RGB(0.1, 0.2, 0.5)));
-    let material_left = Arc::new(Dielectric::new(1.5));
-    let material_right = Arc::new(Metal::new(RGB(0.8, 0.6, 0.2), 0.0));
-
-    scene.add(Arc::new(Sphere {
-        center: point![0.0, -100.5, -1.0],
-        radius: 100.0,
-        material: material_ground.clone()
-    }));
-    scene.add(Arc::new(Sphere {
-        center: point![0.0, 0.0, -1.0],
-        radius: 0.5,
-        material: material_center.clone()
-    }));
-    scene.add(Arc::new(Sphere {
-        center: point![-1.0, 0.0, -1.0],
-        radius: 0.5,
-        material: material_left.clone()
-    }));
-    scene.add(Arc::new(Sphere {
-        center: point![1.0, 0.0, -1.0],
-        radius: 0.5,
-        material: material_right.clone()
-    }));
-    scene
+// One image per exposure, named by bracket_path
+fn save_bracket(accumulator: &Accumulator, evs: &[raytracer::Float], config: &Config) -> Result<()> {
+    for (ev, image) in evs.iter().zip(develop_bracket(accumulator, evs, ToneMap::default(), WRITER_GAMMA)) {
+        let path = bracket_path(&config.output, *ev);
+        config.format.save(&image, &mut std::fs::File::create(&path)?)?;
+        eprintln!("Saved {}", path.display());
+    }
+    Ok(())
 }
 
-fn setup_scene2() -> Scene {
-    let mut scene = Scene::new();
-
-    let R = (PI / 4.0).cos();
-    let mat_left = Arc::new(Lambertian::new(RGB(0.0, 0.0, 1.0)));
-    let mat_right = Arc::new(Lambertian::new(RGB(1.0, 0.0, 0.0)));
-
-    scene.add(Arc::new(Sphere {
-        center: point![-R, 0.0, -1.0],
-        radius: R,
-        material: mat_left.clone()
-    }));
-    scene.add(Arc::new(Sphere {
-        center: point![R, 0.0, -1.0],
-        radius: R,
-        material: mat_right.clone()
-    }));
-    scene
+#[cfg(feature = "serve")]
+fn serve(address: std::net::SocketAddr) -> Result<()> {
+    let server = raytracer::serve::Server::bind(address)?;
+    eprintln!("Listening on {}", server.local_addr()?);
+    server.run()
 }
 
-fn final_scene() -> Arc<Scene> {
-    let mut scene = Scene::new();
-    let ground_material = Arc::new(Lambertian::new(RGB(0.5, 0.5, 0.5)));
-
-    scene.add(Arc::new(Sphere {
-        center: point![0.0, -1000.0, 0.0],
-        radius: 1000.0,
-        material: ground_material.clone()
-    }));
-
-    for a in -5..5 {
-        for b in -5..5 {
-            let af = a as f64;
-            let bf = b as f64;
-            let choose_mat = rand();
-            let center = point![af + 0.9 * rand(), 0.2, bf + 0.9 * rand()];
+// Config::parse turns --serve down in this build
+#[cfg(not(feature = "serve"))]
+fn serve(_: std::net::SocketAddr) -> Result<()> {
+    unreachable!()
+}
 
-            if (center - point![4.0, 0.2, 0.0]).norm() > 0.9 {
-                if choose_mat < 0.8 {
-                    // diffuse
-                    let albedo = RGB::random() * RGB::random();
-                    scene.add(Arc::new(Sphere {
-                        center,
-                        radius: 0.2,
-                        material: Arc::new(Lambertian::new(albedo))
-                    }));
-                } else if choose_mat < 0.95 {
-                    // Metal
-                    let albedo = RGB::rand_range(0.5, 1.0);
-                    let fuzz = rand_range(0.0, 0.5);
-                    scene.add(Arc::new(Sphere {
-                        center,
-                        radius: 0.2,
-                        material: Arc::new(Metal::new(albedo, fuzz))
-                    }));
-                } else {
-                    // glass
-                    scene.add(Arc::new(Sphere {
-                        center,
-                        radius: 0.2,
-                        material: Arc::new(Dielectric::new(1.5))
-                    }));
-                }
-            }
-        }
+// Saves whatever was rendered when the window closes
+#[cfg(feature = "preview")]
+fn preview(renderer: &Renderer, scene: Arc<raytracer::scene::Scene>, config: &Config) -> Result<()> {
+    let accumulator = raytracer::preview::run_window(renderer, scene, 16)?;
+    if let Some(evs) = &config.bracket {
+        return save_bracket(&accumulator, evs, config);
     }
-
-    let mat1 = Arc::new(Dielectric::new(1.5));
-    scene.add(Arc::new(Sphere {
-        center: point![0.0, 1.0, 0.0],
-        radius: 1.0,
-        material: mat1.clone()
-    }));
-
-    let mat2 = Arc::new(Lambertian::new(RGB(0.4, 0.2, 0.1)));
-    scene.add(Arc::new(Sphere {
-        center: point![-4.0, 1.0, 0.0],
-        radius: 1.0,
-        material: mat2.clone()
-    }));
-
-    let mat3 = Arc::new(Metal::new(RGB(0.7, 0.6, 0.5), 0.0));
-    scene.add(Arc::new(Sphere {
-        center: point![4.0, 1.0, 0.0],
-        radius: 1.0,
-        material: mat3.clone()
-    }));
-
-    Arc::new(scene)
+    let mut file = std::fs::File::create(&config.output)?;
+    config.format.save(&accumulator.to_ppm(), &mut file)
 }
 
+// Config::parse turns --preview down in this build
+#[cfg(not(feature = "preview"))]
+fn preview(_: &Renderer, _: Arc<raytracer::scene::Scene>, _: &Config) -> Result<()> {
+    unreachable!()
+}
 
 #[cfg(test)]
 mod test {
-    use approx::{assert_relative_eq, relative_eq};
-    use na::{vector, Vector3};
-    use crate::utils::rand_unit_vector;
-
     #[test]
     fn test_fn() {
 
     }
-}
\ No newline at end of file
+}
@@ -1,14 +1,63 @@
+pub mod registry;
+
+use std::sync::Arc;
 use na::Vector3;
 use crate::color::RGB;
+use crate::Float;
 use crate::ray::Ray;
 use crate::scene::HitRecord;
+use crate::scene::desc::{array, MaterialDesc};
+use crate::spectral::{blackbody, cauchy, Wavelengths};
+use crate::texture::{hit_footprint, ImageTexture};
 use crate::utils::{rand_unit_vector, NearZero, reflect, refract, rand};
 
 pub trait Material: Sync + Send {
     fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, RGB)>;
+
+    fn emitted(&self) -> RGB {
+        RGB::default()
+    }
+
+    // Emitted radiance at the wavelengths of a spectral path, the upsampled RGB emission unless
+    // the material has a spectrum of its own
+    fn emitted_spectral(&self, wavelengths: &Wavelengths) -> RGB {
+        wavelengths.upsample(self.emitted())
+    }
+
+    // Surface color without lighting, used for the albedo AOV
+    fn albedo(&self) -> RGB {
+        RGB::white()
+    }
+
+    // Mirror-like materials carry caustic photons instead of storing them
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    // Light group the emitted light is counted in, see RenderSettings::light_groups
+    fn light_group(&self) -> usize {
+        0
+    }
+
+    // Plain data version for saving, None if the material can't be saved
+    fn to_desc(&self) -> Option<MaterialDesc> {
+        None
+    }
+}
+
+// The built-in materials in one type, so objects can hold them inline and calls resolve with a
+// match that the compiler can inline. Anything else goes behind Custom.
+#[derive(Clone)]
+pub enum MaterialKind {
+    Lambertian(Lambertian),
+    TexturedLambertian(TexturedLambertian),
+    Metal(Metal),
+    Dielectric(Dielectric),
+    DiffuseLight(DiffuseLight),
+    Custom(Arc<dyn Material>),
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Lambertian {
     pub albedo: RGB,
 }
@@ -19,66 +68,156 @@ impl Lambertian {
     }
 }
 
-#[derive(Default)]
+// Lambertian with the albedo from a texture, multiplied by factor
+#[derive(Clone)]
+pub struct TexturedLambertian {
+    pub texture: Arc<ImageTexture>,
+    pub factor: RGB,
+}
+
+impl TexturedLambertian {
+    pub fn new(texture: Arc<ImageTexture>, factor: RGB) -> Self {
+        Self { texture, factor }
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct Metal {
     pub albedo: RGB,
-    pub fuzz: f64,
+    pub fuzz: Float,
 }
 
 impl Metal {
-    pub fn new(color: RGB, fuzz: f64) -> Self {
+    pub fn new(color: RGB, fuzz: Float) -> Self {
         Self { albedo: color, fuzz }
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Dielectric {
-    pub refraction_index: f64,
+    pub refraction_index: Float,
+    // Cauchy B coefficient in µm², how much the index grows towards blue, see spectral::cauchy.
+    // Only spectral renders split light by it.
+    pub dispersion: Float,
 }
 
 impl Dielectric {
-    pub fn new(refraction_index: f64) -> Self {
-        Self { refraction_index }
+    pub fn new(refraction_index: Float) -> Self {
+        Self { refraction_index, dispersion: 0.0 }
+    }
+
+    pub fn dispersion(mut self, dispersion: Float) -> Self {
+        self.dispersion = dispersion;
+        self
     }
 
-    fn reflectance(&self, cos_theta: f64, refraction_ratio: f64) -> f64 {
+    fn reflectance(&self, cos_theta: Float, refraction_ratio: Float) -> Float {
         // Use Shlicks approximation for reflectance
         let r0 = ((1.0 - refraction_ratio) / (1.0 + refraction_ratio)).powi(2);
         r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5)
     }
 }
 
+#[derive(Clone, Default)]
+pub struct DiffuseLight {
+    pub emit: RGB,
+    pub group: usize, // Light group, see RenderSettings::light_groups
+    // Spectral renders emit a black body spectrum of this many kelvin, peaking at the average
+    // of emit. RGB renders use emit as it is.
+    pub temperature: Option<Float>,
+}
+
+impl DiffuseLight {
+    pub fn new(color: RGB) -> Self {
+        Self { emit: color, group: 0, temperature: None }
+    }
+
+    pub fn group(mut self, group: usize) -> Self {
+        self.group = group;
+        self
+    }
+
+    pub fn blackbody(mut self, kelvin: Float) -> Self {
+        self.temperature = Some(kelvin);
+        self
+    }
+}
+
 impl Material for Lambertian {
-    fn scatter(&self, _: &Ray, hit: &HitRecord) -> Option<(Ray, RGB)> {
-        let mut direction = (hit.normal + rand_unit_vector()) as Vector3<f64>;
+    fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, RGB)> {
+        let mut direction = (hit.normal + rand_unit_vector()) as Vector3<Float>;
         // Account for when random vector subtracts the normal to zero
         if direction.is_near_zero() {
             direction = hit.normal;
         }
 
-        let bounce_ray = Ray::new(hit.p, direction);
+        let bounce_ray = hit.spawn_ray(ray, direction);
         Some((bounce_ray, self.albedo))
     }
+
+    fn albedo(&self) -> RGB {
+        self.albedo
+    }
+
+    fn to_desc(&self) -> Option<MaterialDesc> {
+        Some(MaterialDesc::Lambertian { albedo: array(self.albedo) })
+    }
+}
+
+impl Material for TexturedLambertian {
+    fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, RGB)> {
+        let mut direction = (hit.normal + rand_unit_vector()) as Vector3<Float>;
+        if direction.is_near_zero() {
+            direction = hit.normal;
+        }
+        let footprint = match hit.uv_derivatives {
+            Some(derivatives) => derivatives.footprint(),
+            None => hit_footprint(hit.t * ray.dir.norm(), ray.dir.normalize().dot(&hit.normal)),
+        };
+        let albedo = self.factor * self.texture.sample_footprint(hit.u, hit.v, footprint);
+        Some((hit.spawn_ray(ray, direction), albedo))
+    }
+
+    // Without a hit point there are no texture coordinates
+    fn albedo(&self) -> RGB {
+        self.factor
+    }
 }
 
 impl Material for Metal {
     fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, RGB)> {
         let reflected = reflect(&ray.dir.normalize(), &hit.normal);
-        let scattered = Ray::new(hit.p, reflected + self.fuzz * rand_unit_vector());
+        let scattered = hit.spawn_ray(ray, reflected + self.fuzz * rand_unit_vector());
         if scattered.dir.dot(&hit.normal) > 0.0 {
             Some((scattered, self.albedo))
         } else {
             None
         }
     }
+
+    fn albedo(&self) -> RGB {
+        self.albedo
+    }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    fn to_desc(&self) -> Option<MaterialDesc> {
+        Some(MaterialDesc::Metal { albedo: array(self.albedo), fuzz: self.fuzz })
+    }
 }
 
 impl Material for Dielectric {
     fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, RGB)> {
-        let refraction_ratio = if hit.front { 1.0 / self.refraction_index } else { self.refraction_index };
+        let refraction_index = match ray.wavelengths {
+            Some(wavelengths) if self.dispersion != 0.0 => cauchy(self.refraction_index, self.dispersion, wavelengths.hero()),
+            _ => self.refraction_index
+        };
+        let refraction_ratio = if hit.front { 1.0 / refraction_index } else { refraction_index };
         let unit_direction = ray.dir.normalize();
 
-        let cos_theta = f64::min((-unit_direction).dot(&hit.normal), 1.0);
+        let cos_theta = Float::min((-unit_direction).dot(&hit.normal), 1.0);
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
         let can_refract = refraction_ratio * sin_theta <= 1.0;
         let direction = if !can_refract || self.reflectance(cos_theta, refraction_ratio) > rand() {
@@ -86,6 +225,137 @@ impl Material for Dielectric {
         } else {
             refract(&unit_direction, &hit.normal, refraction_ratio)
         };
-        Some((Ray::new(hit.p, direction), RGB::white()))
+        let mut scattered = hit.spawn_ray(ray, direction);
+        // The direction only holds for the hero
+        if self.dispersion != 0.0 {
+            scattered.wavelengths = scattered.wavelengths.map(Wavelengths::terminate_secondary);
+        }
+        Some((scattered, RGB::white()))
+    }
+
+    fn is_specular(&self) -> bool {
+        true
     }
-}
\ No newline at end of file
+
+    fn to_desc(&self) -> Option<MaterialDesc> {
+        Some(MaterialDesc::Dielectric { refraction_index: self.refraction_index, dispersion: self.dispersion })
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _: &Ray, _: &HitRecord) -> Option<(Ray, RGB)> {
+        None
+    }
+
+    fn emitted(&self) -> RGB {
+        self.emit
+    }
+
+    fn emitted_spectral(&self, wavelengths: &Wavelengths) -> RGB {
+        match self.temperature {
+            Some(kelvin) => {
+                let [a, b, c] = wavelengths.lambda.map(|lambda| blackbody(lambda, kelvin));
+                RGB(a, b, c) * ((self.emit.0 + self.emit.1 + self.emit.2) / 3.0)
+            },
+            None => wavelengths.upsample(self.emit)
+        }
+    }
+
+    fn albedo(&self) -> RGB {
+        RGB(self.emit.0.min(1.0), self.emit.1.min(1.0), self.emit.2.min(1.0))
+    }
+
+    fn to_desc(&self) -> Option<MaterialDesc> {
+        Some(MaterialDesc::DiffuseLight { emit: array(self.emit), group: self.group, temperature: self.temperature })
+    }
+
+    fn light_group(&self) -> usize {
+        self.group
+    }
+}
+
+macro_rules! delegate {
+    ($self:expr, $material:ident => $call:expr) => {
+        match $self {
+            MaterialKind::Lambertian($material) => $call,
+            MaterialKind::TexturedLambertian($material) => $call,
+            MaterialKind::Metal($material) => $call,
+            MaterialKind::Dielectric($material) => $call,
+            MaterialKind::DiffuseLight($material) => $call,
+            MaterialKind::Custom($material) => $call,
+        }
+    };
+}
+
+impl Material for MaterialKind {
+    fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, RGB)> {
+        delegate!(self, material => material.scatter(ray, hit))
+    }
+
+    fn emitted(&self) -> RGB {
+        delegate!(self, material => material.emitted())
+    }
+
+    fn emitted_spectral(&self, wavelengths: &Wavelengths) -> RGB {
+        delegate!(self, material => material.emitted_spectral(wavelengths))
+    }
+
+    fn albedo(&self) -> RGB {
+        delegate!(self, material => material.albedo())
+    }
+
+    fn is_specular(&self) -> bool {
+        delegate!(self, material => material.is_specular())
+    }
+
+    fn light_group(&self) -> usize {
+        delegate!(self, material => material.light_group())
+    }
+
+    fn to_desc(&self) -> Option<MaterialDesc> {
+        delegate!(self, material => material.to_desc())
+    }
+}
+
+impl From<Lambertian> for MaterialKind {
+    fn from(material: Lambertian) -> Self {
+        MaterialKind::Lambertian(material)
+    }
+}
+
+impl From<TexturedLambertian> for MaterialKind {
+    fn from(material: TexturedLambertian) -> Self {
+        MaterialKind::TexturedLambertian(material)
+    }
+}
+
+impl From<Metal> for MaterialKind {
+    fn from(material: Metal) -> Self {
+        MaterialKind::Metal(material)
+    }
+}
+
+impl From<Dielectric> for MaterialKind {
+    fn from(material: Dielectric) -> Self {
+        MaterialKind::Dielectric(material)
+    }
+}
+
+impl From<DiffuseLight> for MaterialKind {
+    fn from(material: DiffuseLight) -> Self {
+        MaterialKind::DiffuseLight(material)
+    }
+}
+
+// Any material behind an Arc stays a trait object, even a built-in one
+impl<M: Material + 'static> From<Arc<M>> for MaterialKind {
+    fn from(material: Arc<M>) -> Self {
+        MaterialKind::Custom(material)
+    }
+}
+
+impl From<Arc<dyn Material>> for MaterialKind {
+    fn from(material: Arc<dyn Material>) -> Self {
+        MaterialKind::Custom(material)
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use crate::material::{Dielectric, DiffuseLight, Lambertian, Material, MaterialKind, Metal, TexturedLambertian};
+use crate::scene::desc::MaterialDesc;
+
+// Materials shared by name. Objects hold a copy of the material they were built with, so replacing
+// an entry only changes what later lookups return.
+#[derive(Clone, Default)]
+pub struct MaterialRegistry {
+    entries: Vec<(String, MaterialKind)>, // In registration order
+    names: HashMap<String, usize>,
+}
+
+// Refers to an entry of the registry that handed it out
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MaterialHandle(usize);
+
+// What scene building helpers take, a registered material or one that isn't shared by name
+#[derive(Clone)]
+pub enum MaterialRef {
+    Handle(MaterialHandle),
+    Material(MaterialKind),
+}
+
+impl From<MaterialHandle> for MaterialRef {
+    fn from(handle: MaterialHandle) -> Self {
+        MaterialRef::Handle(handle)
+    }
+}
+
+impl From<MaterialKind> for MaterialRef {
+    fn from(material: MaterialKind) -> Self {
+        MaterialRef::Material(material)
+    }
+}
+
+impl From<Lambertian> for MaterialRef {
+    fn from(material: Lambertian) -> Self {
+        MaterialRef::Material(material.into())
+    }
+}
+
+impl From<TexturedLambertian> for MaterialRef {
+    fn from(material: TexturedLambertian) -> Self {
+        MaterialRef::Material(material.into())
+    }
+}
+
+impl From<Metal> for MaterialRef {
+    fn from(material: Metal) -> Self {
+        MaterialRef::Material(material.into())
+    }
+}
+
+impl From<Dielectric> for MaterialRef {
+    fn from(material: Dielectric) -> Self {
+        MaterialRef::Material(material.into())
+    }
+}
+
+impl From<DiffuseLight> for MaterialRef {
+    fn from(material: DiffuseLight) -> Self {
+        MaterialRef::Material(material.into())
+    }
+}
+
+impl<M: Material + 'static> From<Arc<M>> for MaterialRef {
+    fn from(material: Arc<M>) -> Self {
+        MaterialRef::Material(material.into())
+    }
+}
+
+impl From<Arc<dyn Material>> for MaterialRef {
+    fn from(material: Arc<dyn Material>) -> Self {
+        MaterialRef::Material(material.into())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegistryError {
+    DuplicateName(String),
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::DuplicateName(name) => write!(f, "a material named '{}' is already registered", name),
+        }
+    }
+}
+
+impl Error for RegistryError {}
+
+impl MaterialRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, name: &str, material: impl Into<MaterialKind>) -> Result<MaterialHandle, RegistryError> {
+        if self.names.contains_key(name) {
+            return Err(RegistryError::DuplicateName(name.to_string()));
+        }
+        self.entries.push((name.to_string(), material.into()));
+        self.names.insert(name.to_string(), self.entries.len() - 1);
+        Ok(MaterialHandle(self.entries.len() - 1))
+    }
+
+    // Swaps the material behind a name, returning the previous one. None if the name is unknown.
+    pub fn replace(&mut self, name: &str, material: impl Into<MaterialKind>) -> Option<MaterialKind> {
+        let idx = *self.names.get(name)?;
+        Some(std::mem::replace(&mut self.entries[idx].1, material.into()))
+    }
+
+    pub fn get(&self, name: &str) -> Option<MaterialKind> {
+        self.names.get(name).map(|&idx| self.entries[idx].1.clone())
+    }
+
+    pub fn handle(&self, name: &str) -> Option<MaterialHandle> {
+        self.names.get(name).map(|&idx| MaterialHandle(idx))
+    }
+
+    // Panics for a handle from another registry that has fewer materials
+    pub fn material(&self, handle: MaterialHandle) -> MaterialKind {
+        self.entries[handle.0].1.clone()
+    }
+
+    pub fn name(&self, handle: MaterialHandle) -> &str {
+        &self.entries[handle.0].0
+    }
+
+    pub fn resolve(&self, material: MaterialRef) -> MaterialKind {
+        match material {
+            MaterialRef::Handle(handle) => self.material(handle),
+            MaterialRef::Material(material) => material,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &MaterialKind)> {
+        self.entries.iter().map(|(name, material)| (name.as_str(), material))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // The materials section of a scene file, None if any material has no description
+    pub fn to_desc(&self) -> Option<BTreeMap<String, MaterialDesc>> {
+        self.iter().map(|(name, material)| Some((name.to_string(), material.to_desc()?))).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use na::{point, vector};
+    use crate::Float;
+    use crate::interval::Interval;
+    use crate::material::{Dielectric, Lambertian, Material, MaterialKind, Metal};
+    use crate::material::registry::{MaterialRegistry, RegistryError};
+    use crate::Ray;
+    use crate::RGB;
+    use crate::scene::{Hittable, Scene};
+    use crate::scene::desc::MaterialDesc;
+    use crate::utils::INF;
+
+    #[test]
+    fn test_registry() {
+        let mut registry = MaterialRegistry::new();
+        let glass = registry.register("glass", Dielectric::new(1.5)).unwrap();
+        registry.register("gold", Metal::new(RGB(1.0, 0.8, 0.3), 0.1)).unwrap();
+        assert_eq!(registry.register("glass", Dielectric::new(1.3)).err(), Some(RegistryError::DuplicateName("glass".to_string())));
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.handle("glass"), Some(glass));
+        assert_eq!(registry.name(glass), "glass");
+        assert!(registry.get("silver").is_none());
+
+        let names: Vec<_> = registry.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["glass", "gold"]);
+        let desc = registry.to_desc().unwrap();
+        assert_eq!(desc["glass"], MaterialDesc::Dielectric { refraction_index: 1.5, dispersion: 0.0 });
+    }
+
+    #[test]
+    fn test_shared_materials() {
+        let mut scene = Scene::new();
+        let red = scene.materials.register("red", Lambertian::new(RGB(1.0, 0.0, 0.0))).unwrap();
+        scene.add_sphere(point![-1.0, 0.0, -2.0], 0.5, red);
+        scene.add_sphere(point![1.0, 0.0, -2.0], 0.5, scene.materials.get("red").unwrap());
+
+        fn albedo_at(scene: &Scene, x: Float) -> RGB {
+            scene.hit(&Ray::new(point![x, 0.0, 0.0], vector![0.0, 0.0, -1.0]), Interval::new(0.001, INF)).unwrap().material.albedo()
+        }
+        assert_eq!(albedo_at(&scene, -1.0).0, 1.0);
+        assert_eq!(albedo_at(&scene, 1.0).0, 1.0);
+
+        // Objects keep what they were built with, later lookups and objects see the replacement
+        let old = scene.materials.replace("red", Lambertian::new(RGB(0.0, 0.0, 1.0))).unwrap();
+        assert_eq!(old.albedo().0, 1.0);
+        assert_eq!(albedo_at(&scene, -1.0).0, 1.0);
+        assert_eq!(scene.materials.get("red").unwrap().albedo().2, 1.0);
+        scene.add_sphere(point![5.0, 0.0, -2.0], 0.5, red);
+        assert_eq!(albedo_at(&scene, 5.0).2, 1.0);
+        assert!(scene.materials.replace("green", old).is_none());
+
+        // Trait objects can be registered too
+        let custom = scene.materials.register("custom", Arc::new(Lambertian::new(RGB(0.0, 1.0, 0.0)))).unwrap();
+        assert!(matches!(scene.materials.material(custom), MaterialKind::Custom(_)));
+        scene.add_sphere(point![7.0, 0.0, -2.0], 0.5, custom);
+        assert_eq!(albedo_at(&scene, 7.0).1, 1.0);
+    }
+}
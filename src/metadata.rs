@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::camera::Camera;
+use crate::Float;
+use crate::scene::desc::CameraDesc;
+
+// What produced an image, saved as JSON next to it. The camera section has the same format as in
+// scene files, so a render can be repeated from it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RenderMetadata {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: u32,
+    pub max_bounces: u32,
+    pub seed: Option<u64>,
+    pub camera: CameraDesc,
+    pub render_seconds: Float, // Wall clock time of the render
+    pub version: String, // Version of this crate
+    // Counters collected during the render, if any
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub stats: BTreeMap<String, u64>,
+}
+
+impl RenderMetadata {
+    pub fn new(camera: &Camera, render_time: Duration) -> Self {
+        let desc = camera.to_desc();
+        Self {
+            width: camera.width(),
+            height: camera.height(),
+            samples_per_pixel: desc.samples.unwrap_or_default(),
+            max_bounces: desc.max_bounces.unwrap_or_default(),
+            seed: camera.seed(),
+            camera: desc,
+            render_seconds: render_time.as_secs_f64() as Float,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            stats: BTreeMap::new(),
+        }
+    }
+
+    // image.ppm -> image.ppm.meta.json
+    pub fn sidecar_path(image: &Path) -> PathBuf {
+        let mut path = image.as_os_str().to_owned();
+        path.push(".meta.json");
+        PathBuf::from(path)
+    }
+
+    pub fn save(&self, writer: &mut dyn Write) -> Result<()> {
+        serde_json::to_writer_pretty(&mut *writer, self).map_err(Error::other)?;
+        writeln!(writer)
+    }
+
+    pub fn load(reader: &mut dyn Read) -> Result<Self> {
+        serde_json::from_reader(reader).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    // Writes the sidecar of the given image file and returns its path
+    pub fn save_sidecar(&self, image: &Path) -> Result<PathBuf> {
+        let path = Self::sidecar_path(image);
+        let mut file = std::fs::File::create(&path)?;
+        self.save(&mut file)?;
+        Ok(path)
+    }
+
+    pub fn load_sidecar(image: &Path) -> Result<Self> {
+        Self::load(&mut std::fs::File::open(Self::sidecar_path(image))?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use na::point;
+    use crate::camera::Camera;
+    use crate::material::Lambertian;
+    use crate::metadata::RenderMetadata;
+    use crate::RGB;
+    use crate::scene::Scene;
+
+    #[test]
+    fn test_sidecar_round_trip() {
+        let mut scene = Scene::new();
+        scene.add_sphere(point![0.0, 0.0, -1.0], 0.5, Lambertian::new(RGB(0.5, 0.5, 0.5)));
+        let camera = Camera::builder()
+            .dimensions(8, 6)
+            .samples_per_pixel(3)
+            .max_bounces(4)
+            .seed(17)
+            .fov(50.0)
+            .look_from(point![0.0, 0.5, 1.0])
+            .look_at(point![0.0, 0.0, -1.0])
+            .defocus_angle(2.0)
+            .focus_dist(2.5)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let image = camera.renderer().render_parallel(Arc::new(scene));
+        let metadata = RenderMetadata::new(&camera, start.elapsed());
+
+        let dir = std::env::temp_dir().join(format!("raytracer_metadata_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("image.ppm");
+        image.save_binary(&mut std::fs::File::create(&output).unwrap()).unwrap();
+        let sidecar = metadata.save_sidecar(&output).unwrap();
+        assert_eq!(sidecar, dir.join("image.ppm.meta.json"));
+
+        let loaded = RenderMetadata::load_sidecar(&output).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded, metadata);
+        assert_eq!((loaded.width, loaded.height, loaded.samples_per_pixel, loaded.max_bounces), (8, 6, 3, 4));
+        assert_eq!(loaded.seed, Some(17));
+        assert_eq!(loaded.camera.lookfrom, Some([0.0, 0.5, 1.0]));
+        assert_eq!(loaded.camera.lookat, Some([0.0, 0.0, -1.0]));
+        assert_eq!((loaded.camera.fov, loaded.camera.defocus_angle, loaded.camera.focus_dist), (Some(50.0), Some(2.0), Some(2.5)));
+        assert_eq!(loaded.version, env!("CARGO_PKG_VERSION"));
+
+        // The settings are enough to build the same camera again
+        let rebuilt = loaded.camera.builder().build().unwrap();
+        assert_eq!(rebuilt.to_desc(), camera.to_desc());
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert_eq!(RenderMetadata::sidecar_path(Path::new("out/a.b.png")), PathBuf::from("out/a.b.png.meta.json"));
+        let mut text: &[u8] = b"{\"width\": 3}";
+        assert!(RenderMetadata::load(&mut text).is_err());
+
+        let metadata = RenderMetadata::new(&Camera::default(), Duration::from_millis(1500));
+        let mut json = vec![];
+        metadata.save(&mut json).unwrap();
+        assert!(String::from_utf8(json.clone()).unwrap().contains("\"render_seconds\": 1.5"));
+        assert_eq!(RenderMetadata::load(&mut json.as_slice()).unwrap(), metadata);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::consts::PI;
+use crate::Float;
+use na::{vector, Vector3};
+use rand::Rng;
+
+// Orthonormal basis around a unit vector w, right handed: u x v = w. Built with the branchless
+// construction of Duff et al. 2017 ("Building an Orthonormal Basis, Revisited"), which stays
+// accurate for w near +z and -z where crossing with a fixed axis breaks down.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Onb {
+    pub u: Vector3<Float>,
+    pub v: Vector3<Float>,
+    pub w: Vector3<Float>,
+}
+
+impl Onb {
+    // The normal has to be unit length, it becomes w
+    pub fn from_normal(normal: &Vector3<Float>) -> Self {
+        let n = normal;
+        let sign = Float::copysign(1.0, n.z);
+        let a = -1.0 / (sign + n.z);
+        let b = n.x * n.y * a;
+        Self {
+            u: vector![1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x],
+            v: vector![b, sign + n.y * n.y * a, -n.y],
+            w: *n,
+        }
+    }
+
+    // From local coordinates along u, v and w to world space
+    pub fn local(&self, a: Float, b: Float, c: Float) -> Vector3<Float> {
+        a * self.u + b * self.v + c * self.w
+    }
+
+    pub fn local_vector(&self, local: &Vector3<Float>) -> Vector3<Float> {
+        self.local(local.x, local.y, local.z)
+    }
+
+    // From world space to coordinates along u, v and w
+    pub fn to_local(&self, world: &Vector3<Float>) -> Vector3<Float> {
+        vector![world.dot(&self.u), world.dot(&self.v), world.dot(&self.w)]
+    }
+}
+
+// Unit vector in the local frame around +z with a density of cos(theta) / pi, i.e. more
+// directions close to the normal. Put it through Onb::local_vector for world space.
+pub fn random_cosine_direction(rng: &mut impl Rng) -> Vector3<Float> {
+    let r1: Float = rng.gen();
+    let r2: Float = rng.gen();
+    let phi = 2.0 * PI * r1;
+    let r = r2.sqrt();
+    vector![phi.cos() * r, phi.sin() * r, (1.0 - r2).sqrt()]
+}
+
+#[cfg(test)]
+mod test {
+    use crate::consts::PI;
+    use na::{vector, Vector3};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::SmallRng;
+    use crate::Float;
+    use crate::onb::{random_cosine_direction, Onb};
+    use crate::utils::tolerance;
+
+    fn random_normal(rng: &mut SmallRng) -> Vector3<Float> {
+        loop {
+            let v = vector![rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)];
+            if v.norm_squared() > 1e-6 && v.norm_squared() < 1.0 {
+                return v.normalize();
+            }
+        }
+    }
+
+    fn assert_orthonormal(onb: &Onb) {
+        for axis in [onb.u, onb.v, onb.w] {
+            assert!((axis.norm() - 1.0).abs() < tolerance(16.0), "{:?}", onb);
+        }
+        let eps = tolerance(16.0);
+        assert!(onb.u.dot(&onb.v).abs() < eps && onb.u.dot(&onb.w).abs() < eps && onb.v.dot(&onb.w).abs() < eps, "{:?}", onb);
+        assert!((onb.u.cross(&onb.v) - onb.w).norm() < eps, "{:?}", onb);
+    }
+
+    #[test]
+    fn test_orthonormal() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut normals: Vec<_> = (0..5000).map(|_| random_normal(&mut rng)).collect();
+        // Right at and around the poles, where naive constructions degenerate
+        for z in [1.0, -1.0] {
+            normals.push(vector![0.0, 0.0, z]);
+            for eps in [1e-3, 1e-6, 1e-9, 1e-12, 1e-15] {
+                normals.push(vector![eps, 0.0, z].normalize());
+                normals.push(vector![-eps, eps, z].normalize());
+                normals.push(vector![0.0, -eps, z].normalize());
+            }
+        }
+        normals.extend([Vector3::x(), Vector3::y(), -Vector3::x(), -Vector3::y()]);
+
+        for normal in normals {
+            let onb = Onb::from_normal(&normal);
+            assert_eq!(onb.w, normal);
+            assert_orthonormal(&onb);
+
+            let v = vector![0.3, -1.2, 2.5];
+            assert!((onb.local_vector(&onb.to_local(&v)) - v).norm() < tolerance(16.0) * v.norm());
+            assert!((onb.local(0.0, 0.0, 1.0) - normal).norm() < tolerance(4.0));
+        }
+    }
+
+    #[test]
+    fn test_cosine_distribution() {
+        // cos^2(theta) of cosine distributed directions is uniform in [0, 1] and so is the
+        // azimuth around the normal divided by 2 pi
+        const SAMPLES: usize = 100_000;
+        const BINS: usize = 20;
+        // 99.9th percentile of chi-squared with 19 degrees of freedom
+        const CRITICAL: Float = 43.82;
+
+        let mut rng = SmallRng::seed_from_u64(2);
+        for normal in [random_normal(&mut rng), vector![0.0, 0.0, -1.0], vector![1e-9, 0.0, 1.0].normalize()] {
+            let onb = Onb::from_normal(&normal);
+            // A second frame around the same normal to measure the azimuth independently
+            let reference = vector![0.6, 0.0, 0.8].cross(&normal).normalize();
+            let other = normal.cross(&reference);
+
+            let mut cos2 = [0usize; BINS];
+            let mut azimuth = [0usize; BINS];
+            for _ in 0..SAMPLES {
+                let direction = onb.local_vector(&random_cosine_direction(&mut rng));
+                assert!((direction.norm() - 1.0).abs() < tolerance(16.0));
+                let cos_theta = direction.dot(&normal);
+                assert!(cos_theta >= 0.0);
+                cos2[((cos_theta * cos_theta * BINS as Float) as usize).min(BINS - 1)] += 1;
+                let phi = direction.dot(&other).atan2(direction.dot(&reference)) + PI;
+                azimuth[((phi / (2.0 * PI) * BINS as Float) as usize).min(BINS - 1)] += 1;
+            }
+
+            let expected = SAMPLES as Float / BINS as Float;
+            let chi2 = |bins: &[usize]| bins.iter().map(|&n| (n as Float - expected).powi(2) / expected).sum::<Float>();
+            assert!(chi2(&cos2) < CRITICAL, "cos^2 {:?}", cos2);
+            assert!(chi2(&azimuth) < CRITICAL, "azimuth {:?}", azimuth);
+        }
+    }
+}
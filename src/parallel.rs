@@ -0,0 +1,57 @@
+// Rayon, or stand-ins for the few parts of it the renderer uses that run everything in order on
+// the calling thread. Those are used without the parallel feature, e.g. on wasm32.
+
+#[cfg(feature = "parallel")]
+pub use rayon::{current_num_threads, prelude, ThreadPool, ThreadPoolBuilder};
+
+#[cfg(not(feature = "parallel"))]
+pub use sequential::*;
+
+#[cfg(not(feature = "parallel"))]
+mod sequential {
+    use std::convert::Infallible;
+
+    pub mod prelude {
+        // into_par_iter is into_iter, so the adapters after it are the ones of Iterator
+        pub trait IntoParallelIterator: IntoIterator + Sized {
+            fn into_par_iter(self) -> Self::IntoIter {
+                self.into_iter()
+            }
+        }
+
+        impl<I: IntoIterator> IntoParallelIterator for I {}
+    }
+
+    pub fn current_num_threads() -> usize {
+        1
+    }
+
+    pub struct ThreadPool;
+
+    impl ThreadPool {
+        pub fn install<R>(&self, work: impl FnOnce() -> R) -> R {
+            work()
+        }
+
+        pub fn current_num_threads(&self) -> usize {
+            1
+        }
+    }
+
+    #[derive(Default)]
+    pub struct ThreadPoolBuilder;
+
+    impl ThreadPoolBuilder {
+        pub fn new() -> Self {
+            Self
+        }
+
+        pub fn num_threads(self, _: usize) -> Self {
+            self
+        }
+
+        pub fn build(self) -> Result<ThreadPool, Infallible> {
+            Ok(ThreadPool)
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt::{Display, Formatter};
+use std::io::{BufWriter, Result, Write};
+use na::{Point3, Vector3};
+use crate::Float;
+use crate::RGB;
+use crate::scene::ObjectId;
+
+// Everything that happened to one sample of a pixel, see Renderer::trace_pixel
+#[derive(Clone, Debug)]
+pub struct PathTrace {
+    pub x: usize,
+    pub y: usize,
+    pub sample: u32,
+    // In the order the rays were traced, starting with the camera ray
+    pub bounces: Vec<Bounce>,
+    // What the sample adds to the pixel sum, before the check for NaN and infinite values
+    pub color: RGB,
+}
+
+#[derive(Clone, Debug)]
+pub struct Bounce {
+    pub origin: Point3<Float>,
+    pub direction: Vector3<Float>,
+    pub event: BounceEvent,
+}
+
+#[derive(Clone, Debug)]
+pub enum BounceEvent {
+    Hit {
+        object: ObjectId,
+        t: Float,
+        point: Point3<Float>,
+        normal: Vector3<Float>, // Against the ray
+        front: bool,
+        emitted: RGB,
+        specular: bool,
+        attenuation: Option<RGB>, // None if the material absorbed the ray
+    },
+    // The ray left the scene and brought back the background
+    Escaped { background: RGB },
+}
+
+// How Renderer::export_paths writes the traced paths
+#[derive(Copy, Clone, Debug)]
+pub struct PathExport {
+    pub format: PathFormat,
+    pub escape_length: Float, // Rays that leave the scene are drawn this long
+}
+
+impl Default for PathExport {
+    fn default() -> Self {
+        Self { format: PathFormat::Obj, escape_length: 5.0 }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum PathFormat {
+    // Line elements in 3D, one object per path
+    #[default]
+    Obj,
+    // Looking down the y axis, x to the right and z down the page
+    Svg,
+}
+
+impl PathTrace {
+    // False for paths that were absorbed or ran out of bounces
+    pub fn escaped(&self) -> bool {
+        matches!(self.bounces.last(), Some(Bounce { event: BounceEvent::Escaped { .. }, .. }))
+    }
+
+    // The camera ray origin and every hit point, plus a point escape_length along an escaped ray
+    pub fn polyline(&self, escape_length: Float) -> Vec<Point3<Float>> {
+        let mut points: Vec<_> = self.bounces.first().map(|bounce| bounce.origin).into_iter().collect();
+        for bounce in &self.bounces {
+            points.push(match bounce.event {
+                BounceEvent::Hit { point, .. } => point,
+                BounceEvent::Escaped { .. } => bounce.origin + bounce.direction.normalize() * escape_length,
+            });
+        }
+        points
+    }
+
+    // Name of the path in exported files
+    pub fn name(&self) -> String {
+        format!("pixel_{}_{}_sample_{}", self.x, self.y, self.sample)
+    }
+}
+
+pub fn write_paths(traces: &[PathTrace], options: PathExport, writer: &mut dyn Write) -> Result<()> {
+    match options.format {
+        PathFormat::Obj => write_obj(traces, options.escape_length, writer),
+        PathFormat::Svg => write_svg(traces, options.escape_length, writer),
+    }
+}
+
+// Every segment is its own line element, so viewers that don't join polylines show them too
+pub fn write_obj(traces: &[PathTrace], escape_length: Float, writer: &mut dyn Write) -> Result<()> {
+    let mut contents = BufWriter::new(writer);
+    let mut first = 1; // OBJ indices start at 1 and count through the whole file
+    for trace in traces {
+        let points = trace.polyline(escape_length);
+        writeln!(contents, "o {}", trace.name())?;
+        for p in &points {
+            writeln!(contents, "v {} {} {}", p.x, p.y, p.z)?;
+        }
+        for idx in first..first + points.len().saturating_sub(1) {
+            writeln!(contents, "l {} {}", idx, idx + 1)?;
+        }
+        first += points.len();
+    }
+    contents.flush()
+}
+
+pub fn write_svg(traces: &[PathTrace], escape_length: Float, writer: &mut dyn Write) -> Result<()> {
+    let paths: Vec<_> = traces.iter().map(|trace| (trace, trace.polyline(escape_length))).collect();
+    let (mut min, mut max) = ((Float::MAX, Float::MAX), (Float::MIN, Float::MIN));
+    for p in paths.iter().flat_map(|(_, points)| points) {
+        min = (min.0.min(p.x), min.1.min(p.z));
+        max = (max.0.max(p.x), max.1.max(p.z));
+    }
+    if paths.iter().all(|(_, points)| points.is_empty()) {
+        (min, max) = ((0.0, 0.0), (1.0, 1.0));
+    }
+    let size = (max.0 - min.0).max(max.1 - min.1).max(1e-6);
+    let margin = 0.05 * size;
+
+    let mut contents = BufWriter::new(writer);
+    writeln!(
+        contents,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{} {} {} {}">"#,
+        min.0 - margin, min.1 - margin, max.0 - min.0 + 2.0 * margin, max.1 - min.1 + 2.0 * margin
+    )?;
+    for (trace, points) in paths {
+        let coordinates: Vec<_> = points.iter().map(|p| format!("{},{}", p.x, p.z)).collect();
+        writeln!(
+            contents,
+            r#"  <polyline id="{}" points="{}" fill="none" stroke="black" stroke-width="{}"/>"#,
+            trace.name(), coordinates.join(" "), size / 500.0
+        )?;
+    }
+    writeln!(contents, "</svg>")?;
+    contents.flush()
+}
+
+fn point(p: &Point3<Float>) -> String {
+    vector(&p.coords)
+}
+
+fn vector(v: &Vector3<Float>) -> String {
+    format!("({:.4}, {:.4}, {:.4})", v.x, v.y, v.z)
+}
+
+fn color(c: RGB) -> String {
+    format!("({:.4}, {:.4}, {:.4})", c.0, c.1, c.2)
+}
+
+impl Display for PathTrace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Pixel ({}, {}) sample {}: color {}", self.x, self.y, self.sample, color(self.color))?;
+        for (idx, bounce) in self.bounces.iter().enumerate() {
+            writeln!(f, "  {}: ray from {} along {}", idx, point(&bounce.origin), vector(&bounce.direction))?;
+            match bounce.event {
+                BounceEvent::Hit { object, t, point: p, normal, front, emitted, specular, attenuation } => {
+                    let side = if front { "front" } else { "back" };
+                    writeln!(f, "     hit {:?} at t {:.6}, point {}, {} face normal {}", object, t, point(&p), side, vector(&normal))?;
+                    if (emitted.0, emitted.1, emitted.2) != (0.0, 0.0, 0.0) {
+                        writeln!(f, "     emitted {}", color(emitted))?;
+                    }
+                    match attenuation {
+                        Some(attenuation) => {
+                            let kind = if specular { "specular" } else { "diffuse" };
+                            writeln!(f, "     scattered ({}), attenuation {}", kind, color(attenuation))?;
+                        },
+                        None => writeln!(f, "     absorbed")?,
+                    }
+                },
+                BounceEvent::Escaped { background } => writeln!(f, "     escaped, background {}", color(background))?,
+            }
+        }
+        if let Some(Bounce { event: BounceEvent::Hit { attenuation: Some(_), .. }, .. }) = self.bounces.last() {
+            writeln!(f, "  out of bounces")?;
+        }
+        Ok(())
+    }
+}
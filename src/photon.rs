@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use crate::consts::PI;
+use na::{Point3, Vector3};
+use crate::parallel::prelude::*;
+use crate::Float;
+use crate::interval::Interval;
+use crate::ray::Ray;
+use crate::RGB;
+use crate::material::Material;
+use crate::scene::{Hittable, Primitive, Scene};
+use crate::utils::{hash_seed, INF, MIN_T, offset_origin, rand, rand_unit_vector, seed_rng, NearZero};
+
+#[derive(Copy, Clone, Debug)]
+pub struct PhotonMapSettings {
+    pub photon_count: usize, // Photons emitted from all lights together
+    pub gather_radius: Float, // Largest distance a photon can be from the shading point
+    pub k: usize, // Nearest photons used for a single estimate
+    pub max_bounces: u32,
+    pub seed: Option<u64>, // Makes the photon pass reproducible
+}
+
+impl Default for PhotonMapSettings {
+    fn default() -> Self {
+        Self { photon_count: 200_000, gather_radius: 0.1, k: 50, max_bounces: 10, seed: None }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Photon {
+    p: Point3<Float>,
+    power: RGB,
+    group: usize, // Light group of the emitter
+}
+
+// Caustic photon map: only photons that went through at least one specular bounce
+// on their way from a light to a diffuse surface are stored.
+pub struct PhotonMap {
+    settings: PhotonMapSettings,
+    photons: Vec<Photon>,
+    // Hash grid with cells of gather_radius size, so a lookup only needs the 27 neighbouring cells
+    cells: HashMap<(i64, i64, i64), Vec<usize>>,
+}
+
+impl PhotonMap {
+    pub fn build(scene: &Scene, settings: PhotonMapSettings) -> Self {
+        let lights: Vec<(&Primitive, Float)> = scene.iter().filter_map(|hittable| {
+            let sample = hittable.sample_surface()?;
+            let flux = brightness(sample.material.emitted()) * sample.area * PI;
+            if flux > 0.0 { Some((hittable, flux)) } else { None }
+        }).collect();
+        let total_flux: Float = lights.iter().map(|(_, flux)| flux).sum();
+
+        let photons: Vec<Photon> = if lights.is_empty() {
+            vec![]
+        } else {
+            (0..settings.photon_count).into_par_iter().filter_map(|idx| {
+                if let Some(seed) = settings.seed {
+                    seed_rng(hash_seed(&[seed, idx as u64]));
+                }
+                let light = pick_light(&lights, total_flux);
+                trace_photon(scene, light, total_flux, &settings)
+            }).collect()
+        };
+
+        let mut cells: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+        for (idx, photon) in photons.iter().enumerate() {
+            cells.entry(cell_of(&photon.p, settings.gather_radius)).or_default().push(idx);
+        }
+
+        Self { settings, photons, cells }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    // Caustic radiance leaving a diffuse surface with the given albedo at point p,
+    // estimated from the density of the k nearest photons.
+    pub fn estimate(&self, p: &Point3<Float>, albedo: RGB) -> RGB {
+        let Some((nearest, area_radius2)) = self.gather(p) else {
+            return RGB::default();
+        };
+        let flux = nearest.iter().fold(RGB::default(), |sum, (_, idx)| sum + self.photons[*idx].power);
+        // Lambertian BRDF is albedo / pi
+        albedo * flux * (1.0 / (PI * PI * area_radius2))
+    }
+
+    // estimate split by the light group the photons came from, add gets the share of every
+    // nearby photon together with its group
+    pub fn estimate_groups(&self, p: &Point3<Float>, albedo: RGB, mut add: impl FnMut(usize, RGB)) {
+        if let Some((nearest, area_radius2)) = self.gather(p) {
+            let scale = 1.0 / (PI * PI * area_radius2);
+            for (_, idx) in nearest {
+                let photon = &self.photons[idx];
+                add(photon.group, albedo * photon.power * scale);
+            }
+        }
+    }
+
+    // The k nearest photons within the gather radius as (squared distance, index), and the
+    // squared radius of the disk they cover. None if there are none.
+    fn gather(&self, p: &Point3<Float>) -> Option<(Vec<(Float, usize)>, Float)> {
+        let radius = self.settings.gather_radius;
+        let (ci, cj, ck) = cell_of(p, radius);
+
+        let mut nearest: Vec<(Float, usize)> = vec![];
+        for i in ci - 1..=ci + 1 {
+            for j in cj - 1..=cj + 1 {
+                for k in ck - 1..=ck + 1 {
+                    if let Some(cell) = self.cells.get(&(i, j, k)) {
+                        for &idx in cell {
+                            let dist2 = (self.photons[idx].p - p).norm_squared();
+                            if dist2 < radius * radius {
+                                nearest.push((dist2, idx));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if nearest.is_empty() {
+            return None;
+        }
+
+        // With fewer than k photons around, fall back to the full gather disk
+        let mut area_radius2 = radius * radius;
+        if nearest.len() > self.settings.k {
+            nearest.select_nth_unstable_by(self.settings.k - 1, |a, b| a.0.total_cmp(&b.0));
+            nearest.truncate(self.settings.k);
+            area_radius2 = nearest.iter().map(|(dist2, _)| *dist2).fold(0.0, Float::max);
+        }
+        Some((nearest, area_radius2))
+    }
+}
+
+fn brightness(color: RGB) -> Float {
+    (color.0 + color.1 + color.2) / 3.0
+}
+
+fn cell_of(p: &Point3<Float>, size: Float) -> (i64, i64, i64) {
+    ((p.x / size).floor() as i64, (p.y / size).floor() as i64, (p.z / size).floor() as i64)
+}
+
+fn pick_light<'a>(lights: &'a [(&'a Primitive, Float)], total_flux: Float) -> &'a (&'a Primitive, Float) {
+    let mut target = rand() * total_flux;
+    for light in lights {
+        if target < light.1 {
+            return light;
+        }
+        target -= light.1;
+    }
+    &lights[lights.len() - 1]
+}
+
+fn trace_photon(
+    scene: &Scene,
+    light: &(&Primitive, Float),
+    total_flux: Float,
+    settings: &PhotonMapSettings
+) -> Option<Photon> {
+    let (hittable, flux) = light;
+    let sample = hittable.sample_surface()?;
+
+    // Cosine-weighted direction around the surface normal
+    let mut direction = sample.normal + rand_unit_vector();
+    if direction.is_near_zero() {
+        direction = sample.normal;
+    }
+
+    // Every emitted photon carries an equal share of the total flux
+    let emitted = sample.material.emitted();
+    let probability = flux / total_flux;
+    let mut power = emitted * (sample.area * PI / (probability * settings.photon_count as Float));
+    let mut ray = Ray::new(offset_origin(sample.p, &sample.normal, &direction, 0.0), direction as Vector3<Float>);
+    let mut specular = false;
+
+    for _ in 0..settings.max_bounces {
+        let hit = scene.hit(&ray, Interval::new(MIN_T, INF))?;
+        if !hit.material.is_specular() {
+            return if specular && brightness(hit.material.emitted()) == 0.0 {
+                Some(Photon { p: hit.p, power, group: sample.material.light_group() })
+            } else {
+                None
+            };
+        }
+
+        let (scattered, attenuation) = hit.material.scatter(&ray, &hit)?;
+        power = power * attenuation;
+        ray = scattered;
+        specular = true;
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use na::point;
+    use crate::camera::Camera;
+    use crate::material::{Dielectric, DiffuseLight, Lambertian};
+    use crate::photon::{PhotonMap, PhotonMapSettings};
+    use crate::RGB;
+    use crate::scene::{Scene, Sphere};
+    use crate::utils::tolerance;
+
+    fn caustic_scene() -> Scene {
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            center: point![0.0, -1000.0, 0.0],
+            radius: 1000.0,
+            material: Lambertian::new(RGB(0.8, 0.8, 0.8)).into()
+        });
+        scene.add(Sphere {
+            center: point![0.0, 1.8, 0.0],
+            radius: 1.0,
+            material: Dielectric::new(1.5).into()
+        });
+        scene.add(Sphere {
+            center: point![0.0, 10.0, 0.0],
+            radius: 0.2,
+            material: DiffuseLight::new(RGB(250.0, 250.0, 250.0)).into()
+        });
+        scene
+    }
+
+    // Number of lit pixels in the middle of the image, which looks at the floor under the sphere
+    fn lit_pixels_under_sphere(caustics: Option<PhotonMapSettings>) -> usize {
+        let mut camera = Camera::builder()
+            .width(16)
+            .samples_per_pixel(2)
+            .fov(10.0)
+            .look_from(point![5.0, 0.8, 0.0])
+            .look_at(point![0.0, 0.0, 0.0])
+            .focus_dist(5.0)
+            .background(RGB::default())
+            .seed(1);
+        if let Some(caustics) = caustics {
+            camera = camera.caustics(caustics);
+        }
+        let camera = camera.build().unwrap();
+        let image = camera.renderer().render_parallel(Arc::new(caustic_scene()));
+
+        let mut lit = 0;
+        for i in 7..9 {
+            for j in 6..10 {
+                let px = image[(i, j)];
+                if px.0 + px.1 + px.2 > 1.0 {
+                    lit += 1;
+                }
+            }
+        }
+        lit
+    }
+
+    #[test]
+    fn test_photons_stored_only_after_specular_bounce() {
+        let scene = caustic_scene();
+        let settings = PhotonMapSettings { photon_count: 20_000, seed: Some(1), ..Default::default() };
+        let map = PhotonMap::build(&scene, settings);
+
+        // Only the small fraction of photons focused by the glass sphere is kept
+        assert!(!map.is_empty());
+        assert!(map.len() < settings.photon_count / 10);
+
+        let under = map.estimate(&point![0.0, 0.0, 0.0], RGB(0.8, 0.8, 0.8));
+        let aside = map.estimate(&point![3.0, 0.0, 0.0], RGB(0.8, 0.8, 0.8));
+        assert!(under.0 > 0.0);
+        assert_eq!(aside.0, 0.0);
+    }
+
+    #[test]
+    fn test_estimate_groups() {
+        let mut scene = caustic_scene();
+        scene.add(Sphere {
+            center: point![0.3, 10.0, 0.0],
+            radius: 0.2,
+            material: DiffuseLight::new(RGB(250.0, 0.0, 0.0)).group(1).into()
+        });
+        let map = PhotonMap::build(&scene, PhotonMapSettings { photon_count: 20_000, seed: Some(1), ..Default::default() });
+
+        // Both lights get through the glass and their shares add up to the whole estimate
+        let p = point![0.0, 0.0, 0.0];
+        let mut groups = [RGB::default(); 2];
+        map.estimate_groups(&p, RGB(0.8, 0.8, 0.8), |group, radiance| groups[group] += radiance);
+        let total = map.estimate(&p, RGB(0.8, 0.8, 0.8));
+        assert!(groups[0].2 > 0.0 && groups[1].0 > 0.0 && groups[1].2 == 0.0);
+        assert!(((groups[0] + groups[1]).0 - total.0).abs() < tolerance(1e3) * total.0);
+    }
+
+    #[test]
+    fn test_caustic_appears_under_glass_sphere() {
+        // The path tracer alone almost never finds the tiny light through the glass
+        let without = lit_pixels_under_sphere(None);
+        let with = lit_pixels_under_sphere(Some(PhotonMapSettings {
+            photon_count: 100_000,
+            ..Default::default()
+        }));
+        assert!(with >= 6, "with: {}, without: {}", with, without);
+        assert!(without <= 2, "with: {}, without: {}", with, without);
+    }
+
+    #[test]
+    fn test_no_lights_no_photons() {
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            center: point![0.0, 0.0, 0.0],
+            radius: 1.0,
+            material: Dielectric::new(1.5).into()
+        });
+        let map = PhotonMap::build(&scene, PhotonMapSettings::default());
+        assert!(map.is_empty());
+        assert_eq!(map.estimate(&point![0.0, 0.0, 0.0], RGB::white()).0, 0.0);
+    }
+}
@@ -0,0 +1,606 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{Result, Write};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+// Largest payload of an uncompressed deflate block
+const MAX_STORED_BLOCK: usize = 65535;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorType {
+    Rgb,
+    // Straight (not premultiplied) alpha
+    Rgba,
+}
+
+impl ColorType {
+    pub fn channels(&self) -> usize {
+        match self {
+            ColorType::Rgb => 3,
+            ColorType::Rgba => 4,
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            ColorType::Rgb => 2,
+            ColorType::Rgba => 6,
+        }
+    }
+}
+
+// Writes 8-bit pixel rows as a PNG. Compression is deliberately minimal: the image data
+// is wrapped into uncompressed deflate blocks, which every decoder accepts.
+pub fn encode(width: usize, height: usize, color: ColorType, pixels: &[u8], writer: &mut dyn Write) -> Result<()> {
+    assert_eq!(pixels.len(), width * height * color.channels(), "pixel data does not match the image size");
+
+    writer.write_all(&SIGNATURE)?;
+
+    let mut ihdr = vec![];
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, default compression, filter and no interlace
+    ihdr.extend_from_slice(&[8, color.code(), 0, 0, 0]);
+    write_chunk(writer, b"IHDR", &ihdr)?;
+
+    // Every scanline starts with its filter type, 0 means unfiltered
+    let row_len = width * color.channels();
+    let mut raw = Vec::with_capacity(height * (row_len + 1));
+    for row in pixels.chunks(row_len.max(1)).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(writer, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(writer, b"IEND", &[])
+}
+
+fn write_chunk(writer: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    writer.write_all(&crc.finish().to_be_bytes())
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // CMF/FLG: deflate with 32K window, no preset dictionary, check bits making it divisible by 31
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(if last { 1 } else { 0 });
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+// Samples of a decoded PNG, row by row with channels interleaved. 1 to 4 channels: gray, gray
+// and alpha, RGB or RGBA, with straight alpha. Every sample is in 0..=max, 255 or 65535.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decoded {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub max: u16,
+    pub samples: Vec<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    // The signature is missing
+    NotPng,
+    // The file ends in the middle of a chunk or the image data
+    Truncated,
+    // The checksum of the named chunk does not match, or of the whole image data for IDAT
+    BadChecksum([u8; 4]),
+    // Valid PNG this decoder leaves out, e.g. palettes or interlacing
+    Unsupported(String),
+    Corrupt(&'static str),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::NotPng => write!(f, "not a PNG file, the signature is missing"),
+            DecodeError::Truncated => write!(f, "the PNG file ends early"),
+            DecodeError::BadChecksum(kind) => write!(f, "checksum mismatch in the {} chunk", String::from_utf8_lossy(kind)),
+            DecodeError::Unsupported(what) => write!(f, "unsupported PNG: {}", what),
+            DecodeError::Corrupt(what) => write!(f, "corrupt PNG: {}", what),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+// Reads non-interlaced gray, RGB, gray-alpha and RGBA images with 8 or 16 bits per sample and any
+// filters and deflate compression. Ancillary chunks are skipped.
+pub fn decode(bytes: &[u8]) -> std::result::Result<Decoded, DecodeError> {
+    if bytes.len() < SIGNATURE.len() || bytes[..SIGNATURE.len()] != SIGNATURE {
+        return Err(DecodeError::NotPng);
+    }
+    let mut pos = SIGNATURE.len();
+    let mut header = None;
+    let mut zlib = vec![];
+    loop {
+        let field = |at: usize| bytes.get(at..at + 4).map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+        let len = field(pos).ok_or(DecodeError::Truncated)? as usize;
+        let kind: [u8; 4] = bytes.get(pos + 4..pos + 8).ok_or(DecodeError::Truncated)?.try_into().unwrap();
+        let data = bytes.get(pos + 8..pos + 8 + len).ok_or(DecodeError::Truncated)?;
+        let crc = field(pos + 8 + len).ok_or(DecodeError::Truncated)?;
+        let mut expected = Crc32::new();
+        expected.update(&kind);
+        expected.update(data);
+        if crc != expected.finish() {
+            return Err(DecodeError::BadChecksum(kind));
+        }
+        pos += 12 + len;
+
+        match &kind {
+            b"IHDR" => header = Some(parse_header(data)?),
+            b"IDAT" => zlib.extend_from_slice(data),
+            b"IEND" => break,
+            b"PLTE" => return Err(DecodeError::Unsupported("palette images".to_string())),
+            // Lowercase first letters mark chunks that can be ignored
+            _ if kind[0].is_ascii_uppercase() => {
+                return Err(DecodeError::Unsupported(format!("critical chunk {}", String::from_utf8_lossy(&kind))));
+            },
+            _ => {},
+        }
+    }
+    let (width, height, channels, depth) = header.ok_or(DecodeError::Corrupt("no IHDR chunk"))?;
+
+    let raw = inflate_zlib(&zlib)?;
+    let bytes_per_pixel = channels * depth / 8;
+    let row_len = width.checked_mul(bytes_per_pixel).ok_or(DecodeError::Corrupt("image too large"))?;
+    if (raw.len() / (row_len + 1)) < height {
+        return Err(DecodeError::Truncated);
+    }
+    let mut pixels = vec![0; height * row_len];
+    for i in 0..height {
+        let row = &raw[i * (row_len + 1)..(i + 1) * (row_len + 1)];
+        let (done, rest) = pixels.split_at_mut(i * row_len);
+        let previous = if i == 0 { None } else { Some(&done[(i - 1) * row_len..]) };
+        unfilter(row[0], &row[1..], previous, &mut rest[..row_len], bytes_per_pixel)?;
+    }
+
+    let samples = match depth {
+        8 => pixels.iter().map(|&b| b as u16).collect(),
+        _ => pixels.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect(),
+    };
+    Ok(Decoded { width, height, channels, max: if depth == 8 { 255 } else { 65535 }, samples })
+}
+
+// Width, height, channels and bits per sample
+fn parse_header(data: &[u8]) -> std::result::Result<(usize, usize, usize, usize), DecodeError> {
+    if data.len() != 13 {
+        return Err(DecodeError::Corrupt("IHDR has the wrong length"));
+    }
+    let width = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+    let height = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+    let (depth, color, compression, filter, interlace) = (data[8], data[9], data[10], data[11], data[12]);
+    let channels = match color {
+        0 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        3 => return Err(DecodeError::Unsupported("palette images".to_string())),
+        _ => return Err(DecodeError::Corrupt("unknown color type")),
+    };
+    if depth != 8 && depth != 16 {
+        return Err(DecodeError::Unsupported(format!("{} bits per sample", depth)));
+    }
+    if compression != 0 || filter != 0 {
+        return Err(DecodeError::Corrupt("unknown compression or filter method"));
+    }
+    if interlace != 0 {
+        return Err(DecodeError::Unsupported("interlacing".to_string()));
+    }
+    if width == 0 || height == 0 {
+        return Err(DecodeError::Corrupt("zero width or height"));
+    }
+    Ok((width, height, channels, depth as usize))
+}
+
+// Reverses the filter of one scanline, bytes_per_pixel to the left is the previous pixel
+fn unfilter(
+    filter: u8,
+    row: &[u8],
+    previous: Option<&[u8]>,
+    out: &mut [u8],
+    bytes_per_pixel: usize
+) -> std::result::Result<(), DecodeError> {
+    for idx in 0..row.len() {
+        let left = if idx >= bytes_per_pixel { out[idx - bytes_per_pixel] } else { 0 };
+        let up = previous.map_or(0, |previous| previous[idx]);
+        let up_left = if idx >= bytes_per_pixel { previous.map_or(0, |previous| previous[idx - bytes_per_pixel]) } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err(DecodeError::Corrupt("unknown filter type")),
+        };
+        out[idx] = row[idx].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// Order in which a dynamic block lists the code lengths of its code length alphabet
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn inflate_zlib(data: &[u8]) -> std::result::Result<Vec<u8>, DecodeError> {
+    if data.len() < 6 {
+        return Err(DecodeError::Truncated);
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    if cmf & 0x0f != 8 || !((cmf as u16) << 8 | flg as u16).is_multiple_of(31) {
+        return Err(DecodeError::Corrupt("bad zlib header"));
+    }
+    if flg & 0x20 != 0 {
+        return Err(DecodeError::Corrupt("zlib preset dictionary"));
+    }
+    let mut bits = BitReader { data: &data[2..], pos: 0, bit: 0 };
+    let out = inflate(&mut bits)?;
+    let end = 2 + bits.pos + (bits.bit > 0) as usize;
+    let checksum = data.get(end..end + 4).ok_or(DecodeError::Truncated)?;
+    if u32::from_be_bytes(checksum.try_into().unwrap()) != adler32(&out) {
+        return Err(DecodeError::BadChecksum(*b"IDAT"));
+    }
+    Ok(out)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32, // Bits of data[pos] already read, least significant first
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> std::result::Result<u32, DecodeError> {
+        let mut value = 0;
+        for n in 0..count {
+            let byte = *self.data.get(self.pos).ok_or(DecodeError::Truncated)?;
+            value |= ((byte >> self.bit) as u32 & 1) << n;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+// Canonical Huffman code as the number of codes of every length and the symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|&symbol| lengths[symbol as usize] > 0).collect();
+        symbols.sort_by_key(|&symbol| lengths[symbol as usize]);
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> std::result::Result<u16, DecodeError> {
+        // Codes of one length are consecutive, first is the smallest code of the current length
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(DecodeError::Corrupt("invalid Huffman code"))
+    }
+}
+
+fn inflate(bits: &mut BitReader) -> std::result::Result<Vec<u8>, DecodeError> {
+    let mut out = vec![];
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let header = bits.data.get(bits.pos..bits.pos + 4).ok_or(DecodeError::Truncated)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(DecodeError::Corrupt("stored block length check"));
+                }
+                let start = bits.pos + 4;
+                out.extend_from_slice(bits.data.get(start..start + len as usize).ok_or(DecodeError::Truncated)?);
+                bits.pos = start + len as usize;
+            },
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                inflate_block(bits, &Huffman::new(&lengths), &Huffman::new(&[5; 30]), &mut out)?;
+            },
+            2 => {
+                let (literals, distances) = dynamic_codes(bits)?;
+                inflate_block(bits, &literals, &distances, &mut out)?;
+            },
+            _ => return Err(DecodeError::Corrupt("reserved deflate block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn dynamic_codes(bits: &mut BitReader) -> std::result::Result<(Huffman, Huffman), DecodeError> {
+    let literal_count = bits.bits(5)? as usize + 257;
+    let distance_count = bits.bits(5)? as usize + 1;
+    let length_count = bits.bits(4)? as usize + 4;
+    let mut code_lengths = [0; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..length_count] {
+        code_lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_lengths.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or(DecodeError::Corrupt("length repeat without a previous length"))?, 3 + bits.bits(2)?),
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err(DecodeError::Corrupt("code lengths overrun"));
+    }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+fn inflate_block(
+    bits: &mut BitReader,
+    literals: &Huffman,
+    distances: &Huffman,
+    out: &mut Vec<u8>
+) -> std::result::Result<(), DecodeError> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let idx = symbol - 257;
+                let len = LENGTH_BASE[idx] as usize + bits.bits(LENGTH_EXTRA[idx] as u32)? as usize;
+                let idx = distances.decode(bits)? as usize;
+                if idx >= DISTANCE_BASE.len() {
+                    return Err(DecodeError::Corrupt("invalid distance code"));
+                }
+                let distance = DISTANCE_BASE[idx] as usize + bits.bits(DISTANCE_EXTRA[idx] as u32)? as usize;
+                if distance > out.len() {
+                    return Err(DecodeError::Corrupt("distance before the start of the data"));
+                }
+                // Copies byte by byte, the source may overlap what is being written
+                for _ in 0..len {
+                    out.push(out[out.len() - distance]);
+                }
+            },
+            _ => return Err(DecodeError::Corrupt("invalid literal or length code")),
+        }
+    }
+}
+
+pub(crate) fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+pub(crate) struct Crc32 {
+    value: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { value: 0xffff_ffff }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.value ^= byte as u32;
+            for _ in 0..8 {
+                let mask = (self.value & 1).wrapping_neg();
+                self.value = (self.value >> 1) ^ (0xedb8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.value
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::png::{adler32, ColorType, Crc32, DecodeError};
+
+    pub struct Chunk {
+        pub kind: [u8; 4],
+        pub data: Vec<u8>,
+    }
+
+    // Splits a PNG file into chunks, checking the signature and every CRC on the way
+    pub fn read_chunks(bytes: &[u8]) -> Vec<Chunk> {
+        assert_eq!(&bytes[..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+        let mut chunks = vec![];
+        let mut pos = 8;
+        while pos < bytes.len() {
+            let len = u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = bytes[pos + 4..pos + 8].try_into().unwrap();
+            let data = bytes[pos + 8..pos + 8 + len].to_vec();
+            let crc = u32::from_be_bytes(bytes[pos + 8 + len..pos + 12 + len].try_into().unwrap());
+
+            let mut expected = Crc32::new();
+            expected.update(&kind);
+            expected.update(&data);
+            assert_eq!(crc, expected.finish(), "bad CRC in chunk {:?}", kind);
+
+            chunks.push(Chunk { kind, data });
+            pos += 12 + len;
+        }
+        chunks
+    }
+
+    // Decodes the pixel bytes of a PNG written with stored deflate blocks and no filtering
+    pub fn decode(bytes: &[u8]) -> (usize, usize, Vec<u8>) {
+        let chunks = read_chunks(bytes);
+        assert_eq!(&chunks[0].kind, b"IHDR");
+        let width = u32::from_be_bytes(chunks[0].data[0..4].try_into().unwrap()) as usize;
+        let height = u32::from_be_bytes(chunks[0].data[4..8].try_into().unwrap()) as usize;
+        let channels = match chunks[0].data[9] { 2 => 3, 6 => 4, other => panic!("color type {}", other) };
+        assert_eq!(&chunks.last().unwrap().kind, b"IEND");
+
+        let zlib: Vec<u8> = chunks.iter().filter(|c| &c.kind == b"IDAT").flat_map(|c| c.data.clone()).collect();
+        assert_eq!(((zlib[0] as u16) << 8 | zlib[1] as u16) % 31, 0);
+        let mut raw = vec![];
+        let mut pos = 2;
+        loop {
+            let last = zlib[pos] & 1 == 1;
+            assert_eq!(zlib[pos] >> 1, 0, "only stored blocks are expected");
+            let len = u16::from_le_bytes([zlib[pos + 1], zlib[pos + 2]]) as usize;
+            let nlen = u16::from_le_bytes([zlib[pos + 3], zlib[pos + 4]]) as usize;
+            assert_eq!(len, !nlen & 0xffff);
+            raw.extend_from_slice(&zlib[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(u32::from_be_bytes(zlib[pos..pos + 4].try_into().unwrap()), adler32(&raw));
+
+        let mut pixels = vec![];
+        for row in raw.chunks(width * channels + 1) {
+            assert_eq!(row[0], 0);
+            pixels.extend_from_slice(&row[1..]);
+        }
+        assert_eq!(pixels.len(), width * height * channels);
+        (width, height, pixels)
+    }
+
+    #[test]
+    fn test_checksums() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    // Made with zlib: fixed Huffman codes and every filter type, and dynamic codes with 16-bit samples
+    const FILTERS_RGB8: &[u8] = include_bytes!("../tests/images/filters_rgb8.png");
+    const GRAY_ALPHA16: &[u8] = include_bytes!("../tests/images/gray_alpha16.png");
+
+    #[test]
+    fn test_decode_compressed() {
+        let decoded = crate::png::decode(FILTERS_RGB8).unwrap();
+        assert_eq!((decoded.width, decoded.height, decoded.channels, decoded.max), (6, 5, 3, 255));
+        let expected: Vec<u16> = (0..5).flat_map(|i| (0..6).flat_map(move |j| (0..3).map(move |c| (i * 40 + j * 7 + c * 90) % 256))).collect();
+        assert_eq!(decoded.samples, expected);
+
+        let decoded = crate::png::decode(GRAY_ALPHA16).unwrap();
+        assert_eq!((decoded.width, decoded.height, decoded.channels, decoded.max), (40, 6, 2, 65535));
+        let expected: Vec<u16> = (0..6u32).flat_map(|i| (0..40u32).flat_map(move |j| {
+            [((j * 1000 + i * 77) % 65536) as u16, if j % 3 == 0 { 30000 } else { 65535 }]
+        })).collect();
+        assert_eq!(decoded.samples, expected);
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        for color in [ColorType::Rgb, ColorType::Rgba] {
+            let pixels: Vec<u8> = (0..300 * 70 * color.channels()).map(|i| (i * 7 % 253) as u8).collect();
+            let mut bytes = vec![];
+            crate::png::encode(300, 70, color, &pixels, &mut bytes).unwrap();
+            let decoded = crate::png::decode(&bytes).unwrap();
+            assert_eq!((decoded.width, decoded.height, decoded.channels), (300, 70, color.channels()));
+            assert!(decoded.samples.iter().map(|&s| s as u8).eq(pixels.iter().copied()));
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(crate::png::decode(b"P6\n1 1\n255\n"), Err(DecodeError::NotPng));
+        assert_eq!(crate::png::decode(&FILTERS_RGB8[..60]), Err(DecodeError::Truncated));
+
+        let mut corrupt = FILTERS_RGB8.to_vec();
+        corrupt[20] ^= 1;
+        assert_eq!(crate::png::decode(&corrupt), Err(DecodeError::BadChecksum(*b"IHDR")));
+
+        // A palette image, with the IHDR checksum fixed up
+        let mut palette = FILTERS_RGB8.to_vec();
+        palette[25] = 3;
+        let mut crc = Crc32::new();
+        crc.update(&palette[12..29]);
+        palette[29..33].copy_from_slice(&crc.finish().to_be_bytes());
+        let err = crate::png::decode(&palette).unwrap_err();
+        assert_eq!(err, DecodeError::Unsupported("palette images".to_string()));
+        assert_eq!(err.to_string(), "unsupported PNG: palette images");
+    }
+
+    #[test]
+    fn test_large_data_spans_several_blocks() {
+        let width = 200;
+        let height = 120;
+        let rgb: Vec<u8> = (0..width * height * 3).map(|i| (i % 251) as u8).collect();
+        let mut bytes = vec![];
+        crate::png::encode(width, height, ColorType::Rgb, &rgb, &mut bytes).unwrap();
+
+        let (w, h, pixels) = decode(&bytes);
+        assert_eq!((w, h), (width, height));
+        assert_eq!(pixels, rgb);
+    }
+}
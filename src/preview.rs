@@ -0,0 +1,173 @@
+// Live preview: the image is rendered in passes of a few samples per pixel and every pass is shown
+// as soon as it's done. produce_frames makes the frames and works without a window, run_window
+// (with the preview feature) shows them.
+
+use std::ops::Range;
+use std::sync::mpsc::Sender;
+use crate::accumulator::Accumulator;
+use crate::camera::{CancelToken, Renderer};
+use crate::image::Image;
+use crate::scene::Scene;
+
+// The image after a pass, ready for a window
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub samples: u32, // Samples per pixel so far
+    pub rgba: Vec<u8>,
+}
+
+// Sample ranges of the passes, 1, 1, 2, 4, ... samples per pixel up to max_pass each, so the first
+// frames come quickly and the later ones don't cost much overhead
+pub fn passes(samples_per_pixel: u32, max_pass: u32) -> Vec<Range<u32>> {
+    let max_pass = max_pass.max(1);
+    let mut passes = vec![];
+    let mut start = 0;
+    while start < samples_per_pixel {
+        let size = start.clamp(1, max_pass).min(samples_per_pixel - start);
+        passes.push(start..start + size);
+        start += size;
+    }
+    passes
+}
+
+// Renders the passes in order and sends a frame after each. Stops early when the token is
+// cancelled or nobody receives the frames anymore. The samples of the finished passes are
+// returned, e.g. to save what was rendered before cancelling.
+pub fn produce_frames(
+    renderer: &Renderer,
+    scene: &Scene,
+    max_pass: u32,
+    cancel: &CancelToken,
+    frames: &Sender<Frame>
+) -> Accumulator {
+    let (width, height) = (renderer.width(), renderer.height());
+    let mut accumulator = Accumulator::new(width, height);
+    for pass in passes(renderer.settings().samples_per_pixel, max_pass) {
+        let end = pass.end;
+        if !renderer.accumulate_cancellable(scene, pass, &mut accumulator, cancel).unwrap() {
+            break;
+        }
+        let frame = Frame { width, height, samples: end, rgba: accumulator.to_ppm().to_rgba8() };
+        if frames.send(frame).is_err() {
+            break;
+        }
+    }
+    accumulator
+}
+
+// RGBA bytes as 0RGB words, the pixel format of the window buffer
+pub fn to_0rgb(rgba: &[u8]) -> Vec<u32> {
+    rgba.chunks(4).map(|px| u32::from_be_bytes([0, px[0], px[1], px[2]])).collect()
+}
+
+// Opens a window of the render size and shows every pass while it renders on the renderer's
+// threads. Esc or closing the window cancels the render, otherwise the window stays open with the
+// finished image until it's closed. Returns the samples rendered so far either way.
+#[cfg(feature = "preview")]
+pub fn run_window(renderer: &Renderer, scene: std::sync::Arc<Scene>, max_pass: u32) -> std::io::Result<Accumulator> {
+    use minifb::{Key, Window, WindowOptions};
+
+    let (width, height) = (renderer.width(), renderer.height());
+    let total = renderer.settings().samples_per_pixel;
+    let mut window = Window::new("raytracer", width, height, WindowOptions::default()).map_err(std::io::Error::other)?;
+    window.set_target_fps(30);
+    let mut buffer = vec![0; width * height];
+    let cancel = CancelToken::new();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::scope(|s| {
+        let producer = s.spawn(|| produce_frames(renderer, &scene, max_pass, &cancel, &sender));
+        let mut result = Ok(());
+        while window.is_open() && !window.is_key_down(Key::Escape) {
+            // Passes can finish faster than frames are shown, only the last one counts
+            if let Some(frame) = receiver.try_iter().last() {
+                buffer = to_0rgb(&frame.rgba);
+                window.set_title(&format!("raytracer - {}/{} samples", frame.samples, total));
+            }
+            if let Err(e) = window.update_with_buffer(&buffer, width, height) {
+                result = Err(std::io::Error::other(e));
+                break;
+            }
+        }
+        cancel.cancel();
+        let accumulator = producer.join().unwrap();
+        result.map(|_| accumulator)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use na::point;
+    use crate::camera::{Camera, CancelToken};
+    use crate::material::Lambertian;
+    use crate::preview::{passes, produce_frames, to_0rgb};
+    use crate::RGB;
+    use crate::scene::{Scene, Sphere};
+
+    fn renderer(samples: u32) -> crate::camera::Renderer {
+        Camera::builder()
+            .dimensions(8, 6)
+            .samples_per_pixel(samples)
+            .max_bounces(4)
+            .look_from(point![0.0, 0.0, 1.0])
+            .look_at(point![0.0, 0.0, -1.0])
+            .seed(3)
+            .build()
+            .unwrap()
+            .renderer()
+    }
+
+    fn scene() -> Arc<Scene> {
+        let mut scene = Scene::new();
+        scene.add(Sphere { center: point![0.0, 0.0, -1.0], radius: 0.5, material: Lambertian::new(RGB(0.5, 0.2, 0.2)).into() });
+        Arc::new(scene)
+    }
+
+    #[test]
+    fn test_passes() {
+        assert_eq!(passes(10, 4), vec![0..1, 1..2, 2..4, 4..8, 8..10]);
+        assert_eq!(passes(3, 0), vec![0..1, 1..2, 2..3]);
+        assert!(passes(0, 4).is_empty());
+    }
+
+    #[test]
+    fn test_produce_frames() {
+        let renderer = renderer(12);
+        let (sender, receiver) = channel();
+        let accumulator = produce_frames(&renderer, &scene(), 4, &CancelToken::new(), &sender);
+        drop(sender);
+        let frames: Vec<_> = receiver.iter().collect();
+        let samples: Vec<_> = frames.iter().map(|frame| frame.samples).collect();
+        assert_eq!(samples, vec![1, 2, 4, 8, 12]);
+        assert!(frames.iter().all(|frame| (frame.width, frame.height, frame.rgba.len()) == (8, 6, 8 * 6 * 4)));
+        assert_eq!(accumulator.count(3, 4), 12);
+        // Later passes add to the earlier ones, the last frame is the whole render
+        let mut expected = crate::accumulator::Accumulator::new(8, 6);
+        renderer.render_samples_into(scene(), 0..12, &mut expected, false).unwrap();
+        assert!((accumulator.sum(3, 4) - expected.sum(3, 4)).norm() < 1e-4);
+    }
+
+    #[test]
+    fn test_produce_frames_stops() {
+        let renderer = renderer(8);
+        let (sender, receiver) = channel();
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let accumulator = produce_frames(&renderer, &scene(), 4, &cancel, &sender);
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(accumulator.count(0, 0), 0);
+
+        // Nobody watching anymore
+        drop(receiver);
+        let accumulator = produce_frames(&renderer, &scene(), 4, &CancelToken::new(), &sender);
+        assert_eq!(accumulator.count(0, 0), 1);
+    }
+
+    #[test]
+    fn test_to_0rgb() {
+        assert_eq!(to_0rgb(&[0x12, 0x34, 0x56, 0xff, 1, 2, 3, 0]), vec![0x123456, 0x010203]);
+    }
+}
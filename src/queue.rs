@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::camera::{Camera, CameraBuilder, CameraError};
+use crate::cli::OutputFormat;
+use crate::image::PPM;
+use crate::scene::Scene;
+
+// Several named views of one scene rendered in a single run. The scene is shared by all of them,
+// every view has its own camera with its own size and sample settings.
+pub struct RenderQueue {
+    scene: Arc<Scene>,
+    views: Vec<(String, CameraBuilder)>,
+    output: Option<String>, // Path template, {name} becomes the view name
+    max_concurrent: usize,
+    progress: Option<ProgressCallback>,
+}
+
+pub type ProgressCallback = Box<dyn Fn(&JobEvent) + Send + Sync>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum JobEvent {
+    Started { view: String },
+    // done out of total views are finished, path is where the image was saved
+    Finished { view: String, path: Option<PathBuf>, done: usize, total: usize },
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    DuplicateView(String),
+    Camera { view: String, error: CameraError },
+    // The template has no {name}, so every view would be saved to the same file
+    NoNamePlaceholder(String),
+    UnknownFormat(PathBuf),
+    Io { path: PathBuf, error: std::io::Error },
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueError::DuplicateView(view) => write!(f, "there is already a view named '{}'", view),
+            QueueError::Camera { view, error } => write!(f, "view '{}': {}", view, error),
+            QueueError::NoNamePlaceholder(template) => write!(f, "no {{name}} in output template {}", template),
+            QueueError::UnknownFormat(path) => write!(f, "can't tell the image format of '{}'", path.display()),
+            QueueError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl Error for QueueError {}
+
+impl RenderQueue {
+    pub fn new(scene: Arc<Scene>) -> Self {
+        Self { scene, views: vec![], output: None, max_concurrent: 1, progress: None }
+    }
+
+    pub fn scene(&self) -> &Arc<Scene> {
+        &self.scene
+    }
+
+    pub fn add_view(&mut self, name: &str, camera: CameraBuilder) -> Result<(), QueueError> {
+        if self.views.iter().any(|(view, _)| view == name) {
+            return Err(QueueError::DuplicateView(name.to_string()));
+        }
+        self.views.push((name.to_string(), camera));
+        Ok(())
+    }
+
+    // Saves every image to the template with {name} replaced by the view name, e.g.
+    // "out/{name}.png". The format comes from the extension like on the command line.
+    pub fn output(mut self, template: &str) -> Self {
+        self.output = Some(template.to_string());
+        self
+    }
+
+    // Views rendered at the same time, 1 renders them one after the other. Each render uses the
+    // whole rayon pool either way.
+    pub fn max_concurrent(mut self, jobs: usize) -> Self {
+        self.max_concurrent = jobs.max(1);
+        self
+    }
+
+    // Called from the thread rendering the view
+    pub fn on_progress(mut self, progress: impl Fn(&JobEvent) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    pub fn output_path(&self, view: &str) -> Option<PathBuf> {
+        self.output.as_ref().map(|template| PathBuf::from(template.replace("{name}", view)))
+    }
+
+    // Renders all views and returns the images by view name. Every camera and the output
+    // template are checked before anything is rendered.
+    pub fn run(&self) -> Result<BTreeMap<String, Box<PPM>>, QueueError> {
+        let mut jobs = vec![];
+        for (view, camera) in &self.views {
+            let camera = camera.clone().build()
+                .map_err(|error| QueueError::Camera { view: view.clone(), error })?;
+            let output = match self.output_path(view) {
+                Some(path) => {
+                    let format = OutputFormat::from_path(&path).ok_or_else(|| QueueError::UnknownFormat(path.clone()))?;
+                    Some((path, format))
+                },
+                None => None,
+            };
+            jobs.push((view.as_str(), camera, output));
+        }
+        if let Some(template) = &self.output {
+            if self.views.len() > 1 && !template.contains("{name}") {
+                return Err(QueueError::NoNamePlaceholder(template.clone()));
+            }
+        }
+
+        let next = AtomicUsize::new(0);
+        let done = AtomicUsize::new(0);
+        let results = Mutex::new(BTreeMap::new());
+        let first_error = Mutex::new(None);
+        std::thread::scope(|s| {
+            for _ in 0..self.max_concurrent.min(jobs.len()) {
+                s.spawn(|| {
+                    while let Some((view, camera, output)) = jobs.get(next.fetch_add(1, Ordering::SeqCst)) {
+                        if first_error.lock().unwrap().is_some() {
+                            return;
+                        }
+                        match self.render_job(view, camera, output, &done, jobs.len()) {
+                            Ok(image) => {
+                                results.lock().unwrap().insert(view.to_string(), image);
+                            },
+                            Err(e) => {
+                                first_error.lock().unwrap().get_or_insert(e);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        match first_error.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(results.into_inner().unwrap()),
+        }
+    }
+
+    fn render_job(
+        &self,
+        view: &str,
+        camera: &Camera,
+        output: &Option<(PathBuf, OutputFormat)>,
+        done: &AtomicUsize,
+        total: usize
+    ) -> Result<Box<PPM>, QueueError> {
+        self.report(&JobEvent::Started { view: view.to_string() });
+        let image = camera.renderer().render_parallel(self.scene.clone());
+        if let Some((path, format)) = output {
+            let io_error = |error| QueueError::Io { path: path.clone(), error };
+            let mut file = std::fs::File::create(path).map_err(io_error)?;
+            format.save(&image, &mut file).map_err(io_error)?;
+        }
+        self.report(&JobEvent::Finished {
+            view: view.to_string(),
+            path: output.as_ref().map(|(path, _)| path.clone()),
+            done: done.fetch_add(1, Ordering::SeqCst) + 1,
+            total,
+        });
+        Ok(image)
+    }
+
+    fn report(&self, event: &JobEvent) {
+        if let Some(progress) = &self.progress {
+            progress(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use na::{point, vector};
+    use crate::camera::Camera;
+    use crate::image::Image;
+    use crate::material::Lambertian;
+    use crate::queue::{JobEvent, QueueError, RenderQueue};
+    use crate::RGB;
+    use crate::scene::Scene;
+
+    fn two_views() -> (Arc<Scene>, RenderQueue) {
+        let mut scene = Scene::new();
+        scene.add_sphere(point![0.0, 0.0, -1.0], 0.5, Lambertian::new(RGB(0.5, 0.5, 0.5)));
+        let scene = Arc::new(scene);
+
+        let mut queue = RenderQueue::new(scene.clone());
+        let tiny = Camera::builder().samples_per_pixel(2).max_bounces(2).transparent_background(true);
+        queue.add_view("front", tiny.clone().dimensions(12, 8)).unwrap();
+        let top = tiny.dimensions(6, 6).look_from(point![0.0, 1.5, -1.0]).look_at(point![0.0, 0.0, -1.0]).vup(vector![0.0, 0.0, -1.0]);
+        queue.add_view("top", top).unwrap();
+        (scene, queue)
+    }
+
+    #[test]
+    fn test_views_share_the_scene() {
+        let (scene, queue) = two_views();
+        assert!(Arc::ptr_eq(queue.scene(), &scene));
+
+        let dir = std::env::temp_dir().join(format!("raytracer_queue_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let template = dir.join("shot_{name}.ppm");
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        let queue = queue
+            .output(template.to_str().unwrap())
+            .max_concurrent(2)
+            .on_progress(move |event| recorded.lock().unwrap().push(event.clone()));
+
+        let images = queue.run().unwrap();
+        assert_eq!(images.keys().collect::<Vec<_>>(), ["front", "top"]);
+        assert_eq!((images["front"].width(), images["front"].height()), (12, 8));
+        assert_eq!((images["top"].width(), images["top"].height()), (6, 6));
+        // Both views see the sphere in the middle
+        assert!(images["front"].alpha(4, 6) > 0.0 && images["top"].alpha(3, 3) > 0.0);
+
+        // All renders have let go of the scene again
+        assert_eq!(Arc::strong_count(&scene), 2);
+
+        assert_eq!(queue.output_path("top").unwrap(), dir.join("shot_top.ppm"));
+        assert!(dir.join("shot_front.ppm").exists() && dir.join("shot_top.ppm").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        let finished: Vec<_> = events.iter().filter_map(|event| match event {
+            JobEvent::Finished { done, total, .. } => Some((*done, *total)),
+            JobEvent::Started { .. } => None,
+        }).collect();
+        assert_eq!(finished, [(1, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn test_queue_errors() {
+        let (_, mut queue) = two_views();
+        assert!(matches!(queue.add_view("top", Camera::builder()), Err(QueueError::DuplicateView(_))));
+        queue.add_view("broken", Camera::builder().fov(0.0)).unwrap();
+        assert!(matches!(queue.run(), Err(QueueError::Camera { view, .. }) if view == "broken"));
+
+        let (_, queue) = two_views();
+        assert!(matches!(queue.output("same.png").run(), Err(QueueError::NoNamePlaceholder(_))));
+        let (_, queue) = two_views();
+        assert!(matches!(queue.output("{name}.jpg").run(), Err(QueueError::UnknownFormat(_))));
+    }
+}
@@ -1,18 +1,57 @@
 extern crate nalgebra as na;
+#[cfg(feature = "packets")]
+pub mod packet;
+
 use na::{Point3, Vector3};
+use crate::Float;
+use crate::spectral::Wavelengths;
 
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct Ray {
-    pub orig: Point3<f64>,
-    pub dir: Vector3<f64>,
+    pub orig: Point3<Float>,
+    pub dir: Vector3<Float>,
+    pub time: Float, // Moment within the shutter interval, only moving objects care
+    // Camera rays with RenderSettings::ray_differentials only, bounces start without
+    pub differentials: Option<RayDifferentials>,
+    // Paths of spectral renders only, bounces carry them on
+    pub wavelengths: Option<Wavelengths>,
+}
+
+// The same camera sample one pixel to the right and one pixel down. Where they meet the surface
+// around a hit tells how much of it the pixel covers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RayDifferentials {
+    pub x_orig: Point3<Float>,
+    pub x_dir: Vector3<Float>,
+    pub y_orig: Point3<Float>,
+    pub y_dir: Vector3<Float>,
+}
+
+impl RayDifferentials {
+    // Where both rays meet the plane through p with the given normal, None if one runs parallel
+    // to it
+    pub fn plane_points(&self, p: &Point3<Float>, normal: &Vector3<Float>) -> Option<[Point3<Float>; 2]> {
+        let meet = |orig: &Point3<Float>, dir: &Vector3<Float>| {
+            let denominator = normal.dot(dir);
+            if denominator.abs() < 1e-12 {
+                return None;
+            }
+            Some(orig + dir * (normal.dot(&(p - orig)) / denominator))
+        };
+        Some([meet(&self.x_orig, &self.x_dir)?, meet(&self.y_orig, &self.y_dir)?])
+    }
 }
 
 impl Ray {
-    pub fn new(orig: Point3<f64>, dir: Vector3<f64>) -> Self {
-        Self { orig, dir }
+    pub fn new(orig: Point3<Float>, dir: Vector3<Float>) -> Self {
+        Self { orig, dir, time: 0.0, differentials: None, wavelengths: None }
+    }
+
+    pub fn new_at_time(orig: Point3<Float>, dir: Vector3<Float>, time: Float) -> Self {
+        Self { orig, dir, time, differentials: None, wavelengths: None }
     }
 
-    pub fn at(&self, t: f64) -> Point3<f64> {
+    pub fn at(&self, t: Float) -> Point3<Float> {
         self.orig + t * self.dir
     }
 }
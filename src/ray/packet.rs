@@ -0,0 +1,78 @@
+use crate::Float;
+use crate::Ray;
+
+// SIMD vector of Float with one element per lane
+#[cfg(not(feature = "f32"))]
+pub type FloatX4 = wide::f64x4;
+#[cfg(feature = "f32")]
+pub type FloatX4 = wide::f32x4;
+
+pub const LANES: usize = 4;
+
+// Four rays traced together, with origins and directions split into one vector per coordinate.
+// Lanes without a ray are inactive, tests skip them and report no hit.
+pub struct RayPacket4 {
+    rays: [Option<Ray>; LANES],
+    pub ox: FloatX4,
+    pub oy: FloatX4,
+    pub oz: FloatX4,
+    pub dx: FloatX4,
+    pub dy: FloatX4,
+    pub dz: FloatX4,
+    active: i32,
+}
+
+impl RayPacket4 {
+    pub fn new(rays: [Option<Ray>; LANES]) -> Self {
+        // Inactive lanes get a zero ray, its NaN results are masked off anyway
+        let lane = |coordinate: fn(&Ray) -> Float| {
+            FloatX4::new(std::array::from_fn(|idx| rays[idx].as_ref().map_or(0.0, coordinate)))
+        };
+        Self {
+            ox: lane(|ray| ray.orig.x),
+            oy: lane(|ray| ray.orig.y),
+            oz: lane(|ray| ray.orig.z),
+            dx: lane(|ray| ray.dir.x),
+            dy: lane(|ray| ray.dir.y),
+            dz: lane(|ray| ray.dir.z),
+            active: (0..LANES).filter(|&idx| rays[idx].is_some()).fold(0, |mask, idx| mask | 1 << idx),
+            rays,
+        }
+    }
+
+    pub fn ray(&self, lane: usize) -> Option<&Ray> {
+        self.rays[lane].as_ref()
+    }
+
+    pub fn is_active(&self, lane: usize) -> bool {
+        self.rays[lane].is_some()
+    }
+
+    // Bit i is set for an active lane i, comparable to the move_mask of a vector
+    pub fn active_mask(&self) -> i32 {
+        self.active
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na::{point, vector};
+    use crate::Ray;
+    use crate::ray::packet::RayPacket4;
+
+    #[test]
+    fn test_lanes() {
+        let packet = RayPacket4::new([
+            Some(Ray::new(point![1.0, 2.0, 3.0], vector![0.0, 0.0, -1.0])),
+            None,
+            Some(Ray::new(point![4.0, 5.0, 6.0], vector![1.0, 0.0, 0.0])),
+            None,
+        ]);
+        assert_eq!(packet.ox.to_array(), [1.0, 0.0, 4.0, 0.0]);
+        assert_eq!(packet.dz.to_array(), [-1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(packet.active_mask(), 0b0101);
+        assert!(packet.is_active(2) && !packet.is_active(3));
+        assert_eq!(packet.ray(2).unwrap().orig, point![4.0, 5.0, 6.0]);
+        assert!(packet.ray(1).is_none());
+    }
+}
@@ -1,92 +1,1343 @@
-use std::ops::{Range};
+pub mod csg;
+pub mod desc;
+pub mod generators;
+pub mod loader;
+pub mod sphere_list;
+#[cfg(feature = "gltf")]
+pub mod gltf_import;
+
+use crate::consts::PI;
 use std::sync::Arc;
+use crate::aabb::Aabb;
+use crate::Float;
+use crate::interval::Interval;
 use crate::Ray;
+use crate::RGB;
+#[cfg(feature = "packets")]
+use crate::ray::packet::{FloatX4, LANES, RayPacket4};
+#[cfg(feature = "packets")]
+use wide::{CmpGe, CmpLt};
 use na::{Point3, Vector3};
-use crate::material::Material;
+use crate::material::{Material, MaterialKind};
+use crate::material::registry::{MaterialRef, MaterialRegistry};
+use crate::scene::desc::HittableDesc;
+use crate::utils::{offset_origin, rand, rand_unit_vector};
 
-pub struct HitRecord {
-    pub p: Point3<f64>,
-    pub normal: Vector3<f64>,
-    pub t: f64,
+// Borrows the material from the object that was hit, so finding hits costs no refcounting
+pub struct HitRecord<'a> {
+    pub p: Point3<Float>,
+    // Shading normal, against the ray like geometric_normal
+    pub normal: Vector3<Float>,
+    // Normal of the actual surface, rays leaving it are offset along this one
+    pub geometric_normal: Vector3<Float>,
+    pub t: Float,
     pub front: bool,
-    pub material: Arc<dyn Material>
+    pub material: &'a MaterialKind,
+    // Set by the scene the object is in, None for hits on objects outside of a scene
+    pub object: Option<ObjectId>,
+    // Texture coordinates of the hit point
+    pub u: Float,
+    pub v: Float,
+    // From the ray differentials, if the ray has them
+    pub uv_derivatives: Option<UvDerivatives>,
+}
+
+// How far the texture coordinates move from the pixel of a hit to the next one to the right (x)
+// and down (y)
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct UvDerivatives {
+    pub du_dx: Float,
+    pub dv_dx: Float,
+    pub du_dy: Float,
+    pub dv_dy: Float,
+}
+
+impl UvDerivatives {
+    // From the texture coordinates uv_at gives where the ray differentials meet the tangent plane
+    // of the hit at p
+    fn new(ray: &Ray, p: &Point3<Float>, normal: &Vector3<Float>, (u, v): (Float, Float), uv_at: impl Fn(&Point3<Float>) -> (Float, Float)) -> Option<Self> {
+        let [px, py] = ray.differentials?.plane_points(p, normal)?;
+        let ((ux, vx), (uy, vy)) = (uv_at(&px), uv_at(&py));
+        Some(Self { du_dx: ux - u, dv_dx: vx - v, du_dy: uy - u, dv_dy: vy - v })
+    }
+
+    // Width of the part of the texture a pixel covers, in texture coordinates
+    pub fn footprint(&self) -> Float {
+        self.du_dx.hypot(self.dv_dx).max(self.du_dy.hypot(self.dv_dy))
+    }
+}
+
+impl HitRecord<'_> {
+    // Ray leaving the hit point in direction. Reflected and transmitted rays start on opposite
+    // sides of the surface. The time and wavelengths of ray carry over.
+    pub fn spawn_ray(&self, ray: &Ray, direction: Vector3<Float>) -> Ray {
+        let distance = self.t * ray.dir.norm();
+        let orig = offset_origin(self.p, &self.geometric_normal, &direction, distance);
+        Ray { wavelengths: ray.wavelengths, ..Ray::new_at_time(orig, direction, ray.time) }
+    }
+}
+
+// A point picked uniformly over the surface of an object, used to emit light from it
+pub struct SurfaceSample {
+    pub p: Point3<Float>,
+    pub normal: Vector3<Float>,
+    pub area: Float,
+    pub material: MaterialKind
+}
+
+// Work it took to find the closest hit of a ray, see Hittable::hit_counted
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TraversalStats {
+    pub intersection_tests: u32, // Ray tests against single primitives
+    pub node_visits: u32, // Objects of scenes and bounding boxes looked at on the way
 }
 
 pub trait Hittable: Sync + Send {
-    fn hit(&self, ray: &Ray, trange: Range<f64>) -> Option<HitRecord>;
+    // Closest hit with t strictly inside trange, see Interval::surrounds
+    fn hit(&self, ray: &Ray, trange: Interval) -> Option<HitRecord<'_>>;
+
+    // hit that also adds the work it takes to stats, e.g. for a heatmap. Objects that don't
+    // count for themselves are a single intersection test.
+    fn hit_counted(&self, ray: &Ray, trange: Interval, stats: &mut TraversalStats) -> Option<HitRecord<'_>> {
+        stats.intersection_tests += 1;
+        self.hit(ray, trange)
+    }
+
+    // hit for every active lane of the packet, each with its own trange. Objects without a
+    // vectorized test go through the lanes one by one.
+    #[cfg(feature = "packets")]
+    fn hit4(&self, packet: &RayPacket4, tranges: [Interval; LANES]) -> [Option<HitRecord<'_>>; LANES] {
+        std::array::from_fn(|lane| packet.ray(lane).and_then(|ray| self.hit(ray, tranges[lane])))
+    }
+
+    // Whether the object is the surface of a volume, with front hits on the way in and back hits
+    // on the way out. Only solids can be combined with CSG, see csg.
+    fn is_solid(&self) -> bool {
+        false
+    }
+
+    // Every hit with t inside trange, closest first. Objects without a test of their own are
+    // searched again after each hit.
+    fn hit_all(&self, ray: &Ray, trange: Interval) -> Vec<HitRecord<'_>> {
+        let mut hits: Vec<HitRecord<'_>> = vec![];
+        while let Some(hit) = self.hit(ray, Interval::new(hits.last().map_or(trange.min, |last| last.t), trange.max)) {
+            hits.push(hit);
+        }
+        hits
+    }
+
+    fn sample_surface(&self) -> Option<SurfaceSample> {
+        None
+    }
+
+    // Plain data version for saving, None if the object or its material can't be saved
+    fn to_desc(&self) -> Option<HittableDesc> {
+        None
+    }
+
+    // Box around everything the object can be hit on, None if there is no finite one
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
 }
 
+#[derive(Clone)]
 pub struct Sphere {
-    pub center: Point3<f64>,
-    pub radius: f64,
-    pub material: Arc<dyn Material>,
+    pub center: Point3<Float>,
+    pub radius: Float,
+    pub material: MaterialKind,
 }
 
-impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, trange: Range<f64>) -> Option<HitRecord> {
-        let oc = ray.orig - self.center;
-        let a = ray.dir.norm_squared(); // ray.dir.dot(&ray.dir);
-        let half_b = oc.dot(&ray.dir);
-        let c = oc.norm_squared() - self.radius * self.radius;
-        let discriminant = half_b * half_b - a * c;
-        if discriminant < 0.0 {
+// Roots of |oc + t dir| = radius in increasing order, None if the line misses the sphere.
+// The stable form from Ray Tracing Gems, chapter 7: the discriminant comes from the distance
+// between the center and the line, which doesn't cancel out for a distant sphere, and the root
+// of smaller magnitude from the product of the roots rather than a difference of close values.
+pub(crate) fn sphere_roots(oc: Vector3<Float>, dir: &Vector3<Float>, radius: Float) -> Option<(Float, Float)> {
+    let a = dir.norm_squared();
+    let half_b = oc.dot(dir);
+    let c = oc.norm_squared() - radius * radius;
+    let perpendicular = oc - (half_b / a) * dir;
+    let discriminant = a * (radius * radius - perpendicular.norm_squared());
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let q = -(half_b + discriminant.sqrt().copysign(half_b));
+    let (t0, t1) = (c / q, q / a);
+    Some(if t0 < t1 { (t0, t1) } else { (t1, t0) })
+}
+
+fn hit_sphere<'a>(
+    center: Point3<Float>,
+    radius: Float,
+    material: &'a MaterialKind,
+    ray: &Ray,
+    trange: Interval
+) -> Option<HitRecord<'a>> {
+    let (near, far) = sphere_roots(ray.orig - center, &ray.dir, radius)?;
+
+    // Try both roots
+    let root = if trange.surrounds(near) {
+        near
+    } else if trange.surrounds(far) {
+        far
+    } else {
+        return None;
+    };
+
+    let hitpoint = ray.at(root);
+    let normal = (hitpoint - center) / radius;
+    let outside = ray.dir.dot(&normal) < 0.0;
+    let (u, v) = sphere_uv(&normal);
+    let uv_derivatives = ray.differentials.and_then(|_| {
+        // The longitude of a neighbor across the seam is taken on the same side as u
+        UvDerivatives::new(ray, &hitpoint, &normal, (u, v), |p| {
+            let (neighbor_u, neighbor_v) = sphere_uv(&(p - center).normalize());
+            (u + (neighbor_u - u) - (neighbor_u - u).round(), neighbor_v)
+        })
+    });
+    let hit = HitRecord {
+        t: root,
+        p: hitpoint,
+        normal: if outside { normal } else { -normal },
+        geometric_normal: if outside { normal } else { -normal },
+        front: outside,
+        material,
+        object: None,
+        u,
+        v,
+        uv_derivatives,
+    };
+    Some(hit)
+}
+
+// Longitude around y from -x and latitude from the bottom of the point of the unit sphere
+fn sphere_uv(normal: &Vector3<Float>) -> (Float, Float) {
+    let theta = (-normal.y).clamp(-1.0, 1.0).acos();
+    let phi = (-normal.z).atan2(normal.x) + PI;
+    (phi / (2.0 * PI), theta / PI)
+}
+
+// Finds the lanes that hit with the arithmetic of hit_sphere on all four rays at once, then
+// builds their records with hit_sphere itself, so every lane matches a scalar test exactly
+#[cfg(feature = "packets")]
+fn hit_sphere4<'a>(
+    center: Point3<Float>,
+    radius: Float,
+    material: &'a MaterialKind,
+    packet: &RayPacket4,
+    tranges: [Interval; LANES]
+) -> [Option<HitRecord<'a>>; LANES] {
+    let (ocx, ocy, ocz) = (packet.ox - center.x, packet.oy - center.y, packet.oz - center.z);
+    let (dx, dy, dz) = (packet.dx, packet.dy, packet.dz);
+    let r2 = FloatX4::splat(radius * radius);
+    let a = dx * dx + dy * dy + dz * dz;
+    let half_b = ocx * dx + ocy * dy + ocz * dz;
+    let c = (ocx * ocx + ocy * ocy + ocz * ocz) - r2;
+    let scale = half_b / a;
+    let (px, py, pz) = (ocx - scale * dx, ocy - scale * dy, ocz - scale * dz);
+    let discriminant = a * (r2 - (px * px + py * py + pz * pz));
+    // Most rays miss most spheres, skip the square root and divisions then
+    if discriminant.cmp_ge(FloatX4::ZERO).move_mask() & packet.active_mask() == 0 {
+        return Default::default();
+    }
+
+    // A negative discriminant gives NaN roots, which fail every comparison. Which root is the
+    // near one doesn't matter for finding the lanes that hit.
+    let sqrtd = discriminant.sqrt();
+    let q = -(half_b + half_b.cmp_lt(FloatX4::ZERO).blend(-sqrtd, sqrtd));
+    let (near, far) = (c / q, q / a);
+    let min = FloatX4::new(tranges.map(|trange| trange.min));
+    let max = FloatX4::new(tranges.map(|trange| trange.max));
+    let surrounds = |root: FloatX4| min.cmp_lt(root) & root.cmp_lt(max);
+    let hits = (surrounds(near) | surrounds(far)).move_mask() & packet.active_mask();
+    if hits == 0 {
+        return Default::default();
+    }
+    std::array::from_fn(|lane| {
+        if hits & (1 << lane) == 0 {
             return None;
         }
+        hit_sphere(center, radius, material, packet.ray(lane)?, tranges[lane])
+    })
+}
 
-        let sqrtd = discriminant.sqrt();
-        let mut root = (-half_b - sqrtd) / a;
+impl Hittable for Sphere {
+    fn hit(&self, ray: &Ray, trange: Interval) -> Option<HitRecord<'_>> {
+        hit_sphere(self.center, self.radius, &self.material, ray, trange)
+    }
 
-        // Try both roots
-        if root <= trange.start || root >= trange.end {
-            root = (-half_b + sqrtd) / a;
-            if root <= trange.start || root >= trange.end {
-                return None;
-            }
+    #[cfg(feature = "packets")]
+    fn hit4(&self, packet: &RayPacket4, tranges: [Interval; LANES]) -> [Option<HitRecord<'_>>; LANES] {
+        hit_sphere4(self.center, self.radius, &self.material, packet, tranges)
+    }
+
+    // A negative radius turns the sphere inside out
+    fn is_solid(&self) -> bool {
+        self.radius > 0.0
+    }
+
+    fn sample_surface(&self) -> Option<SurfaceSample> {
+        let normal = rand_unit_vector();
+        Some(SurfaceSample {
+            p: self.center + self.radius * normal,
+            normal,
+            area: 4.0 * PI * self.radius * self.radius,
+            material: self.material.clone(),
+        })
+    }
+
+    fn to_desc(&self) -> Option<HittableDesc> {
+        Some(HittableDesc::Sphere {
+            center: self.center.coords.into(),
+            radius: self.radius,
+            material: self.material.to_desc()?,
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let r = Vector3::repeat(self.radius.abs());
+        Some(Aabb::new(self.center - r, self.center + r))
+    }
+}
+
+// Sphere moving along a straight line from center0 at time0 to center1 at time1
+#[derive(Clone)]
+pub struct MovingSphere {
+    pub center0: Point3<Float>,
+    pub center1: Point3<Float>,
+    pub time0: Float,
+    pub time1: Float,
+    pub radius: Float,
+    pub material: MaterialKind,
+}
+
+impl MovingSphere {
+    pub fn center(&self, time: Float) -> Point3<Float> {
+        if self.time1 == self.time0 {
+            return self.center0;
         }
+        let s = (time - self.time0) / (self.time1 - self.time0);
+        self.center0 + s * (self.center1 - self.center0)
+    }
+}
+
+impl Hittable for MovingSphere {
+    fn hit(&self, ray: &Ray, trange: Interval) -> Option<HitRecord<'_>> {
+        hit_sphere(self.center(ray.time), self.radius, &self.material, ray, trange)
+    }
+
+    fn is_solid(&self) -> bool {
+        self.radius > 0.0
+    }
 
-        let hitpoint = ray.at(root);
-        let normal = (hitpoint - self.center) / self.radius;
-        let outside = ray.dir.dot(&normal) < 0.0;
-        let hit = HitRecord {
-            t: root,
-            p: hitpoint,
+    fn sample_surface(&self) -> Option<SurfaceSample> {
+        let normal = rand_unit_vector();
+        let time = self.time0 + rand() * (self.time1 - self.time0);
+        Some(SurfaceSample {
+            p: self.center(time) + self.radius * normal,
+            normal,
+            area: 4.0 * PI * self.radius * self.radius,
+            material: self.material.clone(),
+        })
+    }
+
+    fn to_desc(&self) -> Option<HittableDesc> {
+        Some(HittableDesc::MovingSphere {
+            center0: self.center0.coords.into(),
+            center1: self.center1.coords.into(),
+            time0: self.time0,
+            time1: self.time1,
+            radius: self.radius,
+            material: self.material.to_desc()?,
+        })
+    }
+
+    // Covers the whole path of the sphere
+    fn bounding_box(&self) -> Option<Aabb> {
+        let r = Vector3::repeat(self.radius.abs());
+        Some(Aabb::new(self.center0 - r, self.center0 + r).union(&Aabb::new(self.center1 - r, self.center1 + r)))
+    }
+}
+
+// Triangle with optional per-vertex normals for smooth shading and texture coordinates.
+// Without normals it is flat shaded, without texture coordinates u and v are barycentric.
+#[derive(Clone)]
+pub struct Triangle {
+    pub vertices: [Point3<Float>; 3],
+    pub normals: Option<[Vector3<Float>; 3]>,
+    pub uvs: Option<[(Float, Float); 3]>,
+    pub material: MaterialKind,
+}
+
+impl Triangle {
+    // Texture coordinates at the barycentric coordinates b1, b2 of the second and third vertex,
+    // those two themselves without uvs
+    fn uv(&self, b1: Float, b2: Float) -> (Float, Float) {
+        let b0 = 1.0 - b1 - b2;
+        match self.uvs {
+            Some([uv0, uv1, uv2]) => (b0 * uv0.0 + b1 * uv1.0 + b2 * uv2.0, b0 * uv0.1 + b1 * uv1.1 + b2 * uv2.1),
+            None => (b1, b2),
+        }
+    }
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, ray: &Ray, trange: Interval) -> Option<HitRecord<'_>> {
+        // Moller-Trumbore
+        let [p0, p1, p2] = self.vertices;
+        let e1 = p1 - p0;
+        let e2 = p2 - p0;
+        let pvec = ray.dir.cross(&e2);
+        let det = e1.dot(&pvec);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let tvec = ray.orig - p0;
+        let b1 = tvec.dot(&pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let qvec = tvec.cross(&e1);
+        let b2 = ray.dir.dot(&qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = e2.dot(&qvec) * inv_det;
+        if !trange.surrounds(t) {
+            return None;
+        }
+
+        let b0 = 1.0 - b1 - b2;
+        let geometric = e1.cross(&e2).normalize();
+        let outside = ray.dir.dot(&geometric) < 0.0;
+        let normal = match self.normals {
+            Some([n0, n1, n2]) => (b0 * n0 + b1 * n1 + b2 * n2).normalize(),
+            None => geometric,
+        };
+        let (u, v) = self.uv(b1, b2);
+        let p = ray.at(t);
+        let uv_derivatives = ray.differentials.and_then(|_| {
+            UvDerivatives::new(ray, &p, &geometric, (u, v), |point| {
+                // Barycentric coordinates of a point in the plane of the triangle
+                let offset = point - p0;
+                let (d11, d12, d22) = (e1.dot(&e1), e1.dot(&e2), e2.dot(&e2));
+                let (d1, d2) = (offset.dot(&e1), offset.dot(&e2));
+                let denominator = d11 * d22 - d12 * d12;
+                self.uv((d22 * d1 - d12 * d2) / denominator, (d11 * d2 - d12 * d1) / denominator)
+            })
+        });
+        Some(HitRecord {
+            t,
+            p,
             normal: if outside { normal } else { -normal },
+            geometric_normal: if outside { geometric } else { -geometric },
             front: outside,
+            material: &self.material,
+            object: None,
+            u,
+            v,
+            uv_derivatives,
+        })
+    }
+
+    fn sample_surface(&self) -> Option<SurfaceSample> {
+        let [p0, p1, p2] = self.vertices;
+        let cross = (p1 - p0).cross(&(p2 - p0));
+        // Uniform over the triangle by folding the unit square
+        let (mut b1, mut b2) = (rand(), rand());
+        if b1 + b2 > 1.0 {
+            (b1, b2) = (1.0 - b1, 1.0 - b2);
+        }
+        Some(SurfaceSample {
+            p: p0 + b1 * (p1 - p0) + b2 * (p2 - p0),
+            normal: cross.normalize(),
+            area: cross.norm() / 2.0,
             material: self.material.clone(),
-        };
-        return Some(hit);
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(&self.vertices))
+    }
+}
+
+// The built-in objects in one type. A scene keeps them inline in one array, so finding hits runs
+// through a match the compiler can inline instead of a virtual call per object. Anything else
+// goes behind Custom.
+#[derive(Clone)]
+pub enum Primitive {
+    Sphere(Sphere),
+    MovingSphere(MovingSphere),
+    Triangle(Box<Triangle>), // Much larger than the spheres, which would otherwise grow to its size
+    Custom(Arc<dyn Hittable>),
+}
+
+impl Hittable for Primitive {
+    fn hit(&self, ray: &Ray, trange: Interval) -> Option<HitRecord<'_>> {
+        match self {
+            Primitive::Sphere(sphere) => sphere.hit(ray, trange),
+            Primitive::MovingSphere(sphere) => sphere.hit(ray, trange),
+            Primitive::Triangle(triangle) => triangle.hit(ray, trange),
+            Primitive::Custom(hittable) => hittable.hit(ray, trange),
+        }
+    }
+
+    fn hit_counted(&self, ray: &Ray, trange: Interval, stats: &mut TraversalStats) -> Option<HitRecord<'_>> {
+        match self {
+            Primitive::Custom(hittable) => hittable.hit_counted(ray, trange, stats),
+            _ => {
+                stats.intersection_tests += 1;
+                self.hit(ray, trange)
+            }
+        }
+    }
+
+    #[cfg(feature = "packets")]
+    fn hit4(&self, packet: &RayPacket4, tranges: [Interval; LANES]) -> [Option<HitRecord<'_>>; LANES] {
+        match self {
+            Primitive::Sphere(sphere) => sphere.hit4(packet, tranges),
+            Primitive::MovingSphere(sphere) => sphere.hit4(packet, tranges),
+            Primitive::Triangle(triangle) => triangle.hit4(packet, tranges),
+            Primitive::Custom(hittable) => hittable.hit4(packet, tranges),
+        }
+    }
+
+    fn is_solid(&self) -> bool {
+        match self {
+            Primitive::Sphere(sphere) => sphere.is_solid(),
+            Primitive::MovingSphere(sphere) => sphere.is_solid(),
+            Primitive::Triangle(triangle) => triangle.is_solid(),
+            Primitive::Custom(hittable) => hittable.is_solid(),
+        }
     }
+
+    fn hit_all(&self, ray: &Ray, trange: Interval) -> Vec<HitRecord<'_>> {
+        match self {
+            Primitive::Sphere(sphere) => sphere.hit_all(ray, trange),
+            Primitive::MovingSphere(sphere) => sphere.hit_all(ray, trange),
+            Primitive::Triangle(triangle) => triangle.hit_all(ray, trange),
+            Primitive::Custom(hittable) => hittable.hit_all(ray, trange),
+        }
+    }
+
+    fn sample_surface(&self) -> Option<SurfaceSample> {
+        match self {
+            Primitive::Sphere(sphere) => sphere.sample_surface(),
+            Primitive::MovingSphere(sphere) => sphere.sample_surface(),
+            Primitive::Triangle(triangle) => triangle.sample_surface(),
+            Primitive::Custom(hittable) => hittable.sample_surface(),
+        }
+    }
+
+    fn to_desc(&self) -> Option<HittableDesc> {
+        match self {
+            Primitive::Sphere(sphere) => sphere.to_desc(),
+            Primitive::MovingSphere(sphere) => sphere.to_desc(),
+            Primitive::Triangle(triangle) => triangle.to_desc(),
+            Primitive::Custom(hittable) => hittable.to_desc(),
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        match self {
+            Primitive::Sphere(sphere) => sphere.bounding_box(),
+            Primitive::MovingSphere(sphere) => sphere.bounding_box(),
+            Primitive::Triangle(triangle) => triangle.bounding_box(),
+            Primitive::Custom(hittable) => hittable.bounding_box(),
+        }
+    }
+}
+
+impl From<Sphere> for Primitive {
+    fn from(sphere: Sphere) -> Self {
+        Primitive::Sphere(sphere)
+    }
+}
+
+impl From<MovingSphere> for Primitive {
+    fn from(sphere: MovingSphere) -> Self {
+        Primitive::MovingSphere(sphere)
+    }
+}
+
+impl From<Triangle> for Primitive {
+    fn from(triangle: Triangle) -> Self {
+        Primitive::Triangle(Box::new(triangle))
+    }
+}
+
+// Any object behind an Arc stays a trait object, even a built-in one
+impl<H: Hittable + 'static> From<Arc<H>> for Primitive {
+    fn from(hittable: Arc<H>) -> Self {
+        Primitive::Custom(hittable)
+    }
+}
+
+impl From<Arc<dyn Hittable>> for Primitive {
+    fn from(hittable: Arc<dyn Hittable>) -> Self {
+        Primitive::Custom(hittable)
+    }
+}
+
+// Handle to an object in a scene. It stays valid until that object is removed, whatever happens
+// to the others, and never refers to a later object that reuses the slot.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ObjectId {
+    slot: u32,
+    generation: u32,
 }
 
+impl ObjectId {
+    // Color in object id images, objects added one after another get very different hues
+    pub fn color(&self) -> RGB {
+        let hue = (self.slot as Float * 0.618_034 + self.generation as Float * 0.1).fract() * 360.0;
+        RGB::from_hsv(hue, 0.8, if self.slot.is_multiple_of(2) { 0.95 } else { 0.7 })
+    }
+}
+
+struct Slot {
+    generation: u32,
+    index: Option<usize>, // Position in hittables while the object is alive
+}
+
+#[derive(Default)]
 pub struct Scene {
-    pub hittables: Vec<Arc<dyn Hittable>>,
+    // Kept dense so hit doesn't step over removed objects, removal moves the last object into the gap
+    pub(crate) hittables: Vec<Primitive>,
+    owners: Vec<u32>, // Slot of each entry in hittables
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    revision: u64,
+    pub materials: MaterialRegistry,
 }
 
 impl Scene {
     pub fn new() -> Self {
-        Self { hittables: vec![] }
+        Self::default()
+    }
+
+    pub fn add(&mut self, hittable: impl Into<Primitive>) -> ObjectId {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                self.slots.push(Slot { generation: 0, index: None });
+                (self.slots.len() - 1) as u32
+            }
+        };
+        self.slots[slot as usize].index = Some(self.hittables.len());
+        self.hittables.push(hittable.into());
+        self.owners.push(slot);
+        self.revision += 1;
+        ObjectId { slot, generation: self.slots[slot as usize].generation }
     }
 
-    pub fn add(&mut self, hittable: Arc<dyn Hittable>) {
-        self.hittables.push(hittable);
+    fn index(&self, id: ObjectId) -> Option<usize> {
+        let slot = self.slots.get(id.slot as usize)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+        slot.index
+    }
+
+    pub fn add_sphere(&mut self, center: Point3<Float>, radius: Float, material: impl Into<MaterialRef>) -> ObjectId {
+        let material = self.materials.resolve(material.into());
+        self.add(Sphere { center, radius, material })
+    }
+
+    pub fn get(&self, id: ObjectId) -> Option<&Primitive> {
+        self.index(id).map(|idx| &self.hittables[idx])
+    }
+
+    // False if the object was already gone
+    pub fn remove(&mut self, id: ObjectId) -> bool {
+        let Some(idx) = self.index(id) else {
+            return false;
+        };
+        self.hittables.swap_remove(idx);
+        self.owners.swap_remove(idx);
+        if let Some(&moved) = self.owners.get(idx) {
+            self.slots[moved as usize].index = Some(idx);
+        }
+        self.release(id.slot);
+        self.revision += 1;
+        true
     }
 
+    // Puts another object in place of id, which keeps referring to it. Returns the previous
+    // object, or None without changing anything if id is no longer in the scene.
+    pub fn replace(&mut self, id: ObjectId, hittable: impl Into<Primitive>) -> Option<Primitive> {
+        let idx = self.index(id)?;
+        self.revision += 1;
+        Some(std::mem::replace(&mut self.hittables[idx], hittable.into()))
+    }
+
+    // Registered materials stay
     pub fn clear(&mut self) {
+        for slot in std::mem::take(&mut self.owners) {
+            self.release(slot);
+        }
         self.hittables.clear();
+        self.revision += 1;
     }
-}
 
-impl Hittable for Scene {
-    fn hit(&self, ray: &Ray, trange: Range<f64>) -> Option<HitRecord> {
-        let mut closest_so_far = trange.end;
+    fn release(&mut self, slot: u32) {
+        let entry = &mut self.slots[slot as usize];
+        entry.index = None;
+        entry.generation = entry.generation.wrapping_add(1);
+        self.free.push(slot);
+    }
+
+    pub fn len(&self) -> usize {
+        self.hittables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hittables.is_empty()
+    }
+
+    // Box around all bounded objects, empty if there are none
+    pub fn bounds(&self) -> Aabb {
+        self.hittables.iter().filter_map(|hittable| hittable.bounding_box()).fold(Aabb::empty(), |aabb, b| aabb.union(&b))
+    }
+
+    pub fn from_objects<P: Into<Primitive>>(objects: impl IntoIterator<Item = P>) -> Self {
+        objects.into_iter().collect()
+    }
+
+    // Handles in the order of the objects
+    pub fn add_all<P: Into<Primitive>>(&mut self, objects: Vec<P>) -> Vec<ObjectId> {
+        objects.into_iter().map(|hittable| self.add(hittable)).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Primitive> {
+        self.hittables.iter()
+    }
+
+    // Like iter, with the handle of every object
+    pub fn objects(&self) -> impl Iterator<Item = (ObjectId, &Primitive)> {
+        self.hittables.iter().enumerate().map(|(index, hittable)| (self.id_at(index), hittable))
+    }
+
+    // Handle of the object at index in hittables
+    fn id_at(&self, index: usize) -> ObjectId {
+        let slot = self.owners[index];
+        ObjectId { slot, generation: self.slots[slot as usize].generation }
+    }
+
+    // Closest of the hits of every object, hit tests one object within a trange
+    fn closest_hit<'a>(
+        &'a self,
+        trange: Interval,
+        mut hit: impl FnMut(&'a Primitive, Interval) -> Option<HitRecord<'a>>
+    ) -> Option<HitRecord<'a>> {
+        let mut closest_so_far = trange.max;
         let mut result = None;
-        self.hittables.iter().for_each(|hittable| {
-            if let Some(hit) = hittable.hit(ray, trange.start..closest_so_far) {
+        self.hittables.iter().enumerate().for_each(|(index, hittable)| {
+            if let Some(hit) = hit(hittable, trange.with_max(closest_so_far)) {
                 closest_so_far = hit.t;
-                result = Some(hit);
+                result = Some((index, hit));
             }
         });
-        return result;
+        result.map(|(index, hit)| HitRecord { object: Some(self.id_at(index)), ..hit })
+    }
+
+    // Goes up with every add, remove, replace and clear. Anything derived from the contents, like
+    // an acceleration structure, is stale once the revision it was built from has passed.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+// Every object gets a handle, like with add
+impl<P: Into<Primitive>> Extend<P> for Scene {
+    fn extend<T: IntoIterator<Item = P>>(&mut self, objects: T) {
+        for hittable in objects {
+            self.add(hittable);
+        }
     }
 }
 
+impl<P: Into<Primitive>> FromIterator<P> for Scene {
+    fn from_iter<T: IntoIterator<Item = P>>(objects: T) -> Self {
+        let mut scene = Scene::new();
+        scene.extend(objects);
+        scene
+    }
+}
+
+impl Hittable for Scene {
+    // The hit gets the handle of the object in this scene, replacing any from a nested scene
+    fn hit(&self, ray: &Ray, trange: Interval) -> Option<HitRecord<'_>> {
+        self.closest_hit(trange, |hittable, trange| hittable.hit(ray, trange))
+    }
+
+    // Every object is a node visit
+    fn hit_counted(&self, ray: &Ray, trange: Interval, stats: &mut TraversalStats) -> Option<HitRecord<'_>> {
+        self.closest_hit(trange, |hittable, trange| {
+            stats.node_visits += 1;
+            hittable.hit_counted(ray, trange, stats)
+        })
+    }
+
+    // Same closest-hit search as hit, separately for every lane
+    #[cfg(feature = "packets")]
+    fn hit4(&self, packet: &RayPacket4, tranges: [Interval; LANES]) -> [Option<HitRecord<'_>>; LANES] {
+        let mut closest_so_far = tranges;
+        let mut result = [None, None, None, None];
+        for (index, hittable) in self.hittables.iter().enumerate() {
+            for (lane, hit) in hittable.hit4(packet, closest_so_far).into_iter().enumerate() {
+                if let Some(hit) = hit {
+                    closest_so_far[lane] = closest_so_far[lane].with_max(hit.t);
+                    result[lane] = Some((index, hit));
+                }
+            }
+        }
+        result.map(|hit| hit.map(|(index, hit)| HitRecord { object: Some(self.id_at(index)), ..hit }))
+    }
+
+    // None if any object is unbounded
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.hittables.iter().try_fold(Aabb::empty(), |aabb, hittable| Some(aabb.union(&hittable.bounding_box()?)))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use na::{point, vector};
+    use crate::camera::Camera;
+    use crate::cli::BuiltinScene;
+    use crate::Float;
+    use crate::image::{Image, PPM};
+    use crate::interval::Interval;
+    use crate::material::{Lambertian, Material, MaterialKind};
+    use crate::Ray;
+    use crate::ray::RayDifferentials;
+    use crate::RGB;
+    use crate::scene::{Hittable, MovingSphere, Primitive, Scene, Sphere, Triangle};
+    use crate::scene::generators::RandomSpheres;
+    use crate::utils::{reflect, refract, tolerance, INF, MIN_T, OFFSET_EPSILON};
+
+    fn sphere(x: Float) -> Sphere {
+        Sphere { center: point![x, 0.0, -2.0], radius: 0.5, material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into() }
+    }
+
+    // Straight down -z at the given x
+    fn probe(scene: &Scene, x: Float) -> bool {
+        scene.hit(&Ray::new(point![x, 0.0, 0.0], vector![0.0, 0.0, -1.0]), Interval::new(0.001, INF)).is_some()
+    }
+
+    fn moving_sphere() -> MovingSphere {
+        MovingSphere {
+            center0: point![0.0, 0.0, -2.0],
+            center1: point![2.0, 0.0, -2.0],
+            time0: 0.0,
+            time1: 1.0,
+            radius: 0.5,
+            material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into()
+        }
+    }
+
+    #[test]
+    fn test_hits_exclude_both_ends() {
+        // The ray enters the sphere at t = 0.5 and leaves at t = 1.5
+        let sphere = Sphere { center: point![0.0, 0.0, -1.0], radius: 0.5, material: Lambertian::new(RGB::white()).into() };
+        let ray = Ray::new(point![0.0, 0.0, 0.0], vector![0.0, 0.0, -1.0]);
+        let hit = |min: Float, max: Float| sphere.hit(&ray, Interval::new(min, max)).map(|hit| hit.t);
+        assert_eq!(hit(0.001, INF), Some(0.5));
+        // Starting right on the surface skips to the exit, ending right on it misses it
+        assert_eq!(hit(0.5, INF), Some(1.5));
+        assert_eq!(hit(0.001, 0.5), None);
+        assert_eq!(hit(1.0, 1.5), None);
+        assert_eq!(hit(0.001, 0.5 + tolerance(4.0)), Some(0.5));
+
+        // Same for a triangle at t = 2
+        let triangle = Triangle {
+            vertices: [point![-1.0, -1.0, -2.0], point![1.0, -1.0, -2.0], point![0.0, 1.0, -2.0]],
+            normals: None,
+            uvs: None,
+            material: Lambertian::new(RGB::white()).into(),
+        };
+        assert!(triangle.hit(&ray, Interval::new(0.001, 2.0)).is_none());
+        assert!(triangle.hit(&ray, Interval::new(2.0, INF)).is_none());
+        assert_eq!(triangle.hit(&ray, Interval::UNIVERSE).map(|hit| hit.t), Some(2.0));
+
+        // The scene narrows the interval to the closest hit so far, so an equally far hit later
+        // in the list doesn't replace the first one
+        let mut scene = Scene::new();
+        scene.add(Arc::new(sphere));
+        let red = MaterialKind::from(Lambertian::new(RGB(1.0, 0.0, 0.0)));
+        scene.add_sphere(point![0.0, 0.0, -1.0], 0.5, red);
+        assert_eq!(scene.hit(&ray, Interval::new(0.001, INF)).unwrap().material.albedo().1, 1.0);
+    }
+
+    #[test]
+    fn test_spawned_rays_leave_the_surface() {
+        for scale in [1e-3, 1.0, 1e3] {
+            let sphere = Sphere { center: point![0.0, 0.0, -2.0] * scale, radius: scale, material: Lambertian::new(RGB::white()).into() };
+            let ray = Ray::new(point![0.3, 0.2, 0.0] * scale, vector![0.0, 0.0, -1.0]);
+            let hit = sphere.hit(&ray, Interval::new(MIN_T, INF)).unwrap();
+            let inside = |ray: &Ray| (ray.orig - sphere.center).norm() < sphere.radius;
+
+            // Reflected rays start outside and miss the sphere, transmitted ones start inside
+            let reflected = hit.spawn_ray(&ray, reflect(&ray.dir, &hit.normal));
+            assert!(!inside(&reflected));
+            assert!(sphere.hit(&reflected, Interval::new(MIN_T, INF)).is_none());
+            let transmitted = hit.spawn_ray(&ray, refract(&ray.dir, &hit.normal, 1.0 / 1.5));
+            assert!(inside(&transmitted));
+            let exit = sphere.hit(&transmitted, Interval::new(MIN_T, INF)).unwrap();
+            assert!(!exit.front && exit.t * transmitted.dir.norm() > scale);
+
+            // The offset is far below anything visible at the scale of the scene
+            assert!((reflected.orig - hit.p).norm() < 4.0 * OFFSET_EPSILON * scale);
+        }
+    }
+
+    #[test]
+    fn test_distant_sphere_precision() {
+        // Camera 1e6 away from a sphere of radius 1e5, rays passing the center at distance h
+        // hit at t = 1e6 - sqrt(r^2 - h^2) exactly. The grazing ones used to lose most digits.
+        let (distance, radius) = (1e6, 1e5);
+        let dir = vector![1.0, 2.0, 3.0].normalize();
+        let side = dir.cross(&vector![1.0, 0.0, 0.0]).normalize();
+        let naive = |ray: &Ray, sphere: &Sphere| {
+            let oc = ray.orig - sphere.center;
+            let (a, half_b, c) = (ray.dir.norm_squared(), oc.dot(&ray.dir), oc.norm_squared() - radius * radius);
+            (-half_b - (half_b * half_b - a * c).sqrt()) / a
+        };
+        let (mut worst, mut worst_naive): (Float, Float) = (0.0, 0.0);
+        for gap in [1e-3, 0.01, 0.1, 1.0, 10.0, 1e3, 5e4] {
+            let h = radius - gap;
+            let orig = point![0.5, -0.25, 0.125];
+            let sphere = Sphere { center: orig + distance * dir + h * side, radius, material: Lambertian::new(RGB::white()).into() };
+            let ray = Ray::new(orig, dir);
+            let expected = distance - ((radius - h) * (radius + h)).sqrt();
+            let hit = sphere.hit(&ray, Interval::new(MIN_T, INF)).unwrap();
+            worst = worst.max((hit.t - expected).abs() / expected);
+            worst_naive = worst_naive.max((naive(&ray, &sphere) - expected).abs() / expected);
+        }
+        assert!(worst < tolerance(4e3), "{:e}", worst);
+        // f32 can't tell the smallest gaps from the radius, only f64 shows the naive one falling behind
+        if cfg!(not(feature = "f32")) {
+            assert!(worst_naive > 1e-12, "{:e}", worst_naive);
+        }
+
+        // Tangent rays touch the sphere once, rays from the center leave it at t = radius
+        let sphere = Sphere { center: point![radius, 0.0, -distance], radius, material: Lambertian::new(RGB::white()).into() };
+        let ray = Ray::new(point![0.0, 0.0, 0.0], vector![0.0, 0.0, -1.0]);
+        assert_eq!(sphere.hit(&ray, Interval::new(MIN_T, INF)).map(|hit| hit.t), Some(distance));
+        let ray = Ray::new(sphere.center, vector![0.0, 0.6, 0.8]);
+        let hit = sphere.hit(&ray, Interval::new(MIN_T, INF)).unwrap();
+        assert!((hit.t - radius).abs() < tolerance(16.0) * radius && !hit.front);
+    }
+
+    #[test]
+    fn test_moving_sphere_uses_center_at_ray_time() {
+        let sphere = moving_sphere();
+        let towards_start = vector![0.0, 0.0, -1.0];
+        let towards_end = vector![2.0, 0.0, -2.0];
+
+        let hit = sphere.hit(&Ray::new_at_time(point![0.0, 0.0, 0.0], towards_start, 0.0), Interval::new(0.001, INF)).unwrap();
+        assert!((hit.t - 1.5).abs() < tolerance(16.0));
+        assert!((hit.normal - vector![0.0, 0.0, 1.0]).norm() < tolerance(16.0));
+        assert!(sphere.hit(&Ray::new_at_time(point![0.0, 0.0, 0.0], towards_end, 0.0), Interval::new(0.001, INF)).is_none());
+
+        let hit = sphere.hit(&Ray::new_at_time(point![0.0, 0.0, 0.0], towards_end, 1.0), Interval::new(0.001, INF)).unwrap();
+        assert!((hit.p - point![2.0, 0.0, -2.0]).norm() > 0.49);
+        assert!(((hit.p - point![2.0, 0.0, -2.0]).norm() - 0.5).abs() < tolerance(16.0) * 2.0);
+        assert!(sphere.hit(&Ray::new_at_time(point![0.0, 0.0, 0.0], towards_start, 1.0), Interval::new(0.001, INF)).is_none());
+
+        // Halfway through
+        assert_eq!(sphere.center(0.5), point![1.0, 0.0, -2.0]);
+    }
+
+    #[test]
+    fn test_motion_blur_streak() {
+        let mut scene = Scene::new();
+        scene.add(Arc::new(moving_sphere()));
+        let camera = Camera::builder()
+            .width(40)
+            .samples_per_pixel(16)
+            .max_bounces(2)
+            .fov(90.0)
+            .look_at(point![1.0, 0.0, -2.0])
+            .shutter(0.0, 1.0)
+            .transparent_background(true)
+            .seed(1)
+            .build()
+            .unwrap();
+        let image = camera.renderer().render_parallel(Arc::new(scene));
+
+        // The sphere is smeared horizontally over its whole path and only partially covers the streak
+        let covered = |i: usize, j: usize| image.alpha(i, j) > 0.0;
+        let width = (0..40).filter(|&j| covered(20, j)).count();
+        let height = (0..40).filter(|&i| covered(i, 20)).count();
+        assert!(width > 2 * height, "{} x {}", width, height);
+        assert!(image.alpha(20, 20) < 1.0);
+    }
+
+    #[test]
+    fn test_triangle_hit() {
+        let triangle = Triangle {
+            vertices: [point![0.0, 0.0, -2.0], point![1.0, 0.0, -2.0], point![0.0, 1.0, -2.0]],
+            normals: None,
+            uvs: Some([(0.0, 1.0), (1.0, 1.0), (0.0, 0.0)]),
+            material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into()
+        };
+        let hit = triangle.hit(&Ray::new(point![0.25, 0.25, 0.0], vector![0.0, 0.0, -1.0]), Interval::new(0.001, INF)).unwrap();
+        assert!((hit.t - 2.0).abs() < 1e-12);
+        assert!(hit.front);
+        assert!((hit.normal - vector![0.0, 0.0, 1.0]).norm() < 1e-12);
+        assert!((hit.u - 0.25).abs() < 1e-12 && (hit.v - 0.75).abs() < 1e-12);
+        assert!(hit.uv_derivatives.is_none());
+
+        // Neighbors a bit to the right and below, u runs along x and v against y
+        let mut ray = Ray::new(point![0.25, 0.25, 0.0], vector![0.0, 0.0, -1.0]);
+        ray.differentials = Some(RayDifferentials {
+            x_orig: point![0.35, 0.25, 0.0],
+            x_dir: vector![0.0, 0.0, -1.0],
+            y_orig: point![0.25, 0.2, 0.0],
+            y_dir: vector![0.0, 0.0, -1.0],
+        });
+        let derivatives = triangle.hit(&ray, Interval::new(0.001, INF)).unwrap().uv_derivatives.unwrap();
+        // Differences of nearby hit points, so rounding of the points shows up in full
+        let eps = tolerance(16.0);
+        assert!((derivatives.du_dx - 0.1).abs() < eps && derivatives.dv_dx.abs() < eps);
+        assert!(derivatives.du_dy.abs() < eps && (derivatives.dv_dy - 0.05).abs() < eps);
+        assert!((derivatives.footprint() - 0.1).abs() < eps);
+
+        // From behind the normal faces the ray
+        let hit = triangle.hit(&Ray::new(point![0.25, 0.25, -4.0], vector![0.0, 0.0, 1.0]), Interval::new(0.001, INF)).unwrap();
+        assert!(!hit.front);
+        assert!((hit.normal - vector![0.0, 0.0, -1.0]).norm() < 1e-12);
+
+        // Outside of the edges, parallel and out of range
+        assert!(triangle.hit(&Ray::new(point![0.6, 0.6, 0.0], vector![0.0, 0.0, -1.0]), Interval::new(0.001, INF)).is_none());
+        assert!(triangle.hit(&Ray::new(point![0.2, 0.2, 0.0], vector![1.0, 0.0, 0.0]), Interval::new(0.001, INF)).is_none());
+        assert!(triangle.hit(&Ray::new(point![0.2, 0.2, 0.0], vector![0.0, 0.0, -1.0]), Interval::new(0.001, 1.0)).is_none());
+
+        let sample = triangle.sample_surface().unwrap();
+        assert_eq!(sample.area, 0.5);
+        assert!(sample.p.x >= 0.0 && sample.p.y >= 0.0 && sample.p.x + sample.p.y <= 1.0);
+    }
+
+    #[test]
+    fn test_object_handles() {
+        let mut scene = Scene::new();
+        let ids: Vec<_> = (0..4).map(|x| scene.add(sphere(x as Float * 2.0))).collect();
+        assert_eq!(scene.len(), 4);
+        assert!((0..4).all(|x| probe(&scene, x as Float * 2.0)));
+
+        // Removing from the middle leaves the other handles working
+        let revision = scene.revision();
+        assert!(scene.remove(ids[1]));
+        assert!(scene.revision() > revision);
+        assert!(!probe(&scene, 2.0));
+        assert!(probe(&scene, 0.0) && probe(&scene, 4.0) && probe(&scene, 6.0));
+        assert!(!scene.remove(ids[1]));
+        assert!(scene.get(ids[1]).is_none());
+        for (idx, x) in [(0, 0.0), (2, 4.0), (3, 6.0)] {
+            let hit = scene.get(ids[idx]).unwrap().hit(&Ray::new(point![x, 0.0, 0.0], vector![0.0, 0.0, -1.0]), Interval::new(0.001, INF));
+            assert!(hit.is_some(), "object {}", idx);
+        }
+
+        // The freed slot is reused without reviving the old handle
+        let moved = scene.add(sphere(-2.0));
+        assert_ne!(moved, ids[1]);
+        assert!(scene.get(ids[1]).is_none());
+        assert!(probe(&scene, -2.0));
+
+        // Replace keeps the handle, stale handles change nothing
+        assert!(scene.replace(ids[3], sphere(8.0)).is_some());
+        assert!(!probe(&scene, 6.0) && probe(&scene, 8.0));
+        assert!(scene.replace(ids[1], sphere(6.0)).is_none());
+        assert!(!probe(&scene, 6.0));
+        assert!(scene.remove(ids[3]));
+        assert!(!probe(&scene, 8.0));
+
+        let live: Vec<_> = scene.objects().map(|(id, _)| id).collect();
+        assert_eq!(live.len(), 3);
+        assert!([ids[0], ids[2], moved].iter().all(|id| live.contains(id)));
+        let object_at = |x: Float| scene.hit(&Ray::new(point![x, 0.0, 0.0], vector![0.0, 0.0, -1.0]), Interval::new(0.001, INF)).and_then(|hit| hit.object);
+        assert_eq!(object_at(4.0), Some(ids[2]));
+        assert_eq!(object_at(-2.0), Some(moved));
+        assert_eq!(object_at(8.0), None);
+
+        scene.clear();
+        assert!(scene.is_empty());
+        assert!(scene.get(ids[0]).is_none() && !scene.remove(moved));
+        let fresh = scene.add(sphere(0.0));
+        assert!(scene.get(fresh).is_some() && scene.get(ids[0]).is_none());
+    }
+
+    #[test]
+    fn test_collect_and_extend() {
+        let mut scene: Scene = (0..3).map(|x| sphere(x as Float * 2.0)).collect();
+        assert_eq!(scene.len(), 3);
+        assert!(probe(&scene, 0.0) && probe(&scene, 4.0));
+
+        let revision = scene.revision();
+        scene.extend([Arc::new(sphere(6.0)) as Arc<dyn Hittable>, Arc::new(sphere(8.0))]);
+        assert_eq!(scene.len(), 5);
+        assert!(scene.revision() > revision);
+        assert!(probe(&scene, 8.0));
+
+        // Extended objects get handles like added ones
+        let ids: Vec<_> = scene.objects().map(|(id, _)| id).collect();
+        assert!(scene.remove(ids[3]));
+        assert!(!probe(&scene, 6.0));
+        let added = scene.add_all(vec![sphere(10.0), sphere(12.0)]);
+        assert_eq!(added.len(), 2);
+        assert!(scene.remove(added[0]) && !probe(&scene, 10.0) && probe(&scene, 12.0));
+        assert_eq!(scene.iter().count(), 5);
+        assert!(scene.get(ids[4]).is_some());
+
+        let copy = Scene::from_objects(scene.iter().cloned());
+        assert_eq!(copy.len(), scene.len());
+        assert!(copy.iter().zip(scene.iter()).all(|(a, b)| a.bounding_box() == b.bounding_box()));
+        assert!(Scene::from_objects(Vec::<Primitive>::new()).is_empty());
+    }
+
+    // The same objects with every sphere and its material behind a trait object
+    fn behind_trait_objects(scene: &Scene) -> Scene {
+        let dynamic = |material: &MaterialKind| MaterialKind::Custom(Arc::new(material.clone()));
+        scene.iter().map(|primitive| match primitive {
+            Primitive::Sphere(sphere) => Primitive::Custom(Arc::new(Sphere { material: dynamic(&sphere.material), ..sphere.clone() })),
+            Primitive::MovingSphere(sphere) => Primitive::Custom(Arc::new(MovingSphere { material: dynamic(&sphere.material), ..sphere.clone() })),
+            other => other.clone(),
+        }).collect()
+    }
+
+    #[test]
+    fn test_trait_objects_render_the_same() {
+        // The same random spheres once inline and once behind trait objects
+        let scene = RandomSpheres::new().extent(4).bouncing(true).seed(4).generate();
+        let boxed = behind_trait_objects(&scene);
+        assert!(boxed.iter().all(|primitive| matches!(primitive, Primitive::Custom(_))));
+
+        let camera = Camera::builder()
+            .width(32)
+            .samples_per_pixel(4)
+            .look_from(point![13.0, 2.0, 3.0])
+            .look_at(point![0.0, 0.0, 0.0])
+            .shutter(0.0, 1.0)
+            .seed(6)
+            .build()
+            .unwrap();
+        let inline = camera.renderer().render_parallel(Arc::new(scene));
+        let behind_arcs = camera.renderer().render_parallel(Arc::new(boxed));
+        let values = |image: &PPM| image.pixels().iter().map(|px| (px.0, px.1, px.2)).collect::<Vec<_>>();
+        assert_eq!(values(&inline), values(&behind_arcs));
+    }
+
+    // final_scene with every material behind an Arc of its own, and those Arcs
+    fn shared_materials(scene: &Scene) -> (Scene, Vec<Arc<dyn Material>>) {
+        let mut materials: Vec<Arc<dyn Material>> = vec![];
+        let shared = scene.iter().map(|primitive| match primitive {
+            Primitive::Sphere(sphere) => {
+                materials.push(Arc::new(sphere.material.clone()));
+                Primitive::Sphere(Sphere { material: MaterialKind::Custom(materials.last().unwrap().clone()), ..sphere.clone() })
+            },
+            other => other.clone(),
+        }).collect();
+        (shared, materials)
+    }
+
+    // From around the default view into the field of spheres
+    fn random_ray(rng: &mut rand::rngs::SmallRng) -> Ray {
+        use rand::Rng;
+        let orig = point![rng.gen_range(-12.0..12.0), rng.gen_range(0.5..3.0), rng.gen_range(-12.0..12.0)];
+        Ray::new(orig, vector![rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..0.2), rng.gen_range(-1.0..1.0)])
+    }
+
+    #[test]
+    fn test_hits_borrow_materials() {
+        use rand::SeedableRng;
+
+        let scene = RandomSpheres::new().seed(3).generate();
+        let (shared, materials) = shared_materials(&scene);
+        let counts = |materials: &[Arc<dyn Material>]| materials.iter().map(Arc::strong_count).collect::<Vec<_>>();
+        let before = counts(&materials);
+
+        // Hits borrow the material, keeping them around doesn't touch the reference counts
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(3);
+        let hits: Vec<_> = (0..2_000).filter_map(|_| shared.hit(&random_ray(&mut rng), Interval::new(0.001, INF))).collect();
+        assert!(hits.len() > 500, "{} hits", hits.len());
+        assert_eq!(counts(&materials), before);
+
+        // And the seeded render is the same as with the materials inline
+        let camera = BuiltinScene::Final.camera().width(48).samples_per_pixel(4).seed(1).build().unwrap();
+        let values = |image: &PPM| image.pixels().iter().map(|px| (px.0, px.1, px.2)).collect::<Vec<_>>();
+        assert_eq!(values(&camera.render(&scene)), values(&camera.render(&shared)));
+        assert_eq!(counts(&materials), before);
+    }
+
+    // Closest hits per second against final_scene with shared materials, and how many material
+    // references a HitRecord owning its material would have cloned and dropped again:
+    // cargo test --release bench_final_scene_hits -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_final_scene_hits() {
+        use std::hint::black_box;
+        use std::time::Instant;
+        use rand::SeedableRng;
+
+        let (scene, _materials) = shared_materials(&RandomSpheres::new().seed(3).generate());
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(3);
+        let rays: Vec<_> = (0..20_000).map(|_| random_ray(&mut rng)).collect();
+        let trange = Interval::new(0.001, INF);
+        let rounds = 10;
+
+        // Every hit closer than the closest so far made a record of its own
+        let records: usize = rays.iter().map(|ray| {
+            let mut closest = trange;
+            scene.iter().filter(|primitive| match primitive.hit(ray, closest) {
+                Some(hit) => {
+                    closest = closest.with_max(hit.t);
+                    true
+                },
+                None => false,
+            }).count()
+        }).sum();
+
+        let start = Instant::now();
+        let mut hits = 0;
+        for _ in 0..rounds {
+            for ray in &rays {
+                hits += black_box(scene.hit(ray, trange)).is_some() as usize;
+            }
+        }
+        let elapsed = start.elapsed();
+        println!("{:.2} M rays/s, {} hits, {:.2} records per ray",
+            (rounds * rays.len()) as f64 / elapsed.as_secs_f64() / 1e6, hits / rounds, records as f64 / rays.len() as f64);
+    }
+
+    // Closest hits and whole renders of final_scene with the built-in objects and materials
+    // inline, and with all of them behind trait objects:
+    // cargo test --release bench_enum_dispatch -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_enum_dispatch() {
+        use std::hint::black_box;
+        use std::time::{Duration, Instant};
+        use rand::SeedableRng;
+
+        let inline = Arc::new(RandomSpheres::new().seed(3).generate());
+        let boxed = Arc::new(behind_trait_objects(&inline));
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(3);
+        let rays: Vec<_> = (0..20_000).map(|_| random_ray(&mut rng)).collect();
+        let trange = Interval::new(0.001, INF);
+        let camera = BuiltinScene::Final.camera().width(200).samples_per_pixel(8).seed(1).build().unwrap();
+
+        let time = |work: &dyn Fn()| {
+            // Best of three
+            (0..3).map(|_| {
+                let start = Instant::now();
+                work();
+                start.elapsed()
+            }).min().unwrap_or(Duration::ZERO)
+        };
+        for (name, scene) in [("enums", &inline), ("trait objects", &boxed)] {
+            let hits = time(&|| {
+                for ray in &rays {
+                    black_box(scene.hit(ray, trange));
+                }
+            });
+            let render = time(&|| {
+                black_box(camera.renderer().render_parallel(scene.clone()));
+            });
+            println!("{}: {:.2} M rays/s, render {:.0} ms", name, rays.len() as f64 / hits.as_secs_f64() / 1e6, render.as_secs_f64() * 1e3);
+        }
+    }
+
+    #[cfg(feature = "packets")]
+    fn random_packet(rng: &mut rand::rngs::SmallRng) -> crate::ray::packet::RayPacket4 {
+        use rand::Rng;
+        crate::ray::packet::RayPacket4::new(std::array::from_fn(|_| {
+            let orig = point![rng.gen_range(-12.0..12.0), rng.gen_range(-0.5..3.0), rng.gen_range(-12.0..12.0)];
+            let dir = vector![rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..0.2), rng.gen_range(-1.0..1.0)];
+            rng.gen_bool(0.9).then(|| Ray::new_at_time(orig, dir, rng.gen()))
+        }))
+    }
+
+    #[test]
+    #[cfg(feature = "packets")]
+    fn test_packet_hits_match_scalar() {
+        use rand::{Rng, SeedableRng};
+        use crate::ray::packet::LANES;
+
+        // Spheres go through the vectorized test, the rest lane by lane
+        let mut scene = RandomSpheres::new().bouncing(true).seed(8).generate();
+        scene.add(Triangle {
+            vertices: [point![-3.0, 0.0, 0.0], point![3.0, 0.0, 0.0], point![0.0, 4.0, 0.0]],
+            normals: None,
+            uvs: None,
+            material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into(),
+        });
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(2);
+        let mut hits = 0;
+        for _ in 0..5_000 {
+            let packet = random_packet(&mut rng);
+            let tranges: [Interval; LANES] = std::array::from_fn(|_| Interval::new(0.001, if rng.gen_bool(0.2) { rng.gen_range(0.1..5.0) } else { INF }));
+            for (lane, hit) in scene.hit4(&packet, tranges).into_iter().enumerate() {
+                let expected = packet.ray(lane).and_then(|ray| scene.hit(ray, tranges[lane]));
+                match (expected, hit) {
+                    (Some(expected), Some(hit)) => {
+                        hits += 1;
+                        assert_eq!((hit.t, hit.p, hit.normal, hit.front), (expected.t, expected.p, expected.normal, expected.front));
+                        assert_eq!((hit.u, hit.v), (expected.u, expected.v));
+                    }
+                    (None, None) => {}
+                    (expected, hit) => panic!("lane {}: expected {:?}, got {:?}", lane, expected.map(|h| h.t), hit.map(|h| h.t)),
+                }
+            }
+        }
+        assert!(hits > 1000, "{} hits", hits);
+    }
+
+    // Sphere tests per second against the random spheres, one ray after the other and in packets:
+    // cargo test --release --features packets bench_sphere_packets -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    #[cfg(feature = "packets")]
+    fn bench_sphere_packets() {
+        use std::hint::black_box;
+        use std::time::Instant;
+        use rand::SeedableRng;
+        use crate::ray::packet::LANES;
+
+        let scene = RandomSpheres::new().seed(3).generate();
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(3);
+        let packets: Vec<_> = (0..2_000).map(|_| random_packet(&mut rng)).collect();
+        let trange = Interval::new(0.001, INF);
+        let rounds = 50;
+
+        let start = Instant::now();
+        let mut scalar_hits = 0;
+        for _ in 0..rounds {
+            for packet in &packets {
+                for lane in 0..LANES {
+                    scalar_hits += black_box(packet.ray(lane).and_then(|ray| scene.hit(ray, trange))).is_some() as usize;
+                }
+            }
+        }
+        let scalar = start.elapsed();
+
+        let start = Instant::now();
+        let mut packet_hits = 0;
+        for _ in 0..rounds {
+            for packet in &packets {
+                packet_hits += black_box(scene.hit4(packet, [trange; LANES])).iter().filter(|hit| hit.is_some()).count();
+            }
+        }
+        let packed = start.elapsed();
+
+        assert_eq!(scalar_hits, packet_hits);
+        let tests = (rounds * packets.len() * LANES * scene.len()) as f64;
+        println!("scalar {:.0} M sphere tests/s, packets {:.0} M sphere tests/s",
+            tests / scalar.as_secs_f64() / 1e6, tests / packed.as_secs_f64() / 1e6);
+    }
+}
@@ -0,0 +1,303 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use crate::aabb::Aabb;
+use crate::interval::Interval;
+use crate::Ray;
+use crate::scene::{HitRecord, Hittable};
+use crate::utils::INF;
+
+// Constructive solid geometry: shapes made of the volumes of two solids, see Hittable::is_solid.
+// Hits are found by Roth's method. The crossings of both operands along the whole line of the ray
+// tell where it is inside each of them, and the composite surface is wherever being inside the
+// composite changes. Every hit keeps the material of the operand surface it is on.
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CsgError {
+    LeftNotSolid,
+    RightNotSolid,
+}
+
+impl Display for CsgError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let side = if *self == CsgError::LeftNotSolid { "left" } else { "right" };
+        write!(f, "the {} operand doesn't enclose a volume, CSG needs closed solids", side)
+    }
+}
+
+impl Error for CsgError {}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Operation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl Operation {
+    fn inside(self, left: bool, right: bool) -> bool {
+        match self {
+            Operation::Union => left || right,
+            Operation::Intersection => left && right,
+            Operation::Difference => left && !right,
+        }
+    }
+}
+
+fn check_solids(left: &Arc<dyn Hittable>, right: &Arc<dyn Hittable>) -> Result<(), CsgError> {
+    if !left.is_solid() {
+        return Err(CsgError::LeftNotSolid);
+    }
+    if !right.is_solid() {
+        return Err(CsgError::RightNotSolid);
+    }
+    Ok(())
+}
+
+// The composite's hits in trange, closest first. Rays start outside of everything at -infinity,
+// so the crossings before trange decide whether the ray starts inside. Hits record whether they
+// enter the composite in front. Normals already face the ray, so a surface of right cut out of
+// left keeps its normal and only turns from a front hit into a back hit or the other way round.
+fn combined_hits<'a>(
+    left: &'a dyn Hittable,
+    right: &'a dyn Hittable,
+    operation: Operation,
+    ray: &Ray,
+    trange: Interval
+) -> Vec<HitRecord<'a>> {
+    let line = Interval::new(-INF, trange.max);
+    let mut crossings: Vec<(bool, HitRecord<'a>)> = left.hit_all(ray, line).into_iter().map(|hit| (false, hit)).collect();
+    crossings.extend(right.hit_all(ray, line).into_iter().map(|hit| (true, hit)));
+    crossings.sort_by(|(_, a), (_, b)| a.t.total_cmp(&b.t));
+
+    let (mut in_left, mut in_right, mut inside) = (false, false, false);
+    let mut hits = vec![];
+    for (is_right, hit) in crossings {
+        // Front hits enter the operand, back hits leave it
+        if is_right { in_right = hit.front } else { in_left = hit.front }
+        let now_inside = operation.inside(in_left, in_right);
+        if now_inside != inside {
+            inside = now_inside;
+            if trange.surrounds(hit.t) {
+                hits.push(HitRecord { front: inside, ..hit });
+            }
+        }
+    }
+    hits
+}
+
+// Everything inside either operand
+pub struct CsgUnion {
+    left: Arc<dyn Hittable>,
+    right: Arc<dyn Hittable>,
+}
+
+impl CsgUnion {
+    pub fn new(left: Arc<dyn Hittable>, right: Arc<dyn Hittable>) -> Result<Self, CsgError> {
+        check_solids(&left, &right)?;
+        Ok(Self { left, right })
+    }
+}
+
+impl Hittable for CsgUnion {
+    fn hit(&self, ray: &Ray, trange: Interval) -> Option<HitRecord<'_>> {
+        self.hit_all(ray, trange).into_iter().next()
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn hit_all(&self, ray: &Ray, trange: Interval) -> Vec<HitRecord<'_>> {
+        combined_hits(self.left.as_ref(), self.right.as_ref(), Operation::Union, ray, trange)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.left.bounding_box()?.union(&self.right.bounding_box()?))
+    }
+}
+
+// Everything inside both operands
+pub struct CsgIntersection {
+    left: Arc<dyn Hittable>,
+    right: Arc<dyn Hittable>,
+}
+
+impl CsgIntersection {
+    pub fn new(left: Arc<dyn Hittable>, right: Arc<dyn Hittable>) -> Result<Self, CsgError> {
+        check_solids(&left, &right)?;
+        Ok(Self { left, right })
+    }
+}
+
+impl Hittable for CsgIntersection {
+    fn hit(&self, ray: &Ray, trange: Interval) -> Option<HitRecord<'_>> {
+        self.hit_all(ray, trange).into_iter().next()
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn hit_all(&self, ray: &Ray, trange: Interval) -> Vec<HitRecord<'_>> {
+        combined_hits(self.left.as_ref(), self.right.as_ref(), Operation::Intersection, ray, trange)
+    }
+
+    // The overlap of the boxes, either box alone if the other operand is unbounded
+    fn bounding_box(&self) -> Option<Aabb> {
+        match (self.left.bounding_box(), self.right.bounding_box()) {
+            (Some(a), Some(b)) => Some(Aabb { min: a.min.sup(&b.min), max: a.max.inf(&b.max) }),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+// Everything inside left but not inside right, e.g. a sphere with a hole
+pub struct CsgDifference {
+    left: Arc<dyn Hittable>,
+    right: Arc<dyn Hittable>,
+}
+
+impl CsgDifference {
+    pub fn new(left: Arc<dyn Hittable>, right: Arc<dyn Hittable>) -> Result<Self, CsgError> {
+        check_solids(&left, &right)?;
+        Ok(Self { left, right })
+    }
+}
+
+impl Hittable for CsgDifference {
+    fn hit(&self, ray: &Ray, trange: Interval) -> Option<HitRecord<'_>> {
+        self.hit_all(ray, trange).into_iter().next()
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn hit_all(&self, ray: &Ray, trange: Interval) -> Vec<HitRecord<'_>> {
+        combined_hits(self.left.as_ref(), self.right.as_ref(), Operation::Difference, ray, trange)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.left.bounding_box()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use na::{point, vector, Vector3};
+    use crate::Float;
+    use crate::interval::Interval;
+    use crate::material::Lambertian;
+    use crate::Ray;
+    use crate::RGB;
+    use crate::scene::{HitRecord, Hittable, Primitive, Scene, Sphere, Triangle};
+    use crate::scene::csg::{CsgDifference, CsgError, CsgIntersection, CsgUnion};
+    use crate::utils::{INF, MIN_T};
+
+    fn sphere(z: Float, radius: Float) -> Arc<dyn Hittable> {
+        Arc::new(Sphere { center: point![0.0, 0.0, z], radius, material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into() })
+    }
+
+    // Outward normal of the composite at the hit
+    fn outward(hit: &HitRecord) -> Vector3<Float> {
+        if hit.front { hit.normal } else { -hit.normal }
+    }
+
+    fn assert_hits(hits: &[HitRecord], expected: &[(Float, bool)]) {
+        let found: Vec<_> = hits.iter().map(|hit| (hit.t, hit.front)).collect();
+        assert_eq!(hits.len(), expected.len(), "{:?}", found);
+        for (hit, &(t, front)) in hits.iter().zip(expected) {
+            assert!((hit.t - t).abs() < 1e-5 && hit.front == front, "{:?} instead of {:?}", found, expected);
+        }
+    }
+
+    #[test]
+    fn test_shell() {
+        let shell = CsgDifference::new(sphere(0.0, 2.0), sphere(0.0, 1.0)).unwrap();
+        let trange = Interval::new(MIN_T, INF);
+
+        // In through the outer surface, through the hole and out again
+        let ray = Ray::new(point![0.0, 0.0, -5.0], vector![0.0, 0.0, 1.0]);
+        let hits = shell.hit_all(&ray, trange);
+        assert_hits(&hits, &[(3.0, true), (4.0, false), (6.0, true), (7.0, false)]);
+        // The hole's surface faces its center, the other way round from the smaller sphere's
+        let normals: Vec<_> = hits.iter().map(outward).collect();
+        assert_eq!(normals, [vector![0.0, 0.0, -1.0], vector![0.0, 0.0, 1.0], vector![0.0, 0.0, -1.0], vector![0.0, 0.0, 1.0]]);
+        assert!(hits.iter().all(|hit| hit.normal.dot(&ray.dir) < 0.0));
+        assert_eq!(shell.hit(&ray, trange).unwrap().t, hits[0].t);
+        assert_eq!(shell.hit(&ray, Interval::new(3.5, INF)).unwrap().t, hits[1].t);
+
+        // Off center, through the hole along a chord and past it
+        let (outer, inner) = ((4.0 as Float - 0.25).sqrt(), (1.0 as Float - 0.25).sqrt());
+        let chord = Ray::new(point![0.5, 0.0, -5.0], vector![0.0, 0.0, 1.0]);
+        assert_hits(&shell.hit_all(&chord, trange), &[(5.0 - outer, true), (5.0 - inner, false), (5.0 + inner, true), (5.0 + outer, false)]);
+        let outer = (4.0 as Float - 2.25).sqrt();
+        let past = Ray::new(point![1.5, 0.0, -5.0], vector![0.0, 0.0, 1.0]);
+        assert_hits(&shell.hit_all(&past, trange), &[(5.0 - outer, true), (5.0 + outer, false)]);
+
+        // Starting in the hole, and in the shell itself
+        let from_hole = Ray::new(point![0.0, 0.0, 0.0], vector![0.0, 0.0, 1.0]);
+        let hit = shell.hit(&from_hole, trange).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-5 && hit.front);
+        assert_eq!(outward(&hit), vector![0.0, 0.0, -1.0]);
+        let from_shell = Ray::new(point![0.0, 0.0, 1.5], vector![0.0, 0.0, -1.0]);
+        assert_hits(&shell.hit_all(&from_shell, trange), &[(0.5, false), (2.5, true), (3.5, false)]);
+
+        // The same behind an Arc in a scene
+        let scene: Scene = [Primitive::from(Arc::new(shell) as Arc<dyn Hittable>)].into_iter().collect();
+        assert!((scene.hit(&chord, trange).unwrap().t - (5.0 - (3.75 as Float).sqrt())).abs() < 1e-5);
+        assert!(scene.hit(&Ray::new(point![2.5, 0.0, -5.0], vector![0.0, 0.0, 1.0]), trange).is_none());
+    }
+
+    #[test]
+    fn test_operations() {
+        // Spheres of radius 1 at z = -0.5 and z = 0.5, along the z axis from z = -5
+        let (a, b) = (sphere(-0.5, 1.0), sphere(0.5, 1.0));
+        let ray = Ray::new(point![0.0, 0.0, -5.0], vector![0.0, 0.0, 1.0]);
+        let trange = Interval::new(MIN_T, INF);
+        assert_hits(&CsgUnion::new(a.clone(), b.clone()).unwrap().hit_all(&ray, trange), &[(3.5, true), (6.5, false)]);
+        assert_hits(&CsgIntersection::new(a.clone(), b.clone()).unwrap().hit_all(&ray, trange), &[(4.5, true), (5.5, false)]);
+        assert_hits(&CsgDifference::new(a.clone(), b.clone()).unwrap().hit_all(&ray, trange), &[(3.5, true), (4.5, false)]);
+        assert_hits(&CsgDifference::new(b.clone(), a.clone()).unwrap().hit_all(&ray, trange), &[(5.5, true), (6.5, false)]);
+        // Only the hits inside trange
+        assert_hits(&CsgUnion::new(a.clone(), b.clone()).unwrap().hit_all(&ray, Interval::new(MIN_T, 6.0)), &[(3.5, true)]);
+
+        // Composites are solids themselves: the lens between the spheres cut out of their union
+        let union: Arc<dyn Hittable> = Arc::new(CsgUnion::new(a.clone(), b.clone()).unwrap());
+        let lens: Arc<dyn Hittable> = Arc::new(CsgIntersection::new(a.clone(), b.clone()).unwrap());
+        let nested = CsgDifference::new(union, lens).unwrap();
+        assert!(nested.is_solid());
+        assert_hits(&nested.hit_all(&ray, trange), &[(3.5, true), (4.5, false), (5.5, true), (6.5, false)]);
+
+        // Disjoint operands
+        let far = sphere(5.0, 1.0);
+        assert!(CsgIntersection::new(a.clone(), far.clone()).unwrap().hit(&ray, trange).is_none());
+        assert_hits(&CsgDifference::new(a.clone(), far).unwrap().hit_all(&ray, trange), &[(3.5, true), (5.5, false)]);
+    }
+
+    #[test]
+    fn test_solids_and_bounds() {
+        let triangle: Arc<dyn Hittable> = Arc::new(Triangle {
+            vertices: [point![0.0, 0.0, 0.0], point![1.0, 0.0, 0.0], point![0.0, 1.0, 0.0]],
+            normals: None,
+            uvs: None,
+            material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into(),
+        });
+        let ball = sphere(0.0, 1.0);
+        assert_eq!(CsgUnion::new(triangle.clone(), ball.clone()).err(), Some(CsgError::LeftNotSolid));
+        assert_eq!(CsgIntersection::new(ball.clone(), triangle).err(), Some(CsgError::RightNotSolid));
+        // Inside out
+        assert_eq!(CsgDifference::new(ball.clone(), sphere(0.0, -0.5)).err(), Some(CsgError::RightNotSolid));
+        assert!(CsgError::LeftNotSolid.to_string().contains("left"));
+
+        let other = sphere(1.5, 1.0);
+        let union = CsgUnion::new(ball.clone(), other.clone()).unwrap().bounding_box().unwrap();
+        assert_eq!((union.min, union.max), (point![-1.0, -1.0, -1.0], point![1.0, 1.0, 2.5]));
+        let intersection = CsgIntersection::new(ball.clone(), other.clone()).unwrap().bounding_box().unwrap();
+        assert_eq!((intersection.min, intersection.max), (point![-1.0, -1.0, 0.5], point![1.0, 1.0, 1.0]));
+        let difference = CsgDifference::new(ball, other).unwrap().bounding_box().unwrap();
+        assert_eq!((difference.min, difference.max), (point![-1.0, -1.0, -1.0], point![1.0, 1.0, 1.0]));
+    }
+}
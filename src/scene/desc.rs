@@ -0,0 +1,303 @@
+use na::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use crate::camera::{Camera, CameraBuilder};
+use crate::Float;
+use crate::material::{Dielectric, DiffuseLight, Lambertian, MaterialKind, Metal};
+use crate::RGB;
+use crate::scene::{Hittable, MovingSphere, Primitive, Scene, Sphere};
+
+// Plain data versions of the scene types, which can be saved and loaded with serde.
+// Runtime objects describe themselves through Material::to_desc and Hittable::to_desc.
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum MaterialDesc {
+    Lambertian { albedo: [Float; 3] },
+    Metal { albedo: [Float; 3], #[serde(default)] fuzz: Float },
+    Dielectric {
+        refraction_index: Float,
+        // Left out for glass without dispersion
+        #[serde(default, skip_serializing_if = "is_zero")]
+        dispersion: Float,
+    },
+    DiffuseLight {
+        emit: [Float; 3],
+        // Light group, left out for the default group 0
+        #[serde(default, skip_serializing_if = "is_default_group")]
+        group: usize,
+        // Black body temperature in kelvin, left out for the RGB emission
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        temperature: Option<Float>,
+    },
+}
+
+fn is_default_group(group: &usize) -> bool {
+    *group == 0
+}
+
+fn is_zero(value: &Float) -> bool {
+    *value == 0.0
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum HittableDesc {
+    Sphere { center: [Float; 3], radius: Float, material: MaterialDesc },
+    MovingSphere {
+        center0: [Float; 3],
+        center1: [Float; 3],
+        time0: Float,
+        time1: Float,
+        radius: Float,
+        material: MaterialDesc,
+    },
+}
+
+// Camera settings, anything missing keeps the builder default
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDesc {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aspect_ratio: Option<Float>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub samples: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bounces: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fov: Option<Float>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookfrom: Option<[Float; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookat: Option<[Float; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vup: Option<[Float; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub defocus_angle: Option<Float>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focus_dist: Option<Float>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shutter: Option<[Float; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background: Option<[Float; 3]>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneDesc {
+    pub objects: Vec<HittableDesc>,
+}
+
+fn color([r, g, b]: [Float; 3]) -> RGB {
+    RGB(r, g, b)
+}
+
+pub(crate) fn array(color: RGB) -> [Float; 3] {
+    [color.0, color.1, color.2]
+}
+
+impl MaterialDesc {
+    pub fn build(&self) -> MaterialKind {
+        match *self {
+            MaterialDesc::Lambertian { albedo } => Lambertian::new(color(albedo)).into(),
+            MaterialDesc::Metal { albedo, fuzz } => Metal::new(color(albedo), fuzz).into(),
+            MaterialDesc::Dielectric { refraction_index, dispersion } => Dielectric::new(refraction_index).dispersion(dispersion).into(),
+            MaterialDesc::DiffuseLight { emit, group, temperature } => {
+                DiffuseLight { temperature, ..DiffuseLight::new(color(emit)).group(group) }.into()
+            },
+        }
+    }
+}
+
+impl HittableDesc {
+    pub fn build(&self) -> Primitive {
+        match self {
+            HittableDesc::Sphere { center, radius, material } => Sphere {
+                center: Point3::from(*center),
+                radius: *radius,
+                material: material.build(),
+            }.into(),
+            HittableDesc::MovingSphere { center0, center1, time0, time1, radius, material } => MovingSphere {
+                center0: Point3::from(*center0),
+                center1: Point3::from(*center1),
+                time0: *time0,
+                time1: *time1,
+                radius: *radius,
+                material: material.build(),
+            }.into(),
+        }
+    }
+}
+
+impl SceneDesc {
+    pub fn build(&self) -> Scene {
+        let mut scene = Scene::new();
+        for object in &self.objects {
+            scene.add(object.build());
+        }
+        scene
+    }
+}
+
+impl Scene {
+    // None if any object or material has no description
+    pub fn to_desc(&self) -> Option<SceneDesc> {
+        let objects = self.iter().map(|hittable| hittable.to_desc()).collect::<Option<Vec<_>>>()?;
+        Some(SceneDesc { objects })
+    }
+}
+
+impl CameraDesc {
+    pub fn builder(&self) -> CameraBuilder {
+        let mut camera = Camera::builder();
+        if let Some(width) = self.width {
+            camera = camera.width(width);
+        }
+        if let Some(aspect_ratio) = self.aspect_ratio {
+            camera = camera.aspect_ratio(aspect_ratio);
+        }
+        if let Some(height) = self.height {
+            camera = camera.height(height);
+        }
+        if let Some(samples) = self.samples {
+            camera = camera.samples_per_pixel(samples);
+        }
+        if let Some(max_bounces) = self.max_bounces {
+            camera = camera.max_bounces(max_bounces);
+        }
+        if let Some(fov) = self.fov {
+            camera = camera.fov(fov);
+        }
+        if let Some(lookfrom) = self.lookfrom {
+            camera = camera.look_from(Point3::from(lookfrom));
+        }
+        if let Some(lookat) = self.lookat {
+            camera = camera.look_at(Point3::from(lookat));
+        }
+        if let Some(vup) = self.vup {
+            camera = camera.vup(Vector3::from(vup));
+        }
+        if let Some(defocus_angle) = self.defocus_angle {
+            camera = camera.defocus_angle(defocus_angle);
+        }
+        if let Some(focus_dist) = self.focus_dist {
+            camera = camera.focus_dist(focus_dist);
+        }
+        if let Some([open, close]) = self.shutter {
+            camera = camera.shutter(open, close);
+        }
+        if let Some(seed) = self.seed {
+            camera = camera.seed(seed);
+        }
+        if let Some(background) = self.background {
+            camera = camera.background(color(background));
+        }
+        camera
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use na::point;
+    use crate::camera::Camera;
+    use crate::material::{DiffuseLight, Material, Metal};
+    use crate::RGB;
+    use crate::scene::desc::{CameraDesc, HittableDesc, MaterialDesc, SceneDesc};
+    use crate::scene::{Scene, Sphere};
+    use crate::scenes::{bouncing_spheres, final_scene};
+
+    fn round_trip(desc: &SceneDesc) -> SceneDesc {
+        let json = serde_json::to_string(desc).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_final_scene_round_trip() {
+        for scene in [final_scene(), bouncing_spheres()] {
+            let desc = scene.to_desc().unwrap();
+            assert_eq!(desc.objects.len(), scene.hittables.len());
+
+            // Saving and loading again gives the same bytes
+            let loaded = round_trip(&desc);
+            assert_eq!(loaded, desc);
+            assert_eq!(serde_json::to_string(&loaded).unwrap(), serde_json::to_string(&desc).unwrap());
+
+            let rebuilt = loaded.build();
+            assert_eq!(rebuilt.hittables.len(), scene.hittables.len());
+            assert_eq!(rebuilt.to_desc().unwrap(), desc);
+        }
+
+        // The three big spheres at the end
+        let desc = final_scene().to_desc().unwrap();
+        let n = desc.objects.len();
+        assert_eq!(desc.objects[n - 3], HittableDesc::Sphere {
+            center: [0.0, 1.0, 0.0],
+            radius: 1.0,
+            material: MaterialDesc::Dielectric { refraction_index: 1.5, dispersion: 0.0 }
+        });
+        assert_eq!(desc.objects[n - 1], HittableDesc::Sphere {
+            center: [4.0, 1.0, 0.0],
+            radius: 1.0,
+            material: MaterialDesc::Metal { albedo: [0.7, 0.6, 0.5], fuzz: 0.0 }
+        });
+    }
+
+    #[test]
+    fn test_tagged_json() {
+        let light = Sphere {
+            center: point![0.0, 2.0, 0.0],
+            radius: 0.5,
+            material: DiffuseLight::new(RGB(4.0, 4.0, 4.0)).into()
+        };
+        let mut scene = Scene::new();
+        scene.add(Arc::new(light));
+        let json = serde_json::to_string(&scene.to_desc().unwrap()).unwrap();
+        assert_eq!(
+            json,
+            r#"{"objects":[{"type":"sphere","center":[0.0,2.0,0.0],"radius":0.5,"material":{"type":"diffuse_light","emit":[4.0,4.0,4.0]}}]}"#
+        );
+        assert_eq!(Metal::new(RGB(1.0, 1.0, 1.0), 0.5).to_desc(), Some(MaterialDesc::Metal { albedo: [1.0, 1.0, 1.0], fuzz: 0.5 }));
+        let grouped = DiffuseLight::new(RGB(4.0, 4.0, 4.0)).group(2).to_desc().unwrap();
+        assert_eq!(serde_json::to_string(&grouped).unwrap(), r#"{"type":"diffuse_light","emit":[4.0,4.0,4.0],"group":2}"#);
+        let warm = DiffuseLight::new(RGB(4.0, 4.0, 4.0)).blackbody(2700.0).to_desc().unwrap();
+        assert_eq!(serde_json::to_string(&warm).unwrap(), r#"{"type":"diffuse_light","emit":[4.0,4.0,4.0],"temperature":2700.0}"#);
+        let prism: MaterialDesc = serde_json::from_str(r#"{"type":"dielectric","refraction_index":1.5,"dispersion":0.0042}"#).unwrap();
+        assert_eq!(prism.build().to_desc(), Some(prism));
+
+        // Objects without a description can't be saved
+        scene.add(Arc::new(Scene::new()));
+        assert!(scene.to_desc().is_none());
+    }
+
+    #[test]
+    fn test_camera_round_trip() {
+        let camera = Camera::builder()
+            .dimensions(320, 200)
+            .samples_per_pixel(7)
+            .fov(35.0)
+            .look_from(point![1.0, 2.0, 3.0])
+            .look_at(point![0.0, 0.5, 0.0])
+            .defocus_angle(1.5)
+            .focus_dist(3.0)
+            .shutter(0.0, 0.5)
+            .seed(11)
+            .background(RGB(0.1, 0.2, 0.3))
+            .build()
+            .unwrap();
+        let desc = camera.to_desc();
+        assert_eq!(desc.width, Some(320));
+        assert_eq!(desc.height, Some(200));
+        assert_eq!(desc.background, Some([0.1, 0.2, 0.3]));
+
+        let json = serde_json::to_string(&desc).unwrap();
+        let loaded: CameraDesc = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, desc);
+        assert_eq!(loaded.builder().build().unwrap().to_desc(), desc);
+    }
+}
@@ -0,0 +1,305 @@
+use na::{point, vector};
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+use crate::Float;
+use crate::material::{Dielectric, Lambertian, Metal};
+use crate::RGB;
+use crate::scene::{MovingSphere, Scene, Sphere};
+use crate::utils::{rand, rand_range};
+
+// Field of small random spheres on a large ground sphere, the cover of Ray Tracing in One Weekend.
+// Every grid cell in [-extent, extent) on x and z gets one sphere at a random spot inside it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RandomSpheres {
+    extent: i32,
+    radius: (Float, Float),
+    weights: MaterialWeights,
+    min_spacing: Float,
+    seed: Option<u64>,
+    hero_spheres: bool,
+    bouncing: bool,
+    palette: Palette,
+}
+
+// How the diffuse spheres get their colors
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Palette {
+    // Product of two random colors, mostly dark and muted like in the book
+    #[default]
+    Random,
+    // Uniformly random hue at a fixed saturation and value, see RGB::from_hsv
+    Hue { saturation: Float, value: Float },
+}
+
+// Relative chances of the small sphere materials, they don't need to add up to 1
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MaterialWeights {
+    pub diffuse: Float,
+    pub metal: Float,
+    pub glass: Float,
+}
+
+impl Default for MaterialWeights {
+    fn default() -> Self {
+        Self { diffuse: 0.8, metal: 0.15, glass: 0.05 }
+    }
+}
+
+impl Default for RandomSpheres {
+    fn default() -> Self {
+        Self {
+            extent: 5,
+            radius: (0.2, 0.2),
+            weights: MaterialWeights::default(),
+            min_spacing: 0.9,
+            seed: None,
+            hero_spheres: true,
+            bouncing: false,
+            palette: Palette::default(),
+        }
+    }
+}
+
+// Where the numbers come from. Unseeded scenes draw from the thread generator like the rest of
+// the crate, so seed_rng still makes them reproducible.
+enum Source {
+    Thread,
+    Seeded(SmallRng),
+}
+
+impl Source {
+    fn next(&mut self) -> Float {
+        match self {
+            Source::Thread => rand(),
+            Source::Seeded(rng) => rng.gen::<Float>(),
+        }
+    }
+
+    fn range(&mut self, min: Float, max: Float) -> Float {
+        if min >= max {
+            return min;
+        }
+        match self {
+            Source::Thread => rand_range(min, max),
+            Source::Seeded(rng) => rng.gen_range(min..max),
+        }
+    }
+
+    fn color(&mut self) -> RGB {
+        RGB(self.next(), self.next(), self.next())
+    }
+
+    fn color_range(&mut self, min: Float, max: Float) -> RGB {
+        RGB(self.range(min, max), self.range(min, max), self.range(min, max))
+    }
+}
+
+impl RandomSpheres {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Half the number of grid cells along each axis
+    pub fn extent(mut self, extent: i32) -> Self {
+        self.extent = extent;
+        self
+    }
+
+    // Small sphere radius, picked uniformly in [min, max)
+    pub fn radius(mut self, min: Float, max: Float) -> Self {
+        self.radius = (min, max);
+        self
+    }
+
+    pub fn weights(mut self, weights: MaterialWeights) -> Self {
+        self.weights = weights;
+        self
+    }
+
+    // Small spheres closer than this to the spot in front of the metal hero sphere are left out
+    pub fn min_spacing(mut self, min_spacing: Float) -> Self {
+        self.min_spacing = min_spacing;
+        self
+    }
+
+    // The same seed gives the same scene, the thread generator is used if not set
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    // Glass, diffuse and metal spheres of radius 1 along the x axis
+    pub fn hero_spheres(mut self, hero_spheres: bool) -> Self {
+        self.hero_spheres = hero_spheres;
+        self
+    }
+
+    // Diffuse spheres move up during the shutter interval [0, 1]
+    pub fn bouncing(mut self, bouncing: bool) -> Self {
+        self.bouncing = bouncing;
+        self
+    }
+
+    pub fn palette(mut self, palette: Palette) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    pub fn generate(&self) -> Scene {
+        let mut source = match self.seed {
+            Some(seed) => Source::Seeded(SmallRng::seed_from_u64(seed)),
+            None => Source::Thread,
+        };
+        let MaterialWeights { diffuse, metal, glass } = self.weights;
+        let total = diffuse + metal + glass;
+
+        let mut scene = Scene::new();
+        let ground = scene.materials.register("ground", Lambertian::new(RGB(0.5, 0.5, 0.5))).unwrap();
+        // All glass spheres share one material
+        let glass = scene.materials.register("glass", Dielectric::new(1.5)).unwrap();
+
+        scene.add_sphere(point![0.0, -1000.0, 0.0], 1000.0, ground);
+
+        for a in -self.extent..self.extent {
+            for b in -self.extent..self.extent {
+                let af = a as Float;
+                let bf = b as Float;
+                let choose_mat = source.next() * total;
+                let x = af + 0.9 * source.next();
+                let z = bf + 0.9 * source.next();
+                let radius = source.range(self.radius.0, self.radius.1);
+                let center = point![x, radius, z];
+
+                if (center - point![4.0, radius, 0.0]).norm() > self.min_spacing {
+                    if choose_mat < diffuse {
+                        // diffuse
+                        let albedo = match self.palette {
+                            Palette::Random => source.color() * source.color(),
+                            Palette::Hue { saturation, value } => RGB::from_hsv(source.range(0.0, 360.0), saturation, value),
+                        };
+                        if self.bouncing {
+                            scene.add(MovingSphere {
+                                center0: center,
+                                center1: center + vector![0.0, source.range(0.0, 0.5), 0.0],
+                                time0: 0.0,
+                                time1: 1.0,
+                                radius,
+                                material: Lambertian::new(albedo).into()
+                            });
+                        } else {
+                            scene.add(Sphere {
+                                center,
+                                radius,
+                                material: Lambertian::new(albedo).into()
+                            });
+                        }
+                    } else if choose_mat < diffuse + metal {
+                        // Metal
+                        let albedo = source.color_range(0.5, 1.0);
+                        let fuzz = source.range(0.0, 0.5);
+                        scene.add(Sphere {
+                            center,
+                            radius,
+                            material: Metal::new(albedo, fuzz).into()
+                        });
+                    } else {
+                        // glass
+                        scene.add_sphere(center, radius, glass);
+                    }
+                }
+            }
+        }
+
+        if self.hero_spheres {
+            scene.add_sphere(point![0.0, 1.0, 0.0], 1.0, glass);
+
+            let brown = scene.materials.register("brown", Lambertian::new(RGB(0.4, 0.2, 0.1))).unwrap();
+            scene.add_sphere(point![-4.0, 1.0, 0.0], 1.0, brown);
+
+            let mirror = scene.materials.register("mirror", Metal::new(RGB(0.7, 0.6, 0.5), 0.0)).unwrap();
+            scene.add_sphere(point![4.0, 1.0, 0.0], 1.0, mirror);
+        }
+
+        scene
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Float;
+    use crate::scene::generators::{MaterialWeights, Palette, RandomSpheres};
+    use crate::RGB;
+    use crate::scene::desc::{HittableDesc, MaterialDesc};
+    use crate::scenes::final_scene;
+    use crate::utils::seed_rng;
+
+    #[test]
+    fn test_seeded_scenes_repeat() {
+        let spheres = RandomSpheres::new().seed(7).radius(0.1, 0.3).bouncing(true);
+        assert_eq!(spheres.generate().to_desc(), spheres.generate().to_desc());
+        assert_ne!(spheres.generate().to_desc(), spheres.clone().seed(8).generate().to_desc());
+
+        // The seed is independent of the thread generator
+        seed_rng(1);
+        let first = spheres.generate().to_desc();
+        seed_rng(2);
+        assert_eq!(spheres.generate().to_desc(), first);
+
+        // Unseeded scenes follow the thread generator, as final_scene always did
+        seed_rng(3);
+        let first = final_scene().to_desc();
+        seed_rng(3);
+        assert_eq!(final_scene().to_desc(), first);
+    }
+
+    #[test]
+    fn test_extent_and_options() {
+        // One sphere per grid cell plus the ground
+        let grid = |extent: i32| RandomSpheres::new().seed(1).extent(extent).min_spacing(0.0).hero_spheres(false).generate();
+        assert_eq!(grid(0).len(), 1);
+        assert_eq!(grid(2).len(), 17);
+        assert_eq!(grid(5).len(), 101);
+        let centers = grid(3).to_desc().unwrap().objects.into_iter().skip(1).map(|object| match object {
+            HittableDesc::Sphere { center, .. } => center,
+            _ => unreachable!(),
+        });
+        assert!(centers.into_iter().all(|[x, _, z]| (-3.0..3.0).contains(&x) && (-3.0..3.0).contains(&z)));
+
+        // Only glass
+        let weights = MaterialWeights { diffuse: 0.0, metal: 0.0, glass: 1.0 };
+        let scene = RandomSpheres::new().seed(2).weights(weights).radius(0.25, 0.25).generate().to_desc().unwrap();
+        let small: Vec<_> = scene.objects.iter().filter(|object| matches!(object, HittableDesc::Sphere { radius, .. } if *radius == 0.25)).collect();
+        assert!(small.len() > 90);
+        for object in small {
+            if let HittableDesc::Sphere { center, material, .. } = object {
+                assert_eq!(center[1], 0.25);
+                assert_eq!(*material, MaterialDesc::Dielectric { refraction_index: 1.5, dispersion: 0.0 });
+            }
+        }
+        assert_eq!(scene.objects.len(), RandomSpheres::new().seed(2).weights(weights).generate().len());
+    }
+
+    #[test]
+    fn test_hue_palette() {
+        let weights = MaterialWeights { diffuse: 1.0, metal: 0.0, glass: 0.0 };
+        let palette = Palette::Hue { saturation: 0.8, value: 0.9 };
+        let spheres = RandomSpheres::new().seed(4).weights(weights).hero_spheres(false).palette(palette);
+        let scene = spheres.generate().to_desc().unwrap();
+        let mut hues = vec![];
+        for object in scene.objects.iter().skip(1) {
+            let HittableDesc::Sphere { material: MaterialDesc::Lambertian { albedo }, .. } = object else {
+                panic!("expected a diffuse sphere, got {:?}", object);
+            };
+            let (hue, saturation, value) = RGB(albedo[0], albedo[1], albedo[2]).to_hsv();
+            assert!((saturation - 0.8).abs() < 1e-9 && (value - 0.9).abs() < 1e-9);
+            hues.push(hue);
+        }
+        // Spread over the whole color wheel
+        assert!(hues.len() > 90);
+        for sector in 0..6 {
+            let range = sector as Float * 60.0..(sector + 1) as Float * 60.0;
+            assert!(hues.iter().any(|hue| range.contains(hue)), "nothing in {:?}", range);
+        }
+        assert_eq!(spheres.generate().to_desc(), Some(scene));
+    }
+}
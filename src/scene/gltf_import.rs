@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use na::{Isometry3, Matrix3, Matrix4, Point3, Rotation3, Translation3, UnitQuaternion, Vector3};
+use crate::camera::{Camera, CameraBuilder};
+use crate::Float;
+use crate::material::{DiffuseLight, Lambertian, MaterialKind, Metal, TexturedLambertian};
+use crate::RGB;
+use crate::scene::{Scene, Triangle};
+use crate::scene::loader::LoadError;
+use crate::texture::ImageTexture;
+
+// glTF 2.0 import, for .gltf files with embedded or external buffers and binary .glb files.
+// Triangle meshes, node transforms, base color materials and the first perspective camera are
+// read, anything else is skipped with a warning.
+pub struct GltfScene {
+    pub scene: Scene,
+    pub camera: Option<CameraBuilder>,
+    pub warnings: Vec<String>,
+}
+
+pub fn import_gltf(path: &Path) -> Result<GltfScene, LoadError> {
+    let (document, buffers, images) = gltf::import(path).map_err(gltf_error)?;
+    Ok(Importer::new(&buffers, &images).run(&document))
+}
+
+// Everything has to be embedded, there is no directory to resolve file references against
+pub fn import_gltf_slice(bytes: &[u8]) -> Result<GltfScene, LoadError> {
+    let gltf::Gltf { document, blob } = gltf::Gltf::from_slice(bytes).map_err(gltf_error)?;
+    let buffers = gltf::import_buffers(&document, None, blob).map_err(gltf_error)?;
+    // gltf only decodes data URI images when it has a base directory, so hand it one once it's
+    // clear that no image points at a file
+    let external = document.images().any(|image| match image.source() {
+        gltf::image::Source::Uri { uri, .. } => !uri.starts_with("data:"),
+        gltf::image::Source::View { .. } => false,
+    });
+    if external {
+        return Err(gltf_error(gltf::Error::ExternalReferenceInSliceImport));
+    }
+    let images = gltf::import_images(&document, Some(Path::new(".")), &buffers).map_err(gltf_error)?;
+    Ok(Importer::new(&buffers, &images).run(&document))
+}
+
+fn gltf_error(e: gltf::Error) -> LoadError {
+    match e {
+        gltf::Error::Io(e) => LoadError::Io(e),
+        e => LoadError::Invalid { path: ".".to_string(), message: e.to_string() },
+    }
+}
+
+struct Importer<'a> {
+    buffers: &'a [gltf::buffer::Data],
+    images: &'a [gltf::image::Data],
+    scene: Scene,
+    camera: Option<CameraBuilder>,
+    warnings: Vec<String>,
+    // By glTF index, None for the default material
+    materials: HashMap<Option<usize>, MaterialKind>,
+    textures: HashMap<usize, Option<Arc<ImageTexture>>>,
+}
+
+impl<'a> Importer<'a> {
+    fn new(buffers: &'a [gltf::buffer::Data], images: &'a [gltf::image::Data]) -> Self {
+        Self {
+            buffers,
+            images,
+            scene: Scene::new(),
+            camera: None,
+            warnings: Vec::new(),
+            materials: HashMap::new(),
+            textures: HashMap::new(),
+        }
+    }
+
+    fn run(mut self, document: &gltf::Document) -> GltfScene {
+        for extension in document.extensions_used() {
+            self.warnings.push(format!("extension {} is not supported", extension));
+        }
+        if document.skins().len() > 0 {
+            self.warnings.push(format!("{} skin(s) ignored, meshes are imported in their bind pose", document.skins().len()));
+        }
+        if document.animations().len() > 0 {
+            self.warnings.push(format!("{} animation(s) ignored", document.animations().len()));
+        }
+
+        match document.default_scene().or_else(|| document.scenes().next()) {
+            Some(scene) => {
+                for node in scene.nodes() {
+                    self.node(&node, &Matrix4::identity());
+                }
+            },
+            None => self.warnings.push("the file has no scene".to_string()),
+        }
+
+        GltfScene { scene: self.scene, camera: self.camera, warnings: self.warnings }
+    }
+
+    fn node(&mut self, node: &gltf::Node, parent: &Matrix4<Float>) {
+        let local = Matrix4::from(node.transform().matrix()).cast::<Float>();
+        let transform = parent * local;
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                self.primitive(&primitive, &transform, mesh.index());
+            }
+        }
+        if let Some(camera) = node.camera() {
+            self.camera(&camera, &transform);
+        }
+        for child in node.children() {
+            self.node(&child, &transform);
+        }
+    }
+
+    fn primitive(&mut self, primitive: &gltf::Primitive, transform: &Matrix4<Float>, mesh: usize) {
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            self.warnings.push(format!("mesh {}: {:?} primitives are not supported", mesh, primitive.mode()));
+            return;
+        }
+        if primitive.morph_targets().len() > 0 {
+            self.warnings.push(format!("mesh {}: morph targets ignored", mesh));
+        }
+
+        let buffers = self.buffers;
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let positions: Vec<Point3<Float>> = match reader.read_positions() {
+            Some(positions) => positions.map(|p| transform.transform_point(&Point3::from(p).cast())).collect(),
+            None => {
+                self.warnings.push(format!("mesh {}: primitive without positions", mesh));
+                return;
+            }
+        };
+        // Normals go through the inverse transpose so they stay perpendicular under non-uniform scale
+        let normal_transform = transform.fixed_view::<3, 3>(0, 0).try_inverse().unwrap_or_else(Matrix3::identity).transpose();
+        let normals: Option<Vec<Vector3<Float>>> = reader.read_normals()
+            .map(|normals| normals.map(|n| (normal_transform * Vector3::from(n).cast()).normalize()).collect());
+        let uvs: Option<Vec<(Float, Float)>> = reader.read_tex_coords(0)
+            .map(|uvs| uvs.into_f32().map(|[u, v]| (u as Float, v as Float)).collect());
+        let indices: Vec<usize> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().map(|idx| idx as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+
+        let material = self.material(&primitive.material());
+        for triangle in indices.chunks_exact(3) {
+            let [a, b, c] = [triangle[0], triangle[1], triangle[2]];
+            if a.max(b).max(c) >= positions.len() {
+                self.warnings.push(format!("mesh {}: index out of range", mesh));
+                return;
+            }
+            self.scene.add(Triangle {
+                vertices: [positions[a], positions[b], positions[c]],
+                normals: normals.as_ref().map(|n| [n[a], n[b], n[c]]),
+                uvs: uvs.as_ref().map(|uv| [uv[a], uv[b], uv[c]]),
+                material: material.clone(),
+            });
+        }
+    }
+
+    // Base color texture and factor become a Lambertian, mostly metallic materials a Metal with
+    // the roughness as fuzz. Emissive materials become lights.
+    fn material(&mut self, material: &gltf::Material) -> MaterialKind {
+        if let Some(material) = self.materials.get(&material.index()) {
+            return material.clone();
+        }
+
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, _] = pbr.base_color_factor();
+        let factor = RGB(r as Float, g as Float, b as Float);
+        let [er, eg, eb] = material.emissive_factor();
+        let texture = pbr.base_color_texture().and_then(|info| {
+            if info.tex_coord() != 0 {
+                self.warnings.push(format!("{}: only the first texture coordinate set is supported", name(material)));
+                return None;
+            }
+            self.texture(info.texture().source().index())
+        });
+
+        let built: MaterialKind = if er > 0.0 || eg > 0.0 || eb > 0.0 {
+            DiffuseLight::new(RGB(er as Float, eg as Float, eb as Float)).into()
+        } else if pbr.metallic_factor() >= 0.5 {
+            if texture.is_some() {
+                self.warnings.push(format!("{}: base color texture ignored on metal", name(material)));
+            }
+            Metal::new(factor, pbr.roughness_factor() as Float).into()
+        } else {
+            match texture {
+                Some(texture) => TexturedLambertian::new(texture, factor).into(),
+                None => Lambertian::new(factor).into(),
+            }
+        };
+        self.materials.insert(material.index(), built.clone());
+        if let Some(material_name) = material.name() {
+            if let Err(e) = self.scene.materials.register(material_name, built.clone()) {
+                self.warnings.push(format!("{}: {}", name(material), e));
+            }
+        }
+        built
+    }
+
+    fn texture(&mut self, image: usize) -> Option<Arc<ImageTexture>> {
+        if let Some(texture) = self.textures.get(&image) {
+            return texture.clone();
+        }
+        let data = &self.images[image];
+        let channels = match data.format {
+            gltf::image::Format::R8G8B8 => Some(3),
+            gltf::image::Format::R8G8B8A8 => Some(4),
+            _ => None,
+        };
+        // Base color textures are sRGB encoded
+        let texture = channels
+            .and_then(|channels| ImageTexture::from_srgb8(data.width as usize, data.height as usize, channels, &data.pixels))
+            .map(Arc::new);
+        if texture.is_none() {
+            self.warnings.push(format!("image {}: {:?} pixels are not supported", image, data.format));
+        }
+        self.textures.insert(image, texture.clone());
+        texture
+    }
+
+    // The first camera found wins
+    fn camera(&mut self, camera: &gltf::Camera, transform: &Matrix4<Float>) {
+        if self.camera.is_some() {
+            self.warnings.push(format!("camera {} ignored, only the first camera is used", camera.index()));
+            return;
+        }
+        let perspective = match camera.projection() {
+            gltf::camera::Projection::Perspective(perspective) => perspective,
+            gltf::camera::Projection::Orthographic(_) => {
+                self.warnings.push(format!("camera {}: orthographic cameras are not supported", camera.index()));
+                return;
+            }
+        };
+
+        // Scale is dropped, the camera looks down -Z with Y up like in glTF
+        let rotation = Rotation3::from_matrix(&transform.fixed_view::<3, 3>(0, 0).into_owned());
+        let translation = Translation3::new(transform[(0, 3)], transform[(1, 3)], transform[(2, 3)]);
+        let pose = Isometry3::from_parts(translation, UnitQuaternion::from_rotation_matrix(&rotation));
+        let mut builder = Camera::builder().pose(pose).fov((perspective.yfov() as Float).to_degrees());
+        if let Some(aspect_ratio) = perspective.aspect_ratio() {
+            builder = builder.aspect_ratio(aspect_ratio as Float);
+        }
+        self.camera = Some(builder);
+    }
+}
+
+fn name(material: &gltf::Material) -> String {
+    match (material.name(), material.index()) {
+        (Some(name), _) => format!("material '{}'", name),
+        (None, Some(idx)) => format!("material {}", idx),
+        (None, None) => "default material".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use na::{point, vector};
+    use crate::interval::Interval;
+    use crate::material::Material;
+    use crate::Ray;
+    use crate::scene::Hittable;
+    use crate::scene::gltf_import::{import_gltf, import_gltf_slice};
+    use crate::scene::loader::LoadError;
+    use crate::utils::INF;
+
+    const TEXTURED_BOX: &[u8] = include_bytes!("../../scenes/textured_box.gltf");
+
+    #[test]
+    fn test_textured_box() {
+        let imported = import_gltf_slice(TEXTURED_BOX).unwrap();
+        assert_eq!(imported.scene.hittables.len(), 12);
+        assert!(imported.scene.materials.get("Checker").is_some());
+        assert_eq!(imported.warnings.len(), 1);
+        assert!(imported.warnings[0].contains("KHR_materials_variants"), "{:?}", imported.warnings);
+
+        // The unit box is scaled by 2 and moved to z = -3, so its front face is at z = -2. The
+        // upper left quarter of the face shows the red texel.
+        let ray = Ray::new(point![0.0, 0.0, 0.0], vector![-0.5, 0.5, -2.0]);
+        let hit = imported.scene.hit(&ray, Interval::new(0.001, INF)).unwrap();
+        assert!((hit.p - point![-0.5, 0.5, -2.0]).norm() < 1e-6);
+        assert!((hit.normal - vector![0.0, 0.0, 1.0]).norm() < 1e-6);
+        assert!((hit.u - 0.25).abs() < 1e-6 && (hit.v - 0.25).abs() < 1e-6);
+        let (_, attenuation) = hit.material.scatter(&ray, &hit).unwrap();
+        assert_eq!((attenuation.0, attenuation.1, attenuation.2), (1.0, 0.0, 0.0));
+
+        // Lower right quarter is white
+        let ray = Ray::new(point![0.0, 0.0, 0.0], vector![0.5, -0.5, -2.0]);
+        let hit = imported.scene.hit(&ray, Interval::new(0.001, INF)).unwrap();
+        let (_, attenuation) = hit.material.scatter(&ray, &hit).unwrap();
+        assert_eq!((attenuation.0, attenuation.1, attenuation.2), (1.0, 1.0, 1.0));
+
+        let camera = imported.camera.unwrap().width(300).build().unwrap();
+        assert_eq!(camera.height(), 200);
+        assert!((camera.pose().translation.vector - vector![0.0, 0.0, 1.0]).norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_import_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/textured_box.gltf");
+        let imported = import_gltf(&path).unwrap();
+        assert_eq!(imported.scene.hittables.len(), 12);
+        assert!(matches!(import_gltf(&path.with_extension("glb")), Err(LoadError::Io(_))));
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        assert!(matches!(import_gltf_slice(b"{\"asset\": 2}"), Err(LoadError::Invalid { .. })));
+        let external = std::str::from_utf8(TEXTURED_BOX).unwrap().replacen("data:image/png;base64,", "texture.png#", 1);
+        assert!(matches!(import_gltf_slice(external.as_bytes()), Err(LoadError::Invalid { .. })));
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use na::Point3;
+use serde::Deserialize;
+use crate::camera::CameraBuilder;
+use crate::Float;
+use crate::material::TexturedLambertian;
+use crate::RGB;
+use crate::scene::Scene;
+use crate::scene::desc::{CameraDesc, MaterialDesc};
+use crate::texture::TextureCache;
+
+// Scene description as written in a JSON or TOML file. Vectors and colors are [x, y, z] / [r, g, b]
+// arrays. Both formats go through these structs so they accept exactly the same scenes.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SceneFile {
+    #[serde(default)]
+    pub camera: CameraDesc,
+    #[serde(default)]
+    pub materials: BTreeMap<String, MaterialDesc>,
+    // Image textured materials, named like the others
+    #[serde(default)]
+    pub textured: BTreeMap<String, TexturedBlock>,
+    #[serde(default)]
+    pub objects: Vec<ObjectBlock>,
+}
+
+// Lambertian with the albedo from a PNG or PPM file, relative to the scene file, times factor
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TexturedBlock {
+    pub texture: String,
+    #[serde(default = "white")]
+    pub factor: [Float; 3],
+}
+
+fn white() -> [Float; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+// Objects refer to the materials by name
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ObjectBlock {
+    Sphere { center: [Float; 3], radius: Float, material: String },
+}
+
+pub struct LoadedScene {
+    pub scene: Scene,
+    pub camera: CameraBuilder,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    UnknownFormat(String),
+    // Something wrong at the given path into the document, e.g. objects[2].center
+    Invalid { path: String, message: String },
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::UnknownFormat(path) => {
+                write!(f, "can't tell the scene format of '{}', use a .json or .toml extension", path)
+            },
+            LoadError::Invalid { path, message } => write!(f, "{}: {}", path, message),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+impl From<std::io::Error> for LoadError {
+    fn from(e: std::io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+fn invalid(path: impl Into<String>, message: impl Into<String>) -> LoadError {
+    LoadError::Invalid { path: path.into(), message: message.into() }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SceneFormat {
+    Json,
+    Toml,
+}
+
+impl SceneFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "json" => Some(SceneFormat::Json),
+            "toml" => Some(SceneFormat::Toml),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse(text: &str, format: SceneFormat) -> Result<SceneFile, LoadError> {
+    match format {
+        SceneFormat::Json => {
+            let mut deserializer = serde_json::Deserializer::from_str(text);
+            let file = serde_path_to_error::deserialize(&mut deserializer)
+                .map_err(|e| invalid(e.path().to_string(), e.inner().to_string()))?;
+            // Trailing garbage after the document
+            deserializer.end().map_err(|e| invalid(".", e.to_string()))?;
+            Ok(file)
+        },
+        SceneFormat::Toml => {
+            let toml_error = |path: String, e: &toml::de::Error| {
+                let message = match e.span() {
+                    Some(span) => {
+                        let (line, column) = line_column(text, span.start);
+                        format!("{} at line {} column {}", e.message(), line, column)
+                    },
+                    None => e.message().to_string(),
+                };
+                invalid(path, message)
+            };
+            let deserializer = toml::de::Deserializer::parse(text).map_err(|e| toml_error(".".to_string(), &e))?;
+            serde_path_to_error::deserialize(deserializer).map_err(|e| toml_error(e.path().to_string(), e.inner()))
+        }
+    }
+}
+
+// 1-based line and column of a byte offset
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+pub fn load(text: &str, format: SceneFormat) -> Result<LoadedScene, LoadError> {
+    parse(text, format)?.build()
+}
+
+pub fn load_json(text: &str) -> Result<LoadedScene, LoadError> {
+    load(text, SceneFormat::Json)
+}
+
+pub fn load_toml(text: &str) -> Result<LoadedScene, LoadError> {
+    load(text, SceneFormat::Toml)
+}
+
+// The format comes from the extension unless given
+pub fn load_file(path: &Path, format: Option<SceneFormat>) -> Result<LoadedScene, LoadError> {
+    load_file_with_cache(path, format, &TextureCache::new())
+}
+
+// Like load_file, with textures from a cache shared between scenes
+pub fn load_file_with_cache(path: &Path, format: Option<SceneFormat>, textures: &TextureCache) -> Result<LoadedScene, LoadError> {
+    let format = format.or_else(|| SceneFormat::from_path(path))
+        .ok_or_else(|| LoadError::UnknownFormat(path.display().to_string()))?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+    parse(&std::fs::read_to_string(path)?, format)?.build_with(textures, directory)
+}
+
+impl SceneFile {
+    // Texture paths are relative to the working directory
+    pub fn build(&self) -> Result<LoadedScene, LoadError> {
+        self.build_with(&TextureCache::new(), Path::new(""))
+    }
+
+    // Texture paths are relative to directory, every file is loaded once through the cache
+    pub fn build_with(&self, textures: &TextureCache, directory: &Path) -> Result<LoadedScene, LoadError> {
+        // Objects look up the material by name in the registry
+        let mut scene = Scene::new();
+        for (name, material) in &self.materials {
+            scene.materials.register(name, material.build()).map_err(|e| invalid(format!("materials.{}", name), e.to_string()))?;
+        }
+        for (name, block) in &self.textured {
+            let texture = textures.get_or_load(directory.join(&block.texture))
+                .map_err(|e| invalid(format!("textured.{}.texture", name), e.to_string()))?;
+            let [r, g, b] = block.factor;
+            scene.materials.register(name, TexturedLambertian::new(texture, RGB(r, g, b)))
+                .map_err(|e| invalid(format!("textured.{}", name), e.to_string()))?;
+        }
+
+        for (idx, object) in self.objects.iter().enumerate() {
+            match object {
+                ObjectBlock::Sphere { center, radius, material } => {
+                    let material = scene.materials.handle(material)
+                        .ok_or_else(|| invalid(format!("objects[{}].material", idx), format!("unknown material '{}'", material)))?;
+                    scene.add_sphere(Point3::from(*center), *radius, material);
+                }
+            }
+        }
+
+        let camera = self.camera.builder();
+        camera.clone().build().map_err(|e| invalid("camera", e.to_string()))?;
+        Ok(LoadedScene { scene, camera })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na::{point, vector};
+    use crate::interval::Interval;
+    use std::sync::Arc;
+    use crate::material::{Material, MaterialKind};
+    use crate::Ray;
+    use crate::scene::Hittable;
+    use crate::scene::loader::{load_file, load_file_with_cache, load_json, load_toml, parse, LoadError, SceneFormat};
+    use crate::texture::TextureCache;
+    use crate::utils::INF;
+
+    const THREE_SPHERES: &str = include_str!("../../scenes/three_spheres.json");
+    const THREE_SPHERES_TOML: &str = include_str!("../../scenes/three_spheres.toml");
+
+    fn error_path(text: &str) -> (String, String) {
+        match load_json(text) {
+            Err(LoadError::Invalid { path, message }) => (path, message),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("loaded {}", text),
+        }
+    }
+
+    #[test]
+    fn test_load_example() {
+        let loaded = load_json(THREE_SPHERES).unwrap();
+        assert_eq!(loaded.scene.hittables.len(), 4);
+        let camera = loaded.camera.build().unwrap();
+        assert_eq!((camera.width(), camera.height()), (400, 225));
+
+        // Straight down onto the glass sphere in the middle
+        let hit = loaded.scene.hit(&Ray::new(point![0.0, 5.0, 0.0], vector![0.0, -1.0, 0.0]), Interval::new(0.001, INF)).unwrap();
+        assert!((hit.t - 3.0).abs() < 1e-12);
+        assert!(hit.material.is_specular());
+
+        // The diffuse sphere on the left and the metal one on the right
+        let hit = loaded.scene.hit(&Ray::new(point![-4.0, 5.0, 0.0], vector![0.0, -1.0, 0.0]), Interval::new(0.001, INF)).unwrap();
+        assert!((hit.p - point![-4.0, 2.0, 0.0]).norm() < 1e-12);
+        let albedo = hit.material.albedo();
+        assert_eq!((albedo.0, albedo.1, albedo.2), (0.4, 0.2, 0.1));
+        let hit = loaded.scene.hit(&Ray::new(point![4.0, 1.0, 5.0], vector![0.0, 0.0, -1.0]), Interval::new(0.001, INF)).unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-12);
+        assert!(hit.material.is_specular());
+
+        // Between the spheres down to the ground
+        let hit = loaded.scene.hit(&Ray::new(point![2.0, 5.0, 0.0], vector![0.0, -1.0, 0.0]), Interval::new(0.001, INF)).unwrap();
+        assert!(hit.p.y.abs() < 0.01);
+
+        // Materials are registered under their names and the objects use them
+        assert_eq!(loaded.scene.materials.len(), 4);
+        let ground = loaded.scene.materials.get("ground").unwrap();
+        assert_eq!(hit.material.to_desc(), ground.to_desc());
+    }
+
+    #[test]
+    fn test_defaults() {
+        let loaded = load_json("{}").unwrap();
+        assert!(loaded.scene.hittables.is_empty());
+        assert_eq!(loaded.camera.build().unwrap().width(), 100);
+    }
+
+    #[test]
+    fn test_errors() {
+        let (path, message) = error_path(r#"{"materials": {"shiny": {"type": "plastic"}}}"#);
+        assert_eq!(path, "materials.shiny.type");
+        assert!(message.contains("plastic"), "{}", message);
+
+        let (path, message) = error_path(r#"{"objects": [
+            {"type": "sphere", "center": [0, 0, 0], "radius": 1, "material": "gold"}
+        ]}"#);
+        assert_eq!(path, "objects[0].material");
+        assert!(message.contains("gold"), "{}", message);
+
+        let (path, message) = error_path(r#"{"materials": {"m": {"type": "lambertian", "albedo": [1, 1, 1]}}, "objects": [
+            {"type": "sphere", "center": [0, 0, 0], "radius": 1, "material": "m"},
+            {"type": "sphere", "center": [0, 0], "radius": 1, "material": "m"}
+        ]}"#);
+        // Tagged objects are buffered before they are parsed, which loses the field
+        assert_eq!(path, "objects[1]");
+        assert!(message.contains("length 2"), "{}", message);
+
+        let (path, _) = error_path(r#"{"camera": {"lookfrom": [0, "up", 0]}}"#);
+        assert_eq!(path, "camera.lookfrom[1]");
+        let (path, _) = error_path(r#"{"camera": {"fov": 0}}"#);
+        assert_eq!(path, "camera");
+        let (path, _) = error_path(r#"{"camera": {"zoom": 2}}"#);
+        assert_eq!(path, "camera.zoom");
+
+        // Syntax errors carry the position
+        let (_, message) = error_path("{\n  \"objects\": [,]\n}");
+        assert!(message.contains("line 2"), "{}", message);
+        let (path, _) = error_path("{} {}");
+        assert_eq!(path, ".");
+    }
+
+    #[test]
+    fn test_toml_matches_json() {
+        let json = parse(THREE_SPHERES, SceneFormat::Json).unwrap();
+        let toml = parse(THREE_SPHERES_TOML, SceneFormat::Toml).unwrap();
+        assert_eq!(toml, json);
+        assert_eq!(load_toml(THREE_SPHERES_TOML).unwrap().scene.hittables.len(), 4);
+    }
+
+    #[test]
+    fn test_toml_errors() {
+        let error = |text: &str| match load_toml(text) {
+            Err(LoadError::Invalid { path, message }) => (path, message),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("loaded {}", text),
+        };
+
+        let (path, message) = error("[camera]\nfov = 20.0\nlookfrom = [1.0, \"up\", 0.0]\n");
+        assert_eq!(path, "camera.lookfrom[1]");
+        assert!(message.contains("line 3 column 18"), "{}", message);
+
+        let (path, message) = error("[materials.shiny]\ntype = \"plastic\"\n");
+        assert_eq!(path, "materials.shiny.type");
+        assert!(message.contains("plastic") && message.contains("line 2"), "{}", message);
+
+        let (_, message) = error("[camera]\nfov = \n");
+        assert!(message.contains("line 2"), "{}", message);
+
+        let (path, _) = error("[[objects]]\ntype = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"gold\"\n");
+        assert_eq!(path, "objects[0].material");
+    }
+
+    #[test]
+    fn test_textured() {
+        let dir = std::env::temp_dir().join(format!("raytracer_textured_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("earth.ppm"), "P3 1 1 255 255 128 0").unwrap();
+        // Two materials on one file and twenty spheres using them
+        let spheres: Vec<String> = (0..20)
+            .map(|idx| format!(r#"{{"type": "sphere", "center": [{}, 0, 0], "radius": 0.4, "material": "{}"}}"#, idx, ["land", "sea"][idx % 2]))
+            .collect();
+        let text = format!(r#"{{"textured": {{
+            "land": {{"texture": "earth.ppm"}},
+            "sea": {{"texture": "./earth.ppm", "factor": [0.5, 0.5, 1]}}
+        }}, "objects": [{}]}}"#, spheres.join(", "));
+        let path = dir.join("planets.json");
+        std::fs::write(&path, &text).unwrap();
+
+        let cache = TextureCache::new();
+        let loaded = load_file_with_cache(&path, None, &cache).unwrap();
+        assert_eq!(loaded.scene.hittables.len(), 20);
+        let texture = |name: &str| match loaded.scene.materials.get(name).unwrap() {
+            MaterialKind::TexturedLambertian(material) => material.texture,
+            _ => panic!("{} is not textured", name),
+        };
+        assert!(Arc::ptr_eq(&texture("land"), &texture("sea")));
+        assert_eq!(texture("land").level_pixel(0, 0, 0).0, 1.0);
+        assert_eq!(cache.len(), 1);
+        // The next scene through the same cache shares the texture too
+        let again = load_file_with_cache(&path, None, &cache).unwrap();
+        match again.scene.materials.get("land").unwrap() {
+            MaterialKind::TexturedLambertian(material) => assert!(Arc::ptr_eq(&material.texture, &texture("land"))),
+            _ => panic!("land is not textured"),
+        }
+
+        // Paths are relative to the scene file, not the working directory
+        std::fs::write(&path, text.replace("./earth.ppm", "moon.ppm")).unwrap();
+        match load_file(&path, None) {
+            Err(LoadError::Invalid { path, message }) => {
+                assert_eq!(path, "textured.sea.texture");
+                assert!(message.contains("moon.ppm"), "{}", message);
+            },
+            _ => panic!("loaded a missing texture"),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_from_extension() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("raytracer_scene_{}.toml", std::process::id()));
+        std::fs::write(&path, THREE_SPHERES_TOML).unwrap();
+        assert_eq!(load_file(&path, None).unwrap().scene.hittables.len(), 4);
+        // The override wins over the extension
+        assert!(matches!(load_file(&path, Some(SceneFormat::Json)), Err(LoadError::Invalid { .. })));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(load_file(&dir.join("scene.yaml"), None), Err(LoadError::UnknownFormat(_))));
+        assert!(matches!(load_file(&dir.join("missing_scene.json"), None), Err(LoadError::Io(_))));
+    }
+}
@@ -0,0 +1,256 @@
+use na::{point, vector, Point3, Vector3};
+use crate::aabb::Aabb;
+use crate::Float;
+use crate::interval::Interval;
+use crate::material::MaterialKind;
+use crate::Ray;
+use crate::scene::{hit_sphere, sphere_roots, HitRecord, Hittable, Sphere, TraversalStats};
+
+// Relative, so rounding in the box test can't cut off a grazing hit
+const BOUNDS_ROOM: Float = 1e-4;
+
+// Many static spheres in parallel arrays, one per coordinate. Finding the closest hit is a tight
+// loop over plain numbers the compiler can vectorize, instead of a step through every object.
+// Only the winning sphere gets a full HitRecord.
+#[derive(Clone, Default)]
+pub struct SphereList {
+    xs: Vec<Float>,
+    ys: Vec<Float>,
+    zs: Vec<Float>,
+    radii: Vec<Float>,
+    material_ids: Vec<u32>, // Index into materials for every sphere
+    materials: Vec<MaterialKind>,
+    bounds: Aabb, // Around all spheres with some room, rays that miss it skip the loop
+}
+
+impl SphereList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the index to pass to push, all spheres with that index share the material
+    pub fn add_material(&mut self, material: impl Into<MaterialKind>) -> u32 {
+        self.materials.push(material.into());
+        (self.materials.len() - 1) as u32
+    }
+
+    // Panics for a material index that add_material didn't hand out
+    pub fn push(&mut self, center: Point3<Float>, radius: Float, material: u32) {
+        assert!((material as usize) < self.materials.len(), "unknown material {}", material);
+        self.xs.push(center.x);
+        self.ys.push(center.y);
+        self.zs.push(center.z);
+        self.radii.push(radius);
+        self.material_ids.push(material);
+        let room = Vector3::repeat(radius.abs() * (1.0 + BOUNDS_ROOM));
+        self.bounds = self.bounds.union(&Aabb::new(center - room, center + room));
+    }
+
+    pub fn len(&self) -> usize {
+        self.radii.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.radii.is_empty()
+    }
+
+    fn center(&self, idx: usize) -> Point3<Float> {
+        point![self.xs[idx], self.ys[idx], self.zs[idx]]
+    }
+}
+
+// Every sphere keeps its own material
+impl FromIterator<Sphere> for SphereList {
+    fn from_iter<T: IntoIterator<Item = Sphere>>(spheres: T) -> Self {
+        let mut list = SphereList::new();
+        for sphere in spheres {
+            let material = list.add_material(sphere.material);
+            list.push(sphere.center, sphere.radius, material);
+        }
+        list
+    }
+}
+
+impl Hittable for SphereList {
+    fn hit(&self, ray: &Ray, trange: Interval) -> Option<HitRecord<'_>> {
+        if !self.bounds.hit(ray, trange) {
+            return None;
+        }
+        // Same roots as hit_sphere, so the closest sphere is the one Sphere::hit would pick
+        let mut closest = trange.max;
+        let mut winner = None;
+        let spheres = self.xs.iter().zip(&self.ys).zip(&self.zs).zip(&self.radii).enumerate();
+        for (idx, (((&x, &y), &z), &radius)) in spheres {
+            let oc = vector![ray.orig.x - x, ray.orig.y - y, ray.orig.z - z];
+            let Some((near, far)) = sphere_roots(oc, &ray.dir, radius) else {
+                continue;
+            };
+            let root = if trange.min < near && near < closest { near } else { far };
+            if trange.min < root && root < closest {
+                closest = root;
+                winner = Some(idx);
+            }
+        }
+
+        let idx = winner?;
+        let material = &self.materials[self.material_ids[idx] as usize];
+        hit_sphere(self.center(idx), self.radii[idx], material, ray, trange)
+    }
+
+    // A test of the bounds, and one per sphere if it passes
+    fn hit_counted(&self, ray: &Ray, trange: Interval, stats: &mut TraversalStats) -> Option<HitRecord<'_>> {
+        stats.node_visits += 1;
+        if self.bounds.hit(ray, trange) {
+            stats.intersection_tests += self.len() as u32;
+        }
+        self.hit(ray, trange)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let boxes = (0..self.len()).map(|idx| {
+            let r = Vector3::repeat(self.radii[idx].abs());
+            Aabb::new(self.center(idx) - r, self.center(idx) + r)
+        });
+        Some(boxes.fold(Aabb::empty(), |aabb, b| aabb.union(&b)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na::{point, vector, Vector3};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::SmallRng;
+    use crate::Float;
+    use crate::interval::Interval;
+    use crate::material::{Lambertian, Material};
+    use crate::Ray;
+    use crate::RGB;
+    use crate::scene::{Hittable, Primitive, Scene, Sphere, TraversalStats};
+    use crate::scene::generators::RandomSpheres;
+    use crate::scene::sphere_list::SphereList;
+    use crate::utils::INF;
+
+    #[test]
+    fn test_matches_separate_spheres() {
+        let scene = RandomSpheres::new().seed(5).generate();
+        let spheres: Vec<Sphere> = scene.iter().map(|primitive| match primitive {
+            Primitive::Sphere(sphere) => sphere.clone(),
+            _ => panic!("only spheres expected"),
+        }).collect();
+        let list: SphereList = spheres.iter().cloned().collect();
+        assert_eq!(list.len(), scene.len());
+        assert_eq!(list.bounding_box(), scene.bounding_box());
+
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut hits = 0;
+        for _ in 0..20_000 {
+            // From around the default view into the field of spheres, some from inside the ground
+            let orig = point![rng.gen_range(-12.0..12.0), rng.gen_range(-0.5..3.0), rng.gen_range(-12.0..12.0)];
+            let dir = vector![rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..0.2), rng.gen_range(-1.0..1.0)];
+            let ray = Ray::new(orig, dir);
+            let trange = Interval::new(0.001, if rng.gen_bool(0.2) { rng.gen_range(0.1..5.0) } else { INF });
+            match (scene.hit(&ray, trange), list.hit(&ray, trange)) {
+                (Some(expected), Some(hit)) => {
+                    hits += 1;
+                    assert_eq!(hit.t, expected.t);
+                    assert_eq!((hit.p, hit.normal, hit.front), (expected.p, expected.normal, expected.front));
+                    assert_eq!((hit.u, hit.v), (expected.u, expected.v));
+                    assert_eq!(hit.material.to_desc(), expected.material.to_desc());
+                }
+                (None, None) => {}
+                (expected, hit) => panic!("{:?}: expected {:?}, got {:?}", ray, expected.map(|h| h.t), hit.map(|h| h.t)),
+            }
+        }
+        assert!(hits > 1000, "{} hits", hits);
+    }
+
+    #[test]
+    fn test_shared_materials() {
+        let mut list = SphereList::new();
+        let red = list.add_material(Lambertian::new(RGB(1.0, 0.0, 0.0)));
+        let blue = list.add_material(Lambertian::new(RGB(0.0, 0.0, 1.0)));
+        for x in 0..4 {
+            list.push(point![x as Float * 2.0, 0.0, -2.0], 0.5, if x % 2 == 0 { red } else { blue });
+        }
+        assert_eq!(list.len(), 4);
+
+        let albedo_at = |x: Float| list.hit(&Ray::new(point![x, 0.0, 0.0], -Vector3::z()), Interval::new(0.001, INF)).map(|hit| hit.material.albedo());
+        assert_eq!(albedo_at(0.0).unwrap().0, 1.0);
+        assert_eq!(albedo_at(2.0).unwrap().2, 1.0);
+        assert_eq!(albedo_at(4.0).unwrap().0, 1.0);
+        assert!(albedo_at(1.0).is_none());
+
+        // Works like any other object in a scene
+        let mut scene = Scene::new();
+        scene.add(std::sync::Arc::new(list));
+        assert!(scene.hit(&Ray::new(point![6.0, 0.0, 0.0], -Vector3::z()), Interval::new(0.001, INF)).is_some());
+        assert!(SphereList::new().hit(&Ray::new(point![0.0, 0.0, 0.0], -Vector3::z()), Interval::UNIVERSE).is_none());
+    }
+
+    #[test]
+    fn test_hit_counted() {
+        let list: SphereList = (0..4).map(|x| Sphere {
+            center: point![x as Float * 2.0, 0.0, -2.0],
+            radius: 0.5,
+            material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into()
+        }).collect();
+        let mut scene = Scene::new();
+        scene.add(std::sync::Arc::new(list));
+
+        // Rays that miss the bounds don't test a single sphere
+        let count = |x: Float, y: Float| {
+            let mut stats = TraversalStats::default();
+            let hit = scene.hit_counted(&Ray::new(point![x, y, 0.0], -Vector3::z()), Interval::new(0.001, INF), &mut stats);
+            (hit.is_some(), stats)
+        };
+        assert_eq!(count(2.0, 0.0), (true, TraversalStats { intersection_tests: 4, node_visits: 2 }));
+        assert_eq!(count(1.0, 0.0), (false, TraversalStats { intersection_tests: 4, node_visits: 2 }));
+        assert_eq!(count(2.0, 3.0), (false, TraversalStats { intersection_tests: 0, node_visits: 2 }));
+    }
+
+    // Closest hits per second against the random spheres, as separate objects and as one list:
+    // cargo test --release bench_sphere_list -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_sphere_list() {
+        use std::hint::black_box;
+        use std::time::Instant;
+
+        // The book's full field of spheres
+        let scene = RandomSpheres::new().extent(11).seed(5).generate();
+        let list: SphereList = scene.iter().map(|primitive| match primitive {
+            Primitive::Sphere(sphere) => sphere.clone(),
+            _ => panic!("only spheres expected"),
+        }).collect();
+        let mut rng = SmallRng::seed_from_u64(1);
+        let rays: Vec<_> = (0..20_000).map(|_| {
+            let orig = point![rng.gen_range(-12.0..12.0), rng.gen_range(0.5..3.0), rng.gen_range(-12.0..12.0)];
+            Ray::new(orig, vector![rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..0.2), rng.gen_range(-1.0..1.0)])
+        }).collect();
+        let trange = Interval::new(0.001, INF);
+        let rounds = 10;
+
+        let start = Instant::now();
+        let mut scene_hits = 0;
+        for _ in 0..rounds {
+            for ray in &rays {
+                scene_hits += black_box(scene.hit(ray, trange)).is_some() as usize;
+            }
+        }
+        let separate = start.elapsed();
+
+        let start = Instant::now();
+        let mut list_hits = 0;
+        for _ in 0..rounds {
+            for ray in &rays {
+                list_hits += black_box(list.hit(ray, trange)).is_some() as usize;
+            }
+        }
+        let listed = start.elapsed();
+
+        assert_eq!(scene_hits, list_hits);
+        let queries = (rounds * rays.len()) as f64;
+        println!("{} spheres: scene {:.2} M rays/s, sphere list {:.2} M rays/s",
+            list.len(), queries / separate.as_secs_f64() / 1e6, queries / listed.as_secs_f64() / 1e6);
+    }
+}
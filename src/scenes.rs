@@ -0,0 +1,69 @@
+use crate::consts::PI;
+use std::sync::Arc;
+use na::point;
+use crate::material::{Dielectric, Lambertian, Metal};
+use crate::RGB;
+use crate::scene::{Scene, Sphere};
+use crate::scene::generators::RandomSpheres;
+
+// Three spheres of different materials on a large ground sphere
+pub fn setup_scene() -> Scene {
+    let mut scene = Scene::new();
+    let material_ground = Lambertian::new(RGB(0.8, 0.8, 0.0));
+    let material_center = Lambertian::new(RGB(0.1, 0.2, 0.5));
+    let material_left = Dielectric::new(1.5);
+    let material_right = Metal::new(RGB(0.8, 0.6, 0.2), 0.0);
+
+    scene.add(Sphere {
+        center: point![0.0, -100.5, -1.0],
+        radius: 100.0,
+        material: material_ground.into()
+    });
+    scene.add(Sphere {
+        center: point![0.0, 0.0, -1.0],
+        radius: 0.5,
+        material: material_center.into()
+    });
+    scene.add(Sphere {
+        center: point![-1.0, 0.0, -1.0],
+        radius: 0.5,
+        material: material_left.into()
+    });
+    scene.add(Sphere {
+        center: point![1.0, 0.0, -1.0],
+        radius: 0.5,
+        material: material_right.into()
+    });
+    scene
+}
+
+// Two touching spheres filling a 90 degree field of view
+pub fn setup_scene2() -> Scene {
+    let mut scene = Scene::new();
+
+    let r = (PI / 4.0).cos();
+    let mat_left = Lambertian::new(RGB(0.0, 0.0, 1.0));
+    let mat_right = Lambertian::new(RGB(1.0, 0.0, 0.0));
+
+    scene.add(Sphere {
+        center: point![-r, 0.0, -1.0],
+        radius: r,
+        material: mat_left.into()
+    });
+    scene.add(Sphere {
+        center: point![r, 0.0, -1.0],
+        radius: r,
+        material: mat_right.into()
+    });
+    scene
+}
+
+// Random small spheres around three large ones, the cover of Ray Tracing in One Weekend
+pub fn final_scene() -> Arc<Scene> {
+    Arc::new(RandomSpheres::new().generate())
+}
+
+// final_scene with the small diffuse spheres bouncing up during the shutter interval [0, 1]
+pub fn bouncing_spheres() -> Arc<Scene> {
+    Arc::new(RandomSpheres::new().bouncing(true).generate())
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Result, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use serde_json::json;
+use crate::camera::Camera;
+use crate::Float;
+use crate::scene::Scene;
+use crate::scene::loader::load_json;
+
+// Renders waiting behind the current one, more are turned away with 503
+pub const MAX_QUEUED: usize = 4;
+// Jobs remembered for /progress and /result, the oldest finished ones are forgotten first
+pub const MAX_JOBS: usize = 64;
+// Largest scene accepted
+pub const MAX_BODY: usize = 16 << 20;
+
+// Renders scenes sent over HTTP, one at a time on the rayon pool.
+//
+//   POST /render?width=..&samples=..  scene JSON in, PNG out. With wait=false the answer is
+//                                     202 with the job id right away.
+//   GET /progress/<id>                {"id", "state": queued|rendering|done, "percent"}
+//   GET /result/<id>                  the PNG, 202 while it's still rendering
+//
+// Every response to a render carries the job id in an X-Job-Id header. Connections are closed
+// after one request.
+pub struct Server {
+    listener: TcpListener,
+    state: Arc<State>,
+}
+
+struct State {
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+    next_id: AtomicU64,
+    queue: SyncSender<(Arc<Job>, Arc<Scene>, Camera)>,
+}
+
+struct Job {
+    id: u64,
+    total: usize, // Pixels
+    done: AtomicUsize,
+    started: AtomicBool,
+    png: Mutex<Option<std::result::Result<Vec<u8>, String>>>,
+    finished: Condvar,
+}
+
+impl Job {
+    fn is_finished(&self) -> bool {
+        self.png.lock().unwrap().is_some()
+    }
+
+    fn percent(&self) -> Float {
+        100.0 * self.done.load(Ordering::Relaxed) as Float / self.total.max(1) as Float
+    }
+
+    fn wait(&self) -> std::result::Result<Vec<u8>, String> {
+        let png = self.finished.wait_while(self.png.lock().unwrap(), |png| png.is_none()).unwrap();
+        png.clone().unwrap()
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: BTreeMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    job: Option<u64>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: u16, text: impl Into<String>) -> Self {
+        let mut body = text.into().into_bytes();
+        body.push(b'\n');
+        Self { status, content_type: "text/plain; charset=utf-8", job: None, body }
+    }
+
+    fn json(status: u16, value: serde_json::Value) -> Self {
+        Self { status, content_type: "application/json", job: None, body: value.to_string().into_bytes() }
+    }
+
+    fn png(png: Vec<u8>) -> Self {
+        Self { status: 200, content_type: "image/png", job: None, body: png }
+    }
+
+    fn job(mut self, id: u64) -> Self {
+        self.job = Some(id);
+        self
+    }
+
+    fn write(&self, stream: &mut impl Write) -> Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        write!(stream, "HTTP/1.1 {} {}\r\n", self.status, reason)?;
+        write!(stream, "Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n", self.content_type, self.body.len())?;
+        if let Some(id) = self.job {
+            write!(stream, "X-Job-Id: {}\r\n", id)?;
+        }
+        stream.write_all(b"\r\n")?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+impl Server {
+    // Starts the render thread right away, requests are only answered once run is called
+    pub fn bind(address: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let (queue, jobs) = sync_channel(MAX_QUEUED);
+        std::thread::spawn(move || render_jobs(jobs));
+        let state = Arc::new(State { jobs: Mutex::new(BTreeMap::new()), next_id: AtomicU64::new(1), queue });
+        Ok(Self { listener, state })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Answers requests forever, each connection on its own thread
+    pub fn run(&self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let state = self.state.clone();
+            std::thread::spawn(move || {
+                // The client went away, nothing to tell anyone
+                let _ = handle(stream, &state);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn render_jobs(jobs: Receiver<(Arc<Job>, Arc<Scene>, Camera)>) {
+    for (job, scene, camera) in jobs {
+        job.started.store(true, Ordering::Relaxed);
+        let output = camera.renderer().render_with_progress(scene, |done, _| {
+            job.done.fetch_max(done, Ordering::Relaxed);
+        });
+        let mut png = vec![];
+        let result = output.beauty.save_png(&mut png).map(|_| png).map_err(|e| e.to_string());
+        *job.png.lock().unwrap() = Some(result);
+        job.finished.notify_all();
+    }
+}
+
+fn handle(mut stream: TcpStream, state: &State) -> Result<()> {
+    let response = match read_request(&mut BufReader::new(&stream)) {
+        Ok(request) => route(&request, state),
+        Err(response) => response,
+    };
+    response.write(&mut stream)
+}
+
+fn route(request: &Request, state: &State) -> Response {
+    let segments: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["render"]) => submit(request, state),
+        ("GET", ["progress", id]) => match find(state, id) {
+            Ok(job) => progress(&job),
+            Err(response) => response,
+        },
+        ("GET", ["result", id]) => match find(state, id) {
+            Ok(job) if job.is_finished() => result(&job),
+            Ok(job) => Response::text(202, format!("job {} is at {:.1}%", job.id, job.percent())).job(job.id),
+            Err(response) => response,
+        },
+        (_, ["render"] | ["progress", _] | ["result", _]) => Response::text(405, format!("{} isn't supported here", request.method)),
+        _ => Response::text(404, format!("nothing at {}", request.path)),
+    }
+}
+
+fn submit(request: &Request, state: &State) -> Response {
+    let text = match std::str::from_utf8(&request.body) {
+        Ok(text) => text,
+        Err(e) => return Response::text(400, format!("scene isn't UTF-8: {}", e)),
+    };
+    let loaded = match load_json(text) {
+        Ok(loaded) => loaded,
+        Err(e) => return Response::text(400, e.to_string()),
+    };
+    let mut camera = loaded.camera;
+    let mut wait = true;
+    for (name, value) in &request.query {
+        let invalid = || Response::text(400, format!("invalid value for {}: {}", name, value));
+        match name.as_str() {
+            "width" => match value.parse() {
+                Ok(width) if width > 0 => camera = camera.width(width),
+                _ => return invalid(),
+            },
+            "samples" => match value.parse() {
+                Ok(samples) if samples > 0 => camera = camera.samples_per_pixel(samples),
+                _ => return invalid(),
+            },
+            "wait" => match value.parse() {
+                Ok(value) => wait = value,
+                Err(_) => return invalid(),
+            },
+            _ => return Response::text(400, format!("unknown parameter {}", name)),
+        }
+    }
+    let camera = match camera.build() {
+        Ok(camera) => camera,
+        Err(e) => return Response::text(400, e.to_string()),
+    };
+
+    let job = Arc::new(Job {
+        id: state.next_id.fetch_add(1, Ordering::Relaxed),
+        total: camera.width() * camera.height(),
+        done: AtomicUsize::new(0),
+        started: AtomicBool::new(false),
+        png: Mutex::new(None),
+        finished: Condvar::new(),
+    });
+    // Registered first, so the job can be asked about as soon as it starts
+    remember(state, job.clone());
+    match state.queue.try_send((job.clone(), Arc::new(loaded.scene), camera)) {
+        Ok(()) => {},
+        Err(TrySendError::Full(_)) => {
+            state.jobs.lock().unwrap().remove(&job.id);
+            return Response::text(503, format!("{} renders are waiting already, try again later", MAX_QUEUED));
+        },
+        Err(TrySendError::Disconnected(_)) => return Response::text(500, "the render thread is gone"),
+    }
+
+    if wait {
+        result(&job)
+    } else {
+        Response::json(202, json!({ "id": job.id })).job(job.id)
+    }
+}
+
+fn remember(state: &State, job: Arc<Job>) {
+    let mut jobs = state.jobs.lock().unwrap();
+    jobs.insert(job.id, job);
+    while jobs.len() > MAX_JOBS {
+        match jobs.values().find(|job| job.is_finished()).map(|job| job.id) {
+            Some(id) => jobs.remove(&id),
+            None => break,
+        };
+    }
+}
+
+fn find(state: &State, id: &str) -> std::result::Result<Arc<Job>, Response> {
+    id.parse::<u64>().ok()
+        .and_then(|id| state.jobs.lock().unwrap().get(&id).cloned())
+        .ok_or_else(|| Response::text(404, format!("no job {}", id)))
+}
+
+fn progress(job: &Job) -> Response {
+    let state = if job.is_finished() {
+        "done"
+    } else if job.started.load(Ordering::Relaxed) {
+        "rendering"
+    } else {
+        "queued"
+    };
+    Response::json(200, json!({ "id": job.id, "state": state, "percent": job.percent() })).job(job.id)
+}
+
+// Blocks until the job is done
+fn result(job: &Job) -> Response {
+    match job.wait() {
+        Ok(png) => Response::png(png).job(job.id),
+        Err(e) => Response::text(500, e).job(job.id),
+    }
+}
+
+// Only what the routes above need: the request line, Content-Length and the body
+fn read_request(reader: &mut impl BufRead) -> std::result::Result<Request, Response> {
+    let bad_request = |message: &str| Response::text(400, message);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|_| bad_request("can't read the request"))?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(bad_request("malformed request line"));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (name.to_string(), value.to_string())
+        })
+        .collect();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(|_| bad_request("can't read the headers"))?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(bad_request("malformed header"));
+        };
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().map_err(|_| bad_request("invalid Content-Length"))?;
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(Response::text(413, format!("scenes can be at most {} bytes", MAX_BODY)));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(|_| bad_request("body shorter than Content-Length"))?;
+
+    Ok(Request { method: method.to_string(), path: path.to_string(), query, body })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::serve::read_request;
+
+    #[test]
+    fn test_read_request() {
+        let mut text: &[u8] = b"POST /render?width=8&samples=2&wait HTTP/1.1\r\nHost: x\r\ncontent-length: 4\r\n\r\n{}\n\nrest";
+        let request = read_request(&mut text).ok().unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/render"));
+        assert_eq!(request.query.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect::<Vec<_>>(), [("samples", "2"), ("wait", ""), ("width", "8")]);
+        assert_eq!(request.body, b"{}\n\n");
+
+        let status = |mut text: &[u8]| read_request(&mut text).err().unwrap().status;
+        assert_eq!(status(b"GET\r\n\r\n"), 400);
+        assert_eq!(status(b"POST /render HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}"), 400);
+        assert_eq!(status(b"POST /render HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n"), 413);
+    }
+}
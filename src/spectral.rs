@@ -0,0 +1,297 @@
+use crate::Float;
+use crate::RGB;
+use crate::utils::rand;
+
+// Visible range the wavelengths of spectral renders are drawn from, in nanometers
+pub const LAMBDA_MIN: Float = 380.0;
+pub const LAMBDA_MAX: Float = 730.0;
+
+// Wavelengths every path carries, one per channel of the colors along it
+pub const WAVELENGTHS: usize = 3;
+
+// How the wavelengths of a path are drawn, see RenderSettings::spectral
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum WavelengthSampling {
+    // Each of them on its own, uniformly over the visible range
+    Uniform,
+    // One uniformly, the others evenly spaced after it with wrap around. Covers the range more
+    // evenly, so colors converge faster.
+    #[default]
+    Hero,
+}
+
+// The wavelengths of a path. Spectral values along it are stored in the channels of an RGB, the
+// first one is the hero. Dispersion splits light of different wavelengths apart, after that only
+// the hero follows the path and the others are dropped.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Wavelengths {
+    pub lambda: [Float; WAVELENGTHS],
+    pub secondary: bool, // Whether the others still count
+}
+
+impl Wavelengths {
+    pub fn sample(sampling: WavelengthSampling) -> Self {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let lambda = match sampling {
+            WavelengthSampling::Uniform => [0; WAVELENGTHS].map(|_| LAMBDA_MIN + range * rand()),
+            WavelengthSampling::Hero => {
+                let hero = range * rand();
+                std::array::from_fn(|k| LAMBDA_MIN + (hero + range * k as Float / WAVELENGTHS as Float) % range)
+            },
+        };
+        Self { lambda, secondary: true }
+    }
+
+    pub fn hero(&self) -> Float {
+        self.lambda[0]
+    }
+
+    pub fn terminate_secondary(mut self) -> Self {
+        self.secondary = false;
+        self
+    }
+
+    // Weight that keeps the estimate right once the others are dropped, the hero stands in
+    // for all of them
+    pub fn termination_weight() -> RGB {
+        RGB(WAVELENGTHS as Float, 0.0, 0.0)
+    }
+
+    // The spectrum of an RGB color, see upsample, at every wavelength
+    pub fn upsample(&self, color: RGB) -> RGB {
+        let [a, b, c] = self.lambda.map(|lambda| upsample(color, lambda));
+        RGB(a, b, c)
+    }
+
+    // Spectral radiance at the wavelengths as linear sRGB, see white_balanced_rgb. Radiance of
+    // one all over the visible range comes out as white.
+    pub fn to_rgb(&self, radiance: RGB) -> RGB {
+        let values = [radiance.0, radiance.1, radiance.2];
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let mut xyz = [0.0; 3];
+        for (lambda, value) in self.lambda.iter().zip(values) {
+            let cmf = cie_cmf(*lambda);
+            (0..3).for_each(|c| xyz[c] += value * cmf[c] * range / WAVELENGTHS as Float);
+        }
+        white_balanced_rgb(xyz)
+    }
+}
+
+// CIE 1931 2° color matching functions x̄, ȳ, z̄ every 10nm from LAMBDA_MIN to LAMBDA_MAX.
+// The values are kept as published, f32 builds round them.
+const CIE_STEP: Float = 10.0;
+#[allow(clippy::excessive_precision)]
+const CIE_1931: [[Float; 3]; 36] = [
+    [0.001368, 0.000039, 0.006450],
+    [0.004243, 0.000120, 0.020050],
+    [0.014310, 0.000396, 0.067850],
+    [0.043510, 0.001210, 0.207400],
+    [0.134380, 0.004000, 0.645600],
+    [0.283900, 0.011600, 1.385600],
+    [0.348280, 0.023000, 1.747060],
+    [0.336200, 0.038000, 1.772110],
+    [0.290800, 0.060000, 1.669200],
+    [0.195360, 0.090980, 1.287640],
+    [0.095640, 0.139020, 0.812950],
+    [0.032010, 0.208020, 0.465180],
+    [0.004900, 0.323000, 0.272000],
+    [0.009300, 0.503000, 0.158200],
+    [0.063270, 0.710000, 0.078250],
+    [0.165500, 0.862000, 0.042160],
+    [0.290400, 0.954000, 0.020300],
+    [0.433450, 0.994950, 0.008750],
+    [0.594500, 0.995000, 0.003900],
+    [0.762100, 0.952000, 0.002100],
+    [0.916300, 0.870000, 0.001650],
+    [1.026300, 0.757000, 0.001100],
+    [1.062200, 0.631000, 0.000800],
+    [1.002600, 0.503000, 0.000340],
+    [0.854450, 0.381000, 0.000190],
+    [0.642400, 0.265000, 0.000050],
+    [0.447900, 0.175000, 0.000020],
+    [0.283500, 0.107000, 0.000000],
+    [0.164900, 0.061000, 0.000000],
+    [0.087400, 0.032000, 0.000000],
+    [0.046770, 0.017000, 0.000000],
+    [0.022700, 0.008210, 0.000000],
+    [0.011359, 0.004102, 0.000000],
+    [0.005790, 0.002091, 0.000000],
+    [0.002899, 0.001047, 0.000000],
+    [0.001440, 0.000520, 0.000000],
+];
+
+// x̄, ȳ, z̄ at a wavelength in nanometers, interpolated linearly between the table entries and
+// zero outside of the visible range
+pub fn cie_cmf(lambda: Float) -> [Float; 3] {
+    if !(LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+        return [0.0; 3];
+    }
+    let position = (lambda - LAMBDA_MIN) / CIE_STEP;
+    let index = (position as usize).min(CIE_1931.len() - 2);
+    let t = position - index as Float;
+    let (low, high) = (CIE_1931[index], CIE_1931[index + 1]);
+    std::array::from_fn(|c| low[c] + t * (high[c] - low[c]))
+}
+
+// Integrals of x̄, ȳ, z̄ over the visible range, exact for the linear interpolation of cie_cmf
+fn cie_integrals() -> [Float; 3] {
+    let mut sums = [0.0; 3];
+    for pair in CIE_1931.windows(2) {
+        (0..3).for_each(|c| sums[c] += (pair[0][c] + pair[1][c]) / 2.0 * CIE_STEP);
+    }
+    sums
+}
+
+// Linear sRGB of CIE XYZ, for the D65 white point
+#[allow(clippy::excessive_precision)]
+pub fn xyz_to_rgb([x, y, z]: [Float; 3]) -> RGB {
+    RGB(
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.9692660 * x + 1.8760108 * y + 0.0415560 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    )
+}
+
+// xyz_to_rgb of XYZ integrated against the color matching functions, scaled so that a spectrum
+// of one everywhere, the equal energy white, maps to white and not the pink it has under D65
+fn white_balanced_rgb(xyz: [Float; 3]) -> RGB {
+    let integrals = cie_integrals();
+    let white = xyz_to_rgb(integrals.map(|integral| integral / integrals[1]));
+    let rgb = xyz_to_rgb(xyz.map(|value| value / integrals[1]));
+    RGB(rgb.0 / white.0, rgb.1 / white.1, rgb.2 / white.2)
+}
+
+// Smooth reflectance or radiance spectrum of an RGB color at a wavelength, a mix of three bumps
+// for blue, green and red that add up to one everywhere. Gray colors become flat spectra, so
+// white surfaces reflect all wavelengths alike, and colors between zero and one stay in there.
+pub fn upsample(color: RGB, lambda: Float) -> Float {
+    let blue = 1.0 - smoothstep(460.0, 520.0, lambda);
+    let red = smoothstep(570.0, 620.0, lambda);
+    color.2 * blue + color.1 * (1.0 - blue - red) + color.0 * red
+}
+
+fn smoothstep(low: Float, high: Float, x: Float) -> Float {
+    let t = ((x - low) / (high - low)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Planck's law for a black body at kelvin, at a wavelength in nanometers, relative to its value
+// at the peak of the curve. Cool bodies are red, hot ones blue.
+#[allow(clippy::excessive_precision)]
+pub fn blackbody(lambda: Float, kelvin: Float) -> Float {
+    let planck = |lambda: Float| {
+        // In meters, hc/k is 0.0143877 m·K
+        let meters = lambda * 1e-9;
+        1.0 / (meters.powi(5) * ((0.0143877 / (meters * kelvin)).exp() - 1.0))
+    };
+    // Wien's displacement law
+    let peak = 2.8977721e-3 / kelvin * 1e9;
+    planck(lambda) / planck(peak)
+}
+
+// Index of refraction at a wavelength by Cauchy's equation n = A + B / λ², with λ in micrometers.
+// index is the value at the sodium D line at 589.3nm, dispersion is B in µm².
+pub fn cauchy(index: Float, dispersion: Float, lambda: Float) -> Float {
+    let (micrometers, sodium) = (lambda / 1000.0, 0.5893);
+    index + dispersion * (1.0 / (micrometers * micrometers) - 1.0 / (sodium * sodium))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Float;
+    use crate::RGB;
+    use crate::spectral::{blackbody, cauchy, cie_cmf, upsample, WavelengthSampling, Wavelengths, LAMBDA_MAX, LAMBDA_MIN};
+    use crate::utils::seed_rng;
+
+    // Average of to_rgb over many sets of wavelengths, for a spectrum given as a function
+    fn spectrum_to_rgb(sampling: WavelengthSampling, spectrum: impl Fn(Float) -> Float) -> RGB {
+        seed_rng(11);
+        let count = 20_000;
+        let sum = (0..count).fold(RGB::zeros(), |sum, _| {
+            let wavelengths = Wavelengths::sample(sampling);
+            let [a, b, c] = wavelengths.lambda.map(&spectrum);
+            sum + wavelengths.to_rgb(RGB(a, b, c))
+        });
+        sum * (1.0 / count as Float)
+    }
+
+    #[test]
+    fn test_cmf_values() {
+        // Published CIE 1931 2° values
+        let close = |actual: Float, expected: Float| (actual - expected).abs() < 1e-4;
+        let [x, y, z] = cie_cmf(450.0);
+        assert!(close(x, 0.3362) && close(y, 0.0380) && close(z, 1.7721));
+        let [x, y, z] = cie_cmf(600.0);
+        assert!(close(x, 1.0622) && close(y, 0.6310) && close(z, 0.0008));
+        let [x, y, z] = cie_cmf(530.0);
+        assert!(close(x, 0.1655) && close(y, 0.8620) && close(z, 0.0422));
+        // In between the table interpolates, ȳ peaks at 1 around 555nm
+        assert!((cie_cmf(555.0)[1] - 1.0).abs() < 0.01);
+        assert!((cie_cmf(445.0)[2] - 1.7597).abs() < 0.02);
+        assert_eq!(cie_cmf(LAMBDA_MIN - 1.0), [0.0; 3]);
+        assert_eq!(cie_cmf(800.0), [0.0; 3]);
+        assert!(close(cie_cmf(LAMBDA_MAX)[0], 0.00144));
+    }
+
+    #[test]
+    fn test_to_rgb() {
+        // The equal energy white is white with both ways of drawing wavelengths
+        for sampling in [WavelengthSampling::Uniform, WavelengthSampling::Hero] {
+            let white = spectrum_to_rgb(sampling, |_| 1.0);
+            assert!([white.0, white.1, white.2].iter().all(|c| (c - 1.0).abs() < 0.03), "{:?} {:?}", sampling, white);
+        }
+        // Hero wavelengths cover the range evenly, wrapping around at the end
+        seed_rng(3);
+        for _ in 0..100 {
+            let lambda = Wavelengths::sample(WavelengthSampling::Hero).lambda;
+            assert!(lambda.iter().all(|l| (LAMBDA_MIN..LAMBDA_MAX).contains(l)));
+            let mut sorted = lambda;
+            sorted.sort_by(Float::total_cmp);
+            assert!((sorted[1] - sorted[0] - 350.0 / 3.0).abs() < 1e-3 && (sorted[2] - sorted[1] - 350.0 / 3.0).abs() < 1e-3);
+        }
+
+        // Narrow bands come out in their colors
+        let band = |center: Float| move |lambda: Float| if (lambda - center).abs() < 15.0 { 1.0 } else { 0.0 };
+        let blue = spectrum_to_rgb(WavelengthSampling::Hero, band(450.0));
+        assert!(blue.2 > blue.1 && blue.2 > blue.0, "{:?}", blue);
+        let green = spectrum_to_rgb(WavelengthSampling::Hero, band(530.0));
+        assert!(green.1 > green.0 && green.1 > green.2, "{:?}", green);
+        let red = spectrum_to_rgb(WavelengthSampling::Hero, band(630.0));
+        assert!(red.0 > red.1 && red.0 > red.2, "{:?}", red);
+    }
+
+    #[test]
+    fn test_upsample() {
+        for lambda in [380.0, 450.0, 500.0, 555.0, 600.0, 700.0] {
+            assert!((upsample(RGB::white(), lambda) - 1.0).abs() < 1e-12);
+            assert!((upsample(RGB(0.3, 0.3, 0.3), lambda) - 0.3).abs() < 1e-12);
+            let value = upsample(RGB(0.9, 0.2, 0.6), lambda);
+            assert!((0.2..=0.9).contains(&value));
+        }
+        // Upsampled colors keep their hue
+        for color in [RGB(0.8, 0.1, 0.1), RGB(0.1, 0.8, 0.1), RGB(0.1, 0.1, 0.8)] {
+            let rgb = spectrum_to_rgb(WavelengthSampling::Hero, |lambda| upsample(color, lambda));
+            let largest = |c: RGB| if c.0 > c.1 && c.0 > c.2 { 0 } else if c.1 > c.2 { 1 } else { 2 };
+            assert_eq!(largest(rgb), largest(color), "{:?} {:?}", color, rgb);
+        }
+    }
+
+    #[test]
+    fn test_blackbody() {
+        // One at the peak, which moves to shorter wavelengths as it gets hotter
+        assert!((blackbody(2.897772e-3 / 5000.0 * 1e9, 5000.0) - 1.0).abs() < 1e-9);
+        assert!(blackbody(500.0, 5000.0) < 1.0 && blackbody(700.0, 5000.0) < 1.0);
+        let candle = spectrum_to_rgb(WavelengthSampling::Hero, |lambda| blackbody(lambda, 2000.0));
+        assert!(candle.0 > candle.1 && candle.1 > candle.2, "{:?}", candle);
+        let sky = spectrum_to_rgb(WavelengthSampling::Hero, |lambda| blackbody(lambda, 12000.0));
+        assert!(sky.2 > sky.0, "{:?}", sky);
+    }
+
+    #[test]
+    fn test_cauchy() {
+        // Glass bends blue more than red, and is index at the sodium line
+        assert!((cauchy(1.5, 0.0042, 589.3) - 1.5).abs() < 1e-9);
+        assert!(cauchy(1.5, 0.0042, 450.0) > cauchy(1.5, 0.0042, 650.0));
+        assert_eq!(cauchy(1.5, 0.0, 450.0), 1.5);
+    }
+}
@@ -0,0 +1,218 @@
+// Golden image regression tests: tiny fixed scenes rendered at a fixed size, sample count and
+// seed, compared with reference images checked in under tests/golden.
+//
+// Adding a golden: add a variant to ReferenceScene with its name, scene and threshold, list it in
+// ReferenceScene::ALL, then run `UPDATE_GOLDENS=1 cargo test --test golden` once. That writes
+// tests/golden/<name>.hdr, which goes into the same commit. The same command rewrites every
+// golden after a change that is meant to alter the output, look at the new images first.
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use na::point;
+use crate::camera::Camera;
+use crate::Float;
+use crate::image::compare::compare;
+use crate::image::{FloatImage, Image, PPM};
+use crate::material::{Dielectric, DiffuseLight, Lambertian, Metal};
+use crate::RGB;
+use crate::scene::{Scene, Sphere, Triangle};
+
+// Set to anything to write the goldens instead of checking against them
+pub const UPDATE_ENV: &str = "UPDATE_GOLDENS";
+
+const WIDTH: usize = 48;
+const HEIGHT: usize = 32;
+const SAMPLES: u32 = 16;
+const MAX_BOUNCES: u32 = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReferenceScene {
+    // Diffuse sphere on a diffuse ground under the sky
+    OneSphere,
+    // Metal, glass and diffuse spheres side by side
+    MetalGlassTrio,
+    // A diffuse sphere lit only by a square light above it
+    EmissiveQuad,
+}
+
+// Lowest similarity to the golden that still passes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GoldenThreshold {
+    pub psnr: Float,
+    pub ssim: Float,
+}
+
+impl ReferenceScene {
+    pub const ALL: [ReferenceScene; 3] = [ReferenceScene::OneSphere, ReferenceScene::MetalGlassTrio, ReferenceScene::EmissiveQuad];
+
+    // File name of the golden, without the extension
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReferenceScene::OneSphere => "one_sphere",
+            ReferenceScene::MetalGlassTrio => "metal_glass_trio",
+            ReferenceScene::EmissiveQuad => "emissive_quad",
+        }
+    }
+
+    pub fn scene(&self) -> Scene {
+        let mut scene = Scene::new();
+        scene.add(Sphere { center: point![0.0, -100.5, -1.0], radius: 100.0, material: Lambertian::new(RGB(0.5, 0.5, 0.5)).into() });
+        match self {
+            ReferenceScene::OneSphere => {
+                scene.add(Sphere { center: point![0.0, 0.0, -1.0], radius: 0.5, material: Lambertian::new(RGB(0.7, 0.3, 0.3)).into() });
+            },
+            ReferenceScene::MetalGlassTrio => {
+                scene.add(Sphere { center: point![-1.0, 0.0, -1.0], radius: 0.5, material: Metal::new(RGB(0.8, 0.8, 0.8), 0.1).into() });
+                scene.add(Sphere { center: point![0.0, 0.0, -1.0], radius: 0.5, material: Dielectric::new(1.5).into() });
+                scene.add(Sphere { center: point![1.0, 0.0, -1.0], radius: 0.5, material: Lambertian::new(RGB(0.1, 0.2, 0.5)).into() });
+            },
+            ReferenceScene::EmissiveQuad => {
+                scene.add(Sphere { center: point![0.0, 0.0, -1.0], radius: 0.5, material: Lambertian::new(RGB(0.7, 0.7, 0.7)).into() });
+                let corners = [point![-0.5, 1.2, -1.5], point![0.5, 1.2, -1.5], point![0.5, 1.2, -0.5], point![-0.5, 1.2, -0.5]];
+                for [a, b, c] in [[0, 1, 2], [0, 2, 3]] {
+                    scene.add(Triangle {
+                        vertices: [corners[a], corners[b], corners[c]],
+                        normals: None,
+                        uvs: None,
+                        material: DiffuseLight::new(RGB(4.0, 4.0, 4.0)).into(),
+                    });
+                }
+            },
+        }
+        scene
+    }
+
+    pub fn camera(&self, seed: u64) -> Camera {
+        let camera = Camera::builder()
+            .dimensions(WIDTH, HEIGHT)
+            .samples_per_pixel(SAMPLES)
+            .max_bounces(MAX_BOUNCES)
+            .fov(70.0)
+            .look_from(point![0.0, 0.3, 1.0])
+            .look_at(point![0.0, 0.0, -1.0])
+            .focus_dist(2.0)
+            .seed(seed);
+        let camera = if *self == ReferenceScene::EmissiveQuad { camera.background(RGB::zeros()) } else { camera };
+        camera.build().unwrap()
+    }
+
+    // The RGBE goldens alone cost some precision, a render matching one comes out above 50 dB.
+    // That leaves room for the f32 build and for floating point differences between platforms,
+    // while another sample pattern stays far below (around 20 to 30 dB with another seed).
+    pub fn threshold(&self) -> GoldenThreshold {
+        match self {
+            ReferenceScene::OneSphere => GoldenThreshold { psnr: 45.0, ssim: 0.99 },
+            ReferenceScene::MetalGlassTrio => GoldenThreshold { psnr: 45.0, ssim: 0.99 },
+            ReferenceScene::EmissiveQuad => GoldenThreshold { psnr: 45.0, ssim: 0.995 },
+        }
+    }
+
+    pub fn golden_path(&self) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(format!("{}.hdr", self.name()))
+    }
+}
+
+// The reference scene rendered with its camera
+pub fn render_reference(scene: ReferenceScene, seed: u64) -> Box<PPM> {
+    render_scene(scene, scene.scene(), seed)
+}
+
+// A variant of the reference scene, e.g. built with a different structure, rendered with the
+// reference camera
+pub fn render_scene(reference: ReferenceScene, scene: Scene, seed: u64) -> Box<PPM> {
+    reference.camera(seed).renderer().render_parallel(Arc::new(scene))
+}
+
+// Panics if the image isn't close enough to the golden of the scene. With UPDATE_GOLDENS set,
+// the image becomes the golden instead.
+pub fn assert_golden(scene: ReferenceScene, image: &PPM) {
+    let path = scene.golden_path();
+    if std::env::var_os(UPDATE_ENV).is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut file = std::fs::File::create(&path).unwrap();
+        image.to_float_image().save(&mut file).unwrap();
+        return;
+    }
+
+    let golden = std::fs::File::open(&path).and_then(read_hdr).unwrap_or_else(|e| {
+        panic!("can't read the golden {}: {}, run with {}=1 to create it", path.display(), e, UPDATE_ENV)
+    });
+    let comparison = compare(&image.to_float_image(), &golden).unwrap_or_else(|e| panic!("{}: {}", scene.name(), e));
+    let threshold = scene.threshold();
+    assert!(
+        comparison.psnr >= threshold.psnr && comparison.ssim >= threshold.ssim && comparison.nan_pixels == 0,
+        "{} differs from its golden: {:?}, needs {:?}. If that's intended, run with {}=1 to update it.",
+        scene.name(), comparison, threshold, UPDATE_ENV
+    );
+}
+
+// Radiance .hdr with flat RGBE scanlines, as FloatImage::save writes them
+pub fn read_hdr(reader: impl Read) -> std::io::Result<FloatImage> {
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    // Header lines up to an empty one, then the size
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("no image size"));
+        }
+        if line.trim().is_empty() {
+            break;
+        }
+    }
+    line.clear();
+    reader.read_line(&mut line)?;
+    let size: Vec<&str> = line.split_whitespace().collect();
+    let (height, width) = match size[..] {
+        ["-Y", height, "+X", width] => (height.parse(), width.parse()),
+        _ => return Err(invalid("unsupported orientation")),
+    };
+    let (Ok(height), Ok(width)) = (height, width) else {
+        return Err(invalid("invalid image size"));
+    };
+
+    let mut bytes = vec![0; width * height * 4];
+    reader.read_exact(&mut bytes)?;
+    let mut image = FloatImage::new(width, height);
+    for (idx, rgbe) in bytes.chunks(4).enumerate() {
+        if rgbe[3] == 0 {
+            continue;
+        }
+        // Middle of the range each mantissa was truncated from, zero stays black
+        let scale = Float::powi(2.0, rgbe[3] as i32 - 128 - 8);
+        let channel = |mantissa: u8| if mantissa == 0 { 0.0 } else { (mantissa as Float + 0.5) * scale };
+        image[(idx / width, idx % width)] = RGB(channel(rgbe[0]), channel(rgbe[1]), channel(rgbe[2]));
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::image::{FloatImage, Image};
+    use crate::RGB;
+    use crate::testing::read_hdr;
+
+    #[test]
+    fn test_hdr_round_trip() {
+        let mut image = FloatImage::new(3, 2);
+        image[(0, 0)] = RGB(0.5, 0.25, 0.125);
+        image[(0, 2)] = RGB(40.0, 1.0, 0.0);
+        image[(1, 1)] = RGB(0.001, 0.002, 0.003);
+        let mut bytes = vec![];
+        image.save(&mut bytes).unwrap();
+
+        let read = read_hdr(&bytes[..]).unwrap();
+        assert_eq!((read.width(), read.height()), (3, 2));
+        assert_eq!(read[(1, 0)].0, 0.0);
+        assert_eq!(read[(0, 2)].2, 0.0);
+        // Eight bits of mantissa per channel
+        for (a, b) in image.pixels().iter().zip(read.pixels()) {
+            let largest = a.0.max(a.1).max(a.2);
+            assert!((a.0 - b.0).abs() <= largest / 128.0 && (a.2 - b.2).abs() <= largest / 128.0, "{:?} {:?}", a, b);
+        }
+        assert!(read_hdr(&b"#?RADIANCE\n\n+Y 2 +X 3\n"[..]).is_err());
+        assert!(read_hdr(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
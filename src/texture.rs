@@ -0,0 +1,270 @@
+use crate::Float;
+use crate::RGB;
+
+mod cache;
+
+pub use cache::{TextureCache, TextureError};
+
+// How an image texture is looked up
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum TextureFilter {
+    // The texel under the point of the full size image, blocky up close and aliased far away, for
+    // pixel art looks
+    Nearest,
+    // Blend of the four texels around the point in the mip level closest to the footprint
+    Bilinear,
+    // Bilinear in the two mip levels around the footprint, blended between them
+    #[default]
+    Trilinear,
+}
+
+// Footprint of a hit in texture space for every unit of distance from the ray origin, before the
+// bias. About a pixel of a 1000 pixel wide image with a 60 degree field of view, on a texture
+// spanning one unit.
+pub const FOOTPRINT_PER_DISTANCE: Float = 1e-3;
+
+// Image looked up by texture coordinates. (0, 0) is the top left corner of the image and (1, 1)
+// the bottom right, coordinates outside of that repeat the image.
+#[derive(Clone, Debug)]
+pub struct ImageTexture {
+    // The image and its halvings down to 1x1, each box filtered from the one before
+    levels: Vec<MipLevel>,
+    filter: TextureFilter,
+    lod_bias: Float, // Added to the mip level, higher is blurrier
+}
+
+#[derive(Clone, Debug)]
+struct MipLevel {
+    width: usize,
+    height: usize,
+    pixels: Vec<RGB>, // Linear colors, row by row from the top
+}
+
+impl MipLevel {
+    // Repeats in both directions
+    fn texel(&self, x: isize, y: isize) -> RGB {
+        let x = x.rem_euclid(self.width as isize) as usize;
+        let y = y.rem_euclid(self.height as isize) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    fn nearest(&self, u: Float, v: Float) -> RGB {
+        let x = ((u - u.floor()) * self.width as Float) as usize;
+        let y = ((v - v.floor()) * self.height as Float) as usize;
+        self.pixels[y.min(self.height - 1) * self.width + x.min(self.width - 1)]
+    }
+
+    // Texel centers sit at half pixel offsets
+    fn bilinear(&self, u: Float, v: Float) -> RGB {
+        let x = (u - u.floor()) * self.width as Float - 0.5;
+        let y = (v - v.floor()) * self.height as Float - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let top = self.texel(x0, y0).lerp(self.texel(x0 + 1, y0), fx);
+        let bottom = self.texel(x0, y0 + 1).lerp(self.texel(x0 + 1, y0 + 1), fx);
+        top.lerp(bottom, fy)
+    }
+
+    // Average of every 2x2 block, the last row or column of an odd size is folded into the one
+    // before
+    fn halve(&self) -> MipLevel {
+        let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let xs = 2 * x..(2 * x + 2).min(self.width);
+                let ys = 2 * y..(2 * y + 2).min(self.height);
+                let count = (xs.len() * ys.len()) as Float;
+                let sum = ys.flat_map(|y| xs.clone().map(move |x| (x, y)))
+                    .fold(RGB::zeros(), |sum, (x, y)| sum + self.pixels[y * self.width + x]);
+                pixels.push(sum * (1.0 / count));
+            }
+        }
+        MipLevel { width, height, pixels }
+    }
+}
+
+impl ImageTexture {
+    pub fn new(width: usize, height: usize, pixels: Vec<RGB>) -> Option<Self> {
+        if width == 0 || height == 0 || pixels.len() != width * height {
+            return None;
+        }
+        let mut levels = vec![MipLevel { width, height, pixels }];
+        while let Some(last) = levels.last().filter(|level| level.width > 1 || level.height > 1) {
+            let next = last.halve();
+            levels.push(next);
+        }
+        Some(Self { levels, filter: TextureFilter::default(), lod_bias: 0.0 })
+    }
+
+    // 8 bit sRGB encoded pixels with the given number of channels, anything after the third is ignored
+    pub fn from_srgb8(width: usize, height: usize, channels: usize, bytes: &[u8]) -> Option<Self> {
+        if channels < 3 || bytes.len() != width * height * channels {
+            return None;
+        }
+        let pixels = bytes.chunks_exact(channels)
+            .map(|px| RGB(srgb_to_linear(px[0]), srgb_to_linear(px[1]), srgb_to_linear(px[2])))
+            .collect();
+        Self::new(width, height, pixels)
+    }
+
+    pub fn filter(mut self, filter: TextureFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // Mip levels added to the one picked from the footprint, negative for sharper textures
+    pub fn lod_bias(mut self, bias: Float) -> Self {
+        self.lod_bias = bias;
+        self
+    }
+
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
+
+    // Mip levels including the full size image, level n is 2^n times smaller
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    // Pixel (x, y) of a mip level, (width, height) of the level is at least 1
+    pub fn level_pixel(&self, level: usize, x: usize, y: usize) -> RGB {
+        let level = &self.levels[level];
+        level.pixels[y * level.width + x]
+    }
+
+    pub fn level_size(&self, level: usize) -> (usize, usize) {
+        (self.levels[level].width, self.levels[level].height)
+    }
+
+    // Pixel data of all mip levels, what keeping the texture around costs
+    pub fn memory_bytes(&self) -> usize {
+        self.levels.iter().map(|level| level.pixels.len() * size_of::<RGB>()).sum()
+    }
+
+    // The full size image, e.g. for a point seen up close
+    pub fn sample(&self, u: Float, v: Float) -> RGB {
+        self.sample_footprint(u, v, 0.0)
+    }
+
+    // Looks up a point that covers about footprint of the texture across, in texture coordinates
+    pub fn sample_footprint(&self, u: Float, v: Float, footprint: Float) -> RGB {
+        let lod = self.lod(footprint);
+        match self.filter {
+            TextureFilter::Nearest => self.levels[0].nearest(u, v),
+            TextureFilter::Bilinear => self.levels[lod.round() as usize].bilinear(u, v),
+            TextureFilter::Trilinear => {
+                let lower = lod.floor() as usize;
+                let fine = self.levels[lower].bilinear(u, v);
+                if lower + 1 == self.levels.len() {
+                    return fine;
+                }
+                fine.lerp(self.levels[lower + 1].bilinear(u, v), lod - lower as Float)
+            },
+        }
+    }
+
+    // Fractional mip level where a texel is about as wide as the footprint
+    fn lod(&self, footprint: Float) -> Float {
+        let texels = footprint * self.width().max(self.height()) as Float;
+        let lod = if texels > 0.0 { texels.log2() + self.lod_bias } else { self.lod_bias };
+        lod.clamp(0.0, (self.levels.len() - 1) as Float)
+    }
+}
+
+// Estimated footprint of a hit distance away, seen at an angle with cosine cos to the surface
+// normal. Glancing views stretch it, up to ten times.
+pub fn hit_footprint(distance: Float, cos: Float) -> Float {
+    distance * FOOTPRINT_PER_DISTANCE / cos.abs().max(0.1)
+}
+
+pub fn srgb_to_linear(value: u8) -> Float {
+    srgb_decode(value as Float / 255.0)
+}
+
+// sRGB transfer function inverse for an encoded value in [0, 1]
+pub fn srgb_decode(c: Float) -> Float {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Float;
+    use crate::RGB;
+    use crate::texture::{hit_footprint, srgb_to_linear, ImageTexture, TextureFilter};
+
+    #[test]
+    fn test_sample() {
+        let pixels = vec![RGB(1.0, 0.0, 0.0), RGB(0.0, 1.0, 0.0), RGB(0.0, 0.0, 1.0), RGB(1.0, 1.0, 1.0)];
+        let texture = ImageTexture::new(2, 2, pixels).unwrap().filter(TextureFilter::Nearest);
+        assert_eq!(texture.sample(0.25, 0.25).0, 1.0);
+        assert_eq!(texture.sample(0.75, 0.25).1, 1.0);
+        assert_eq!(texture.sample(0.25, 0.75).2, 1.0);
+        // Edges and repetition
+        assert_eq!(texture.sample(1.0, 1.0).0, 1.0);
+        assert_eq!(texture.sample(-0.25, 0.25).1, 1.0);
+        assert_eq!(texture.sample(0.25, 1.75).2, 1.0);
+        assert!(ImageTexture::new(2, 1, vec![RGB::white()]).is_none());
+        // Nearest never blurs, however far away
+        assert_eq!(texture.sample_footprint(0.25, 0.25, 10.0).0, 1.0);
+    }
+
+    fn checker(size: usize) -> ImageTexture {
+        let pixels = (0..size * size).map(|idx| if (idx / size + idx % size).is_multiple_of(2) { RGB::white() } else { RGB::zeros() }).collect();
+        ImageTexture::new(size, size, pixels).unwrap()
+    }
+
+    #[test]
+    fn test_mip_levels() {
+        let texture = checker(2);
+        assert_eq!(texture.levels(), 2);
+        assert_eq!(texture.level_size(1), (1, 1));
+        assert_eq!(texture.level_pixel(1, 0, 0).1, 0.5);
+
+        // Odd sizes fold the last texels into the ones before, down to 1x1
+        let pixels = (0..15).map(|idx| RGB(idx as Float, 0.0, 0.0)).collect();
+        let texture = ImageTexture::new(5, 3, pixels).unwrap();
+        let sizes: Vec<_> = (0..texture.levels()).map(|level| texture.level_size(level)).collect();
+        assert_eq!(sizes, vec![(5, 3), (2, 1), (1, 1)]);
+        assert_eq!(texture.level_pixel(1, 0, 0).0, (0.0 + 1.0 + 5.0 + 6.0) / 4.0);
+        assert_eq!(texture.level_pixel(1, 1, 0).0, (2.0 + 3.0 + 7.0 + 8.0) / 4.0);
+    }
+
+    #[test]
+    fn test_filtering() {
+        let texture = checker(8);
+        // Up close the texels stay sharp at their centers and blend between them
+        assert_eq!(texture.sample(0.5 / 8.0, 0.5 / 8.0).0, 1.0);
+        assert!((texture.sample(1.0 / 8.0, 0.5 / 8.0).0 - 0.5).abs() < 1e-9);
+        // Far away the checker averages out
+        for filter in [TextureFilter::Bilinear, TextureFilter::Trilinear] {
+            let texture = texture.clone().filter(filter);
+            for (u, v) in [(0.5 / 8.0, 0.5 / 8.0), (0.3, 0.7), (1.5 / 8.0, 0.5 / 8.0)] {
+                assert!((texture.sample_footprint(u, v, 1.0).1 - 0.5).abs() < 1e-9);
+                assert!((texture.sample_footprint(u, v, hit_footprint(1e4, 1.0)).1 - 0.5).abs() < 1e-9);
+            }
+        }
+        // Trilinear blends into the next level, all gray, a footprint of sqrt(2) texels is halfway
+        let trilinear = texture.sample_footprint(0.5 / 8.0, 0.5 / 8.0, Float::sqrt(2.0) / 8.0);
+        assert!((trilinear.0 - 0.75).abs() < 1e-6);
+        // The bias shifts the level
+        assert!((texture.clone().lod_bias(3.0).sample(0.5 / 8.0, 0.5 / 8.0).0 - 0.5).abs() < 1e-9);
+        assert!(hit_footprint(10.0, 0.01) > hit_footprint(10.0, 1.0));
+    }
+
+    #[test]
+    fn test_srgb() {
+        assert_eq!(srgb_to_linear(0), 0.0);
+        assert_eq!(srgb_to_linear(255), 1.0);
+        assert!((srgb_to_linear(188) - 0.5).abs() < 0.01);
+        let texture = ImageTexture::from_srgb8(1, 1, 4, &[255, 188, 0, 7]).unwrap();
+        let px = texture.sample(0.5, 0.5);
+        assert!(px.0 == 1.0 && (px.1 - 0.5).abs() < 0.01 && px.2 == 0.0);
+    }
+}
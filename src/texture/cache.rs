@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use crate::Float;
+use crate::image::{Image, LoadError, PPM};
+use crate::png;
+use crate::RGB;
+use crate::texture::{srgb_decode, ImageTexture};
+
+#[derive(Debug)]
+pub enum TextureError {
+    Io(PathBuf, std::io::Error),
+    Image(PathBuf, LoadError),
+    // An image without pixels can't be sampled
+    Empty(PathBuf),
+}
+
+impl Display for TextureError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextureError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            TextureError::Image(path, e) => write!(f, "{}: {}", path.display(), e),
+            TextureError::Empty(path) => write!(f, "{}: the image has no pixels", path.display()),
+        }
+    }
+}
+
+impl Error for TextureError {}
+
+// Image textures by file, each loaded once on first use and shared from then on. Files are told
+// apart by their canonical path, so different spellings of one file share a texture. With a
+// budget, the least recently used textures are dropped to keep the cached pixel data under it
+// and loaded again when asked for. Materials keep their textures alive either way.
+#[derive(Default)]
+pub struct TextureCache {
+    budget: Option<usize>,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<PathBuf, CacheEntry>,
+    resident_bytes: usize,
+    clock: u64, // Counts lookups, for the least recently used order
+}
+
+struct CacheEntry {
+    texture: Arc<ImageTexture>,
+    bytes: usize,
+    last_used: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, path: &Path) -> Option<Arc<ImageTexture>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(path)?;
+        entry.last_used = self.clock;
+        Some(entry.texture.clone())
+    }
+}
+
+impl TextureCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Most bytes of pixel data, see ImageTexture::memory_bytes, kept at once. A texture larger
+    // than the whole budget is loaded for every request.
+    pub fn budget(mut self, bytes: usize) -> Self {
+        self.budget = Some(bytes);
+        self
+    }
+
+    // PNG or PPM file with sRGB encoded colors
+    pub fn get_or_load(&self, path: impl AsRef<Path>) -> Result<Arc<ImageTexture>, TextureError> {
+        let path = path.as_ref();
+        let path = path.canonicalize().map_err(|e| TextureError::Io(path.to_path_buf(), e))?;
+        if let Some(texture) = self.state.lock().unwrap().touch(&path) {
+            return Ok(texture);
+        }
+        // Loads without holding the lock, other textures can be looked up and loaded meanwhile
+        let texture = Arc::new(load_texture(&path)?);
+        Ok(self.insert(path, texture))
+    }
+
+    fn insert(&self, path: PathBuf, texture: Arc<ImageTexture>) -> Arc<ImageTexture> {
+        let mut state = self.state.lock().unwrap();
+        // Another thread got there first, everyone shares its copy
+        if let Some(existing) = state.touch(&path) {
+            return existing;
+        }
+        let bytes = texture.memory_bytes();
+        if self.budget.is_some_and(|budget| bytes > budget) {
+            return texture;
+        }
+        let last_used = state.clock;
+        state.entries.insert(path, CacheEntry { texture: texture.clone(), bytes, last_used });
+        state.resident_bytes += bytes;
+        while self.budget.is_some_and(|budget| state.resident_bytes > budget) {
+            let oldest = state.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(path, _)| path.clone()).unwrap();
+            let evicted = state.entries.remove(&oldest).unwrap();
+            state.resident_bytes -= evicted.bytes;
+        }
+        texture
+    }
+
+    // Pixel data of the cached textures
+    pub fn resident_bytes(&self) -> usize {
+        self.state.lock().unwrap().resident_bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn load_texture(path: &Path) -> Result<ImageTexture, TextureError> {
+    let bytes = std::fs::read(path).map_err(|e| TextureError::Io(path.to_path_buf(), e))?;
+    let image_error = |e: LoadError| TextureError::Image(path.to_path_buf(), e);
+    let (width, height, pixels) = if bytes.starts_with(b"\x89PNG") {
+        let decoded = png::decode(&bytes).map_err(|e| image_error(e.into()))?;
+        let max = decoded.max as Float;
+        let channel = |sample: u16| srgb_decode(sample as Float / max);
+        // Gray is spread to all channels, alpha is ignored
+        let pixels = decoded.samples.chunks_exact(decoded.channels).map(|px| match decoded.channels {
+            1 | 2 => RGB(channel(px[0]), channel(px[0]), channel(px[0])),
+            _ => RGB(channel(px[0]), channel(px[1]), channel(px[2])),
+        }).collect();
+        (decoded.width, decoded.height, pixels)
+    } else {
+        // Encoded values as they are, the sRGB curve isn't a plain gamma
+        let image = PPM::load_with_gamma(&mut &bytes[..], 1.0).map_err(image_error)?;
+        let pixels = image.pixels().iter().map(|px| RGB(srgb_decode(px.0), srgb_decode(px.1), srgb_decode(px.2))).collect();
+        (image.width(), image.height(), pixels)
+    };
+    ImageTexture::new(width, height, pixels).ok_or_else(|| TextureError::Empty(path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use crate::png::{self, ColorType};
+    use crate::texture::{srgb_to_linear, TextureCache, TextureError};
+
+    // A width x 1 PPM in the temp directory with the red channel counting up from first
+    fn write_texture(name: &str, width: usize, first: u8) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("raytracer_texture_{}_{}.ppm", std::process::id(), name));
+        let pixels: Vec<String> = (0..width).map(|j| format!("{} 0 255", first as usize + j)).collect();
+        std::fs::write(&path, format!("P3\n{} 1\n255\n{}\n", width, pixels.join("\n"))).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_shared() {
+        let path = write_texture("shared", 2, 10);
+        let other = write_texture("shared_other", 2, 20);
+        let cache = TextureCache::new();
+        let texture = cache.get_or_load(&path).unwrap();
+        assert_eq!(texture.level_pixel(0, 1, 0).0, srgb_to_linear(11));
+        assert_eq!(texture.level_pixel(0, 0, 0).2, 1.0);
+
+        // The same file by another spelling, and from many threads at once
+        let dotted = path.parent().unwrap().join(".").join(path.file_name().unwrap());
+        assert!(Arc::ptr_eq(&texture, &cache.get_or_load(&dotted).unwrap()));
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8).map(|_| scope.spawn(|| cache.get_or_load(&path).unwrap())).collect();
+            for handle in handles {
+                assert!(Arc::ptr_eq(&texture, &handle.join().unwrap()));
+            }
+        });
+
+        let second = cache.get_or_load(&other).unwrap();
+        assert!(!Arc::ptr_eq(&texture, &second));
+        assert_eq!(second.level_pixel(0, 1, 0).0, srgb_to_linear(21));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.resident_bytes(), texture.memory_bytes() + second.memory_bytes());
+
+        for path in [path, other] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_budget() {
+        let paths: Vec<_> = (0..3).map(|idx| write_texture(&format!("budget_{}", idx), 8, 40 * idx as u8)).collect();
+        let bytes = TextureCache::new().get_or_load(&paths[0]).unwrap().memory_bytes();
+        let cache = TextureCache::new().budget(2 * bytes + bytes / 2);
+
+        let first = cache.get_or_load(&paths[0]).unwrap();
+        let second = cache.get_or_load(&paths[1]).unwrap();
+        // Using the first again makes the second the oldest
+        cache.get_or_load(&paths[0]).unwrap();
+        cache.get_or_load(&paths[2]).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.resident_bytes() <= 2 * bytes + bytes / 2);
+        assert!(Arc::ptr_eq(&first, &cache.get_or_load(&paths[0]).unwrap()));
+
+        // Dropped textures come back from the file
+        let reloaded = cache.get_or_load(&paths[1]).unwrap();
+        assert!(!Arc::ptr_eq(&second, &reloaded));
+        for x in 0..8 {
+            assert_eq!(reloaded.level_pixel(0, x, 0).0, srgb_to_linear(40 + x as u8));
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(cache.resident_bytes() <= 2 * bytes + bytes / 2);
+
+        // Too large to keep at all
+        let small = TextureCache::new().budget(bytes / 2);
+        let texture = small.get_or_load(&paths[2]).unwrap();
+        assert_eq!(texture.level_pixel(0, 3, 0).0, srgb_to_linear(83));
+        assert!(small.is_empty());
+        assert_eq!(small.resident_bytes(), 0);
+
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_png_and_errors() {
+        let path = std::env::temp_dir().join(format!("raytracer_texture_{}.png", std::process::id()));
+        let mut bytes = vec![];
+        png::encode(2, 1, ColorType::Rgba, &[200, 100, 50, 0, 1, 2, 3, 255], &mut bytes).unwrap();
+        std::fs::write(&path, &bytes).unwrap();
+        let cache = TextureCache::new();
+        let texture = cache.get_or_load(&path).unwrap();
+        let px = texture.level_pixel(0, 0, 0);
+        assert_eq!((px.0, px.1, px.2), (srgb_to_linear(200), srgb_to_linear(100), srgb_to_linear(50)));
+
+        std::fs::write(&path, b"P6\n4 4\n255\n").unwrap();
+        // Still the cached texture, files are only read on first use
+        assert!(Arc::ptr_eq(&texture, &cache.get_or_load(&path).unwrap()));
+        let err = TextureCache::new().get_or_load(&path).unwrap_err();
+        assert!(matches!(err, TextureError::Image(..)), "{}", err);
+        assert!(err.to_string().contains("ends after 0 of 48 samples"), "{}", err);
+        std::fs::write(&path, b"P6\n0 0\n255\n").unwrap();
+        assert!(matches!(TextureCache::new().get_or_load(&path), Err(TextureError::Empty(_))));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(cache.get_or_load(&path), Err(TextureError::Io(..))));
+    }
+}
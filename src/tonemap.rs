@@ -0,0 +1,203 @@
+use crate::Float;
+use crate::RGB;
+
+// Maps linear, averaged radiance to the [0, 1] display range before gamma correction
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ToneMap {
+    // Everything above 1.0 is clipped
+    #[default]
+    Clamp,
+    // Extended Reinhard, white_point is the smallest value mapped to pure white
+    Reinhard { white_point: Float },
+    // Narkowicz's fit of the ACES filmic curve
+    AcesApprox,
+}
+
+impl ToneMap {
+    pub fn apply(&self, color: RGB) -> RGB {
+        RGB(self.map(color.0), self.map(color.1), self.map(color.2))
+    }
+
+    fn map(&self, x: Float) -> Float {
+        let x = x.max(0.0);
+        let mapped = match *self {
+            ToneMap::Clamp => x,
+            ToneMap::Reinhard { white_point } => {
+                x * (1.0 + x / (white_point * white_point)) / (1.0 + x)
+            },
+            ToneMap::AcesApprox => {
+                // The curve was fitted to ACES with an exposure of 0.6 applied to the input
+                let x = 0.6 * x;
+                let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+                (x * (a * x + b)) / (x * (c * x + d) + e)
+            }
+        };
+        mapped.clamp(0.0, 1.0)
+    }
+}
+
+// Per-channel (von Kries) scaling applied to linear color before exposure and tone mapping
+#[derive(Copy, Clone, Debug, Default)]
+pub enum WhiteBalance {
+    #[default]
+    None,
+    // Color temperature of the light to neutralize in Kelvin, 6500 leaves colors unchanged
+    Temperature(Float),
+    // This color becomes a neutral gray of the same luminance
+    GrayPick(RGB),
+}
+
+impl WhiteBalance {
+    pub fn apply(&self, color: RGB) -> RGB {
+        color * self.gains()
+    }
+
+    pub fn gains(&self) -> RGB {
+        match *self {
+            WhiteBalance::None => RGB::white(),
+            WhiteBalance::Temperature(kelvin) => {
+                let reference = kelvin_to_rgb(6500.0);
+                let white = kelvin_to_rgb(kelvin);
+                RGB(reference.0 / white.0, reference.1 / white.1, reference.2 / white.2)
+            },
+            // A pick without some of every channel, e.g. black or pure red, can't be made gray
+            // and leaves colors unchanged
+            WhiteBalance::GrayPick(color) if ![color.0, color.1, color.2].iter().all(|c| c.is_finite() && *c > 0.0) => RGB::white(),
+            WhiteBalance::GrayPick(color) => {
+                let gray = color.luminance();
+                RGB(gray / color.0, gray / color.1, gray / color.2)
+            }
+        }
+    }
+}
+
+// Chromaticity of a black body, cubic spline fit by Kim et al. valid in 1667K..25000K.
+// The coefficients are kept as published, f32 builds round them.
+#[allow(clippy::excessive_precision)]
+pub fn kelvin_to_xy(kelvin: Float) -> (Float, Float) {
+    let t = kelvin.clamp(1667.0, 25000.0);
+    let (t2, t3) = (t * t, t * t * t);
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t3 + 2.1070379e6 / t2 + 0.2226347e3 / t + 0.240390
+    };
+
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t <= 2222.0 {
+        -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x3 - 5.87338670 * x2 + 3.75112997 * x - 0.37001483
+    };
+    (x, y)
+}
+
+// Linear sRGB color of a black body with unit luminance
+pub fn kelvin_to_rgb(kelvin: Float) -> RGB {
+    let (x, y) = kelvin_to_xy(kelvin);
+    let (cx, cy, cz) = (x / y, 1.0, (1.0 - x - y) / y);
+    RGB(
+        3.2406 * cx - 1.5372 * cy - 0.4986 * cz,
+        -0.9689 * cx + 1.8758 * cy + 0.0415 * cz,
+        0.0557 * cx - 0.2040 * cy + 1.0570 * cz
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Float;
+    use crate::RGB;
+    use crate::tonemap::{kelvin_to_rgb, kelvin_to_xy, ToneMap, WhiteBalance};
+
+    const OPERATORS: [ToneMap; 3] = [ToneMap::Clamp, ToneMap::Reinhard { white_point: 4.0 }, ToneMap::AcesApprox];
+
+    #[test]
+    fn test_zero_maps_to_zero() {
+        for op in OPERATORS {
+            let black = op.apply(RGB::default());
+            assert_eq!((black.0, black.1, black.2), (0.0, 0.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn test_monotonic() {
+        for op in OPERATORS {
+            let mut prev = 0.0;
+            for i in 0..2000 {
+                let x = i as Float * 0.01;
+                let y = op.apply(RGB(x, x, x)).0;
+                assert!(y >= prev, "{:?} decreases at {}", op, x);
+                prev = y;
+            }
+        }
+    }
+
+    #[test]
+    fn test_compression() {
+        assert!(ToneMap::AcesApprox.apply(RGB(8.0, 8.0, 8.0)).0 < 1.0);
+        assert_eq!(ToneMap::Clamp.apply(RGB(8.0, 8.0, 8.0)).0, 1.0);
+        assert!(ToneMap::Reinhard { white_point: 16.0 }.apply(RGB(8.0, 8.0, 8.0)).0 < 1.0);
+        assert_eq!(ToneMap::Reinhard { white_point: 4.0 }.apply(RGB(4.0, 4.0, 4.0)).0, 1.0);
+        // Values in the display range are left alone by the default
+        assert_eq!(ToneMap::Clamp.apply(RGB(0.3, 0.3, 0.3)).0, 0.3);
+    }
+
+    #[test]
+    fn test_kelvin_chromaticity() {
+        // Planckian locus values from the CIE tables
+        let (x, y) = kelvin_to_xy(2856.0); // Illuminant A
+        assert!((x - 0.4476).abs() < 1e-3 && (y - 0.4074).abs() < 1e-3);
+        let (x, y) = kelvin_to_xy(5000.0);
+        assert!((x - 0.3451).abs() < 1e-3 && (y - 0.3516).abs() < 1e-3);
+        let (x, y) = kelvin_to_xy(10000.0);
+        assert!((x - 0.2807).abs() < 1e-3 && (y - 0.2884).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_kelvin_colors() {
+        let warm = kelvin_to_rgb(2000.0);
+        assert!(warm.0 > warm.1 && warm.1 > warm.2);
+        let cold = kelvin_to_rgb(12000.0);
+        assert!(cold.2 > cold.1 && cold.2 > cold.0);
+        let neutral = kelvin_to_rgb(6500.0);
+        assert!((neutral.0 - neutral.2).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_white_balance_reference_is_identity() {
+        let white = WhiteBalance::Temperature(6500.0).apply(RGB::white());
+        assert_eq!((white.0, white.1, white.2), (1.0, 1.0, 1.0));
+        let white = WhiteBalance::None.apply(RGB::white());
+        assert_eq!((white.0, white.1, white.2), (1.0, 1.0, 1.0));
+
+        // Neutralizing a warm light makes colors cooler
+        let balanced = WhiteBalance::Temperature(3000.0).apply(RGB::white());
+        assert!(balanced.2 > balanced.0);
+        let lit = kelvin_to_rgb(3000.0);
+        let neutral = WhiteBalance::Temperature(3000.0).apply(lit);
+        let reference = kelvin_to_rgb(6500.0);
+        assert!((neutral.0 - reference.0).abs() < 1e-12 && (neutral.2 - reference.2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_gray_pick_neutralizes_color() {
+        let pick = RGB(0.8, 0.5, 0.2);
+        let gray = WhiteBalance::GrayPick(pick).apply(pick);
+        assert!((gray.0 - gray.1).abs() < 1e-12 && (gray.1 - gray.2).abs() < 1e-12);
+        assert!((gray.0 - pick.luminance()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_degenerate_gray_pick() {
+        let color = RGB(0.3, 0.6, 0.9);
+        for pick in [RGB(1.0, 0.0, 0.0), RGB::zeros(), RGB(0.5, -0.1, 0.5), RGB(Float::NAN, 0.5, 0.5), RGB(Float::INFINITY, 1.0, 1.0)] {
+            let balance = WhiteBalance::GrayPick(pick);
+            let gains = balance.gains();
+            assert_eq!((gains.0, gains.1, gains.2), (1.0, 1.0, 1.0));
+            let balanced = balance.apply(color);
+            assert_eq!((balanced.0, balanced.1, balanced.2), (color.0, color.1, color.2));
+        }
+    }
+}
@@ -1,31 +1,103 @@
-use std::f64::consts::PI;
-use na::{vector, Vector3};
-use rand::{random, Rng};
+use std::cell::RefCell;
+use crate::consts::PI;
+use na::{vector, Point3, Vector3};
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+use crate::Float;
+use crate::onb;
 
-pub const INF: f64 = f64::MAX;
+pub const INF: Float = Float::MAX;
 
-pub fn degrees_to_radians(degrees: f64) -> f64 {
+// Tolerances follow the precision of Float. Below NEAR_ZERO in every component a vector counts
+// as zero, about the square root of the machine epsilon.
+#[cfg(not(feature = "f32"))]
+pub const NEAR_ZERO: Float = 1e-8;
+#[cfg(feature = "f32")]
+pub const NEAR_ZERO: Float = 1e-4;
+
+// Hits closer than this are ignored. Rays leaving a surface start off it already, see
+// offset_origin, so this only has to be above zero.
+#[cfg(not(feature = "f32"))]
+pub const MIN_T: Float = 1e-9;
+#[cfg(feature = "f32")]
+pub const MIN_T: Float = 1e-6;
+
+// Bound on the rounding error of a hit point, relative to the size of its coordinates and the
+// distance the ray travelled to it
+#[cfg(not(feature = "f32"))]
+pub const OFFSET_EPSILON: Float = 1e-9;
+#[cfg(feature = "f32")]
+pub const OFFSET_EPSILON: Float = 1e-4;
+
+// Difference allowed between results of about magnitude 1 that only differ by rounding, ulps
+// steps of Float. Lets tests hold with f32 as well as f64, scale it by larger magnitudes.
+pub fn tolerance(ulps: Float) -> Float {
+    ulps * Float::EPSILON
+}
+
+thread_local! {
+    // All sampling goes through this generator so renders can be made reproducible
+    static RNG: RefCell<SmallRng> = RefCell::new(initial_rng());
+}
+
+#[cfg(feature = "entropy")]
+fn initial_rng() -> SmallRng {
+    SmallRng::from_entropy()
+}
+
+// No OS entropy, e.g. on wasm32, so only seeded renders differ
+#[cfg(not(feature = "entropy"))]
+fn initial_rng() -> SmallRng {
+    SmallRng::seed_from_u64(0)
+}
+
+pub fn degrees_to_radians(degrees: Float) -> Float {
     degrees * PI / 180.0
 }
-pub fn rand() -> f64 {
-    random::<f64>()
+
+// Restarts the random sequence of the current thread
+pub fn seed_rng(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = SmallRng::seed_from_u64(seed));
+}
+
+// Current state of the generator, restore_rng later continues the sequence from there
+pub fn save_rng() -> SmallRng {
+    RNG.with(|rng| rng.borrow().clone())
+}
+
+pub fn restore_rng(state: SmallRng) {
+    RNG.with(|rng| *rng.borrow_mut() = state);
+}
+
+// Combines several values into one well mixed seed (SplitMix64 finalizer)
+pub fn hash_seed(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e37_79b9_7f4a_7c15, |acc: u64, v| {
+        let mut z = (acc ^ v).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    })
+}
+
+pub fn rand() -> Float {
+    RNG.with(|rng| rng.borrow_mut().gen::<Float>())
 }
 
-pub fn rand_range(min: f64, max: f64) -> f64 {
-    rand::thread_rng().gen_range(min..max)
+pub fn rand_range(min: Float, max: Float) -> Float {
+    RNG.with(|rng| rng.borrow_mut().gen_range(min..max))
 }
 
-pub fn rand_in_unit_sphere() -> Vector3<f64> {
+pub fn rand_in_unit_sphere() -> Vector3<Float> {
     loop {
         let distribution = rand::distributions::Uniform::new(-1.0, 1.0);
-        let random = Vector3::<f64>::from_distribution(&distribution, &mut rand::thread_rng());
+        let random = RNG.with(|rng| Vector3::<Float>::from_distribution(&distribution, &mut *rng.borrow_mut()));
         if random.norm_squared() < 1.0 {
             return random
         }
     }
 }
 
-pub fn rand_in_unit_disk() -> Vector3<f64> {
+pub fn rand_in_unit_disk() -> Vector3<Float> {
     loop {
         let p = vector![rand_range(-1.0, 1.0), rand_range(-1.0, 1.0), 0.0];
         if p.norm_squared() < 1.0 {
@@ -34,11 +106,11 @@ pub fn rand_in_unit_disk() -> Vector3<f64> {
     }
 }
 
-pub fn rand_unit_vector() -> Vector3<f64> {
+pub fn rand_unit_vector() -> Vector3<Float> {
     rand_in_unit_sphere().normalize()
 }
 
-pub fn rand_on_hemisphere(normal: &Vector3<f64>) -> Vector3<f64> {
+pub fn rand_on_hemisphere(normal: &Vector3<Float>) -> Vector3<Float> {
     let on_unit_sphere = rand_unit_vector();
     if on_unit_sphere.dot(normal) > 0.0 { // In the same hemisphere as the normal
         on_unit_sphere
@@ -47,29 +119,41 @@ pub fn rand_on_hemisphere(normal: &Vector3<f64>) -> Vector3<f64> {
     }
 }
 
-pub fn gamma_correct(linear: f64) -> f64 {
+// Cosine distributed direction around +z, see onb::random_cosine_direction
+pub fn rand_cosine_direction() -> Vector3<Float> {
+    RNG.with(|rng| onb::random_cosine_direction(&mut *rng.borrow_mut()))
+}
+
+pub fn gamma_correct(linear: Float) -> Float {
     linear.sqrt()
 }
 
-pub fn reflect(ray: &Vector3<f64>, normal: &Vector3<f64>) -> Vector3<f64> {
-    ray - 2.0 * ray.dot(&normal) * normal
+pub fn reflect(ray: &Vector3<Float>, normal: &Vector3<Float>) -> Vector3<Float> {
+    ray - 2.0 * ray.dot(normal) * normal
 }
 
-pub fn refract(uv: &Vector3<f64>, n: &Vector3<f64>, etai_over_etat: f64) -> Vector3<f64> {
-    let cos_theta = f64::min((-uv).dot(n), 1.0);
+pub fn refract(uv: &Vector3<Float>, n: &Vector3<Float>, etai_over_etat: Float) -> Vector3<Float> {
+    let cos_theta = Float::min((-uv).dot(n), 1.0);
     let r_out_perp = etai_over_etat * (uv + cos_theta * n);
     let r_out_parallel = -(1.0 - r_out_perp.norm_squared()).abs().sqrt() * n;
     r_out_perp + r_out_parallel
 
 }
 
+// Moves a point computed on a surface off it along the geometric normal, to the side direction
+// points to, so a ray starting there can't hit the surface again because of rounding errors.
+// The offset scales with the error bound of the point, the same at any scene scale.
+pub fn offset_origin(p: Point3<Float>, normal: &Vector3<Float>, direction: &Vector3<Float>, distance: Float) -> Point3<Float> {
+    let offset = OFFSET_EPSILON * p.coords.amax().max(distance) * normal;
+    if direction.dot(normal) < 0.0 { p - offset } else { p + offset }
+}
+
 pub trait NearZero {
     fn is_near_zero(&self) -> bool;
 }
 
-impl NearZero for Vector3<f64> {
+impl NearZero for Vector3<Float> {
     fn is_near_zero(&self) -> bool {
-        let eps = 1e-8;
-        self.x.abs() < eps && self.y.abs() < eps && self.z.abs() < eps
+        self.x.abs() < NEAR_ZERO && self.y.abs() < NEAR_ZERO && self.z.abs() < NEAR_ZERO
     }
 }
\ No newline at end of file
@@ -0,0 +1,56 @@
+// Bindings for the browser. Build with
+// `cargo build --lib --target wasm32-unknown-unknown --no-default-features`, there are no threads
+// or OS entropy there, so renders run on the calling thread and are seeded by the caller.
+
+use wasm_bindgen::prelude::*;
+use crate::camera::Renderer;
+use crate::scene::loader::load_json;
+use crate::scene::Scene;
+
+// A render of a JSON scene that goes a few rows at a time, so the page stays responsive
+#[wasm_bindgen]
+pub struct WasmRender {
+    renderer: Renderer,
+    scene: Scene,
+    rgba: Vec<u8>,
+    next_row: usize,
+}
+
+#[wasm_bindgen]
+impl WasmRender {
+    #[wasm_bindgen(constructor)]
+    pub fn new(scene_json: &str, seed: u64) -> Result<WasmRender, JsError> {
+        let loaded = load_json(scene_json).map_err(|e| JsError::new(&e.to_string()))?;
+        let camera = loaded.camera.seed(seed).build().map_err(|e| JsError::new(&e.to_string()))?;
+        let renderer = camera.renderer();
+        let rgba = vec![0; renderer.width() * renderer.height() * 4];
+        Ok(WasmRender { renderer, scene: loaded.scene, rgba, next_row: 0 })
+    }
+
+    pub fn width(&self) -> usize {
+        self.renderer.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.renderer.height()
+    }
+
+    // Renders the next row_count rows, true once the image is done
+    pub fn render_rows(&mut self, row_count: usize) -> bool {
+        self.next_row = self.renderer.render_rows(&self.scene, self.next_row, row_count, &mut self.rgba).expect("the buffer is made to size");
+        self.next_row == self.renderer.height()
+    }
+
+    // RGBA bytes of the image so far, rows not rendered yet are transparent black
+    pub fn pixels(&self) -> Vec<u8> {
+        self.rgba.clone()
+    }
+}
+
+// The whole image in one call, as RGBA bytes
+#[wasm_bindgen]
+pub fn render(scene_json: &str, seed: u64) -> Result<Vec<u8>, JsError> {
+    let mut render = WasmRender::new(scene_json, seed)?;
+    while !render.render_rows(render.height()) {}
+    Ok(render.rgba)
+}
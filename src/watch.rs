@@ -0,0 +1,194 @@
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use crate::camera::{CancelToken, Camera};
+use crate::cli::OutputFormat;
+use crate::image::PPM;
+use crate::scene::Scene;
+
+// What happened in the watch loop. Generations count the successful loads, starting at 1.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WatchEvent {
+    Started { generation: usize },
+    // The render was told to stop because the file changed, it may have been done already
+    Cancelled { generation: usize },
+    Saved { generation: usize, path: PathBuf },
+    // The edited file didn't load, the render of the last good version carries on
+    LoadFailed(String),
+    SaveFailed(String),
+}
+
+pub type WatchReport = Arc<dyn Fn(&WatchEvent) + Send + Sync>;
+
+struct RenderTask {
+    cancel: CancelToken,
+    thread: JoinHandle<()>,
+}
+
+// Renders whatever load returns, then waits for changed and renders again. load is only called
+// again after a change, changed blocks until the next one and returns false to stop watching.
+// A new render only starts, and the previous one is only cancelled, once the new version loads.
+// Every finished render replaces output in one step, cancelled ones leave it alone.
+pub fn watch_loop(
+    mut load: impl FnMut() -> std::result::Result<(Arc<Scene>, Camera), String>,
+    output: &Path,
+    format: OutputFormat,
+    mut changed: impl FnMut() -> bool,
+    report: WatchReport,
+) {
+    let mut generation = 0;
+    let mut current: Option<RenderTask> = None;
+    loop {
+        match load() {
+            Ok((scene, camera)) => {
+                if let Some(task) = current.take() {
+                    task.cancel.cancel();
+                    report(&WatchEvent::Cancelled { generation });
+                    let _ = task.thread.join();
+                }
+                generation += 1;
+                current = Some(start_render(scene, camera, output.to_path_buf(), format, generation, report.clone()));
+            },
+            Err(message) => report(&WatchEvent::LoadFailed(message)),
+        }
+        if !changed() {
+            break;
+        }
+    }
+    // Let the last render finish
+    if let Some(task) = current {
+        let _ = task.thread.join();
+    }
+}
+
+fn start_render(
+    scene: Arc<Scene>,
+    camera: Camera,
+    output: PathBuf,
+    format: OutputFormat,
+    generation: usize,
+    report: WatchReport
+) -> RenderTask {
+    let cancel = CancelToken::new();
+    let token = cancel.clone();
+    report(&WatchEvent::Started { generation });
+    let thread = std::thread::spawn(move || {
+        let Some(rendered) = camera.renderer().render_cancellable(scene, &token) else {
+            return;
+        };
+        match save_atomically(&rendered.beauty, format, &output) {
+            Ok(()) => report(&WatchEvent::Saved { generation, path: output }),
+            Err(e) => report(&WatchEvent::SaveFailed(format!("{}: {}", output.display(), e))),
+        }
+    });
+    RenderTask { cancel, thread }
+}
+
+// Writes next to path first and renames, so readers never see half an image
+pub fn save_atomically(image: &PPM, format: OutputFormat, path: &Path) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut file = std::fs::File::create(&temp)?;
+    format.save(image, &mut file)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp, path)
+}
+
+// Blocks until the modification time of path changes, checking every interval. A file that is
+// missing for a moment while an editor saves it counts as changed once it's back.
+pub fn poll_changes(path: &Path, interval: Duration) -> impl FnMut() -> bool {
+    let path = path.to_path_buf();
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last: Option<SystemTime> = modified(&path);
+    move || loop {
+        std::thread::sleep(interval);
+        let now = modified(&path);
+        if now.is_some() && now != last {
+            last = now;
+            return true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use na::point;
+    use crate::camera::Camera;
+    use crate::cli::OutputFormat;
+    use crate::material::Lambertian;
+    use crate::RGB;
+    use crate::scene::Scene;
+    use crate::watch::{poll_changes, watch_loop, WatchEvent};
+
+    fn scene() -> Arc<Scene> {
+        let mut scene = Scene::new();
+        scene.add_sphere(point![0.0, 0.0, -1.0], 0.5, Lambertian::new(RGB(0.5, 0.5, 0.5)));
+        Arc::new(scene)
+    }
+
+    #[test]
+    fn test_change_restarts_render() {
+        let dir = std::env::temp_dir().join(format!("raytracer_watch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("watched.ppm");
+
+        // The first version takes far too long to finish, the second is tiny, the third is broken
+        let mut versions = vec![
+            Ok(Camera::builder().dimensions(2000, 2000).samples_per_pixel(500)),
+            Ok(Camera::builder().dimensions(4, 3).samples_per_pixel(1)),
+            Err("line 3: expected a number".to_string()),
+        ].into_iter();
+        let load = || {
+            let camera = versions.next().unwrap()?;
+            Ok((scene(), camera.build().unwrap()))
+        };
+        let mut changes = 2;
+        let changed = || {
+            changes -= 1;
+            changes >= 0
+        };
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        watch_loop(load, &output, OutputFormat::Ppm, changed, Arc::new(move |event: &WatchEvent| recorded.lock().unwrap().push(event.clone())));
+
+        let events = events.lock().unwrap().clone();
+        assert_eq!(events[..3], [
+            WatchEvent::Started { generation: 1 },
+            WatchEvent::Cancelled { generation: 1 },
+            WatchEvent::Started { generation: 2 },
+        ]);
+        assert!(events.contains(&WatchEvent::LoadFailed("line 3: expected a number".to_string())));
+        assert!(events.contains(&WatchEvent::Saved { generation: 2, path: output.clone() }));
+        assert!(!events.iter().any(|event| matches!(event, WatchEvent::Saved { generation: 1, .. })));
+
+        // The broken version left the image of the second one
+        let image = std::fs::read(&output).unwrap();
+        assert!(image.starts_with(b"P6\n4 3\n"));
+        assert!(!dir.join("watched.ppm.tmp").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_poll_changes() {
+        let path = std::env::temp_dir().join(format!("raytracer_poll_{}.json", std::process::id()));
+        std::fs::write(&path, "{}").unwrap();
+        let mut changed = poll_changes(&path, Duration::from_millis(5));
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                let file = std::fs::File::options().write(true).open(&path).unwrap();
+                file.set_modified(std::time::SystemTime::now() + Duration::from_secs(10)).unwrap();
+            })
+        };
+        assert!(changed());
+        writer.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::Arc;
+use na::point;
+use raytracer::camera::Camera;
+use raytracer::image::compare::compare;
+use raytracer::material::{Lambertian, Metal};
+use raytracer::scene::{Scene, Sphere};
+use raytracer::RGB;
+
+extern crate nalgebra as na;
+
+fn scene() -> Arc<Scene> {
+    let mut scene = Scene::new();
+    scene.add(Sphere {
+        center: point![0.0, -100.5, -1.0],
+        radius: 100.0,
+        material: Lambertian::new(RGB(0.8, 0.8, 0.0)).into()
+    });
+    scene.add(Sphere {
+        center: point![0.0, 0.0, -1.0],
+        radius: 0.5,
+        material: Lambertian::new(RGB(0.1, 0.2, 0.5)).into()
+    });
+    scene.add(Sphere {
+        center: point![1.0, 0.0, -1.0],
+        radius: 0.5,
+        material: Metal::new(RGB(0.8, 0.6, 0.2), 0.0).into()
+    });
+    Arc::new(scene)
+}
+
+fn camera(samples: u32, seed: u64) -> Camera {
+    Camera::builder()
+        .width(32)
+        .aspect_ratio(1.5)
+        .samples_per_pixel(samples)
+        .fov(60.0)
+        .look_from(point![0.0, 0.0, 1.0])
+        .look_at(point![0.0, 0.0, -1.0])
+        .focus_dist(2.0)
+        .seed(seed)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn renders_with_different_seeds_converge() {
+    let scene = scene();
+    let a = camera(64, 1).renderer().render_parallel(scene.clone()).to_float_image();
+    let b = camera(64, 2).renderer().render_parallel(scene).to_float_image();
+
+    let result = compare(&a, &b).unwrap();
+    assert!(result.psnr > 25.0, "{:?}", result);
+    assert!(result.ssim > 0.8, "{:?}", result);
+    assert_eq!(result.nan_pixels, 0);
+}
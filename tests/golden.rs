@@ -0,0 +1,44 @@
+use raytracer::scene::sphere_list::SphereList;
+use raytracer::scene::{Primitive, Scene};
+use raytracer::testing::{assert_golden, render_reference, render_scene, ReferenceScene};
+
+// Seed all goldens were rendered with
+const SEED: u64 = 1;
+
+#[test]
+fn reference_scenes_match_goldens() {
+    for scene in ReferenceScene::ALL {
+        assert_golden(scene, &render_reference(scene, SEED));
+    }
+}
+
+// Hit-for-hit the same as the separate spheres, so the image can't change either
+#[test]
+fn sphere_list_matches_golden() {
+    for reference in ReferenceScene::ALL {
+        let mut scene = Scene::new();
+        let mut list = SphereList::new();
+        for primitive in reference.scene().iter() {
+            match primitive {
+                Primitive::Sphere(sphere) => {
+                    let material = list.add_material(sphere.material.clone());
+                    list.push(sphere.center, sphere.radius, material);
+                },
+                other => {
+                    scene.add(other.clone());
+                }
+            }
+        }
+        scene.add(std::sync::Arc::new(list));
+        assert_golden(reference, &render_scene(reference, scene, SEED));
+    }
+}
+
+// The serial renderer takes the same path through every pixel as the parallel one
+#[test]
+fn serial_render_matches_golden() {
+    for reference in ReferenceScene::ALL {
+        let camera = reference.camera(SEED);
+        assert_golden(reference, &camera.render(&reference.scene()));
+    }
+}
@@ -0,0 +1,60 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+use raytracer::image::compare::compare;
+use raytracer::image::FloatImage;
+use raytracer::{Float, RGB};
+
+// Builds the renderer in release mode in its own target directory, with or without the f32
+// feature, and renders final_scene. Returns the image and how long the render took.
+fn render(f32: bool) -> (FloatImage, Duration) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target = root.join("target").join("precision");
+    let mut build = Command::new(env!("CARGO"));
+    build.current_dir(root).args(["build", "--release", "--target-dir"]).arg(&target);
+    if f32 {
+        build.args(["--features", "f32"]);
+    }
+    assert!(build.status().unwrap().success());
+
+    let output = std::env::temp_dir().join(format!("precision-{}-{}.ppm", std::process::id(), if f32 { 32 } else { 64 }));
+    let start = Instant::now();
+    let status = Command::new(target.join("release").join("raytracer"))
+        .args(["--scene", "final_scene", "--width", "300", "--samples", "32", "--seed", "1", "--output"])
+        .arg(&output)
+        .output()
+        .unwrap()
+        .status;
+    let elapsed = start.elapsed();
+    assert!(status.success());
+    (read_ppm(&output), elapsed)
+}
+
+// The binary PPM the renderer writes, back in [0, 1]
+fn read_ppm(path: &PathBuf) -> FloatImage {
+    let bytes = std::fs::read(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    let header: Vec<&[u8]> = bytes.splitn(4, |&b| b == b'\n').collect();
+    let size = std::str::from_utf8(header[1]).unwrap();
+    let (w, h) = size.split_once(' ').unwrap();
+    let (w, h): (usize, usize) = (w.parse().unwrap(), h.parse().unwrap());
+    let mut image = FloatImage::new(w, h);
+    for (idx, px) in header[3].chunks(3).enumerate() {
+        let channel = |v: u8| v as Float / 255.0;
+        image[(idx / w, idx % w)] = RGB(channel(px[0]), channel(px[1]), channel(px[2]));
+    }
+    image
+}
+
+// Slow, it builds the crate twice: cargo test --test precision -- --ignored --nocapture
+#[test]
+#[ignore]
+fn f32_render_matches_f64() {
+    let (double, double_time) = render(false);
+    let (single, single_time) = render(true);
+    let result = compare(&double, &single).unwrap();
+    println!("f64 render {:.2?}, f32 render {:.2?}, {:?}", double_time, single_time, result);
+    assert!(result.psnr > 35.0, "{:?}", result);
+    assert!(result.ssim > 0.95, "{:?}", result);
+    assert_eq!(result.nan_pixels, 0);
+}
@@ -0,0 +1,104 @@
+#![cfg(feature = "serve")]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use raytracer::serve::Server;
+
+const SCENE: &str = r#"{
+  "camera": { "aspect_ratio": 2.0, "lookfrom": [0.0, 0.0, 0.0], "lookat": [0.0, 0.0, -1.0], "focus_dist": 1.0 },
+  "materials": { "grey": { "type": "lambertian", "albedo": [0.5, 0.5, 0.5] } },
+  "objects": [{ "type": "sphere", "center": [0.0, 0.0, -1.0], "radius": 0.5, "material": "grey" }]
+}"#;
+
+fn start() -> SocketAddr {
+    let server = Server::bind("127.0.0.1:0").unwrap();
+    let address = server.local_addr().unwrap();
+    std::thread::spawn(move || server.run());
+    address
+}
+
+// Status, headers in lower case and body
+fn request(address: SocketAddr, method: &str, target: &str, body: &str) -> (u16, Vec<(String, String)>, Vec<u8>) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", method, target, body.len(), body).unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).unwrap();
+
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8(response[..split].to_vec()).unwrap();
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+    let headers = lines.map(|line| {
+        let (name, value) = line.split_once(':').unwrap();
+        (name.to_ascii_lowercase(), value.trim().to_string())
+    }).collect();
+    (status, headers, response[split + 4..].to_vec())
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> &'a str {
+    &headers.iter().find(|(header, _)| header == name).unwrap().1
+}
+
+// Width and height from the IHDR chunk, which always comes first
+fn png_size(png: &[u8]) -> (u32, u32) {
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    assert_eq!(&png[12..16], b"IHDR");
+    let number = |at: usize| u32::from_be_bytes(png[at..at + 4].try_into().unwrap());
+    (number(16), number(20))
+}
+
+#[test]
+fn test_render_and_poll() {
+    let address = start();
+
+    // Straight to the image
+    let (status, headers, body) = request(address, "POST", "/render?width=16&samples=2", SCENE);
+    assert_eq!(status, 200);
+    assert_eq!(header(&headers, "content-type"), "image/png");
+    assert_eq!(png_size(&body), (16, 8));
+    let first: u64 = header(&headers, "x-job-id").parse().unwrap();
+
+    // Submitted and polled
+    let (status, _, body) = request(address, "POST", "/render?width=40&samples=8&wait=false", SCENE);
+    assert_eq!(status, 202);
+    let submitted: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let id = submitted["id"].as_u64().unwrap();
+    assert_eq!(id, first + 1);
+
+    let mut last = -1.0;
+    loop {
+        let (status, _, body) = request(address, "GET", &format!("/progress/{}", id), "");
+        assert_eq!(status, 200);
+        let progress: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let percent = progress["percent"].as_f64().unwrap();
+        assert!(percent >= last && percent <= 100.0);
+        last = percent;
+        if progress["state"] == "done" {
+            assert_eq!(percent, 100.0);
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let (status, _, body) = request(address, "GET", &format!("/result/{}", id), "");
+    assert_eq!(status, 200);
+    assert_eq!(png_size(&body), (40, 20));
+}
+
+#[test]
+fn test_bad_requests() {
+    let address = start();
+
+    // The loader's message comes back
+    let (status, _, body) = request(address, "POST", "/render", r#"{ "objects": [{ "type": "sphere", "center": [0.0], "radius": 1.0, "material": "grey" }] }"#);
+    assert_eq!(status, 400);
+    let message = String::from_utf8(body).unwrap();
+    assert!(message.starts_with("objects[0]"), "{}", message);
+
+    assert_eq!(request(address, "POST", "/render?width=0", SCENE).0, 400);
+    assert_eq!(request(address, "POST", "/render?colour=red", SCENE).0, 400);
+    assert_eq!(request(address, "GET", "/render", "").0, 405);
+    assert_eq!(request(address, "GET", "/progress/99", "").0, 404);
+    assert_eq!(request(address, "GET", "/result/abc", "").0, 404);
+    assert_eq!(request(address, "GET", "/", "").0, 404);
+}